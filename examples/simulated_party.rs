@@ -0,0 +1,46 @@
+//! A round with many users from one process, through an in-process server: a quick load test.
+//! Takes the number of users, 4 by default.
+//!
+//! ```sh
+//! cargo run --release --example simulated_party -- 8
+//! ```
+use anyhow::Error;
+use karma_calculator::{rocket, Score, SimulatedParty, WebClient};
+use rocket::config::{Config, LogLevel};
+use std::time::{Duration, Instant};
+
+const PORT: u16 = 8766;
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    let users: usize = match std::env::args().nth(1) {
+        Some(users) => users.parse()?,
+        None => 4,
+    };
+    let config = Config {
+        port: PORT,
+        log_level: LogLevel::Off,
+        ..Config::debug_default()
+    };
+    tokio::spawn(rocket().configure(config).launch());
+    let client = WebClient::new(&format!("http://127.0.0.1:{PORT}"));
+    while client.get_seed().await.is_err() {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    // User `i` gives `i` karma to everyone else
+    let scores = (0..users)
+        .map(|i| {
+            (0..users)
+                .map(|j| if i == j { 0 } else { i as Score })
+                .collect()
+        })
+        .collect::<Vec<Vec<Score>>>();
+    let start = Instant::now();
+    let balances = SimulatedParty::new(client, users).run(&scores).await?;
+    println!(
+        "{users} users decrypted {balances:?} in {:.1?}",
+        start.elapsed()
+    );
+    Ok(())
+}
@@ -0,0 +1,59 @@
+//! Two users send each other karma through an in-process server, using only the public API.
+//!
+//! ```sh
+//! cargo run --release --example two_party
+//! ```
+use anyhow::Error;
+use karma_calculator::{rocket, Participant, RunOutcome, WebClient};
+use rocket::config::{Config, LogLevel};
+use std::time::Duration;
+
+const PORT: u16 = 8765;
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    let config = Config {
+        port: PORT,
+        log_level: LogLevel::Off,
+        ..Config::debug_default()
+    };
+    tokio::spawn(rocket().configure(config).launch());
+    let url = format!("http://127.0.0.1:{PORT}");
+    // The admin's, to close registration and start the run
+    let client = WebClient::new(&url);
+    while client.get_seed().await.is_err() {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    // Everyone registers, then registration is closed
+    let mut alice = Participant::new(WebClient::new(&url));
+    let mut bob = Participant::new(WebClient::new(&url));
+    alice.join("alice").await?;
+    bob.join("bob").await?;
+    client.conclude_registration().await?;
+
+    // Alice sends Bob 3 karma, Bob sends Alice 5
+    alice.rate(&[0, 3]).await?;
+    bob.rate(&[5, 0]).await?;
+    for participant in [&mut alice, &mut bob] {
+        let receipt = participant.submit().await?;
+        println!(
+            "{} submitted, receipt {}",
+            participant.name(),
+            receipt.signature
+        );
+    }
+
+    client.trigger_fhe_run().await?;
+    println!("Waiting for the FHE run ...");
+    // Everyone decrypts the output with their share of the key
+    for participant in [&mut alice, &mut bob] {
+        assert_eq!(participant.finalize().await?, RunOutcome::Decryptable);
+    }
+    for participant in [&mut alice, &mut bob] {
+        let result = participant.reveal().await?;
+        println!("{} sees balances {:?}", participant.name(), result.balances);
+        assert_eq!(result.balances, vec![2, -2]);
+    }
+    Ok(())
+}
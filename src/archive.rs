@@ -0,0 +1,120 @@
+use crate::circuit::ParameterSet;
+use crate::types::{CircuitOutput, DecryptionShare, Seed, Timestamp, UserId};
+use anyhow::{bail, ensure, Error};
+use rocket::serde::{msgpack, Deserialize, Serialize};
+
+/// Layout: `MAGIC | version: u32 | index length: u64 | index | sections`.
+/// The index is msgpack of [`ArchiveEntry`]s and each section is zstd-compressed msgpack,
+/// so a reader can decode one section without touching the others.
+const MAGIC: &[u8; 8] = b"KARMAARC";
+const VERSION: u32 = 1;
+const HEADER_LEN: usize = MAGIC.len() + 4 + 8;
+const ZSTD_LEVEL: i32 = 19;
+
+const META: &str = "meta";
+const FHE_OUTPUT: &str = "fhe_output";
+const DECRYPTION_SHARES: &str = "decryption_shares";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ArchiveEntry {
+    pub name: String,
+    /// Offset from the end of the index
+    pub offset: u64,
+    /// Compressed length
+    pub len: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ArchiveMeta {
+    pub seed: Seed,
+    pub parameter: ParameterSet,
+    pub archived_at: Timestamp,
+    pub users: Vec<(UserId, String)>,
+}
+
+/// Record of a completed session. Key material is not included.
+#[derive(Debug, Clone)]
+pub struct SessionArchive {
+    pub meta: ArchiveMeta,
+    pub fhe_output: CircuitOutput,
+    /// Decryption shares of each user, `None` if the user never submitted
+    pub decryption_shares: Vec<Option<Vec<DecryptionShare>>>,
+}
+
+impl SessionArchive {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let sections = [
+            (META, compress(&self.meta)),
+            (FHE_OUTPUT, compress(&self.fhe_output)),
+            (DECRYPTION_SHARES, compress(&self.decryption_shares)),
+        ];
+        let mut offset = 0;
+        let mut index = vec![];
+        for (name, body) in sections.iter() {
+            index.push(ArchiveEntry {
+                name: name.to_string(),
+                offset,
+                len: body.len() as u64,
+            });
+            offset += body.len() as u64;
+        }
+        let index = msgpack::to_compact_vec(&index).expect("serializable");
+
+        let mut out = Vec::with_capacity(HEADER_LEN + index.len() + offset as usize);
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        out.extend_from_slice(&(index.len() as u64).to_le_bytes());
+        out.extend_from_slice(&index);
+        for (_, body) in sections {
+            out.extend_from_slice(&body);
+        }
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        Ok(Self {
+            meta: read_section(bytes, META)?,
+            fhe_output: read_section(bytes, FHE_OUTPUT)?,
+            decryption_shares: read_section(bytes, DECRYPTION_SHARES)?,
+        })
+    }
+}
+
+/// Read the index of an archive without decompressing any section
+pub fn read_index(bytes: &[u8]) -> Result<Vec<ArchiveEntry>, Error> {
+    Ok(split(bytes)?.0)
+}
+
+fn split(bytes: &[u8]) -> Result<(Vec<ArchiveEntry>, &[u8]), Error> {
+    ensure!(
+        bytes.len() >= HEADER_LEN && &bytes[..MAGIC.len()] == MAGIC,
+        "Not a karma archive"
+    );
+    let version = u32::from_le_bytes(bytes[8..12].try_into()?);
+    ensure!(version == VERSION, "Unsupported archive version {version}");
+    let index_len = u64::from_le_bytes(bytes[12..20].try_into()?) as usize;
+    ensure!(
+        bytes.len() >= HEADER_LEN + index_len,
+        "Truncated archive index"
+    );
+    let index = msgpack::from_slice(&bytes[HEADER_LEN..HEADER_LEN + index_len])?;
+    Ok((index, &bytes[HEADER_LEN + index_len..]))
+}
+
+fn read_section<T: for<'de> Deserialize<'de>>(bytes: &[u8], name: &str) -> Result<T, Error> {
+    let (index, sections) = split(bytes)?;
+    let Some(entry) = index.iter().find(|entry| entry.name == name) else {
+        bail!("Section {name} not found in archive")
+    };
+    let (start, end) = (entry.offset as usize, (entry.offset + entry.len) as usize);
+    ensure!(end <= sections.len(), "Truncated archive section {name}");
+    let body = zstd::decode_all(&sections[start..end])?;
+    Ok(msgpack::from_slice(&body)?)
+}
+
+fn compress<T: Serialize>(value: &T) -> Vec<u8> {
+    let body = msgpack::to_compact_vec(value).expect("serializable");
+    zstd::encode_all(body.as_slice(), ZSTD_LEVEL).expect("in-memory compression")
+}
@@ -0,0 +1,81 @@
+//! Credentials for the routes only the admin may call, and for each user's submissions
+use crate::types::Error;
+use rand::{thread_rng, Rng};
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use sha2::{Digest, Sha256};
+
+/// Header the admin token is sent in
+pub(crate) const ADMIN_TOKEN_HEADER: &str = "X-Admin-Token";
+
+/// The token from the `admin_token` config, or one made up at launch. `None` only with
+/// `insecure_open_admin`, for tests, when anyone may call the admin routes.
+pub(crate) struct AdminToken(pub(crate) Option<String>);
+
+impl AdminToken {
+    fn accepts(&self, given: Option<&str>) -> bool {
+        match (&self.0, given) {
+            (None, _) => true,
+            (Some(expected), Some(given)) => same_secret(expected, given),
+            (Some(_), None) => false,
+        }
+    }
+}
+
+/// The request carries the admin token. Take it as `Result<AdminGuard, Error>` to answer 403 with the error.
+pub(crate) struct AdminGuard;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminGuard {
+    type Error = Error;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let given = req.headers().get_one(ADMIN_TOKEN_HEADER);
+        match req.rocket().state::<AdminToken>() {
+            Some(token) if token.accepts(given) => Outcome::Success(Self),
+            _ => Outcome::Error((Status::Forbidden, Error::Unauthorized)),
+        }
+    }
+}
+
+/// Header carrying the hex ed25519 signature of a submission, see [`crate::sign_submission`]
+pub(crate) const SIGNATURE_HEADER: &str = "X-Signature";
+
+/// What a user's request carries to prove who sent it: the bearer token of the `Authorization`
+/// header, see [`crate::RegisteredUser::token`], and the signature of the submission, if any
+pub(crate) struct UserAuth {
+    token: Option<String>,
+    pub(crate) signature: Option<String>,
+}
+
+impl UserAuth {
+    pub(crate) fn new_secret() -> String {
+        hex::encode(thread_rng().gen::<[u8; 32]>())
+    }
+
+    pub(crate) fn token_matches(&self, expected: &str) -> bool {
+        self.token
+            .as_deref()
+            .is_some_and(|given| same_secret(expected, given))
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for UserAuth {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let headers = req.headers();
+        let token = headers
+            .get_one("Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(|token| token.trim().to_string());
+        let signature = headers.get_one(SIGNATURE_HEADER).map(str::to_string);
+        Outcome::Success(Self { token, signature })
+    }
+}
+
+/// Compare digests, so the time taken doesn't tell how much of the secret matched
+fn same_secret(expected: &str, given: &str) -> bool {
+    Sha256::digest(expected.as_bytes()) == Sha256::digest(given.as_bytes())
+}
@@ -0,0 +1,110 @@
+//! How long the steps a user takes on their own machine take here, so they can tell before a
+//! round whether the machine is up to it, see `cli bench`.
+use crate::circuit::{derive_server_key, sum_fhe_dyn, CircuitId, ParameterSet};
+use crate::client::on_pool;
+use crate::compiled::karma_add;
+use crate::server::setup;
+use crate::types::{CircuitOutput, EncryptedInput, Score};
+use anyhow::{ensure, Error};
+use phantom_zone::{gen_client_key, gen_server_key_share};
+use rand::{thread_rng, Rng};
+use rocket::serde::msgpack;
+use std::time::{Duration, Instant};
+
+/// Steps of [`bench`], in the order a round takes them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BenchStep {
+    ClientKey,
+    ServerKeyShare,
+    /// Of a score for every user
+    Encryption,
+    /// Of an output for every user
+    DecryptionShares,
+    /// Of a score from every user, as the server does twice for each output. Not part of the
+    /// user's time.
+    ServerSum,
+    /// The same sum, one addition after another, to tell what [`BenchStep::ServerSum`] gains
+    /// by adding in a tree across the pool. Not part of the user's time either.
+    LinearSum,
+}
+
+impl BenchStep {
+    /// Whether the user's machine takes the step in a round, rather than the server
+    pub fn is_users(&self) -> bool {
+        !matches!(self, Self::ServerSum | Self::LinearSum)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BenchReport {
+    pub parameter_set: ParameterSet,
+    pub users: usize,
+    /// How long each step took, in order
+    pub timings: Vec<(BenchStep, Duration)>,
+    /// As uploaded before compression
+    pub key_share_bytes: u64,
+}
+
+/// Time each step of a user of a room of `users` under `parameter`, on a pool of `threads`
+/// like [`on_pool`]. `on_step` is called as each step starts.
+pub fn bench(
+    parameter: ParameterSet,
+    users: usize,
+    threads: Option<usize>,
+    on_step: impl Fn(BenchStep) + Send + Sync,
+) -> Result<BenchReport, Error> {
+    ensure!(
+        (1..=parameter.max_parties()).contains(&users),
+        "{parameter:?} supports 1 to {} users",
+        parameter.max_parties()
+    );
+    setup(&thread_rng().gen(), parameter);
+    on_pool(parameter, threads, || {
+        let mut timings = vec![];
+        let ck = timed(&mut timings, BenchStep::ClientKey, &on_step, gen_client_key);
+        let sks = timed(&mut timings, BenchStep::ServerKeyShare, &on_step, || {
+            gen_server_key_share(0, users, &ck)
+        });
+        let key_share_bytes = msgpack::to_vec(&sks)?.len() as u64;
+        let scores: Vec<Score> = vec![0; users];
+        let cipher = timed(&mut timings, BenchStep::Encryption, &on_step, || {
+            EncryptedInput::from_plain(&ck, &scores)
+        });
+
+        // The outputs of a run take everyone's key share. A server key of this user alone
+        // switches the cipher into words of the same shape instead.
+        derive_server_key(&[gen_server_key_share(0, 1, &ck)]);
+        let words = cipher.unpack(0);
+        let output = CircuitOutput::new(words.clone(), vec![], CircuitId::Karma);
+        timed(&mut timings, BenchStep::DecryptionShares, &on_step, || {
+            output.gen_decryption_shares(&ck)
+        });
+        timed(&mut timings, BenchStep::ServerSum, &on_step, || {
+            sum_fhe_dyn(&words, parameter)
+        });
+        timed(&mut timings, BenchStep::LinearSum, &on_step, || {
+            let (first, rest) = words.split_first().expect("At least one user");
+            rest.iter()
+                .fold(first.clone(), |sum, word| karma_add(&sum, word))
+        });
+        Ok(BenchReport {
+            parameter_set: parameter,
+            users,
+            timings,
+            key_share_bytes,
+        })
+    })?
+}
+
+fn timed<R>(
+    timings: &mut Vec<(BenchStep, Duration)>,
+    step: BenchStep,
+    on_step: &impl Fn(BenchStep),
+    f: impl FnOnce() -> R,
+) -> R {
+    on_step(step);
+    let started = Instant::now();
+    let result = f();
+    timings.push((step, started.elapsed()));
+    result
+}
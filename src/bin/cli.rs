@@ -1,24 +1,474 @@
 use anyhow::{anyhow, bail, ensure, Error};
-use clap::{command, Parser};
+use clap::{ArgAction, Args, CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+use ed25519_dalek::SigningKey;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use indicatif::{HumanBytes, HumanDuration, ProgressBar, ProgressStyle};
 use itertools::Itertools;
 use karma_calculator::{
-    setup, CircuitOutput, DecryptionSharesMap, EncryptedInput, Score, ServerState, UserId,
-    WebClient,
+    bench, fetch_peer_shares, local_rocket, on_pool, parse_pin, read_index, serve_shares, setup,
+    BenchStep, CircuitOutput, Dashboard, DashboardChange, DecryptionSharesMap, EncryptedInput,
+    InputContract, JobStatus, KarmaDiff, NetworkError, NextStep, ParameterSet, ParticipantId,
+    PeerShares, Receipt, RegisteredUser, RoomEvent, RoomId, RoundResult, Score, SelfScorePolicy,
+    ServerKeyShare, ServerState, Session, SessionArchive, SimulatedParty, TlsOptions,
+    TranscriptArtifact, Trend, UserId, UserStatus, WebClient, PROTOCOL_VERSION,
 };
 use phantom_zone::{gen_client_key, gen_server_key_share, ClientKey};
-use rustyline::{error::ReadlineError, DefaultEditor};
-use std::{collections::HashMap, fmt::Display, iter::zip};
+use rand::{thread_rng, Rng};
+use rustyline::{
+    completion::Completer, error::ReadlineError, highlight::Highlighter, hint::Hinter,
+    history::DefaultHistory, validate::Validator, Context, DefaultEditor, Editor, Helper,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    collections::{hash_map::Entry, HashMap},
+    fmt::Display,
+    fs::OpenOptions,
+    io::{IsTerminal, Write},
+    iter::zip,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+    time::{Duration, Instant, SystemTime},
+};
 use tabled::{settings::Style, Table, Tabled};
+use tokio::net::TcpListener;
+use tokio::time::sleep;
+use tracing::{info, warn};
+use tracing_subscriber::EnvFilter;
+
+/// How often to look for outputs computed while the FHE run goes on
+const OUTPUT_POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// How often `watch` refreshes when no event comes, or the server has no event stream
+const WATCH_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// How long the daemon waits before retrying a failed step or reading the scores file again
+const DAEMON_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+/// Decryption shares downloaded at once
+const SHARE_FETCH_CONCURRENCY: usize = 8;
+/// Signed receipts of this user's submissions, one JSON per line
+const RECEIPTS_FILE: &str = "receipts.jsonl";
+/// Decrypted results of every round this user took part in, one JSON per line
+const RESULTS_FILE: &str = "results.jsonl";
+/// How long the last server key share took, by parameter set and threads, for the next estimate
+const KEYGEN_TIMINGS_FILE: &str = "keygen.json";
+/// Bytes per second of the last upload, for `doctor`'s estimate of a key share upload
+const UPLOAD_SPEED_FILE: &str = "upload.json";
+/// Hex secret key this user registers and signs with, so the server recognizes them in later rounds
+const IDENTITY_FILE: &str = "identity.key";
+/// Passphrase of the session file, asked for if unset
+const SESSION_PASSPHRASE_VAR: &str = "KARMA_SESSION_PASSPHRASE";
+/// Under the home directory, where sessions are kept unless `--session` says otherwise, and the
+/// config file and the profiles' storage
+const KARMA_DIR: &str = ".karma";
+/// Named profiles, in [`KARMA_DIR`]
+const CONFIG_FILE: &str = "config.toml";
+
+/// Set by `--profile`: where the identity, receipts, results and sessions are kept instead of the
+/// working directory
+static STORAGE_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Set by `--threads`: the size of the rayon pool key shares are made on
+static KEYGEN_THREADS: OnceLock<usize> = OnceLock::new();
+
+/// Set by `--json`: stdout then carries JSON only, one value per line, and the prose goes to stderr
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Set by `--yes`: every [`confirm`] is answered yes, for scripts
+static ASSUME_YES: AtomicBool = AtomicBool::new(false);
+
+/// `println!` for prose, which `--json` moves to stderr
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if JSON.load(Ordering::Relaxed) {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    };
+}
+
+fn json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// With `--json`, `value` as a line of JSON. The prose tells it otherwise.
+fn emit(value: &impl Serialize) {
+    if json() {
+        println!(
+            "{}",
+            serde_json::to_string(value).expect("Values printed are serializable")
+        );
+    }
+}
+
+fn report_error(err: &Error) {
+    say!("❌ Error: {:?}", err);
+    emit(&serde_json::json!({ "error": format!("{err:#}") }));
+}
+
+/// As a table, or a JSON array with `--json`
+fn print_table<T: Tabled + Serialize>(rows: &[T]) {
+    if json() {
+        emit(&rows);
+    } else {
+        println!("{}", Table::new(rows).with(Style::ascii_rounded()));
+    }
+}
+
+fn show_dashboard(dashboard: &Dashboard) {
+    if json() {
+        emit(dashboard);
+    } else {
+        dashboard.print_presentation();
+    }
+}
 
-/// HACK: Bound max input value on client side;
-const MAX_INPUT_VALUE: Score = 1000;
+/// Where each peer serves their decryption shares, and the key they registered to check them by
+type Contacts = HashMap<ParticipantId, (String, Option<String>)>;
+/// The seed and parameters my client key was made under, to set up again on resume
+type Crs = ([u8; 32], ParameterSet);
 
+/// Send karma to the other users of a room, encrypted, and decrypt the totals together, from
+/// a prompt or with the subcommands
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    subcommand_negates_reqs = true,
+    args_conflicts_with_subcommands = true
+)]
 struct Cli2 {
+    #[command(subcommand)]
+    command: Option<Commands>,
     /// Optional name to operate on
-    name: String,
-    url: String,
+    #[arg(required_unless_present = "profile")]
+    name: Option<String>,
+    #[arg(required_unless_present = "profile")]
+    url: Option<String>,
+    /// Room to join on the server [default: 0]
+    #[arg(long)]
+    room: Option<RoomId>,
+    /// Profile of `~/.karma/config.toml` to take what isn't given here from, and to keep the
+    /// identity, receipts, results and sessions apart in
+    #[arg(long, global = true)]
+    profile: Option<String>,
+    /// Cap uploads at this many KB/s, for shared connections
+    #[arg(long)]
+    upload_limit: Option<u64>,
+    /// Serve my decryption shares to peers at this host:port, in case the server goes down
+    #[arg(long)]
+    p2p: Option<String>,
+    /// The server's `admin_token`, to conclude registration and start the run
+    #[arg(long)]
+    admin_token: Option<String>,
+    /// Invite code from the admin, for rooms where registering takes one
+    #[arg(long)]
+    invite: Option<String>,
+    /// Where my key and progress are kept encrypted after every step, to quit and resume later.
+    /// By default `~/.karma/<name>-room<room>.session`.
+    #[arg(long)]
+    session: Option<PathBuf>,
+    /// Pick up the saved session
+    #[arg(long)]
+    resume: bool,
+    /// Keep no session, e.g. on a shared machine
+    #[arg(long, conflicts_with_all = ["session", "resume"])]
+    no_session: bool,
+    /// Print results as JSON, one value per line, and everything else to stderr
+    #[arg(long, global = true)]
+    json: bool,
+    /// Threads to generate the server key share on [default: one per core]
+    #[arg(long, global = true)]
+    threads: Option<usize>,
+    /// Answer yes when asked to confirm, e.g. before starting the FHE run or resetting a room
+    #[arg(long, short, global = true)]
+    yes: bool,
+    /// Log to stderr what the client does: `-v` its steps and retries, `-vv` every request too,
+    /// with how long it took and its size
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    verbose: u8,
+    /// Write the log to this file instead, at least as much as `-vv` does, e.g. to attach it when
+    /// reporting a failed upload
+    #[arg(long, global = true)]
+    log_file: Option<PathBuf>,
+    #[command(flatten)]
+    connection: ConnectArgs,
+}
+
+/// How to reach the server, e.g. over `https` with a certificate no public CA signed, or
+/// through a proxy
+#[derive(Args, Debug)]
+struct ConnectArgs {
+    /// PEM file of the CA to trust instead of the public ones
+    #[arg(long, global = true)]
+    ca_cert: Option<PathBuf>,
+    /// Only trust the server certificate with this SHA-256 fingerprint, e.g. from
+    /// `openssl x509 -noout -fingerprint -sha256`
+    #[arg(long, global = true)]
+    pin_cert: Option<String>,
+    /// Send every request through this HTTP proxy
+    #[arg(long, global = true)]
+    proxy: Option<String>,
+    /// Give up on connecting to the server after this many seconds
+    #[arg(long, global = true)]
+    connect_timeout: Option<u64>,
+    /// Give up once the server didn't answer for this many seconds. Uploads take as long as they
+    /// need otherwise.
+    #[arg(long, global = true)]
+    read_timeout: Option<u64>,
+}
+
+impl ConnectArgs {
+    /// A client of `room`, once it's sure the server speaks its protocol
+    async fn connect(&self, url: &str, room: RoomId) -> Result<WebClient, Error> {
+        self.connect_limited(url, room, None).await
+    }
+
+    /// Like [`Self::connect`], with uploads capped at `upload_limit` KB/s
+    async fn connect_limited(
+        &self,
+        url: &str,
+        room: RoomId,
+        upload_limit: Option<u64>,
+    ) -> Result<WebClient, Error> {
+        let client = self.build(url, room, upload_limit)?;
+        client.check_version().await?;
+        Ok(client)
+    }
+
+    /// Like [`Self::connect_limited`], without asking the server anything yet, e.g. to report a
+    /// server of another protocol rather than fail on it
+    fn build(
+        &self,
+        url: &str,
+        room: RoomId,
+        upload_limit: Option<u64>,
+    ) -> Result<WebClient, Error> {
+        let tls = TlsOptions {
+            root_ca: self.ca_cert.as_ref().map(std::fs::read).transpose()?,
+            pinned_cert: self.pin_cert.as_deref().map(parse_pin).transpose()?,
+        };
+        let mut builder = WebClient::builder(url).tls(tls);
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(proxy);
+        }
+        if let Some(secs) = self.connect_timeout {
+            builder = builder.connect_timeout(Duration::from_secs(secs));
+        }
+        if let Some(secs) = self.read_timeout {
+            builder = builder.read_timeout(Duration::from_secs(secs));
+        }
+        if let Some(kb_per_sec) = upload_limit {
+            builder = builder.upload_limit(kb_per_sec);
+        }
+        Ok(builder.build()?.with_room(room))
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Keep a verifiable record of a completed session
+    Archive {
+        #[command(subcommand)]
+        command: ArchiveCommand,
+    },
+    /// Move my client key to another machine, or keep a backup of it
+    Key {
+        #[command(subcommand)]
+        command: KeyCommand,
+    },
+    /// Compare a round's results with the previous round of the room
+    Diff {
+        #[arg(long)]
+        round: u64,
+        #[arg(long, default_value_t = 0)]
+        room: RoomId,
+    },
+    /// Create invite codes to hand out, one per expected participant
+    Invites {
+        url: String,
+        /// How many codes to create
+        count: usize,
+        #[arg(long, default_value_t = 0)]
+        room: RoomId,
+        /// The server's `admin_token`
+        #[arg(long)]
+        admin_token: Option<String>,
+    },
+    /// Start a new round in the room, dropping the users and the inputs of this one, once
+    /// confirmed
+    Reset {
+        url: String,
+        #[arg(long, default_value_t = 0)]
+        room: RoomId,
+        /// The server's `admin_token`
+        #[arg(long)]
+        admin_token: Option<String>,
+        /// Discard a running FHE computation too
+        #[arg(long)]
+        force: bool,
+    },
+    /// Watch a round without taking part, and print the results if the server publishes them
+    Observe {
+        url: String,
+        name: String,
+        #[arg(long, default_value_t = 0)]
+        room: RoomId,
+    },
+    /// Follow the room in a live terminal view: its phase, each user's status and submissions,
+    /// the FHE run, and what `--name` should do next
+    Watch {
+        url: String,
+        #[arg(long, default_value_t = 0)]
+        room: RoomId,
+        /// Whose next step to show
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// List the users of the room
+    Users {
+        url: String,
+        #[arg(long, default_value_t = 0)]
+        room: RoomId,
+        /// Keep following the room, printing only what changes
+        #[arg(long)]
+        watch: bool,
+    },
+    /// Check the server, this client and my session, tell what the room is waiting for, and how
+    /// to fix what's wrong
+    Doctor {
+        url: String,
+        #[arg(long, default_value_t = 0)]
+        room: RoomId,
+        /// Also check that my saved session opens
+        #[arg(long)]
+        name: Option<String>,
+        /// Where my session is kept. By default `~/.karma/<name>-room<room>.session`.
+        #[arg(long, requires = "name")]
+        session: Option<PathBuf>,
+    },
+    /// Print a completion script for the shell, e.g. `cli completions bash > /etc/bash_completion.d/cli`
+    Completions { shell: Shell },
+    /// Print the manual page, e.g. `cli man | man -l -`
+    Man {
+        /// Write a page for every subcommand into this directory instead, e.g. for
+        /// `/usr/local/share/man/man1`
+        #[arg(long)]
+        dir: Option<PathBuf>,
+    },
+    /// Take part in a round unattended, only asking for scores when they are needed
+    Daemon {
+        name: String,
+        url: String,
+        #[arg(long, default_value_t = 0)]
+        room: RoomId,
+        /// File to read my scores from, separated by whitespace in the order of the names
+        #[arg(long, default_value = "scores.txt")]
+        scores: PathBuf,
+        /// Cap uploads at this many KB/s, for shared connections
+        #[arg(long)]
+        upload_limit: Option<u64>,
+        /// Serve my decryption shares to peers at this host:port, in case the server goes down
+        #[arg(long)]
+        p2p: Option<String>,
+        /// Invite code from the admin, for rooms where registering takes one
+        #[arg(long)]
+        invite: Option<String>,
+        /// Keep my key and progress encrypted in this file, to resume after a restart
+        #[arg(long)]
+        session: Option<PathBuf>,
+    },
+    /// Take one command of the prompt on my saved session and exit, e.g. `cli run alice <url>
+    /// submit 3 4 5` in a script. Options go before the command.
+    Run {
+        name: String,
+        url: String,
+        /// The command and its arguments, as entered at the prompt, see `help` there
+        #[arg(required = true, trailing_var_arg = true)]
+        line: Vec<String>,
+        #[arg(long, default_value_t = 0)]
+        room: RoomId,
+        /// Where my session is kept. By default `~/.karma/<name>-room<room>.session`, as for
+        /// the prompt.
+        #[arg(long)]
+        session: Option<PathBuf>,
+        /// Cap uploads at this many KB/s, for shared connections
+        #[arg(long)]
+        upload_limit: Option<u64>,
+        /// Serve my decryption shares to peers at this host:port while the command runs
+        #[arg(long)]
+        p2p: Option<String>,
+        /// The server's `admin_token`, for `conclude`
+        #[arg(long)]
+        admin_token: Option<String>,
+        /// Invite code from the admin, for `setup` in rooms where registering takes one
+        #[arg(long)]
+        invite: Option<String>,
+    },
+    /// Time the steps of a round on this machine, to tell before a round whether it's up to it
+    Bench {
+        /// Users of the room to time [default: 4, or the room's users with --url]
+        #[arg(long)]
+        users: Option<usize>,
+        /// Time the parameters the room of this server uses
+        #[arg(long)]
+        url: Option<String>,
+        #[arg(long, default_value_t = 0)]
+        room: RoomId,
+    },
+    /// Run a whole round on a throwaway local server with simulated users, as a demo or a
+    /// smoke test
+    Simulate {
+        #[arg(long, default_value_t = 3)]
+        users: usize,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum KeyCommand {
+    /// Write my client key, registration and identity to a file encrypted under a passphrase
+    Export {
+        path: PathBuf,
+        #[arg(long)]
+        name: String,
+        #[arg(long, default_value_t = 0)]
+        room: RoomId,
+        /// Where my session is kept. By default `~/.karma/<name>-room<room>.session`.
+        #[arg(long)]
+        session: Option<PathBuf>,
+    },
+    /// Check an exported key against the server, and keep it as the session to resume here
+    Import {
+        path: PathBuf,
+        url: String,
+        /// Where to keep the session. By default `~/.karma/<name>-room<room>.session`.
+        #[arg(long)]
+        session: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ArchiveCommand {
+    /// Download the archive of a completed session, and the server's signature of it next to it
+    Fetch {
+        url: String,
+        /// File to write the archive to. The signature goes to the same path with `.sig` appended.
+        out: PathBuf,
+        #[arg(long, default_value_t = 0)]
+        room: RoomId,
+        /// Once the archive is written, tell the server to drop what's left of the round's
+        /// inputs. Takes the admin token.
+        #[arg(long)]
+        purge: bool,
+    },
+    /// Print the sections and users of an archive file
+    Inspect { path: PathBuf },
 }
 
 enum State {
@@ -33,7 +483,13 @@ enum State {
 
 impl Display for State {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let label = match self {
+        write!(f, "{{{{ {} }}}}", self.label())
+    }
+}
+
+impl State {
+    fn label(&self) -> &'static str {
+        match self {
             State::Init(_) => "Initialization",
             State::Setup(_) => "Setup",
             State::ConcludedRegistration(_) => "Concluded Registration",
@@ -41,52 +497,238 @@ impl Display for State {
             State::TriggeredRun(_) => "Triggered Run",
             State::DownloadedOutput(_) => "Downloaded Output",
             State::Decrypted(_) => "Decrypted",
+        }
+    }
+
+    /// The users scored, once registration has closed
+    fn names(&self) -> &[String] {
+        match self {
+            State::Init(_) | State::Setup(_) => &[],
+            State::ConcludedRegistration(ConcludedRegistration { names, .. })
+            | State::SubmittedInput(SubmittedInput { names, .. })
+            | State::TriggeredRun(StateTriggeredRun { names, .. })
+            | State::DownloadedOutput(StateDownloadedOuput { names, .. }) => names,
+            State::Decrypted(StateDecrypted { result, .. }) => &result.names,
+        }
+    }
+
+    /// The command of the step that's due, as [`State::print_instruction`] tells it
+    fn next_command(&self) -> &'static str {
+        match self {
+            State::Init(_) => "setup",
+            State::Setup(_) => "getNames",
+            State::ConcludedRegistration(ConcludedRegistration { rated: None, .. }) => "rate",
+            State::ConcludedRegistration(_) => "submit",
+            State::SubmittedInput(_) | State::TriggeredRun(_) => "downloadOutput",
+            State::DownloadedOutput(_) => "downloadShares",
+            State::Decrypted(_) => "status",
+        }
+    }
+
+    /// Mine, once registered
+    fn participant_id(&self) -> Option<&ParticipantId> {
+        match self {
+            State::Init(_) | State::Decrypted(_) => None,
+            State::Setup(StateSetup { participant_id, .. })
+            | State::ConcludedRegistration(ConcludedRegistration { participant_id, .. })
+            | State::SubmittedInput(SubmittedInput { participant_id, .. })
+            | State::TriggeredRun(StateTriggeredRun { participant_id, .. })
+            | State::DownloadedOutput(StateDownloadedOuput { participant_id, .. }) => {
+                Some(participant_id)
+            }
+        }
+    }
+
+    /// What to save to come back to this state, `None` once the round is over
+    fn session(&self) -> Option<Session> {
+        let (name, client) = match self {
+            State::Init(StateInit { name, client })
+            | State::Setup(StateSetup { name, client, .. })
+            | State::ConcludedRegistration(ConcludedRegistration { name, client, .. })
+            | State::SubmittedInput(SubmittedInput { name, client, .. })
+            | State::TriggeredRun(StateTriggeredRun { name, client, .. })
+            | State::DownloadedOutput(StateDownloadedOuput { name, client, .. }) => (name, client),
+            State::Decrypted(_) => return None,
         };
-        write!(f, "{{{{ {} }}}}", label)
+        let mut session = Session::new(name, client.room());
+        session.user = self
+            .participant_id()
+            .and_then(|participant_id| client.registered_user(participant_id));
+        match self {
+            State::Init(_) | State::Setup(_) | State::Decrypted(_) => {}
+            State::ConcludedRegistration(s) => {
+                session.ck = Some(s.ck.clone());
+                session.crs = Some(s.crs);
+            }
+            State::SubmittedInput(SubmittedInput {
+                ck, crs, scores, ..
+            })
+            | State::TriggeredRun(StateTriggeredRun {
+                ck, crs, scores, ..
+            }) => {
+                session.ck = Some(ck.clone());
+                session.crs = Some(*crs);
+                session.scores = Some(scores.clone());
+            }
+            State::DownloadedOutput(s) => {
+                session.ck = Some(s.ck.clone());
+                session.crs = Some(s.crs);
+                session.scores = Some(s.scores.clone());
+                session.fhe_output = Some(s.fhe_out.clone());
+                session.decryption_shares = s.shares.clone();
+            }
+        }
+        Some(session)
+    }
+
+    /// Back where `session` left off, once `client` can submit for its user again. If it can't,
+    /// a new round, with the error.
+    async fn resume(session: Session, client: WebClient) -> Result<Self, (Error, Self)> {
+        let Session {
+            name,
+            user,
+            crs,
+            ck,
+            scores,
+            fhe_output,
+            decryption_shares,
+            ..
+        } = session;
+        let Some(user) = user else {
+            return Ok(State::Init(StateInit { name, client }));
+        };
+        let identity = match load_identity() {
+            Ok(identity) => identity,
+            Err(err) => return Err((err, State::Init(StateInit { name, client }))),
+        };
+        let participant_id = user.participant_id.clone();
+        let (Some(ck), Some(crs)) = (ck, crs) else {
+            let user_id = user.id;
+            client.resume_user(user, Some(identity));
+            return Ok(State::Setup(StateSetup {
+                name,
+                client,
+                user_id,
+                participant_id,
+            }));
+        };
+        setup(&crs.0, crs.1);
+        // Users may have been removed in the meantime
+        let checked = async {
+            let dashboard = client.get_dashboard().await?;
+            let user_id = dashboard
+                .user_id_of(&participant_id)
+                .ok_or(anyhow!("You were removed from the room"))?;
+            let contract = match scores {
+                Some(_) => None,
+                None => Some(client.get_circuit().await?),
+            };
+            Ok::<_, Error>((dashboard, user_id, contract))
+        };
+        let (dashboard, user_id, contract) = match checked.await {
+            Ok(checked) => checked,
+            Err(err) => return Err((err, State::Init(StateInit { name, client }))),
+        };
+        client.resume_user(user, Some(identity));
+        client.update_user_id(&participant_id, user_id);
+        let names = dashboard.get_names();
+        Ok(match (scores, fhe_output, contract) {
+            (Some(scores), Some(fhe_out), _) => State::DownloadedOutput(StateDownloadedOuput {
+                name,
+                client,
+                ck,
+                crs,
+                participant_id,
+                names,
+                scores,
+                fhe_out,
+                shares: decryption_shares,
+                contacts: peer_contacts(&dashboard),
+                round: dashboard.round(),
+            }),
+            (Some(scores), None, _) => State::SubmittedInput(SubmittedInput {
+                name,
+                client,
+                ck,
+                crs,
+                participant_id,
+                names,
+                scores,
+            }),
+            (None, _, Some(contract)) => State::ConcludedRegistration(ConcludedRegistration {
+                name,
+                client,
+                ck,
+                crs,
+                user_id,
+                participant_id,
+                names,
+                contract,
+                committed: None,
+                rated: None,
+            }),
+            (None, _, None) => unreachable!("The circuit is fetched for sessions without scores"),
+        })
     }
-}
 
-impl State {
     fn print_status_update(&self) {
         let msg = match self {
             State::Init(StateInit { name, client }) => {
                 format!("Hi {}, we just connected to server {}.", name, client.url())
             }
             State::Setup(StateSetup { .. }) => "✅ Setup completed!".to_string(),
+            State::ConcludedRegistration(ConcludedRegistration { rated: Some(_), .. }) => {
+                "✅ Karma rated!".to_string()
+            }
             State::ConcludedRegistration(_) => "✅ Users' names acquired!".to_string(),
             State::SubmittedInput(_) => "✅ Ciphertext submitted!".to_string(),
             State::TriggeredRun(_) => "✅ FHE run triggered!".to_string(),
             State::DownloadedOutput(_) => "✅ FHE output downloaded!".to_string(),
             State::Decrypted(_) => "✅ FHE output decrypted!".to_string(),
         };
-        println!("{}", msg)
+        say!("{}", msg)
     }
 
     fn print_instruction(&self) {
         let msg = match self {
-            State::Setup(_) => "Enter `conclude` to end registration or `next` to proceed",
-            State::ConcludedRegistration(ConcludedRegistration { names, .. }) => {
-                let total_users = names.len();
+            State::Init(_) => {
+                "Enter `setup` to register. `next` takes whichever step is due, `help` lists the commands"
+            }
+            State::Setup(_) => {
+                "Enter `getNames` once registration has closed, or `conclude` to end it (admin)"
+            }
+            State::ConcludedRegistration(ConcludedRegistration {
+                rated: Some(_), ..
+            }) => "Enter `submit` to encrypt and submit the Karma, or `rate` again",
+            State::ConcludedRegistration(ConcludedRegistration { contract, .. }) => {
+                let (min, max) = contract.value_range;
+                let self_score = match contract.self_score_policy {
+                    SelfScorePolicy::Ignored => "(The Karma you send to yourself is ignored)",
+                };
                 &[
-                    "Enter `next` with Karma values you'd like to send to each user.",
+                    "Enter `rate` to be asked the Karma you'd like to send to each user, or give them all at once.",
                     &format!(
-                        "Example: `next {}`",
-                        (0..total_users)
+                        "Example: `rate {}`",
+                        (0..contract.scores_expected)
                             .map(|n| n.to_string())
                             .collect::<Vec<String>>()
                             .join(" ")
                     ),
-                    &format!(
-                        "(Maxium Karma you can send for each user: {})",
-                        MAX_INPUT_VALUE
-                    ),
+                    &format!("(Karma you can send to each user: {} to {})", min, max),
+                    self_score,
                 ]
                 .join("\n")
             }
+            State::SubmittedInput(_) => {
+                "Enter `downloadOutput` to start or follow the FHE run and download its output"
+            }
+            State::TriggeredRun(_) => "Enter `downloadOutput` to download the FHE output",
+            State::DownloadedOutput(_) => {
+                "Enter `downloadShares` to collect everyone's decryption shares, then `decrypt`"
+            }
             State::Decrypted(_) => "Exit with `CTRL-D`",
-            _ => "Enter `next` to continue",
         };
-        println!("👇 {}", msg)
+        say!("👇 {}", msg)
     }
 }
 
@@ -98,23 +740,32 @@ struct StateInit {
 struct StateSetup {
     name: String,
     client: WebClient,
-    ck: ClientKey,
     user_id: UserId,
+    participant_id: ParticipantId,
 }
 
 struct ConcludedRegistration {
     name: String,
     client: WebClient,
     ck: ClientKey,
+    crs: Crs,
     user_id: UserId,
+    participant_id: ParticipantId,
     names: Vec<String>,
+    /// What the server's circuit expects of my scores
+    contract: InputContract,
+    /// The scores and cipher committed to, which must be submitted as they are
+    committed: Option<(Vec<Score>, EncryptedInput)>,
+    /// Checked by `rate`, for `submit`
+    rated: Option<Vec<Score>>,
 }
 
 struct SubmittedInput {
     name: String,
     client: WebClient,
     ck: ClientKey,
-    user_id: UserId,
+    crs: Crs,
+    participant_id: ParticipantId,
     names: Vec<String>,
     scores: Vec<Score>,
 }
@@ -123,374 +774,2916 @@ struct StateTriggeredRun {
     name: String,
     client: WebClient,
     ck: ClientKey,
-    user_id: UserId,
+    crs: Crs,
+    participant_id: ParticipantId,
     names: Vec<String>,
     scores: Vec<Score>,
 }
 
 struct StateDownloadedOuput {
-    #[allow(dead_code)]
     name: String,
     client: WebClient,
     ck: ClientKey,
+    crs: Crs,
+    participant_id: ParticipantId,
     names: Vec<String>,
     scores: Vec<Score>,
     fhe_out: CircuitOutput,
     shares: DecryptionSharesMap,
+    /// Last known peer contacts, for when the server goes down
+    contacts: Contacts,
+    round: u64,
 }
 
 struct StateDecrypted {
-    names: Vec<String>,
+    name: String,
     client: WebClient,
     scores: Vec<Score>,
-    decrypted_output: Vec<Score>,
+    result: RoundResult,
+    /// Decrypted balances against the previous round
+    diff: Vec<KarmaDiff>,
+}
+
+/// The session file and the passphrase it's encrypted under
+struct SessionFile {
+    path: PathBuf,
+    passphrase: OnceLock<String>,
+}
+
+impl SessionFile {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            passphrase: OnceLock::new(),
+        }
+    }
+
+    /// `~/.karma/<name>-room<room>.session`, so users sharing a machine keep apart, or in the
+    /// profile's storage
+    fn default_path(name: &str, room: RoomId) -> Result<PathBuf, Error> {
+        let home = std::env::home_dir().ok_or(anyhow!(
+            "No home directory to keep the session in, pass --session"
+        ))?;
+        let dir = match STORAGE_DIR.get() {
+            Some(dir) => dir.clone(),
+            None => home.join(KARMA_DIR),
+        };
+        Ok(dir.join(format!("{name}-room{room}.session")))
+    }
+
+    /// From [`SESSION_PASSPHRASE_VAR`], or asked for the first time it's needed
+    fn passphrase(&self) -> Result<&str, Error> {
+        if let Some(passphrase) = self.passphrase.get() {
+            return Ok(passphrase);
+        }
+        let passphrase = match std::env::var(SESSION_PASSPHRASE_VAR) {
+            Ok(passphrase) => passphrase,
+            Err(_) => {
+                rpassword::prompt_password(format!("Passphrase of {}: ", self.path.display()))?
+            }
+        };
+        Ok(self.passphrase.get_or_init(|| passphrase))
+    }
+
+    fn is_saved(&self) -> bool {
+        self.path.exists()
+    }
+
+    /// Where the saved session left off. If it can't be picked up, a new round, with the error.
+    async fn load(&self, name: String, client: WebClient) -> Result<State, (Error, State)> {
+        let session = match self.read(&name, client.room()) {
+            Ok(session) => session,
+            Err(err) => return Err((err, State::Init(StateInit { name, client }))),
+        };
+        say!("📂 Resuming from {}", self.path.display());
+        State::resume(session, client).await
+    }
+
+    fn read(&self, name: &str, room: RoomId) -> Result<Session, Error> {
+        ensure!(
+            self.is_saved(),
+            "No session saved at {}",
+            self.path.display()
+        );
+        let session = Session::load(&self.path, self.passphrase()?)?;
+        ensure!(
+            session.name == name && session.room == room,
+            "{} is the session of {} in room #{}",
+            self.path.display(),
+            session.name,
+            session.room
+        );
+        Ok(session)
+    }
+
+    /// A failed save only costs the chance to resume, so it's reported rather than fatal.
+    /// A new round has nothing to keep yet, so an earlier session stays until `setup`.
+    /// Once the round is decrypted, the file goes: the next round takes a new key.
+    fn save(&self, state: &State) {
+        if matches!(state, State::Init(_)) {
+            return;
+        }
+        let saved = match state.session() {
+            Some(session) => self.write(&session),
+            None if self.is_saved() => std::fs::remove_file(&self.path).map_err(Error::from),
+            None => Ok(()),
+        };
+        if let Err(err) = saved {
+            say!(
+                "⚠️ Failed to save the session to {}: {err}",
+                self.path.display()
+            );
+        }
+    }
+
+    fn write(&self, session: &Session) -> Result<(), Error> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        session.save(&self.path, self.passphrase()?)
+    }
 }
 
 #[tokio::main]
 async fn main() {
     let cli = Cli2::parse();
-    let name = cli.name;
-    let url: String = cli.url;
+    JSON.store(cli.json, Ordering::Relaxed);
+    ASSUME_YES.store(cli.yes, Ordering::Relaxed);
+    if let Err(err) = init_logging(cli.verbose, cli.log_file.as_deref()) {
+        report_error(&err);
+        return;
+    }
+    if let Some(threads) = cli.threads {
+        KEYGEN_THREADS.set(threads).expect("Set once");
+    }
+    let profile = match cli.profile.as_deref().map(Profile::load).transpose() {
+        Ok(profile) => profile.unwrap_or_default(),
+        Err(err) => {
+            report_error(&err);
+            return;
+        }
+    };
+    if let Some(command) = cli.command {
+        if let Err(err) = run_command(command, &cli.connection).await {
+            report_error(&err);
+        }
+        return;
+    }
+    let (Some(name), Some(url)) = (cli.name.or(profile.name), cli.url.or(profile.url)) else {
+        report_error(&anyhow!(
+            "Give the name and the URL, or a profile with them"
+        ));
+        return;
+    };
+    let room = cli.room.or(profile.room).unwrap_or_default();
 
-    let mut rl = DefaultEditor::new().unwrap();
-    let client = WebClient::new(&url);
-    let mut state = State::Init(StateInit { name, client });
-    println!("{}", state);
+    let mut rl = Editor::<ReplHelper, DefaultHistory>::new().unwrap();
+    let mut client = match cli
+        .connection
+        .connect_limited(&url, room, cli.upload_limit)
+        .await
+    {
+        Ok(client) => client,
+        Err(err) => {
+            report_error(&err);
+            return;
+        }
+    };
+    if let Some(token) = cli.admin_token.or(profile.admin_token) {
+        client = client.with_admin_token(&token);
+    }
+    if let Some(code) = &cli.invite {
+        client = client.with_invite(code);
+    }
+    let path = match cli.session {
+        _ if cli.no_session => None,
+        Some(path) => Some(path),
+        None => match SessionFile::default_path(&name, room) {
+            Ok(path) => Some(path),
+            Err(err) => {
+                report_error(&err);
+                return;
+            }
+        },
+    };
+    let session = path.map(SessionFile::new);
+    let mut state = match &session {
+        Some(session) if cli.resume => match session.load(name, client).await {
+            Ok(state) => state,
+            Err((err, _)) => {
+                report_error(&err);
+                return;
+            }
+        },
+        Some(session) => {
+            if session.is_saved() {
+                say!(
+                    "💾 A session is saved at {}. Enter `resume` to pick it up, or `setup` to start over and replace it",
+                    session.path.display()
+                );
+            }
+            State::Init(StateInit { name, client })
+        }
+        None => State::Init(StateInit { name, client }),
+    };
+    say!("{}", state);
     state.print_status_update();
     state.print_instruction();
     loop {
+        rl.set_helper(Some(ReplHelper::of(&state)));
         let readline = rl.readline(">> ");
         match readline {
             Ok(line) => {
                 rl.add_history_entry(line.as_str()).unwrap();
-                state = match run(state, line.as_str()).await {
+                state = match run(state, line.as_str(), cli.p2p.as_deref(), session.as_ref()).await
+                {
                     Ok(state) => {
-                        println!("{}", state);
+                        say!("{}", state);
                         state.print_status_update();
                         state
                     }
                     Err((err, state)) => {
-                        println!("❌ Error: {:?}", err);
-                        println!("Fallback to {}", state);
+                        report_error(&err);
+                        say!("Fallback to {}", state);
                         state
                     }
                 };
+                if let Some(session) = &session {
+                    session.save(&state);
+                }
                 state.print_instruction();
             }
             Err(ReadlineError::Interrupted) => {
-                println!("CTRL-C");
+                say!("CTRL-C");
                 break;
             }
             Err(ReadlineError::Eof) => {
-                println!("CTRL-D");
+                say!("CTRL-D");
                 break;
             }
             Err(err) => {
-                println!("Error: {:?}", err);
+                say!("Error: {:?}", err);
                 break;
             }
         }
     }
 }
 
-async fn cmd_setup(name: &str, client: &WebClient) -> Result<(ClientKey, usize), Error> {
+async fn run_command(command: Commands, connection: &ConnectArgs) -> Result<(), Error> {
+    match command {
+        Commands::Archive {
+            command:
+                ArchiveCommand::Fetch {
+                    url,
+                    out,
+                    room,
+                    purge,
+                },
+        } => {
+            let client = connection.connect(&url, room).await?;
+            say!("Fetching the archive of room #{room}");
+            let (bytes, signature) = client.fetch_archive().await?;
+            signature.verify(&client.get_receipt_key().await?, &bytes)?;
+            let signature_path = PathBuf::from(format!("{}.sig", out.display()));
+            std::fs::write(&out, &bytes)?;
+            std::fs::write(&signature_path, serde_json::to_string_pretty(&signature)?)?;
+            say!(
+                "✅ Archive of {} B written to {}, signed by the server in {}",
+                bytes.len(),
+                out.display(),
+                signature_path.display()
+            );
+            let purged = if purge {
+                let removed = client.acknowledge_archive(&signature).await?;
+                say!("🗑️ The server dropped {removed} files of the round's inputs");
+                Some(removed)
+            } else {
+                None
+            };
+            emit(&serde_json::json!({
+                "room": room,
+                "bytes": bytes.len(),
+                "path": out,
+                "signature": signature_path,
+                "purged": purged,
+            }));
+        }
+        Commands::Archive {
+            command: ArchiveCommand::Inspect { path },
+        } => {
+            let bytes = std::fs::read(&path)?;
+            let entries = read_index(&bytes)?;
+            for entry in &entries {
+                say!("{}: {} B", entry.name, entry.len);
+            }
+            let archive = SessionArchive::from_bytes(&bytes)?;
+            say!("Archived at {} (unix time)", archive.meta.archived_at);
+            let mut users = Vec::new();
+            for ((user_id, name), shares) in zip(&archive.meta.users, &archive.decryption_shares) {
+                let submitted = if shares.is_some() { "✅" } else { "❌" };
+                say!("#{user_id} {name} decryption shares {submitted}");
+                users.push(serde_json::json!({
+                    "user_id": user_id,
+                    "name": name,
+                    "decryption_shares": shares.is_some(),
+                }));
+            }
+            emit(&serde_json::json!({
+                "entries": entries,
+                "archived_at": archive.meta.archived_at,
+                "users": users,
+            }));
+        }
+        Commands::Key {
+            command:
+                KeyCommand::Export {
+                    path,
+                    name,
+                    room,
+                    session,
+                },
+        } => {
+            let session = match session {
+                Some(session) => session,
+                None => SessionFile::default_path(&name, room)?,
+            };
+            export_key(&name, room, &SessionFile::new(session), &path)?;
+        }
+        Commands::Key {
+            command: KeyCommand::Import { path, url, session },
+        } => {
+            import_key(&path, connection, &url, session).await?;
+        }
+        Commands::Diff { round, room } => {
+            let history = load_results()?;
+            let current = history
+                .iter()
+                .find(|result| result.room == room && result.round == round)
+                .ok_or_else(|| anyhow!("No result of round {round} in room #{room}"))?;
+            let previous = current.previous(&history);
+            match previous {
+                Some(previous) => say!("Round {round} against round {}", previous.round),
+                None => say!("No earlier round of room #{room} to compare with"),
+            }
+            let diff = current.diff(previous);
+            print_table(&diff);
+        }
+        Commands::Invites {
+            url,
+            count,
+            room,
+            admin_token,
+        } => {
+            let mut client = connection.connect(&url, room).await?;
+            if let Some(token) = &admin_token {
+                client = client.with_admin_token(token);
+            }
+            let codes = client.create_invites(count).await?;
+            if json() {
+                emit(&codes);
+            } else {
+                for code in codes {
+                    println!("{code}");
+                }
+            }
+        }
+        Commands::Reset {
+            url,
+            room,
+            admin_token,
+            force,
+        } => {
+            let mut client = connection.connect(&url, room).await?;
+            if let Some(token) = &admin_token {
+                client = client.with_admin_token(token);
+            }
+            let dashboard = client.get_dashboard().await?;
+            let running = match dashboard.status() {
+                ServerState::RunningFhe if force => ", and the FHE run going on",
+                _ => "",
+            };
+            if !confirm(&format!(
+                "Reset room #{room}? Round {} and its {} users' inputs{running} are dropped.",
+                dashboard.round(),
+                dashboard.users().len()
+            ))? {
+                bail!("Room #{room} is left as it is");
+            }
+            let state = client.reset_round(force).await?;
+            say!("Room #{room} started a new round: {state}");
+            emit(&state);
+        }
+        Commands::Observe { url, name, room } => {
+            run_observer(&connection.connect(&url, room).await?, &name).await?;
+        }
+        Commands::Watch { url, room, name } => {
+            run_watch(&connection.connect(&url, room).await?, name.as_deref()).await?;
+        }
+        Commands::Users { url, room, watch } => {
+            run_users(&connection.connect(&url, room).await?, watch).await?;
+        }
+        Commands::Doctor {
+            url,
+            room,
+            name,
+            session,
+        } => {
+            let session = match name {
+                Some(name) => {
+                    let path = match session {
+                        Some(path) => path,
+                        None => SessionFile::default_path(&name, room)?,
+                    };
+                    Some((name, SessionFile::new(path)))
+                }
+                None => None,
+            };
+            // Unchecked, so a server of another protocol is reported rather than failed on
+            let client = connection.build(&url, room, None)?;
+            run_doctor(&client, room, session).await?;
+        }
+        Commands::Completions { shell } => {
+            let mut command = Cli2::command();
+            let bin_name = env!("CARGO_BIN_NAME");
+            clap_complete::generate(shell, &mut command, bin_name, &mut std::io::stdout());
+        }
+        Commands::Man { dir } => {
+            let command = Cli2::command().name(env!("CARGO_BIN_NAME"));
+            match dir {
+                Some(dir) => {
+                    std::fs::create_dir_all(&dir)?;
+                    clap_mangen::generate_to(command, &dir)?;
+                    say!("Manual pages written to {}", dir.display());
+                }
+                None => clap_mangen::Man::new(command).render(&mut std::io::stdout())?,
+            }
+        }
+        Commands::Daemon {
+            name,
+            url,
+            room,
+            scores,
+            upload_limit,
+            p2p,
+            invite,
+            session,
+        } => {
+            let mut client = connection.connect_limited(&url, room, upload_limit).await?;
+            if let Some(code) = &invite {
+                client = client.with_invite(code);
+            }
+            let session = session.map(SessionFile::new);
+            // Nobody is there to answer a prompt later
+            if let Some(session) = &session {
+                session.passphrase()?;
+            }
+            let state = match &session {
+                Some(session) if session.is_saved() => {
+                    session.load(name, client).await.map_err(|(err, _)| err)?
+                }
+                _ => State::Init(StateInit { name, client }),
+            };
+            run_daemon(state, &scores, p2p.as_deref(), session.as_ref()).await?;
+        }
+        Commands::Run {
+            name,
+            url,
+            line,
+            room,
+            session,
+            upload_limit,
+            p2p,
+            admin_token,
+            invite,
+        } => {
+            let mut client = connection.connect_limited(&url, room, upload_limit).await?;
+            if let Some(token) = &admin_token {
+                client = client.with_admin_token(token);
+            }
+            if let Some(code) = &invite {
+                client = client.with_invite(code);
+            }
+            let path = match session {
+                Some(path) => path,
+                None => SessionFile::default_path(&name, room)?,
+            };
+            let session = SessionFile::new(path);
+            run_once(name, client, &line.join(" "), p2p.as_deref(), &session).await?;
+        }
+        Commands::Bench { users, url, room } => run_bench(connection, users, url, room).await?,
+        Commands::Simulate { users } => run_simulate(users).await?,
+    }
+    Ok(())
+}
+
+/// The prompt's command `line` on the saved session, or a new one, which it saves after like the
+/// prompt does
+async fn run_once(
+    name: String,
+    client: WebClient,
+    line: &str,
+    p2p: Option<&str>,
+    session: &SessionFile,
+) -> Result<(), Error> {
+    let state = if session.is_saved() {
+        session
+            .load(name.clone(), client)
+            .await
+            .map_err(|(err, _)| err)?
+    } else {
+        State::Init(StateInit {
+            name: name.clone(),
+            client,
+        })
+    };
+    let (state, result) = match run(state, line, p2p, Some(session)).await {
+        Ok(state) => {
+            say!("{}", state);
+            state.print_status_update();
+            (state, Ok(()))
+        }
+        Err((err, state)) => (state, Err(err)),
+    };
+    session.save(&state);
+    say!("👉 Next: `{}`", state.next_command());
+    result
+}
+
+async fn cmd_setup(name: &str, client: &WebClient) -> Result<(UserId, ParticipantId), Error> {
+    // The server keeps my signature of each submission, so the round's transcript can't be disputed
+    let signing_key = load_identity()?;
+    let user = client.register_signed(name, &signing_key).await?;
+    say!("Hi {}, you are registered with ID: {}", user.name, user.id);
+    emit(&RegisteredUser {
+        token: None,
+        ..user.clone()
+    });
+    if let Some(karma) = user.lifetime_karma {
+        say!("Welcome back, your karma so far in this room: {karma}");
+    }
+    Ok((user.id, user.participant_id))
+}
+
+/// The FHE parameters are settled when registration closes, so the client key is generated after
+async fn cmd_gen_client_key(
+    client: &WebClient,
+    dashboard: &Dashboard,
+) -> Result<(ClientKey, Crs), Error> {
     let seed = client.get_seed().await?;
-    println!(
+    say!(
         "Acquired seed for commen reference string (CRS) 0x{}",
         hex::encode(seed)
     );
-    println!("Setup my CRS");
-    setup(&seed);
-    println!("Generate my client key");
-    let ck = gen_client_key();
-    let user = client.register(name).await?;
-    println!("Hi {}, you are registered with ID: {}", user.name, user.id);
-    Ok((ck, user.id))
+    let parameter = dashboard.parameter_set();
+    say!("Setup my CRS with {:?}", parameter);
+    setup(&seed, parameter);
+    say!("Generate my client key");
+    Ok((gen_client_key(), (seed, parameter)))
 }
 
-async fn cmd_get_names(client: &WebClient) -> Result<(bool, Vec<String>), Error> {
+/// The names, what the circuit expects of the scores and my client key, once registration is closed
+async fn cmd_get_names(
+    client: &WebClient,
+) -> Result<Option<(Vec<String>, InputContract, ClientKey, Crs)>, Error> {
     let d = client.get_dashboard().await?;
-    d.print_presentation();
-    Ok((d.is_concluded(), d.get_names()))
+    show_dashboard(&d);
+    if !d.is_concluded() {
+        return Ok(None);
+    }
+    let (ck, crs) = cmd_gen_client_key(client, &d).await?;
+    Ok(Some((d.get_names(), client.get_circuit().await?, ck, crs)))
 }
 
-async fn cmd_conclude_registration(client: &WebClient) -> Result<Vec<String>, Error> {
+async fn cmd_conclude_registration(
+    client: &WebClient,
+) -> Result<(Vec<String>, InputContract, ClientKey, Crs), Error> {
     let dashboard = client.conclude_registration().await?;
-    Ok(dashboard.get_names())
+    let (ck, crs) = cmd_gen_client_key(client, &dashboard).await?;
+    Ok((dashboard.get_names(), client.get_circuit().await?, ck, crs))
 }
 
-async fn cmd_score_encrypt(
-    args: &[&str],
-    client: &WebClient,
-    user_id: &UserId,
-    names: &Vec<String>,
-    ck: &ClientKey,
-) -> Result<Vec<Score>, Error> {
-    let total_users = names.len();
-    let scores: Result<Vec<_>, Error> = args
-        .iter()
-        .map(|s| {
-            s.parse::<Score>()
-                .map_err(|err| anyhow::format_err!(err.to_string()))
-        })
-        .collect_vec()
-        .into_iter()
-        .collect();
-    let scores = scores?;
-    ensure!(
-        scores.len() == total_users,
-        "Mismatch scores and user number. Score: {}, users: {}",
-        scores.len(),
-        total_users
-    );
-    ensure!(
-        scores.iter().all(|&x| x <= MAX_INPUT_VALUE && x >= 0),
-        "All scores should be in range of 0 to {}. Scores: {:#?}",
-        MAX_INPUT_VALUE,
-        scores,
-    );
-    let total: Score = scores.iter().sum();
-    for (name, score) in zip(names, scores.iter()) {
-        println!("Give {name} {score} karma");
+/// `<karma>...` in the order of `names`, or `<name>=<karma>...` with 0 for those left out
+fn parse_scores(args: &[&str], names: &[String]) -> Result<Vec<Score>, Error> {
+    let parse = |s: &str| {
+        s.parse::<Score>()
+            .map_err(|err| anyhow::format_err!(err.to_string()))
+    };
+    if !args.iter().any(|arg| arg.contains('=')) {
+        return args.iter().map(|s| parse(s)).collect();
     }
-    println!("I gave out {total} karma");
-
-    let ei = EncryptedInput::from_plain(ck, &scores);
-
-    println!("Generating server key share");
-    let sks = gen_server_key_share(*user_id, total_users, ck);
+    let mut scores = vec![0; names.len()];
+    for arg in args {
+        let (name, karma) = arg
+            .split_once('=')
+            .ok_or(anyhow!("Give every score as <name>=<karma>, or none"))?;
+        let user_id = names
+            .iter()
+            .position(|n| n == name)
+            .ok_or(anyhow!("No user {name} in the room"))?;
+        scores[user_id] = parse(karma)?;
+    }
+    Ok(scores)
+}
 
-    println!("Submit the cipher and the server key share");
-    client.submit_cipher(*user_id, &ei, &sks).await?;
+/// Ask for the Karma of every other user of the room as it is now. Mine is 0, and the total
+/// I give out has to fit in a [`Score`].
+async fn prompt_scores(s: &ConcludedRegistration) -> Result<Vec<Score>, Error> {
+    let dashboard = s.client.get_dashboard().await?;
+    let me = dashboard
+        .user_id_of(&s.participant_id)
+        .ok_or(anyhow!("You were removed from the room"))?;
+    let (min, max) = s.client.get_circuit().await?.value_range;
+    let mut rl = DefaultEditor::new()?;
+    let mut scores = Vec::new();
+    let mut total: Score = 0;
+    for (user_id, name) in dashboard.get_names().iter().enumerate() {
+        if user_id == me {
+            scores.push(0);
+            continue;
+        }
+        let score = loop {
+            let line = match rl.readline(&format!("How much karma for {name}? ")) {
+                Ok(line) => line,
+                Err(ReadlineError::Interrupted | ReadlineError::Eof) => bail!("Rating cancelled"),
+                Err(err) => return Err(err.into()),
+            };
+            match line.trim().parse::<Score>() {
+                Ok(score) if !(min..=max).contains(&score) => {
+                    say!("Between {min} and {max}, please")
+                }
+                Ok(score) if total.checked_add(score).is_none() => {
+                    say!("You can give out {} more karma at most", Score::MAX - total)
+                }
+                Ok(score) => break score,
+                Err(_) => say!("Enter a whole number"),
+            }
+        };
+        total += score;
+        scores.push(score);
+    }
     Ok(scores)
 }
 
-async fn cmd_run(client: &WebClient) -> Result<(), Error> {
-    println!("Requesting FHE run ...");
-    let resp = client.trigger_fhe_run().await?;
-    println!("Server: {}", resp);
-    Ok(())
+/// `question`, answered yes or no. Anything but yes is no. Always yes with `--yes`.
+fn confirm(question: &str) -> Result<bool, Error> {
+    if ASSUME_YES.load(Ordering::Relaxed) {
+        say!("{question} Yes (--yes)");
+        return Ok(true);
+    }
+    let answer = match DefaultEditor::new()?.readline(&format!("{question} [y/N] ")) {
+        Ok(answer) => answer,
+        Err(ReadlineError::Interrupted | ReadlineError::Eof) => return Ok(false),
+        Err(err) => return Err(err.into()),
+    };
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
 }
 
-async fn cmd_download_output(
-    client: &WebClient,
-    user_id: &UserId,
-    ck: &ClientKey,
-) -> Result<(CircuitOutput, HashMap<(usize, UserId), Vec<u64>>), Error> {
-    let resp = client.trigger_fhe_run().await?;
-    if !matches!(resp, ServerState::CompletedFhe) {
-        bail!("FHE is still running")
+/// Check `scores` against the users and the circuit as they are now, and keep them for
+/// `submit`. Returns the dashboard they were checked against.
+async fn cmd_rate(scores: Vec<Score>, s: &mut ConcludedRegistration) -> Result<Dashboard, Error> {
+    let ConcludedRegistration {
+        client,
+        user_id,
+        participant_id,
+        names,
+        contract,
+        committed,
+        rated,
+        ..
+    } = s;
+    // The admin may have removed someone since registration closed
+    let dashboard = client.get_dashboard().await?;
+    *user_id = dashboard
+        .user_id_of(participant_id)
+        .ok_or(anyhow!("You were removed from the room"))?;
+    client.update_user_id(participant_id, *user_id);
+    if dashboard.get_names() != *names {
+        *names = dashboard.get_names();
+        // Removing someone discards every commitment
+        *committed = None;
+        *rated = None;
+        bail!("Users changed to {:?}. Enter `rate` to score them.", names);
     }
+    *contract = client.get_circuit().await?;
+    contract.validate(&scores)?;
+    show_scores(names, &scores)?;
+    *rated = Some(scores);
+    Ok(dashboard)
+}
 
-    println!("Downloading fhe output");
-    let fhe_out = client.get_fhe_output().await?;
+/// `scores` by name, and their total, which has to fit in a [`Score`]
+fn show_scores(names: &[String], scores: &[Score]) -> Result<(), Error> {
+    let total = scores
+        .iter()
+        .try_fold(0 as Score, |total, &score| total.checked_add(score))
+        .ok_or(anyhow!(
+            "The karma given out should add up to {} at most",
+            Score::MAX
+        ))?;
+    #[derive(Tabled, Serialize)]
+    struct Row<'a> {
+        name: &'a str,
+        karma: Score,
+    }
+    let rows = zip(names.iter(), scores.iter())
+        .map(|(name, &karma)| Row { name, karma })
+        .collect_vec();
+    print_table(&rows);
+    say!("I gave out {total} karma");
+    Ok(())
+}
 
-    println!("Generating my decrypting shares");
-    let mut shares = HashMap::new();
-    let my_decryption_shares = fhe_out.gen_decryption_shares(ck);
-    for (out_id, share) in my_decryption_shares.iter().enumerate() {
-        shares.insert((out_id, *user_id), share.to_vec());
+/// What `rate` or `submit` would check, encrypt and upload for the scores of `args`, or the
+/// ones `rate` kept, without keeping, encrypting or sending anything
+async fn cmd_dry_run(args: &[&str], s: &ConcludedRegistration) -> Result<(), Error> {
+    let scores = match (args, &s.rated) {
+        ([], Some(rated)) => rated.clone(),
+        ([], None) => bail!("Give the Karma for each user to check, e.g. `rate --dry-run 0 3 5`"),
+        (args, _) => parse_scores(args, &s.names)?,
+    };
+    let dashboard = s.client.get_dashboard().await?;
+    let user_id = dashboard
+        .user_id_of(&s.participant_id)
+        .ok_or(anyhow!("You were removed from the room"))?;
+    ensure!(
+        dashboard.get_names() == s.names,
+        "Users changed to {:?}. Enter `rate` to score them.",
+        dashboard.get_names()
+    );
+    s.client.get_circuit().await?.validate(&scores)?;
+    show_scores(&s.names, &scores)?;
+
+    let key_share_kept = dashboard
+        .users()
+        .iter()
+        .any(|user| user.id == user_id && user.status.has_key_share());
+    let (cipher_bytes, key_share_bytes) = upload_sizes(&s.client).await?;
+    let about = |bytes: Option<u64>| match bytes {
+        Some(bytes) => format!("about {}", HumanBytes(bytes)),
+        None => "unknown until someone in the room uploads one".to_string(),
+    };
+    say!("🔍 Dry run, nothing was encrypted or sent. Submitting would:");
+    if s.committed.is_none() {
+        say!("- encrypt {} scores", scores.len());
     }
-    println!("Submitting my decrypting shares");
-    client
-        .submit_decryption_shares(*user_id, &my_decryption_shares)
-        .await?;
-    Ok((fhe_out, shares))
+    if dashboard.is_taking_commitments() && s.committed.is_none() {
+        say!("- commit to the cipher, and wait for everyone to commit");
+    }
+    say!("- upload the cipher, {}", about(cipher_bytes));
+    if key_share_kept {
+        say!("- keep the server key share the server already has");
+    } else {
+        let took = keygen_timings()
+            .get(&keygen_timing_key(s.crs.1))
+            .map_or("untimed on this machine".to_string(), |&secs| {
+                format!("about {}", HumanDuration(Duration::from_secs_f64(secs)))
+            });
+        say!("- generate the server key share, {took}");
+        say!("- upload the server key share, {}", about(key_share_bytes));
+    }
+    emit(&serde_json::json!({
+        "dry_run": true,
+        "names": s.names,
+        "scores": scores,
+        "cipher_bytes": cipher_bytes,
+        "key_share_bytes": if key_share_kept { Some(0) } else { key_share_bytes },
+    }));
+    Ok(())
 }
 
-async fn cmd_download_shares(
-    client: &WebClient,
-    names: &[String],
-    ck: &ClientKey,
-    shares: &mut HashMap<(usize, usize), Vec<u64>>,
-    co: &CircuitOutput,
-    scores: &[Score],
+/// Mean size of the ciphers and of the key shares uploaded in the room so far, from its
+/// transcript. `None` before the first of each.
+async fn upload_sizes(client: &WebClient) -> Result<(Option<u64>, Option<u64>), Error> {
+    let transcript = client.get_transcript().await?;
+    let mean = |artifact: TranscriptArtifact| {
+        let sizes = transcript
+            .iter()
+            .filter(|entry| entry.artifact == artifact && entry.bytes > 0)
+            .map(|entry| entry.bytes)
+            .collect_vec();
+        (!sizes.is_empty()).then(|| sizes.iter().sum::<u64>() / sizes.len() as u64)
+    };
+    Ok((
+        mean(TranscriptArtifact::Cipher),
+        mean(TranscriptArtifact::ServerKeyShare),
+    ))
+}
+
+/// Encrypt and submit the scores of `args`, or the ones `rate` kept
+async fn cmd_score_encrypt(
+    args: &[&str],
+    s: &mut ConcludedRegistration,
 ) -> Result<Vec<Score>, Error> {
+    let scores = match (args, &s.rated) {
+        ([], Some(rated)) => rated.clone(),
+        ([], None) => bail!("Enter `rate` to give the Karma for each user first"),
+        (args, _) => parse_scores(args, &s.names)?,
+    };
+    let dashboard = cmd_rate(scores.clone(), s).await?;
+    let ConcludedRegistration {
+        client,
+        ck,
+        crs,
+        user_id,
+        names,
+        committed,
+        ..
+    } = s;
     let total_users = names.len();
-    println!("Acquiring decryption shares needed");
-    for (output_id, user_id) in (0..co.n()).cartesian_product(0..total_users) {
-        if shares.get(&(output_id, user_id)).is_none() {
-            let ds = client.get_decryption_share(output_id, user_id).await?;
-            shares.insert((output_id, user_id), ds);
+    // After a rejected cipher, the key share is still on the server
+    let key_share_kept = dashboard
+        .users()
+        .iter()
+        .any(|user| user.id == *user_id && user.status.has_key_share());
+
+    let (ei, sks) = match committed {
+        Some((committed_scores, ei)) => {
+            ensure!(
+                *committed_scores == scores,
+                "You committed to scores {:?}, enter them again",
+                committed_scores
+            );
+            (ei.clone(), None)
+        }
+        // The commitment can't wait for the key share
+        None if key_share_kept || dashboard.is_taking_commitments() => {
+            (EncryptedInput::from_plain(ck, &scores), None)
         }
+        None => {
+            let (sks, ei) = gen_key_share_with(*user_id, total_users, ck, crs.1, || {
+                EncryptedInput::from_plain(ck, &scores)
+            })?;
+            (ei, Some(sks))
+        }
+    };
+    if dashboard.is_taking_commitments() {
+        say!("Commit to the cipher");
+        let receipt = client.commit_cipher(*user_id, &ei).await?;
+        save_receipt(&receipt)?;
+        *committed = Some((scores.clone(), ei.clone()));
+        say!("Waiting for everyone to commit ...");
+        wait_for_reveal(client).await?;
     }
-    println!("Decrypt the encrypted output");
-    let dss = (0..co.n())
-        .map(|output_id| {
-            (0..total_users)
-                .map(|user_id| {
-                    shares
-                        .get(&(output_id, user_id))
-                        .expect("exists")
-                        .to_owned()
-                })
-                .collect_vec()
+
+    if key_share_kept {
+        say!("Submit the cipher, the server kept my key share");
+        let receipt = client.submit_cipher(*user_id, &ei).await?;
+        report_upload(client);
+        save_receipt(&receipt)?;
+        return Ok(scores);
+    }
+
+    let sks = match sks {
+        Some(sks) => sks,
+        None => gen_key_share(*user_id, total_users, ck, crs.1)?,
+    };
+
+    say!("Submit the cipher and the server key share");
+    let receipt = client.submit_inputs(*user_id, &ei, &sks).await?;
+    report_upload(client);
+    save_receipt(&receipt)?;
+    Ok(scores)
+}
+
+/// My server key share, made on a pool of `--threads`. It takes minutes, so a spinner tells how
+/// long the last one took under the same parameters and threads, and this one is timed for
+/// the next.
+fn gen_key_share(
+    user_id: UserId,
+    total_users: usize,
+    ck: &ClientKey,
+    parameter: ParameterSet,
+) -> Result<ServerKeyShare, Error> {
+    gen_key_share_with(user_id, total_users, ck, parameter, || ()).map(|(sks, ())| sks)
+}
+
+/// Like [`gen_key_share`], with `alongside` run at the same time on the same pool, e.g. the
+/// encryption of the scores, so it takes no longer than the key share on a multicore machine
+fn gen_key_share_with<R: Send>(
+    user_id: UserId,
+    total_users: usize,
+    ck: &ClientKey,
+    parameter: ParameterSet,
+    alongside: impl FnOnce() -> R + Send,
+) -> Result<(ServerKeyShare, R), Error> {
+    let threads = KEYGEN_THREADS.get().copied();
+    let key = keygen_timing_key(parameter);
+    let estimate = match keygen_timings().get(&key) {
+        Some(&secs) => format!(
+            "about {} on this machine",
+            HumanDuration(Duration::from_secs_f64(secs))
+        ),
+        None => "timed for next time".to_string(),
+    };
+    let spinner = ProgressBar::new_spinner();
+    spinner.set_style(ProgressStyle::with_template("[{elapsed_precise}] {spinner} {msg}").unwrap());
+    spinner.set_message(format!("Generating server key share, {estimate}"));
+    spinner.enable_steady_tick(Duration::from_millis(100));
+
+    let started = SystemTime::now();
+    let (sks, other) = on_pool(parameter, threads, || {
+        rayon::join(|| gen_server_key_share(user_id, total_users, ck), alongside)
+    })?;
+    let took = started.elapsed().unwrap_or_default();
+    spinner.finish_with_message(format!(
+        "Generated server key share in {}",
+        HumanDuration(took)
+    ));
+    info!(
+        user_id,
+        total_users,
+        threads,
+        elapsed_ms = took.as_millis() as u64,
+        "Generated the server key share"
+    );
+
+    keep_keygen_timing(key, took);
+    Ok((sks, other))
+}
+
+/// What [`KEYGEN_TIMINGS_FILE`] keeps a timing under
+fn keygen_timing_key(parameter: ParameterSet) -> String {
+    format!(
+        "{parameter:?} on {} threads",
+        KEYGEN_THREADS
+            .get()
+            .copied()
+            .unwrap_or_else(rayon::current_num_threads)
+    )
+}
+
+fn keygen_timings() -> HashMap<String, f64> {
+    std::fs::read_to_string(stored(KEYGEN_TIMINGS_FILE))
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+/// A failed write only costs the next estimate, so it's reported rather than fatal
+fn keep_keygen_timing(key: String, took: Duration) {
+    let mut timings = keygen_timings();
+    timings.insert(key, took.as_secs_f64());
+    if let Err(err) = serde_json::to_string(&timings)
+        .map_err(Error::from)
+        .and_then(|content| Ok(std::fs::write(stored(KEYGEN_TIMINGS_FILE), content)?))
+    {
+        say!("⚠️ Failed to keep the key share timing: {err}");
+    }
+}
+
+fn report_upload(client: &WebClient) {
+    if let Some(stats) = client.last_upload() {
+        say!("Uploaded {stats}");
+        if let Err(err) = std::fs::write(stored(UPLOAD_SPEED_FILE), stats.throughput().to_string())
+        {
+            say!("⚠️ Failed to keep the upload speed: {err}");
+        }
+    }
+}
+
+async fn cmd_run(client: &WebClient) -> Result<(), Error> {
+    // With the admin token, this starts the run for everyone
+    if client.is_admin() {
+        let dashboard = client.get_dashboard().await?;
+        if *dashboard.status() == ServerState::ReadyForRunning
+            && !confirm(&format!(
+                "Start the FHE run of room #{} for its {} users now?",
+                client.room(),
+                dashboard.users().len()
+            ))?
+        {
+            bail!("The run isn't started, enter `downloadOutput` to start it");
+        }
+    }
+    say!("Requesting FHE run ...");
+    match client.trigger_fhe_run().await {
+        Ok(resp) => say!("Server: {}", resp),
+        // Without the admin token, follow a run the admin started
+        Err(err) => match client.get_dashboard().await?.status() {
+            ServerState::RunningFhe | ServerState::CompletedFhe => {
+                say!("The FHE run has started already")
+            }
+            _ => return Err(err),
+        },
+    }
+    Ok(())
+}
+
+/// The output of the run, my decryption shares of it, the peers' contacts and the round
+type Downloaded = (CircuitOutput, DecryptionSharesMap, Contacts, u64);
+
+/// `None` if the run was an earlier rating of the round, whose output stays encrypted
+async fn cmd_download_output(
+    client: &WebClient,
+    participant_id: &ParticipantId,
+    ck: &ClientKey,
+    p2p: Option<&str>,
+) -> Result<Option<Downloaded>, Error> {
+    let status = client.get_run_status().await?;
+    if status.carried {
+        return Ok(None);
+    }
+    let mut early_shares = HashMap::new();
+    if !status.completed {
+        say!(
+            "FHE is still running. Outputs computed: {}/{}. Decrypting them as they arrive ...",
+            status.outputs_computed,
+            status.total_outputs
+        );
+        match wait_for_fhe(client, ck).await? {
+            Some(shares) => early_shares = shares,
+            None => return Ok(None),
+        }
+    }
+
+    say!("Downloading fhe output");
+    let fhe_out = client.get_fhe_output().await?;
+
+    say!("Generating my decrypting shares");
+    let mut shares = HashMap::new();
+    let my_decryption_shares = (0..fhe_out.n())
+        .map(|output_id| {
+            early_shares
+                .remove(&output_id)
+                .unwrap_or_else(|| fhe_out.gen_decryption_share(ck, output_id))
         })
         .collect_vec();
-    let decrypted_output = co.decrypt(ck, &dss);
-    println!("Final decrypted output:");
-    present_balance(names, scores, &decrypted_output);
-    Ok(decrypted_output)
+    shares.extend(fhe_out.share_entries(participant_id, &my_decryption_shares));
+    say!("Submitting my decrypting shares");
+    let receipt = client
+        .submit_decryption_shares(participant_id, &my_decryption_shares)
+        .await?;
+    save_receipt(&receipt)?;
+    if let Some(contact) = p2p {
+        let listener = TcpListener::bind(contact).await?;
+        tokio::spawn(serve_shares(
+            listener,
+            PeerShares::new(
+                participant_id.clone(),
+                my_decryption_shares,
+                Some(&load_identity()?),
+            ),
+        ));
+        client.publish_contact(participant_id, contact).await?;
+        say!("📡 Serving my decryption shares to peers at {contact}");
+    }
+    let dashboard = client.get_dashboard().await?;
+    Ok(Some((
+        fhe_out,
+        shares,
+        peer_contacts(&dashboard),
+        dashboard.round(),
+    )))
+}
+
+/// After a run that only added up a rating, the scores of the next rating are due
+async fn cmd_next_rating(
+    client: &WebClient,
+    participant_id: &ParticipantId,
+) -> Result<(UserId, InputContract), Error> {
+    let dashboard = client.get_dashboard().await?;
+    let user_id = dashboard
+        .user_id_of(participant_id)
+        .ok_or_else(|| anyhow!("I'm no longer registered in the room"))?;
+    let (rating, ratings) = dashboard.rating();
+    say!("🔁 Rating {rating} of {ratings} is added up and stays encrypted until the last one");
+    Ok((user_id, client.get_circuit().await?))
+}
+
+fn peer_contacts(dashboard: &Dashboard) -> Contacts {
+    dashboard
+        .users()
+        .iter()
+        .filter_map(|user| {
+            let contact = user.contact.clone()?;
+            Some((
+                user.participant_id.clone(),
+                (contact, user.public_key.clone()),
+            ))
+        })
+        .collect()
+}
+
+/// Follow the room's events until every user has committed and ciphers are accepted
+async fn wait_for_reveal(client: &WebClient) -> Result<(), Error> {
+    let mut events = client.subscribe_events().await?;
+    if !client.get_dashboard().await?.is_taking_commitments() {
+        return Ok(());
+    }
+    while let Some(event) = events.next().await {
+        match event? {
+            RoomEvent::StateChanged {
+                to: ServerState::ReadyForInputs,
+                ..
+            } => return Ok(()),
+            RoomEvent::UserRemoved { name } => {
+                bail!("{name} was removed, rate and submit again")
+            }
+            _ => {}
+        }
+    }
+    bail!("The server closed the event stream")
+}
+
+/// Follow the room's events until the FHE run completes. Meanwhile, make decryption shares
+/// of the outputs computed so far, keyed by output ID. `None` if the run was carried to the
+/// next rating instead.
+async fn wait_for_fhe(
+    client: &WebClient,
+    ck: &ClientKey,
+) -> Result<Option<HashMap<usize, Vec<u64>>>, Error> {
+    let mut shares = HashMap::new();
+    // Subscribe before checking, so the completion can't slip in between
+    let mut events = client.subscribe_events().await?;
+    if client.get_dashboard().await?.is_fhe_complete() {
+        return Ok(Some(shares));
+    }
+    if client.get_run_status().await?.carried {
+        return Ok(None);
+    }
+    loop {
+        tokio::select! {
+            event = events.next() => match event {
+                Some(Ok(RoomEvent::StateChanged {
+                    to: ServerState::CompletedFhe,
+                    ..
+                })) => return Ok(Some(shares)),
+                Some(Ok(RoomEvent::StateChanged {
+                    from: ServerState::RunningFhe,
+                    to: ServerState::ReadyForCommitments | ServerState::ReadyForInputs,
+                })) => return Ok(None),
+                Some(Ok(RoomEvent::StateChanged {
+                    to: ServerState::ReadyForRunning,
+                    ..
+                })) => match client.get_run_status().await?.failed {
+                    Some(reason) => bail!("The FHE run failed: {reason}"),
+                    None => bail!("The FHE run was cancelled"),
+                },
+                Some(Ok(_)) => {}
+                Some(Err(err)) => return Err(err),
+                None => bail!("The server closed the event stream"),
+            },
+            _ = sleep(OUTPUT_POLL_INTERVAL) => {
+                let status = client.get_run_status().await?;
+                for &output_id in &status.ready_outputs {
+                    if let Entry::Vacant(entry) = shares.entry(output_id) {
+                        let output = client.get_fhe_output_word(output_id).await?;
+                        entry.insert(output.gen_decryption_share(ck));
+                    }
+                }
+            }
+        }
+    }
 }
 
-async fn run(state: State, line: &str) -> Result<State, (Error, State)> {
+/// Collect everyone's decryption shares, from the server or else from the peers
+async fn cmd_download_shares(s: &mut StateDownloadedOuput) -> Result<(), Error> {
+    let StateDownloadedOuput {
+        client,
+        participant_id,
+        shares,
+        fhe_out: co,
+        contacts,
+        ..
+    } = s;
+    say!("Acquiring decryption shares needed");
+    if let Ok(dashboard) = client.get_dashboard().await {
+        contacts.extend(peer_contacts(&dashboard));
+        // Everything the server has in one go, then ask for the rest one by one
+        if let Some(user_id) = dashboard.user_id_of(participant_id) {
+            if let Ok(missing) = client.get_missing_decryption_shares(user_id).await {
+                co.check_shares(&missing)?;
+                for (key, share) in missing {
+                    shares.entry(key).or_insert(share);
+                }
+            }
+        }
+        // Name whoever the server is still waiting for, unless they serve their shares themselves
+        if let Ok(status) = client.get_decryption_status().await {
+            let waiting = status
+                .pending()
+                .into_iter()
+                .filter(|user| !contacts.contains_key(&user.participant_id))
+                .map(|user| user.name.as_str())
+                .collect_vec();
+            if !waiting.is_empty() {
+                bail!(
+                    "Waiting for the decryption shares of {}. Enter `downloadShares` again later",
+                    waiting.join(", ")
+                );
+            }
+        }
+    }
+    let participants = co.participants();
+    if let Err(err) = client
+        .fetch_missing_shares(co, shares, SHARE_FETCH_CONCURRENCY)
+        .await
+    {
+        let failed = participants
+            .iter()
+            .filter(|from| {
+                (0..co.n()).any(|output_id| !shares.contains_key(&(output_id, (*from).clone())))
+            })
+            .collect_vec();
+        if failed.iter().any(|from| !contacts.contains_key(*from)) {
+            return Err(err);
+        }
+        say!("⚠️ The server failed: {err}");
+        // To check the shares of peers without a key, if the server still answers this
+        let transcript = client.get_transcript().await.unwrap_or_default();
+        for from in failed {
+            let (contact, public_key) = &contacts[from];
+            say!("Asking {from} at {contact} directly");
+            let peer = fetch_peer_shares(contact).await?;
+            peer.verify(from, co.n(), public_key.as_deref(), &transcript)?;
+            shares.extend(co.share_entries(from, &peer.decryption_shares));
+        }
+    }
+    Ok(())
+}
+
+/// Decrypt the output, once the missing decryption shares are in
+async fn cmd_decrypt(s: &mut StateDownloadedOuput) -> Result<(RoundResult, Vec<KarmaDiff>), Error> {
+    if s.fhe_out.collect_shares(&s.shares).is_none() {
+        cmd_download_shares(s).await?;
+    }
+    let StateDownloadedOuput {
+        name,
+        client,
+        names,
+        ck,
+        shares,
+        fhe_out: co,
+        scores,
+        round,
+        ..
+    } = s;
+    say!("Decrypt the encrypted output");
+    let dss = co.collect_shares(shares).expect("all acquired");
+    let result = RoundResult {
+        room: client.room(),
+        round: *round,
+        names: names.to_vec(),
+        balances: co.decrypt(ck, &dss)?,
+    };
+    let diff = result.diff(result.previous(&load_results()?));
+    save_result(&result)?;
+    say!("Final decrypted output:");
+    present_balance(name, scores, &diff);
+    Ok((result, diff))
+}
+
+/// `result` as CSV if `path` ends in `.csv`, as JSON otherwise
+fn write_result(result: &RoundResult, path: &Path) -> Result<(), Error> {
+    let content = match path.extension() {
+        Some(extension) if extension.eq_ignore_ascii_case("csv") => result.to_csv(),
+        _ => serde_json::to_string_pretty(result)?,
+    };
+    std::fs::write(path, content)?;
+    say!("💾 Results written to {}", path.display());
+    Ok(())
+}
+
+/// Commands of the prompt besides `next`, which takes whichever step is due
+const COMMANDS: [&str; 12] = [
+    "setup",
+    "resume",
+    "getNames",
+    "conclude",
+    "rate",
+    "submit",
+    "status",
+    "ack",
+    "downloadOutput",
+    "downloadShares",
+    "decrypt",
+    "help",
+];
+
+/// Log events of the client and the library by `-v` and `--log-file`, or not at all. `RUST_LOG`
+/// picks the events instead where it's set.
+fn init_logging(verbose: u8, log_file: Option<&Path>) -> Result<(), Error> {
+    let verbose = if log_file.is_some() {
+        verbose.max(2)
+    } else {
+        verbose
+    };
+    let level = match verbose {
+        0 => return Ok(()),
+        1 => "info",
+        2 => "debug",
+        _ => "trace",
+    };
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(format!("warn,cli={level},karma_calculator={level}")));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    match log_file {
+        Some(path) => {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            builder
+                .with_ansi(false)
+                .with_writer(std::sync::Mutex::new(file))
+                .init();
+        }
+        None => builder
+            .with_ansi(std::io::stderr().is_terminal())
+            .with_writer(std::io::stderr)
+            .init(),
+    }
+    info!(version = env!("CARGO_PKG_VERSION"), "Logging started");
+    Ok(())
+}
+
+/// Take the REPL command `line` in `state`, and log the step it made. Only the command goes in
+/// the log, as its arguments may be the user's scores.
+async fn run(
+    state: State,
+    line: &str,
+    p2p: Option<&str>,
+    session: Option<&SessionFile>,
+) -> Result<State, (Error, State)> {
+    let command = line.split_whitespace().next().unwrap_or_default();
+    let from = state.label();
+    let result = dispatch(state, line, p2p, session).await;
+    match &result {
+        Ok(to) if to.label() != from => info!(command, from, to = to.label(), "Step done"),
+        Ok(_) => info!(command, state = from, "Command done"),
+        Err((err, _)) => warn!(command, state = from, "Command failed: {err:#}"),
+    }
+    result
+}
+
+async fn dispatch(
+    state: State,
+    line: &str,
+    p2p: Option<&str>,
+    session: Option<&SessionFile>,
+) -> Result<State, (Error, State)> {
     let terms: Vec<&str> = line.split_whitespace().collect();
     if terms.is_empty() {
         return Ok(state);
     }
-    let cmd = &terms[0];
+    let cmd = terms[0];
     let args = &terms[1..];
-    if cmd == &"next" {
-        match state {
-            State::Init(s) => match cmd_setup(&s.name, &s.client).await {
-                Ok((ck, user_id)) => Ok(State::Setup(StateSetup {
+    match (cmd, state) {
+        ("rate" | "submit", State::ConcludedRegistration(s)) if args.contains(&"--dry-run") => {
+            let args = args
+                .iter()
+                .copied()
+                .filter(|arg| *arg != "--dry-run")
+                .collect_vec();
+            match cmd_dry_run(&args, &s).await {
+                Ok(()) => Ok(State::ConcludedRegistration(s)),
+                Err(err) => Err((err, State::ConcludedRegistration(s))),
+            }
+        }
+        ("next", state)
+        | ("setup", state @ State::Init(_))
+        | ("getNames", state @ State::Setup(_))
+        | ("submit", state @ State::ConcludedRegistration(_))
+        | ("downloadOutput", state @ State::TriggeredRun(_))
+        | ("decrypt", state @ State::DownloadedOutput(_))
+        | ("decrypt", state @ State::Decrypted(_)) => step(state, args, p2p).await,
+        // Start or follow the run first
+        ("downloadOutput", state @ State::SubmittedInput(_)) => {
+            let state = step(state, args, p2p).await?;
+            step(state, args, p2p).await
+        }
+        ("rate", State::ConcludedRegistration(mut s)) => {
+            let scores = match args {
+                [] => prompt_scores(&s).await,
+                args => parse_scores(args, &s.names),
+            };
+            let rated = match scores {
+                Ok(scores) => cmd_rate(scores, &mut s).await.map(|_| ()),
+                Err(err) => Err(err),
+            };
+            match rated {
+                // Asked for one by one, so confirmed before they're encrypted
+                Ok(()) if args.is_empty() => match confirm("Encrypt and submit them now?") {
+                    Ok(true) => step(State::ConcludedRegistration(s), &[], p2p).await,
+                    Ok(false) => {
+                        say!("Kept for `submit`");
+                        Ok(State::ConcludedRegistration(s))
+                    }
+                    Err(err) => Err((err, State::ConcludedRegistration(s))),
+                },
+                Ok(()) => Ok(State::ConcludedRegistration(s)),
+                Err(err) => Err((err, State::ConcludedRegistration(s))),
+            }
+        }
+        ("resume", State::Init(StateInit { name, client })) => match session {
+            Some(session) => session.load(name, client).await,
+            None => Err((
+                anyhow!("Sessions are off with --no-session"),
+                State::Init(StateInit { name, client }),
+            )),
+        },
+        ("downloadShares", State::DownloadedOutput(mut s)) => {
+            match cmd_download_shares(&mut s).await {
+                Ok(()) => Ok(State::DownloadedOutput(s)),
+                Err(err) => Err((err, State::DownloadedOutput(s))),
+            }
+        }
+        ("conclude", state) => match state {
+            State::Setup(s) => match cmd_conclude_registration(&s.client).await {
+                Ok((names, contract, ck, crs)) => {
+                    Ok(State::ConcludedRegistration(ConcludedRegistration {
+                        name: s.name,
+                        client: s.client,
+                        ck,
+                        crs,
+                        user_id: s.user_id,
+                        participant_id: s.participant_id,
+                        names,
+                        contract,
+                        committed: None,
+                        rated: None,
+                    }))
+                }
+                Err(err) => Err((err, State::Setup(s))),
+            },
+            state => Err(not_a_step(cmd, state)),
+        },
+        ("status", state) => match &state {
+            State::Init(StateInit { client, .. })
+            | State::Setup(StateSetup { client, .. })
+            | State::ConcludedRegistration(ConcludedRegistration { client, .. })
+            | State::SubmittedInput(SubmittedInput { client, .. })
+            | State::TriggeredRun(StateTriggeredRun { client, .. })
+            | State::DownloadedOutput(StateDownloadedOuput { client, .. })
+            | State::Decrypted(StateDecrypted { client, .. }) => {
+                match client.get_dashboard().await {
+                    Ok(dashbaord) => {
+                        show_dashboard(&dashbaord);
+                        let user_id = state
+                            .participant_id()
+                            .and_then(|participant_id| dashbaord.user_id_of(participant_id));
+                        if let Some(user_id) = user_id {
+                            match client.get_user_status(user_id).await {
+                                Ok(progress) => {
+                                    say!("👉 Next: {}", progress.next_step);
+                                    emit(&progress);
+                                }
+                                Err(err) => return Err((err, state)),
+                            }
+                        }
+                        Ok(state)
+                    }
+                    Err(err) => Err((err, state)),
+                }
+            }
+        },
+        ("ack", state) => match &state {
+            State::Setup(StateSetup {
+                client, user_id, ..
+            })
+            | State::ConcludedRegistration(ConcludedRegistration {
+                client, user_id, ..
+            }) => match client.ack_deadline_extension(*user_id).await {
+                Ok(Some(deadline)) => {
+                    say!("Deadline extended to {deadline}");
+                    Ok(state)
+                }
+                Ok(None) => {
+                    say!("Acked. Waiting for the majority");
+                    Ok(state)
+                }
+                Err(err) => Err((err, state)),
+            },
+            _ => Err(not_a_step(cmd, state)),
+        },
+        ("help", state) => {
+            print_help();
+            Ok(state)
+        }
+        (cmd, state) if cmd.starts_with('#') => Ok(state),
+        (cmd, state) if COMMANDS.contains(&cmd) => Err(not_a_step(cmd, state)),
+        (cmd, state) => Err((
+            anyhow!("Unknown command {cmd}, enter `help` for the list"),
+            state,
+        )),
+    }
+}
+
+/// Completes the commands, and the names of the users for `rate` and `submit`. Hints at the
+/// command of the step that's due.
+struct ReplHelper {
+    names: Vec<String>,
+    next_command: &'static str,
+}
+
+impl ReplHelper {
+    fn of(state: &State) -> Self {
+        Self {
+            names: state.names().to_vec(),
+            next_command: state.next_command(),
+        }
+    }
+}
+
+impl Completer for ReplHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let line = &line[..pos];
+        let start = line.rfind(char::is_whitespace).map_or(0, |i| i + 1);
+        let word = &line[start..];
+        let candidates = if start == 0 {
+            ["next"]
+                .iter()
+                .chain(&COMMANDS)
+                .filter(|cmd| cmd.starts_with(word))
+                .map(|cmd| cmd.to_string())
+                .collect()
+        } else if matches!(line.split_whitespace().next(), Some("rate" | "submit")) {
+            self.names
+                .iter()
+                .filter(|name| name.starts_with(word))
+                .map(|name| format!("{name}="))
+                .collect()
+        } else {
+            vec![]
+        };
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+
+    fn hint(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> Option<String> {
+        if pos < line.len() {
+            return None;
+        }
+        self.next_command
+            .strip_prefix(line)
+            .filter(|rest| !rest.is_empty())
+            .map(str::to_string)
+    }
+}
+
+impl Highlighter for ReplHelper {
+    /// Greyed out
+    fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
+        Cow::Owned(format!("\x1b[90m{hint}\x1b[0m"))
+    }
+}
+
+impl Validator for ReplHelper {}
+
+impl Helper for ReplHelper {}
+
+fn not_a_step(cmd: &str, state: State) -> (Error, State) {
+    (anyhow!("`{cmd}` isn't a step of {state}"), state)
+}
+
+fn print_help() {
+    say!(
+        "\
+next                Take whichever step is due
+setup               Register in the room
+resume              Pick up the saved session instead
+getNames            Get the users once registration has closed
+conclude            End registration (admin)
+rate [<karma>...]   Check the Karma for each user, asked by name, in the order of the names,
+                    or as <name>=<karma> with 0 for those left out
+submit [<karma>...] Encrypt and submit the Karma rated, or given here
+  --dry-run         Tell what `rate` or `submit` would encrypt and upload, and how big, but
+                    keep, encrypt and send nothing
+status              Show the dashboard and what the server waits on from me
+ack                 Agree to the deadline extension proposed
+downloadOutput      Start or follow the FHE run, then download the output
+downloadShares      Collect everyone's decryption shares
+decrypt [<file>]    Decrypt my Karma balance, and write everyone's to <file>, as CSV if it
+                    ends in .csv and JSON otherwise"
+    );
+}
+
+/// Take the step that's due in `state`, see [`State::print_instruction`]
+async fn step(state: State, args: &[&str], p2p: Option<&str>) -> Result<State, (Error, State)> {
+    match state {
+        State::Init(s) => match cmd_setup(&s.name, &s.client).await {
+            Ok((user_id, participant_id)) => Ok(State::Setup(StateSetup {
+                name: s.name,
+                client: s.client,
+                user_id,
+                participant_id,
+            })),
+            Err(err) => Err((err, State::Init(s))),
+        },
+        State::Setup(s) => match cmd_get_names(&s.client).await {
+            Ok(Some((names, contract, ck, crs))) => {
+                Ok(State::ConcludedRegistration(ConcludedRegistration {
                     name: s.name,
                     client: s.client,
                     ck,
-                    user_id,
-                })),
-                Err(err) => Err((err, State::Init(s))),
-            },
-            State::Setup(s) => match cmd_get_names(&s.client).await {
-                Ok((is_concluded, names)) => {
-                    if is_concluded {
+                    crs,
+                    user_id: s.user_id,
+                    participant_id: s.participant_id,
+                    names,
+                    contract,
+                    committed: None,
+                    rated: None,
+                }))
+            }
+            Ok(None) => Ok(State::Setup(s)),
+            Err(err) => Err((err, State::Setup(s))),
+        },
+        State::ConcludedRegistration(mut s) => match cmd_score_encrypt(args, &mut s).await {
+            Ok(scores) => Ok(State::SubmittedInput(SubmittedInput {
+                name: s.name,
+                client: s.client,
+                ck: s.ck,
+                crs: s.crs,
+                participant_id: s.participant_id,
+                names: s.names,
+                scores,
+            })),
+            Err(err) => Err((err, State::ConcludedRegistration(s))),
+        },
+        State::SubmittedInput(s) => match cmd_run(&s.client).await {
+            Ok(()) => Ok(State::TriggeredRun(StateTriggeredRun {
+                name: s.name,
+                client: s.client,
+                ck: s.ck,
+                crs: s.crs,
+                participant_id: s.participant_id,
+                names: s.names,
+                scores: s.scores,
+            })),
+            Err(err) => Err((err, State::SubmittedInput(s))),
+        },
+        State::TriggeredRun(s) => {
+            match cmd_download_output(&s.client, &s.participant_id, &s.ck, p2p).await {
+                Ok(None) => match cmd_next_rating(&s.client, &s.participant_id).await {
+                    Ok((user_id, contract)) => {
                         Ok(State::ConcludedRegistration(ConcludedRegistration {
                             name: s.name,
                             client: s.client,
                             ck: s.ck,
-                            user_id: s.user_id,
-                            names,
+                            crs: s.crs,
+                            user_id,
+                            participant_id: s.participant_id,
+                            names: s.names,
+                            contract,
+                            committed: None,
+                            rated: None,
                         }))
-                    } else {
-                        Ok(State::Setup(s))
                     }
-                }
-                Err(err) => Err((err, State::Setup(s))),
-            },
-            State::ConcludedRegistration(s) => {
-                match cmd_score_encrypt(args, &s.client, &s.user_id, &s.names, &s.ck).await {
-                    Ok(scores) => Ok(State::SubmittedInput(SubmittedInput {
+                    Err(err) => Err((err, State::TriggeredRun(s))),
+                },
+                Ok(Some((fhe_out, shares, contacts, round))) => {
+                    Ok(State::DownloadedOutput(StateDownloadedOuput {
                         name: s.name,
                         client: s.client,
                         ck: s.ck,
-                        user_id: s.user_id,
+                        crs: s.crs,
+                        participant_id: s.participant_id,
                         names: s.names,
-                        scores,
-                    })),
-                    Err(err) => Err((err, State::ConcludedRegistration(s))),
+                        scores: s.scores,
+                        fhe_out,
+                        shares,
+                        contacts,
+                        round,
+                    }))
                 }
+                Err(err) => Err((err, State::TriggeredRun(s))),
             }
-            State::SubmittedInput(s) => match cmd_run(&s.client).await {
-                Ok(()) => Ok(State::TriggeredRun(StateTriggeredRun {
+        }
+        State::DownloadedOutput(mut s) => match cmd_decrypt(&mut s).await {
+            Ok((result, diff)) => {
+                let s = StateDecrypted {
                     name: s.name,
                     client: s.client,
-                    ck: s.ck,
-                    user_id: s.user_id,
-                    names: s.names,
                     scores: s.scores,
-                })),
-                Err(err) => Err((err, State::SubmittedInput(s))),
-            },
-            State::TriggeredRun(s) => match cmd_download_output(&s.client, &s.user_id, &s.ck).await
-            {
-                Ok((fhe_out, shares)) => Ok(State::DownloadedOutput(StateDownloadedOuput {
+                    result,
+                    diff,
+                };
+                match args.first() {
+                    Some(path) => match write_result(&s.result, Path::new(path)) {
+                        Ok(()) => Ok(State::Decrypted(s)),
+                        // Decrypted all the same, the file can be written again
+                        Err(err) => Err((err, State::Decrypted(s))),
+                    },
+                    None => Ok(State::Decrypted(s)),
+                }
+            }
+            Err(err) => Err((err, State::DownloadedOutput(s))),
+        },
+        State::Decrypted(s) => {
+            present_balance(&s.name, &s.scores, &s.diff);
+            match args.first() {
+                Some(path) => match write_result(&s.result, Path::new(path)) {
+                    Ok(()) => Ok(State::Decrypted(s)),
+                    Err(err) => Err((err, State::Decrypted(s))),
+                },
+                None => Ok(State::Decrypted(s)),
+            }
+        }
+    }
+}
+
+/// Drive the same steps as the interactive prompt as soon as the room allows them.
+/// The user is only notified when the scores file needs to be written.
+async fn run_daemon(
+    mut state: State,
+    scores_path: &Path,
+    p2p: Option<&str>,
+    session: Option<&SessionFile>,
+) -> Result<(), Error> {
+    // The names the scores were asked for, and when
+    let mut asked: Option<(Vec<String>, SystemTime)> = None;
+    // The version of the scores file already tried, so a rejected one isn't sent again
+    let mut tried: Option<SystemTime> = None;
+    loop {
+        let line = match &state {
+            State::Setup(StateSetup { client, .. }) => {
+                wait_for_dashboard(client, Dashboard::is_concluded).await?;
+                "next".to_string()
+            }
+            State::ConcludedRegistration(ConcludedRegistration {
+                names, contract, ..
+            }) => {
+                let asked_at = match &asked {
+                    Some((asked_names, asked_at)) if asked_names == names => *asked_at,
+                    _ => {
+                        let (min, max) = contract.value_range;
+                        notify(&format!(
+                            "Scores needed: write {} numbers from {min} to {max} to {}, for {}",
+                            names.len(),
+                            scores_path.display(),
+                            names.join(", ")
+                        ));
+                        let asked_at = SystemTime::now();
+                        asked = Some((names.clone(), asked_at));
+                        asked_at
+                    }
+                };
+                let modified = std::fs::metadata(scores_path)
+                    .and_then(|m| m.modified())
+                    .ok();
+                match modified {
+                    Some(modified) if modified > asked_at && tried != Some(modified) => {
+                        tried = Some(modified);
+                        format!("next {}", std::fs::read_to_string(scores_path)?)
+                    }
+                    _ => {
+                        sleep(DAEMON_RETRY_INTERVAL).await;
+                        continue;
+                    }
+                }
+            }
+            State::SubmittedInput(_) => {
+                let State::SubmittedInput(s) = state else {
+                    unreachable!()
+                };
+                // Scores of a later rating are asked for again
+                asked = None;
+                say!("Waiting for the FHE run ...");
+                wait_for_dashboard(&s.client, |d| {
+                    matches!(
+                        d.status(),
+                        ServerState::RunningFhe | ServerState::CompletedFhe
+                    ) || d.awaits_scores(&s.participant_id)
+                })
+                .await?;
+                state = State::TriggeredRun(StateTriggeredRun {
                     name: s.name,
                     client: s.client,
                     ck: s.ck,
+                    crs: s.crs,
+                    participant_id: s.participant_id,
                     names: s.names,
                     scores: s.scores,
-                    fhe_out,
-                    shares,
-                })),
-                Err(err) => Err((err, State::TriggeredRun(s))),
-            },
-            State::DownloadedOutput(mut s) => {
-                match cmd_download_shares(
-                    &s.client,
-                    &s.names,
-                    &s.ck,
-                    &mut s.shares,
-                    &s.fhe_out,
-                    &s.scores,
-                )
-                .await
-                {
-                    Ok(decrypted_output) => Ok(State::Decrypted(StateDecrypted {
-                        names: s.names,
-                        client: s.client,
-                        decrypted_output,
-                        scores: s.scores,
-                    })),
-                    Err(err) => Err((err, State::DownloadedOutput(s))),
-                }
+                });
+                continue;
             }
-            State::Decrypted(StateDecrypted {
-                names,
-                client,
-                decrypted_output,
-                scores,
-            }) => {
-                present_balance(&names, &scores, &decrypted_output);
-                Ok(State::Decrypted(StateDecrypted {
-                    names,
-                    client,
-                    decrypted_output,
-                    scores,
-                }))
+            State::Decrypted(StateDecrypted { diff, .. }) => {
+                let balances = diff
+                    .iter()
+                    .map(|diff| format!("{} {}", diff.name, diff.karma))
+                    .join(", ");
+                notify(&format!("Karma decrypted: {balances}"));
+                return Ok(());
+            }
+            _ => "next".to_string(),
+        };
+        state = match run(state, &line, p2p, session).await {
+            Ok(state) => {
+                state.print_status_update();
+                state
+            }
+            Err((err, state)) => {
+                report_error(&err);
+                if let State::ConcludedRegistration(_) = state {
+                    notify(&format!(
+                        "Scores in {} rejected: {err}",
+                        scores_path.display()
+                    ));
+                } else {
+                    sleep(DAEMON_RETRY_INTERVAL).await;
+                }
+                state
             }
+        };
+        if let Some(session) = session {
+            session.save(&state);
         }
-    } else if cmd == &"conclude" {
-        match state {
-            State::Setup(s) => match cmd_conclude_registration(&s.client).await {
-                Ok(names) => Ok(State::ConcludedRegistration(ConcludedRegistration {
-                    name: s.name,
-                    client: s.client,
-                    ck: s.ck,
-                    user_id: s.user_id,
-                    names,
-                })),
-                Err(err) => Err((err, State::Setup(s))),
+    }
+}
+
+/// Follow the room's events until its dashboard satisfies `done`
+async fn wait_for_dashboard(
+    client: &WebClient,
+    done: impl Fn(&Dashboard) -> bool,
+) -> Result<(), Error> {
+    let mut events = client.subscribe_events().await?;
+    if done(&client.get_dashboard().await?) {
+        return Ok(());
+    }
+    while let Some(event) = events.next().await {
+        event?;
+        if done(&client.get_dashboard().await?) {
+            return Ok(());
+        }
+    }
+    bail!("The server closed the event stream")
+}
+
+/// Log the message and show it as a desktop notification where the platform has a command for it
+fn notify(message: &str) {
+    say!("🔔 {message}");
+    let command = if cfg!(target_os = "macos") {
+        let script = format!("display notification {message:?} with title \"karma\"");
+        Command::new("osascript").args(["-e", &script]).output()
+    } else if cfg!(windows) {
+        return;
+    } else {
+        Command::new("notify-send")
+            .args(["karma", message])
+            .output()
+    };
+    // Headless machines have only the log
+    let _ = command;
+}
+
+/// `file` in the profile's storage, or the working directory
+fn stored(file: &str) -> PathBuf {
+    match STORAGE_DIR.get() {
+        Some(dir) => dir.join(file),
+        None => PathBuf::from(file),
+    }
+}
+
+/// `~/.karma/config.toml`, e.g.
+///
+/// ```toml
+/// [profiles.work]
+/// url = "https://karma.example.com"
+/// name = "alice"
+/// ```
+#[derive(Deserialize, Default)]
+struct Config {
+    #[serde(default)]
+    profiles: HashMap<String, Profile>,
+}
+
+/// What a profile gives when the command line doesn't
+#[derive(Deserialize, Default, Debug)]
+struct Profile {
+    url: Option<String>,
+    name: Option<String>,
+    room: Option<RoomId>,
+    admin_token: Option<String>,
+    /// `~/.karma/<profile>` by default. A leading `~/` is the home directory.
+    storage_dir: Option<PathBuf>,
+}
+
+impl Profile {
+    /// The profile `name` of the config file, with its storage set up for the files this
+    /// client keeps
+    fn load(name: &str) -> Result<Self, Error> {
+        let home =
+            std::env::home_dir().ok_or(anyhow!("No home directory to find the profiles in"))?;
+        let path = home.join(KARMA_DIR).join(CONFIG_FILE);
+        let mut config: Config = match std::fs::read_to_string(&path) {
+            Ok(content) => toml::from_str(&content)
+                .map_err(|err| anyhow!("{} is malformed: {err}", path.display()))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Config::default(),
+            Err(err) => return Err(err.into()),
+        };
+        let Some(mut profile) = config.profiles.remove(name) else {
+            bail!("No profile {name} in {}", path.display());
+        };
+        let storage_dir = match profile.storage_dir.take() {
+            Some(dir) => match dir.strip_prefix("~") {
+                Ok(rest) => home.join(rest),
+                Err(_) => dir,
             },
-            _ => Err((anyhow!("Invalid state for command {}", cmd), state)),
+            None => home.join(KARMA_DIR).join(name),
+        };
+        std::fs::create_dir_all(&storage_dir)?;
+        STORAGE_DIR.set(storage_dir).expect("One profile per run");
+        Ok(profile)
+    }
+}
+
+/// Keep the server's receipt in case the group disputes who stalled the round
+fn save_receipt(receipt: &Receipt) -> Result<(), Error> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(stored(RECEIPTS_FILE))?;
+    writeln!(file, "{}", serde_json::to_string(receipt)?)?;
+    say!("🧾 Receipt saved to {}", stored(RECEIPTS_FILE).display());
+    Ok(())
+}
+
+/// Keep the decrypted result so later rounds can be compared with it
+fn save_result(result: &RoundResult) -> Result<(), Error> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(stored(RESULTS_FILE))?;
+    writeln!(file, "{}", serde_json::to_string(result)?)?;
+    Ok(())
+}
+
+/// My signing key, created on first use
+fn load_identity() -> Result<SigningKey, Error> {
+    if let Ok(secret) = std::fs::read_to_string(stored(IDENTITY_FILE)) {
+        let secret: [u8; 32] = hex::decode(secret.trim())?
+            .try_into()
+            .map_err(|_| anyhow!("{IDENTITY_FILE} should hold a 32 byte key"))?;
+        return Ok(SigningKey::from_bytes(&secret));
+    }
+    let key = SigningKey::from_bytes(&thread_rng().gen());
+    save_identity(&key)?;
+    Ok(key)
+}
+
+/// Keep `key` as this machine's identity, readable by the owner only like the session files
+fn save_identity(key: &SigningKey) -> Result<(), Error> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(stored(IDENTITY_FILE))?;
+    file.write_all(hex::encode(key.to_bytes()).as_bytes())?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// A passphrase for a new file, from [`SESSION_PASSPHRASE_VAR`] or asked for twice, as a typo
+/// would lock the file for good
+fn new_passphrase(path: &Path) -> Result<String, Error> {
+    if let Ok(passphrase) = std::env::var(SESSION_PASSPHRASE_VAR) {
+        return Ok(passphrase);
+    }
+    let passphrase = rpassword::prompt_password(format!("New passphrase of {}: ", path.display()))?;
+    ensure!(
+        rpassword::prompt_password("Again: ")? == passphrase,
+        "The passphrases differ"
+    );
+    Ok(passphrase)
+}
+
+/// Write the saved session of `name` to `path` with the identity, for `key import` on another
+/// machine
+fn export_key(name: &str, room: RoomId, session: &SessionFile, path: &Path) -> Result<(), Error> {
+    ensure!(!path.exists(), "{} exists already", path.display());
+    let mut exported = session.read(name, room)?;
+    ensure!(
+        exported.ck.is_some(),
+        "The session holds no client key yet, it's made once registration closes"
+    );
+    exported.identity = Some(load_identity()?.to_bytes());
+    exported.save(path, &new_passphrase(path)?)?;
+    say!(
+        "🔑 Key of {name} in room #{room} exported to {}",
+        path.display()
+    );
+    emit(&serde_json::json!({ "name": name, "room": room, "path": path }));
+    Ok(())
+}
+
+/// Check the key exported to `path` against the room it was made for: the same round, and the
+/// user still registered with the same identity. Then keep it as the session to resume, and its
+/// identity as this machine's.
+async fn import_key(
+    path: &Path,
+    connection: &ConnectArgs,
+    url: &str,
+    session: Option<PathBuf>,
+) -> Result<(), Error> {
+    let mut imported = Session::load(path, SessionFile::new(path.to_path_buf()).passphrase()?)?;
+    let (name, room) = (imported.name.clone(), imported.room);
+    let (Some(user), Some((seed, _)), Some(_)) = (&imported.user, &imported.crs, &imported.ck)
+    else {
+        bail!("{} holds no client key", path.display());
+    };
+    let client = connection.connect(url, room).await?;
+    ensure!(
+        client.get_seed().await? == *seed,
+        "The key was made for another round of room #{room}"
+    );
+    let dashboard = client.get_dashboard().await?;
+    let registered = dashboard
+        .users()
+        .iter()
+        .find(|registered| registered.participant_id == user.participant_id)
+        .ok_or(anyhow!("{name} isn't registered in room #{room} anymore"))?;
+    let identity = imported
+        .identity
+        .take()
+        .map(|secret| SigningKey::from_bytes(&secret));
+    if let (Some(public_key), Some(identity)) = (&registered.public_key, &identity) {
+        ensure!(
+            *public_key == hex::encode(identity.verifying_key().as_bytes()),
+            "{name} registered with another identity than the exported one"
+        );
+    }
+
+    let session = SessionFile::new(match session {
+        Some(session) => session,
+        None => SessionFile::default_path(&name, room)?,
+    });
+    ensure!(
+        !session.is_saved(),
+        "A session is saved at {} already, move it away first",
+        session.path.display()
+    );
+    if let Some(identity) = identity {
+        let file = stored(IDENTITY_FILE);
+        if file.exists() {
+            ensure!(
+                load_identity()?.to_bytes() == identity.to_bytes(),
+                "{} holds another identity, import under another --profile to keep them apart",
+                file.display()
+            );
+        } else {
+            save_identity(&identity)?;
         }
-    } else if cmd == &"status" {
-        match &state {
-            State::Init(StateInit { client, .. })
-            | State::Setup(StateSetup { client, .. })
-            | State::ConcludedRegistration(ConcludedRegistration { client, .. })
-            | State::SubmittedInput(SubmittedInput { client, .. })
-            | State::TriggeredRun(StateTriggeredRun { client, .. })
-            | State::DownloadedOutput(StateDownloadedOuput { client, .. })
-            | State::Decrypted(StateDecrypted { client, .. }) => {
-                match client.get_dashboard().await {
-                    Ok(dashbaord) => {
-                        dashbaord.print_presentation();
-                        Ok(state)
-                    }
-                    Err(err) => Err((err, state)),
+    }
+    session.write(&imported)?;
+    say!(
+        "✅ Key of {name} (user #{}) in room #{room} imported to {}. Resume with `cli {name} {url} --room {room} --resume`",
+        registered.id,
+        session.path.display()
+    );
+    emit(
+        &serde_json::json!({ "name": name, "room": room, "user_id": registered.id, "session": session.path }),
+    );
+    Ok(())
+}
+
+fn load_results() -> Result<Vec<RoundResult>, Error> {
+    let Ok(content) = std::fs::read_to_string(stored(RESULTS_FILE)) else {
+        return Ok(vec![]);
+    };
+    content
+        .lines()
+        .map(|line| Ok(serde_json::from_str(line)?))
+        .collect()
+}
+
+async fn run_observer(client: &WebClient, name: &str) -> Result<(), Error> {
+    let observer = client.register_observer(name).await?;
+    say!(
+        "👀 Watching as {} (observer #{})",
+        observer.name,
+        observer.id
+    );
+    wait_for_dashboard(client, |d| {
+        show_dashboard(d);
+        d.is_fhe_complete()
+    })
+    .await?;
+    if !client.get_dashboard().await?.publishes_results() {
+        say!("The room keeps the results to its users");
+        return Ok(());
+    }
+    say!("Waiting for the decryption shares ...");
+    let result = loop {
+        match client.get_results().await {
+            Ok(result) => break result,
+            Err(_) => sleep(DAEMON_RETRY_INTERVAL).await,
+        }
+    };
+    #[derive(Tabled, Serialize)]
+    struct Row<'a> {
+        name: &'a str,
+        karma: Score,
+    }
+    let rows = zip(&result.names, &result.balances)
+        .map(|(name, &karma)| Row { name, karma })
+        .collect_vec();
+    print_table(&rows);
+    Ok(())
+}
+
+/// What `watch` shows, fetched again on every room event
+#[derive(Serialize)]
+struct WatchView {
+    dashboard: Dashboard,
+    run: Option<JobStatus>,
+    /// Bytes each participant submitted this round, from the transcript
+    submitted: HashMap<ParticipantId, u64>,
+    next_step: Option<NextStep>,
+    /// Of the last refresh. The view keeps what it had before.
+    error: Option<String>,
+}
+
+impl WatchView {
+    async fn fetch(client: &WebClient, name: Option<&str>) -> Result<Self, Error> {
+        let dashboard = client.get_dashboard().await?;
+        let run = match dashboard.status() {
+            ServerState::RunningFhe => Some(client.get_run_status().await?),
+            _ => None,
+        };
+        let mut submitted = HashMap::new();
+        for entry in client.get_transcript().await? {
+            if entry.round == dashboard.round() {
+                *submitted.entry(entry.participant_id).or_default() += entry.bytes;
+            }
+        }
+        let next_step =
+            match name.and_then(|name| dashboard.users().iter().find(|u| u.name == name)) {
+                Some(user) => Some(client.get_user_status(user.id).await?.next_step),
+                None => None,
+            };
+        Ok(Self {
+            dashboard,
+            run,
+            submitted,
+            next_step,
+            error: None,
+        })
+    }
+
+    async fn refresh(&mut self, client: &WebClient, name: Option<&str>) {
+        match Self::fetch(client, name).await {
+            Ok(view) => *self = view,
+            Err(err) => self.error = Some(err.to_string()),
+        }
+    }
+
+    fn render(&self, frame: &mut ratatui::Frame, name: Option<&str>) {
+        use ratatui::layout::{Constraint, Layout};
+        use ratatui::style::{Color, Style as TuiStyle, Stylize};
+        use ratatui::widgets::{Block, Gauge, Paragraph, Row as TuiRow, Table as TuiTable};
+
+        let d = &self.dashboard;
+        let [header, users, run, footer] = Layout::vertical([
+            Constraint::Length(4),
+            Constraint::Min(3),
+            Constraint::Length(3),
+            Constraint::Length(3),
+        ])
+        .areas(frame.area());
+
+        let mut summary = format!(
+            "{}  round {}  {:?}",
+            d.status(),
+            d.round(),
+            d.parameter_set()
+        );
+        let (rating, ratings) = d.rating();
+        if ratings > 1 {
+            summary += &format!("  rating {}/{ratings}", rating + 1);
+        }
+        let mut lines = vec![summary];
+        let deadline = d
+            .registration_deadline()
+            .or(d.deadline())
+            .or(d.decryption_deadline());
+        if let Some(deadline) = deadline {
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |now| now.as_secs());
+            lines.push(format!("Phase closes in {}s", deadline.saturating_sub(now)));
+        }
+        frame.render_widget(
+            Paragraph::new(lines.join("\n")).block(Block::bordered().title(" Room ")),
+            header,
+        );
+
+        let rows = d.users().iter().map(|user| {
+            let submitted = self
+                .submitted
+                .get(&user.participant_id)
+                .map_or(String::new(), |&bytes| HumanBytes(bytes).to_string());
+            TuiRow::new([
+                user.id.to_string(),
+                user.name.clone(),
+                user.status.to_string(),
+                if user.committed { "✅" } else { "" }.to_string(),
+                submitted,
+            ])
+        });
+        frame.render_widget(
+            TuiTable::new(
+                rows,
+                [
+                    Constraint::Length(4),
+                    Constraint::Percentage(25),
+                    Constraint::Percentage(40),
+                    Constraint::Length(10),
+                    Constraint::Length(12),
+                ],
+            )
+            .header(TuiRow::new(["ID", "Name", "Status", "Committed", "Submitted"]).bold())
+            .block(Block::bordered().title(" Users ")),
+            users,
+        );
+
+        let (ratio, label) = match &self.run {
+            Some(job) if job.total_outputs > 0 => (
+                job.outputs_computed as f64 / job.total_outputs as f64,
+                format!("{}/{} outputs", job.outputs_computed, job.total_outputs),
+            ),
+            Some(_) => (0.0, "Aggregating the server key".to_string()),
+            None if d.is_fhe_complete() => (1.0, "Done".to_string()),
+            None => (0.0, "Not running".to_string()),
+        };
+        frame.render_widget(
+            Gauge::default()
+                .block(Block::bordered().title(" FHE run "))
+                .gauge_style(TuiStyle::new().fg(Color::Green))
+                .ratio(ratio)
+                .label(label),
+            run,
+        );
+
+        let status = match (&self.error, name, &self.next_step) {
+            (Some(err), ..) => format!("❌ {err}"),
+            (None, Some(name), Some(step)) => format!("👉 Next for {name}: {step}"),
+            (None, Some(name), None) => format!("No user {name} in the room"),
+            (None, None, _) => String::new(),
+        };
+        frame.render_widget(
+            Paragraph::new(status).block(Block::bordered().title(" q to quit ")),
+            footer,
+        );
+    }
+}
+
+/// The users table, then with `watch`, each change on a line of its own as it comes, on every
+/// room event or every [`WATCH_POLL_INTERVAL`] without one
+async fn run_users(client: &WebClient, watch: bool) -> Result<(), Error> {
+    use ratatui::crossterm::style::Stylize;
+
+    let mut dashboard = client.get_dashboard().await?;
+    print_table(dashboard.users());
+    if !watch {
+        return Ok(());
+    }
+    let mut events = client.subscribe_events().await.ok();
+    loop {
+        wait_for_change(&mut events).await;
+        let latest = match client.get_dashboard().await {
+            Ok(latest) => latest,
+            Err(err) => {
+                say!("⚠️ {err}");
+                continue;
+            }
+        };
+        let at = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs());
+        for change in latest.changes_since(&dashboard) {
+            if json() {
+                emit(&serde_json::json!({ "at": at, "change": change }));
+                continue;
+            }
+            let line = format!("{} {change}", clock(at));
+            let line = match (std::io::stdout().is_terminal(), &change) {
+                (false, _) => line,
+                (_, DashboardChange::UserJoined { .. } | DashboardChange::Committed { .. }) => {
+                    line.green().to_string()
                 }
+                (_, DashboardChange::UserLeft { .. }) => line.red().to_string(),
+                (_, DashboardChange::StatusChanged { .. }) => line.cyan().to_string(),
+                (_, DashboardChange::ObserverJoined { .. }) => line.dark_grey().to_string(),
+                (
+                    _,
+                    DashboardChange::RoundStarted { .. } | DashboardChange::StateChanged { .. },
+                ) => line.yellow().bold().to_string(),
+            };
+            println!("{line}");
+        }
+        dashboard = latest;
+    }
+}
+
+/// `HH:MM:SS` of unix time `at`, in UTC
+fn clock(at: u64) -> String {
+    let secs = at % (24 * 60 * 60);
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Redrawn on every room event, or every [`WATCH_POLL_INTERVAL`] without one, until `q` or Esc
+async fn run_watch(client: &WebClient, name: Option<&str>) -> Result<(), Error> {
+    use ratatui::crossterm::event::{self, Event, KeyCode};
+
+    let mut view = WatchView::fetch(client, name).await?;
+    let mut events = client.subscribe_events().await.ok();
+    if json() {
+        // A line for every change instead of the terminal view
+        let mut last = String::new();
+        loop {
+            let line = serde_json::to_string(&view)?;
+            if line != last {
+                println!("{line}");
+                last = line;
+            }
+            wait_for_change(&mut events).await;
+            view.refresh(client, name).await;
+        }
+    }
+    // Reading the terminal blocks, so it gets a thread of its own
+    let (input_tx, mut inputs) = tokio::sync::mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        while let Ok(input) = event::read() {
+            if input_tx.send(input).is_err() {
+                break;
             }
         }
-    } else if cmd.starts_with('#') {
-        Ok(state)
+    });
+    let mut terminal = ratatui::init();
+    let watched = async {
+        loop {
+            terminal.draw(|frame| view.render(frame, name))?;
+            tokio::select! {
+                input = inputs.recv() => match input {
+                    Some(Event::Key(key)) if matches!(key.code, KeyCode::Char('q') | KeyCode::Esc) => {
+                        return Ok(());
+                    }
+                    None => return Ok(()),
+                    // Drawn again, e.g. at the new size
+                    Some(_) => continue,
+                },
+                _ = wait_for_change(&mut events) => {}
+            }
+            view.refresh(client, name).await;
+        }
+    };
+    let watched: Result<(), Error> = watched.await;
+    ratatui::restore();
+    watched
+}
+
+/// Until the next room event, or [`WATCH_POLL_INTERVAL`] without one. Once the stream ends,
+/// only the interval is left.
+async fn wait_for_change(events: &mut Option<BoxStream<'_, Result<RoomEvent, Error>>>) {
+    let Some(stream) = events else {
+        return sleep(WATCH_POLL_INTERVAL).await;
+    };
+    tokio::select! {
+        event = stream.next() => {
+            if !matches!(event, Some(Ok(_))) {
+                *events = None;
+            }
+        }
+        _ = sleep(WATCH_POLL_INTERVAL) => {}
+    }
+}
+
+/// Round trips to `/healthz` that `doctor` takes the median of
+const LATENCY_PINGS: usize = 5;
+/// Past this, `doctor` warns that every step will feel slow
+const SLOW_ROUND_TRIP: Duration = Duration::from_millis(500);
+
+/// A check of `doctor`, as emitted with `--json`
+#[derive(Serialize)]
+struct Finding<'a> {
+    check: &'a str,
+    ok: bool,
+    detail: &'a str,
+    fix: Option<&'a str>,
+}
+
+/// What `doctor` found so far. Each check is printed with what to do about it.
+#[derive(Default)]
+struct Checkup {
+    problems: usize,
+}
+
+impl Checkup {
+    fn pass(&mut self, check: &str, detail: &str) {
+        self.report(Finding {
+            check,
+            ok: true,
+            detail,
+            fix: None,
+        });
+    }
+
+    /// Nothing fails yet, but `fix` saves trouble
+    fn warn(&mut self, check: &str, detail: &str, fix: &str) {
+        self.report(Finding {
+            check,
+            ok: true,
+            detail,
+            fix: Some(fix),
+        });
+    }
+
+    fn fail(&mut self, check: &str, detail: &str, fix: &str) {
+        self.problems += 1;
+        self.report(Finding {
+            check,
+            ok: false,
+            detail,
+            fix: Some(fix),
+        });
+    }
+
+    fn report(&self, finding: Finding) {
+        let icon = match finding {
+            Finding { ok: false, .. } => "❌",
+            Finding { fix: Some(_), .. } => "⚠️",
+            Finding { fix: None, .. } => "✅",
+        };
+        say!("{icon} {}", finding.detail);
+        if let Some(fix) = finding.fix {
+            say!("   👉 {fix}");
+        }
+        emit(&finding);
+    }
+
+    fn verdict(&self) -> Result<(), Error> {
+        ensure!(
+            self.problems == 0,
+            "Found {} problem(s), see the fixes above",
+            self.problems
+        );
+        Ok(())
+    }
+}
+
+async fn run_doctor(
+    client: &WebClient,
+    room: RoomId,
+    session: Option<(String, SessionFile)>,
+) -> Result<(), Error> {
+    let mut checkup = Checkup::default();
+    // On this machine alone, so it's checked even if the server is down
+    if let Some((name, session)) = &session {
+        check_session(&mut checkup, name, room, session);
+    }
+
+    if let Err(err) = client.healthz().await {
+        let fix = match NetworkError::of_error(&err) {
+            Some(NetworkError::Timeout) => {
+                "The server is slow to answer, try a longer --connect-timeout and --read-timeout"
+            }
+            Some(_) => "Check the URL, that the server runs, and --proxy if you need one to reach it",
+            None => "Check the URL, and pass --ca-cert or --pin-cert if the server's certificate isn't signed by a public CA",
+        };
+        checkup.fail(
+            "reachable",
+            &format!("The server is unreachable: {err}"),
+            fix,
+        );
+        return checkup.verdict();
+    }
+    checkup.pass("reachable", "The server is up");
+
+    let ours = env!("CARGO_PKG_VERSION");
+    let version = match client.get_version().await {
+        Ok(version) => version,
+        Err(err) => {
+            checkup.fail(
+                "version",
+                &format!("The server didn't tell its version: {err}"),
+                "The server predates this client, use a client of the server's karma_calculator version",
+            );
+            return checkup.verdict();
+        }
+    };
+    if version.protocol != PROTOCOL_VERSION {
+        checkup.fail(
+            "version",
+            &format!(
+                "The server speaks protocol version {} (karma_calculator {}), this client speaks version {PROTOCOL_VERSION} (karma_calculator {ours})",
+                version.protocol, version.crate_version
+            ),
+            &format!("Use a client of karma_calculator {}", version.crate_version),
+        );
+        return checkup.verdict();
+    }
+    let parameters = match version.parameter_set {
+        Some(parameter_set) => format!("every room uses {parameter_set:?}"),
+        None => "each room picks its parameters".to_string(),
+    };
+    checkup.pass(
+        "version",
+        &format!(
+            "The server speaks protocol version {PROTOCOL_VERSION} like this client (karma_calculator {} and {ours}), {parameters}",
+            version.crate_version
+        ),
+    );
+
+    let mut round_trips = Vec::with_capacity(LATENCY_PINGS);
+    for _ in 0..LATENCY_PINGS {
+        let started = Instant::now();
+        client.healthz().await?;
+        round_trips.push(started.elapsed());
+    }
+    round_trips.sort();
+    let latency = round_trips[LATENCY_PINGS / 2];
+    let detail = format!("Round trip to the server: {latency:.0?}");
+    if latency > SLOW_ROUND_TRIP {
+        checkup.warn(
+            "latency",
+            &detail,
+            "Expect slow steps, and try a longer --read-timeout if requests time out",
+        );
+    } else {
+        checkup.pass("latency", &detail);
+    }
+
+    let readiness = client.readyz().await?;
+    if let Some(bytes) = readiness.memory_bytes {
+        say!("Server memory: {} MB", bytes / (1024 * 1024));
+    }
+    if readiness.running {
+        say!("⏳ An FHE run is in progress, expect slow responses");
+    }
+    if json() {
+        emit(&readiness);
     } else {
-        Err((anyhow!("Unknown command {}", cmd), state))
+        #[derive(Tabled)]
+        struct Row {
+            room: RoomId,
+            status: ServerState,
+            users: usize,
+        }
+        let rows = readiness.rooms.iter().map(|summary| Row {
+            room: summary.id,
+            status: summary.status.clone(),
+            users: summary.users,
+        });
+        println!("{}", Table::new(rows).with(Style::ascii_rounded()));
+    }
+    let Some(summary) = readiness.rooms.iter().find(|summary| summary.id == room) else {
+        checkup.fail(
+            "room",
+            &format!("No room #{room} on the server"),
+            "Pick one of the rooms above with --room",
+        );
+        return checkup.verdict();
+    };
+    check_upload(&mut checkup, client, &summary.status).await?;
+    match summary.status {
+        ServerState::ReadyForJoining => {
+            say!("Room #{room} takes registrations, the admin closes them")
+        }
+        ServerState::ReadyForCommitments => {
+            say!("Room #{room} waits for every user to commit to a cipher")
+        }
+        ServerState::ReadyForInputs => {
+            let dashboard = client.get_dashboard().await?;
+            let missing = dashboard
+                .users()
+                .iter()
+                .filter(|user| {
+                    !matches!(
+                        user.status,
+                        UserStatus::Submitted {
+                            cipher: true,
+                            key_share: true,
+                            ..
+                        }
+                    )
+                })
+                .map(|user| user.name.clone())
+                .collect_vec();
+            say!(
+                "Room #{room} waits for the inputs of {}",
+                missing.join(", ")
+            );
+        }
+        ServerState::ReadyForRunning => {
+            say!("Room #{room} has every input, the admin starts the run")
+        }
+        ServerState::RunningFhe => {
+            let status = client.get_run_status().await?;
+            say!(
+                "Room #{room} is running: key aggregated {}, {}/{} outputs",
+                status.keys_aggregated,
+                status.outputs_computed,
+                status.total_outputs
+            );
+        }
+        ServerState::CompletedFhe => {
+            let status = client.get_decryption_status().await?;
+            let missing = status
+                .users
+                .iter()
+                .filter(|user| !user.submitted.iter().all(|&submitted| submitted))
+                .map(|user| user.name.clone())
+                .collect_vec();
+            say!(
+                "Room #{room} completed the run and waits for the decryption shares of {}",
+                missing.join(", ")
+            );
+        }
+    }
+    checkup.verdict()
+}
+
+/// Whether the saved session of `name` opens, where it should be
+fn check_session(checkup: &mut Checkup, name: &str, room: RoomId, session: &SessionFile) {
+    let path = session.path.display();
+    if !session.is_saved() {
+        checkup.pass(
+            "session",
+            &format!("No session saved at {path}, nothing to resume"),
+        );
+        return;
+    }
+    match session.read(name, room) {
+        Ok(session) => {
+            let step = if session.fhe_output.is_some() {
+                "decrypting the output"
+            } else if session.scores.is_some() {
+                "submitted"
+            } else if session.ck.is_some() {
+                "set up"
+            } else {
+                "registered"
+            };
+            checkup.pass(
+                "session",
+                &format!("The session at {path} opens, {name} is {step} in room #{room}"),
+            );
+        }
+        Err(err) => checkup.fail(
+            "session",
+            &format!("The session doesn't open: {err}"),
+            &format!("Check {SESSION_PASSPHRASE_VAR} and --session, or move {path} away and start over with `setup`"),
+        ),
+    }
+}
+
+/// How long a key share takes to upload at the speed of the last upload from this machine,
+/// sized after the key shares the room has, and whether that makes the deadline
+async fn check_upload(
+    checkup: &mut Checkup,
+    client: &WebClient,
+    status: &ServerState,
+) -> Result<(), Error> {
+    let (_, Some(bytes)) = upload_sizes(client).await? else {
+        say!("No key share in the room yet to estimate an upload from");
+        return Ok(());
+    };
+    let Some(speed) = std::fs::read_to_string(stored(UPLOAD_SPEED_FILE))
+        .ok()
+        .and_then(|content| content.parse::<f64>().ok())
+    else {
+        say!(
+            "A key share is about {}, its upload time is estimated after the first upload from this machine",
+            HumanBytes(bytes)
+        );
+        return Ok(());
+    };
+    let estimate = Duration::from_secs_f64(bytes as f64 / speed.max(f64::EPSILON));
+    let detail = format!(
+        "A key share of about {} takes about {} to upload at {}/s, the speed of the last upload from this machine",
+        HumanBytes(bytes),
+        HumanDuration(estimate),
+        HumanBytes(speed as u64)
+    );
+    let deadline = match status {
+        ServerState::ReadyForInputs => client.get_dashboard().await?.deadline(),
+        _ => None,
+    };
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs());
+    match deadline {
+        Some(deadline) if now + estimate.as_secs() > deadline => checkup.fail(
+            "upload",
+            &format!(
+                "{detail}, past the deadline in {}s",
+                deadline.saturating_sub(now)
+            ),
+            "Submit now over a faster link, or ask the admin to extend the deadline",
+        ),
+        _ => checkup.pass("upload", &detail),
+    }
+    Ok(())
+}
+
+/// Users of the room `bench` times without a server to ask
+const BENCH_USERS: usize = 4;
+
+/// Time the steps of a user of a room of `users`, under the parameters of the room at `url` if
+/// given. The key share timing is kept for the estimate of the next one.
+async fn run_bench(
+    connection: &ConnectArgs,
+    users: Option<usize>,
+    url: Option<String>,
+    room: RoomId,
+) -> Result<(), Error> {
+    let for_parties = |users| {
+        ParameterSet::for_parties(users).ok_or(anyhow!("No parameters support {users} users"))
+    };
+    let (parameter, users) = match url {
+        Some(url) => {
+            let client = connection.connect(&url, room).await?;
+            let dashboard = client.get_dashboard().await?;
+            let users = users.unwrap_or(dashboard.users().len()).max(1);
+            let parameter = match client.get_version().await?.parameter_set {
+                Some(parameter) => parameter,
+                None if dashboard.is_concluded() => dashboard.parameter_set(),
+                None => for_parties(users)?,
+            };
+            (parameter, users)
+        }
+        None => {
+            let users = users.unwrap_or(BENCH_USERS);
+            (for_parties(users)?, users)
+        }
+    };
+    say!("⏱️ Timing a user of a room of {users} under {parameter:?}");
+    let spinner = ProgressBar::new_spinner();
+    spinner.set_style(ProgressStyle::with_template("[{elapsed_precise}] {spinner} {msg}").unwrap());
+    spinner.enable_steady_tick(Duration::from_millis(100));
+    let threads = KEYGEN_THREADS.get().copied();
+    let report = {
+        let spinner = spinner.clone();
+        tokio::task::spawn_blocking(move || {
+            bench(parameter, users, threads, |step| {
+                spinner.set_message(bench_step_name(step, users))
+            })
+        })
+        .await??
+    };
+    spinner.finish_and_clear();
+
+    #[derive(Tabled, Serialize)]
+    struct Row {
+        step: String,
+        took: String,
+    }
+    let rows = report
+        .timings
+        .iter()
+        .map(|&(step, took)| Row {
+            step: bench_step_name(step, users),
+            took: format!("{took:.1?}"),
+        })
+        .collect_vec();
+    print_table(&rows);
+    let total: Duration = report
+        .timings
+        .iter()
+        .filter(|(step, _)| step.is_users())
+        .map(|(_, took)| took)
+        .sum();
+    say!(
+        "A round takes about {} of this machine's time, and a key share of {} to upload",
+        HumanDuration(total),
+        HumanBytes(report.key_share_bytes)
+    );
+    if let Some(&(_, took)) = report
+        .timings
+        .iter()
+        .find(|(step, _)| *step == BenchStep::ServerKeyShare)
+    {
+        keep_keygen_timing(keygen_timing_key(parameter), took);
+    }
+    Ok(())
+}
+
+fn bench_step_name(step: BenchStep, users: usize) -> String {
+    match step {
+        BenchStep::ClientKey => "Client key".to_string(),
+        BenchStep::ServerKeyShare => "Server key share".to_string(),
+        BenchStep::Encryption => format!("Encryption of {users} scores"),
+        BenchStep::DecryptionShares => format!("Decryption shares of {users} outputs"),
+        BenchStep::ServerSum => format!("Server sum of {users} scores"),
+        BenchStep::LinearSum => format!("Server sum of {users} scores, one after another"),
     }
 }
 
-fn present_balance(names: &[String], scores: &[Score], final_balances: &[Score]) {
-    #[derive(Tabled)]
+/// Highest score a simulated user gives, so the balances stay readable
+const SIMULATED_MAX_SCORE: Score = 10;
+
+/// A round of `users` simulated users on a throwaway server in this process, from registration
+/// to decryption, with random scores
+async fn run_simulate(users: usize) -> Result<(), Error> {
+    ensure!(users >= 2, "Simulate at least 2 users");
+    ensure!(
+        ParameterSet::for_parties(users).is_some(),
+        "No parameters support {users} users"
+    );
+    let port = std::net::TcpListener::bind("127.0.0.1:0")?
+        .local_addr()?
+        .port();
+    let admin_token = hex::encode(thread_rng().gen::<[u8; 32]>());
+    let server = local_rocket(port, &admin_token).ignite().await?;
+    let shutdown = server.shutdown();
+    tokio::spawn(server.launch());
+    let url = format!("http://127.0.0.1:{port}");
+    say!("🖥️ Server up at {url}");
+
+    let client = WebClient::new(&url).with_admin_token(&admin_token);
+    let (min, max) = client.get_circuit().await?.value_range;
+    let scores = (0..users)
+        .map(|me| {
+            (0..users)
+                .map(|user_id| match user_id == me {
+                    true => 0,
+                    false => thread_rng().gen_range(min..=max.min(SIMULATED_MAX_SCORE)),
+                })
+                .collect_vec()
+        })
+        .collect_vec();
+    let mut party = SimulatedParty::new(client, users);
+    if let Some(&threads) = KEYGEN_THREADS.get() {
+        party = party.with_threads(threads);
+    }
+    let spinner = ProgressBar::new_spinner();
+    spinner.set_style(ProgressStyle::with_template("[{elapsed_precise}] {spinner} {msg}").unwrap());
+    spinner.set_message(format!(
+        "{users} users make their keys in parallel, submit, run the circuit and decrypt"
+    ));
+    spinner.enable_steady_tick(Duration::from_millis(100));
+    let balances = party.run(&scores).await;
+    spinner.finish_and_clear();
+    let names = party.client().get_dashboard().await?.get_names();
+    shutdown.notify();
+    let balances = balances?;
+
+    #[derive(Tabled, Serialize)]
     struct Row {
+        name: String,
+        karma_given: Score,
+        karma_received: Score,
+        decrypted_karma_balance: Score,
+    }
+    let rows = zip(names, balances)
+        .enumerate()
+        .map(|(user_id, (name, balance))| Row {
+            name,
+            karma_given: scores[user_id].iter().sum(),
+            karma_received: scores.iter().map(|scores| scores[user_id]).sum(),
+            decrypted_karma_balance: balance,
+        })
+        .collect_vec();
+    print_table(&rows);
+    say!("✅ Every user decrypted the balances the scores add up to");
+    Ok(())
+}
+
+/// Every user's net karma by name, with my row marked
+fn present_balance(me: &str, scores: &[Score], diff: &[KarmaDiff]) {
+    #[derive(Tabled, Serialize)]
+    struct Row {
+        #[tabled(rename = "", display_with = "my_row")]
+        me: bool,
         name: String,
         karma_i_sent: Score,
         decrypted_karma_balance: Score,
+        since_last_round: Trend,
     }
-    let table = zip(zip(names, scores), final_balances)
-        .map(|((name, &karma_i_sent), &decrypted_karma_balance)| Row {
-            name: name.to_string(),
+    let table = zip(scores, diff)
+        .map(|(&karma_i_sent, diff)| Row {
+            me: diff.name == me,
+            name: diff.name.to_string(),
             karma_i_sent,
-            decrypted_karma_balance,
+            decrypted_karma_balance: diff.karma,
+            since_last_round: diff.trend,
         })
         .collect_vec();
-    println!("{}", Table::new(table).with(Style::ascii_rounded()));
+    print_table(&table);
+    match diff.iter().find(|diff| diff.name == me) {
+        Some(KarmaDiff {
+            karma,
+            trend: Trend::New,
+            ..
+        }) => say!("My net karma: {karma}"),
+        Some(KarmaDiff { karma, trend, .. }) => {
+            say!("My net karma: {karma} ({trend} since the last round)")
+        }
+        None => {}
+    }
+}
+
+fn my_row(me: &bool) -> &'static str {
+    if *me {
+        "👉"
+    } else {
+        ""
+    }
 }
@@ -1,7 +1,8 @@
-use karma_calculator::rocket;
+use karma_calculator::{init_tracing, rocket};
 
 #[rocket::main]
-async fn main() -> Result<(), rocket::Error> {
+async fn main() -> Result<(), Box<rocket::Error>> {
+    init_tracing();
     rocket().launch().await?;
     Ok(())
 }
@@ -0,0 +1,7 @@
+//! Evaluates one FHE run for a server configured with `worker`: reads the job from stdin and
+//! writes the outputs to stdout
+use karma_calculator::run_worker;
+
+fn main() -> Result<(), anyhow::Error> {
+    run_worker(std::io::stdin().lock(), std::io::stdout())
+}
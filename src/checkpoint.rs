@@ -0,0 +1,60 @@
+//! Outputs of a run saved as they are computed, so a restarted server resumes the run
+//! from the last completed output instead of evaluating everything again
+use crate::persist::{read, write_atomic};
+use crate::receipt::artifact_hash;
+use crate::types::{UserId, UserInputs, Word};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// A directory of outputs, one file per user. It is named after the round and the inputs,
+/// so a replaced cipher or key share starts a fresh checkpoint.
+#[derive(Debug, Clone)]
+pub(crate) struct Checkpoint {
+    dir: PathBuf,
+}
+
+impl Checkpoint {
+    /// `artifact_dir` holds the inputs on disk, so their handles are only paths and cheap to hash
+    pub(crate) fn new(artifact_dir: &Path, round: u64, inputs: &[UserInputs]) -> Self {
+        let digest = artifact_hash(&(round, inputs));
+        Self {
+            dir: artifact_dir.join(format!("checkpoint-{}", &digest[..16])),
+        }
+    }
+
+    fn path(&self, user_id: UserId) -> PathBuf {
+        self.dir.join(format!("output-{user_id}.msgpack"))
+    }
+
+    /// Blocking. The outputs saved so far, in [`UserId`] order. An unreadable one is computed again.
+    pub(crate) fn load(&self, users: usize) -> Vec<Option<Word>> {
+        (0..users)
+            .map(|user_id| {
+                let path = self.path(user_id);
+                if !path.exists() {
+                    return None;
+                }
+                read(&path)
+                    .inspect_err(
+                        |err| warn!(path = %path.display(), "Skipping checkpoint: {:?}", err),
+                    )
+                    .ok()
+            })
+            .collect()
+    }
+
+    /// Blocking and best effort, the run goes on even if the disk fails us
+    pub(crate) fn save(&self, user_id: UserId, output: &Word) {
+        if let Err(err) = write_atomic(&self.path(user_id), output) {
+            warn!(user_id, "Failed to checkpoint the output: {:?}", err);
+        }
+    }
+
+    /// For a completed run, whose outputs are in the room snapshot from now on
+    pub(crate) fn discard(&self) {
+        if let Err(err) = fs::remove_dir_all(&self.dir) {
+            warn!(dir = %self.dir.display(), "Failed to remove the checkpoint: {err}");
+        }
+    }
+}
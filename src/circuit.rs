@@ -1,51 +1,311 @@
 use crate::{
     compiled::{karma_add, karma_sub},
-    time,
-    types::{CircuitInput, CircuitOutput, ServerKeyShare, Word},
+    types::{CircuitInput, EncryptedInput, Score, ServerKeyShare, UserId, Word, SCORE_BITS},
 };
+use anyhow::ensure;
 use itertools::Itertools;
 use phantom_zone::{aggregate_server_key_shares, set_parameter_set, ParameterSelector};
-use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
+use rayon::{
+    iter::{IntoParallelRefIterator, ParallelIterator},
+    slice::ParallelSlice,
+};
+use rocket::serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+
+/// Serializable name of a [`ParameterSelector`], so it can be stored per room and shown to clients.
+/// Fewer parties take smaller parameters, and much smaller server key shares.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub enum ParameterSet {
+    NonInteractiveLTE2Party,
+    NonInteractiveLTE4Party,
+    NonInteractiveLTE8Party,
+    #[default]
+    NonInteractiveLTE40PartyExperimental,
+}
+
+impl ParameterSet {
+    const ALL: [Self; 4] = [
+        Self::NonInteractiveLTE2Party,
+        Self::NonInteractiveLTE4Party,
+        Self::NonInteractiveLTE8Party,
+        Self::NonInteractiveLTE40PartyExperimental,
+    ];
+
+    pub fn selector(&self) -> ParameterSelector {
+        match self {
+            Self::NonInteractiveLTE2Party => ParameterSelector::NonInteractiveLTE2Party,
+            Self::NonInteractiveLTE4Party => ParameterSelector::NonInteractiveLTE4Party,
+            Self::NonInteractiveLTE8Party => ParameterSelector::NonInteractiveLTE8Party,
+            Self::NonInteractiveLTE40PartyExperimental => {
+                ParameterSelector::NonInteractiveLTE40PartyExperimental
+            }
+        }
+    }
 
-pub const PARAMETER: ParameterSelector = ParameterSelector::NonInteractiveLTE40PartyExperimental;
+    /// Most users a run with these parameters supports
+    pub fn max_parties(&self) -> usize {
+        match self {
+            Self::NonInteractiveLTE2Party => 2,
+            Self::NonInteractiveLTE4Party => 4,
+            Self::NonInteractiveLTE8Party => 8,
+            Self::NonInteractiveLTE40PartyExperimental => 40,
+        }
+    }
+
+    /// The smallest parameters that support `parties` users
+    pub fn for_parties(parties: usize) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|parameter| parties <= parameter.max_parties())
+    }
+}
+
+/// How the circuit treats the score users give themselves
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub enum SelfScorePolicy {
+    /// Counted as both sent and received, so it cancels out
+    Ignored,
+}
+
+/// What the active circuit expects of each user's scores, see `/circuit`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct InputContract {
+    /// One score per user, in [`crate::UserId`] order
+    pub scores_expected: usize,
+    /// Lowest and highest score, inclusive
+    pub value_range: (Score, Score),
+    pub self_score_policy: SelfScorePolicy,
+}
+
+impl InputContract {
+    pub(crate) fn new(total_users: usize) -> Self {
+        // A balance is at most what the `total_users - 1` others send, so the highest score
+        // keeps that sum in a [`Score`] for the homomorphic additions not to wrap
+        let others = total_users.saturating_sub(1).max(1);
+        let max_score = (Score::MAX as usize / others) as Score;
+        Self {
+            scores_expected: total_users,
+            value_range: (0, max_score),
+            self_score_policy: SelfScorePolicy::Ignored,
+        }
+    }
+
+    /// Check scores before encrypting them
+    pub fn validate(&self, scores: &[Score]) -> Result<(), anyhow::Error> {
+        ensure!(
+            scores.len() == self.scores_expected,
+            "Mismatch scores and user number. Score: {}, users: {}",
+            scores.len(),
+            self.scores_expected
+        );
+        let (min, max) = self.value_range;
+        ensure!(
+            scores.iter().all(|x| (min..=max).contains(x)),
+            "All scores should be in range of {} to {}. Scores: {:?}",
+            min,
+            max,
+            scores,
+        );
+        Ok(())
+    }
+
+    /// What the server can check of a cipher as it arrives: one encrypted score per expected
+    /// score. The values are encrypted, so their range is checked by the client with
+    /// [`Self::validate`], and their width once the run unpacks them, see [`Self::check_input`].
+    pub(crate) fn check_cipher(&self, cipher: &EncryptedInput) -> Result<(), String> {
+        if cipher.n() != self.scores_expected {
+            return Err(format!(
+                "Expect {} encrypted scores, got {}",
+                self.scores_expected,
+                cipher.n()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Check an unpacked cipher: one word per expected score, each as wide as a [`Score`]
+    pub(crate) fn check_input(&self, input: &CircuitInput) -> Result<(), String> {
+        if input.len() != self.scores_expected {
+            return Err(format!(
+                "Expect {} scores, got {}",
+                self.scores_expected,
+                input.len()
+            ));
+        }
+        let bits = SCORE_BITS;
+        match input.iter().position(|word| word.len() != bits) {
+            Some(index) => Err(format!(
+                "Score #{index} has {} bits instead of {bits}",
+                input[index].len(),
+            )),
+            None => Ok(()),
+        }
+    }
+}
 
 /// Circuit
-pub(crate) fn sum_fhe_dyn(input: &[Word]) -> Word {
-    let sum = input
-        .par_iter()
-        .cloned()
-        .reduce_with(|a, b| {
-            // HACK: How come the set_parameter_set didn't propagate to karma_add?
-            set_parameter_set(PARAMETER);
-            karma_add(&a, &b)
-        })
-        .expect("Not None");
-    sum
+pub(crate) fn sum_fhe_dyn(input: &[Word], parameter: ParameterSet) -> Word {
+    tree_reduce(input, |a, b| {
+        // HACK: How come the set_parameter_set didn't propagate to karma_add?
+        set_parameter_set(parameter.selector());
+        karma_add(a, b)
+    })
+    .expect("Not None")
+}
+
+/// Combine neighbouring `items` with `op`, level by level across the pool, until one is left.
+/// The sum of `n` words then waits on `log2(n)` additions in a row rather than up to `n - 1`.
+/// Keeps the order of `items`, so `op` needn't commute. `None` if `items` is empty.
+pub(crate) fn tree_reduce<T: Clone + Send + Sync>(
+    items: &[T],
+    op: impl Fn(&T, &T) -> T + Sync,
+) -> Option<T> {
+    let pairwise = |level: &[T]| {
+        level
+            .par_chunks(2)
+            .map(|pair| match pair {
+                [a, b] => op(a, b),
+                [odd] => odd.clone(),
+                _ => unreachable!("Chunks of 1 or 2"),
+            })
+            .collect::<Vec<_>>()
+    };
+    let mut level = match items {
+        [] => return None,
+        [one] => return Some(one.clone()),
+        _ => pairwise(items),
+    };
+    while level.len() > 1 {
+        level = pairwise(&level);
+    }
+    level.pop()
 }
 
 /// Server work
 /// Warning: global variable change
+#[tracing::instrument(skip_all, fields(shares = server_key_shares.len()))]
 pub(crate) fn derive_server_key(server_key_shares: &[ServerKeyShare]) {
-    let server_key = time!(
-        || aggregate_server_key_shares(server_key_shares),
-        "Aggregate server key shares"
-    );
-    server_key.set_server_key();
+    aggregate_server_key_shares(server_key_shares).set_server_key();
+}
+
+/// What the server computes on every user's encrypted inputs. Rather than one
+/// `eval(inputs) -> Vec<Word>`, outputs are computed one at a time by ID, so a run can publish
+/// them early, checkpoint them and split them across workers. Outputs are addressed by ID all
+/// the way to decryption, and every participant makes a decryption share of each.
+pub(crate) trait Circuit: Sync {
+    /// How many outputs a run of `users` users has
+    fn output_count(&self, users: usize) -> usize;
+
+    /// The user output `output_id` belongs to, if outputs belong to users at all
+    fn output_owner(&self, output_id: usize) -> Option<UserId>;
+
+    /// What each user's inputs must be, see `/circuit`
+    fn input_contract(&self, users: usize) -> InputContract;
+
+    /// Output `output_id` of `inputs`, which holds every user's input in [`UserId`] order.
+    /// The input of a user left out of the run, whose cipher is quarantined, is empty.
+    fn eval_output(
+        &self,
+        inputs: &[CircuitInput],
+        output_id: usize,
+        parameter: ParameterSet,
+    ) -> Word;
+
+    /// Fold `output` of a later rating into the same output `earlier` of the round's earlier
+    /// ratings, see [`crate::types::RoomConfig::ratings`]
+    fn carry(&self, earlier: &Word, output: &Word, parameter: ParameterSet) -> Word;
+}
+
+/// Serializable name of a [`Circuit`] the server can run, so it can be set in the config, stored
+/// per room and sent to workers. Another app is hosted by implementing [`Circuit`] and adding
+/// it here.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub enum CircuitId {
+    /// See [`KarmaCircuit`]
+    #[default]
+    Karma,
+}
+
+impl CircuitId {
+    pub(crate) fn circuit(&self) -> &'static dyn Circuit {
+        match self {
+            Self::Karma => &KarmaCircuit,
+        }
+    }
+}
+
+/// Each user's output is their karma balance: the karma they received minus the karma they
+/// sent, wrapping around like [`crate::Score`]
+pub(crate) struct KarmaCircuit;
+
+impl Circuit for KarmaCircuit {
+    fn output_count(&self, users: usize) -> usize {
+        users
+    }
+
+    fn output_owner(&self, output_id: usize) -> Option<UserId> {
+        Some(output_id)
+    }
+
+    fn input_contract(&self, users: usize) -> InputContract {
+        InputContract::new(users)
+    }
+
+    /// A user left out of the run sent nothing, and still receives what the others sent
+    fn eval_output(&self, cis: &[CircuitInput], my_id: UserId, parameter: ParameterSet) -> Word {
+        let received = cis
+            .iter()
+            .filter_map(|enc| enc.get(my_id).cloned())
+            .collect_vec();
+        let received = sum_fhe_dyn(&received, parameter);
+        if cis[my_id].is_empty() {
+            return received;
+        }
+        let sent = sum_fhe_dyn(&cis[my_id], parameter);
+        set_parameter_set(parameter.selector());
+        karma_sub(&received, &sent)
+    }
+
+    /// Balances of each rating add up
+    fn carry(&self, earlier: &Word, output: &Word, parameter: ParameterSet) -> Word {
+        set_parameter_set(parameter.selector());
+        karma_add(earlier, output)
+    }
 }
 
 /// Server work
-pub(crate) fn evaluate_circuit(cis: &[CircuitInput]) -> CircuitOutput {
-    let mut outs = vec![];
-
-    cis.par_iter()
-        .enumerate()
-        .map(|(my_id, my_ci)| {
-            let sent = sum_fhe_dyn(my_ci);
-            let received = cis.iter().map(|enc| enc[my_id].clone()).collect_vec();
-            let received = sum_fhe_dyn(&received);
-            set_parameter_set(PARAMETER);
-            karma_sub(&received, &sent)
+///
+/// Returns the outputs `output_ids` of `circuit`, in that order, so a run can be split across
+/// workers. `cis` holds every user's input, as each output may need all of them.
+/// Each output is [`Circuit::carry`]-ed onto the same output `carried` from earlier ratings, if any.
+/// `on_output` is called with the output's ID and the output each time one is computed.
+/// Returns `None` if `cancel` fires, checked before each output.
+pub(crate) fn evaluate_circuit(
+    circuit: &dyn Circuit,
+    cis: &[CircuitInput],
+    carried: &[Word],
+    output_ids: &[usize],
+    parameter: ParameterSet,
+    cancel: &CancellationToken,
+    on_output: impl Fn(usize, &Word) + Sync + Send,
+) -> Option<Vec<Word>> {
+    output_ids
+        .par_iter()
+        .map(|&output_id| {
+            if cancel.is_cancelled() {
+                return None;
+            }
+            let _span = tracing::info_span!("output", output_id).entered();
+            let mut output = circuit.eval_output(cis, output_id, parameter);
+            if let Some(earlier) = carried.get(output_id) {
+                output = circuit.carry(earlier, &output, parameter);
+            }
+            on_output(output_id, &output);
+            Some(output)
         })
-        .collect_into_vec(&mut outs);
-    CircuitOutput::new(outs)
+        .collect()
 }
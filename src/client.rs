@@ -1,261 +1,1417 @@
 use crate::{
-    dashboard::{Dashboard, RegisteredUser},
+    auth::{ADMIN_TOKEN_HEADER, SIGNATURE_HEADER},
+    circuit::{InputContract, ParameterSet},
+    compression::{compress, ZSTD},
+    dashboard::{Dashboard, RegisteredUser, UserProgress},
+    events::RoomEvent,
+    health::Readiness,
+    history::LogEntry,
+    idempotency::IDEMPOTENCY_KEY_HEADER,
+    receipt::{
+        artifact_hash, sign_submission, ArchiveSignature, Receipt, SignedResults,
+        ARCHIVE_SIGNATURE_HEADER,
+    },
+    report::RoundResult,
+    retry::RetryPolicy,
+    room::{RoomId, RoomSummary},
+    server::setup,
+    tls::TlsOptions,
+    transport::{upload_bar, HttpOptions, ReqwestTransport, Response, Transport},
     types::{
-        EncryptedInput, CircuitOutput, DecryptionShare, DecryptionShareSubmission, InputSubmission,
-        Seed, ServerKeyShare, ServerState, UserId,
+        CipherCommitment, CipherSubmission, CircuitOutput, DeadlineExtension, DecryptionShare,
+        DecryptionShareSubmission, DecryptionSharesMap, DecryptionStatus, EncryptedInput,
+        ErrorBody, ErrorCode, FheOutput, InputSubmission, InvalidResponse, JobStatus,
+        KeyShareSubmission, Observer, ParticipantId, Score, Seed, ServerKeyShare, ServerState,
+        Timestamp, TranscriptEntry, Transition, UserId,
     },
+    upload::{UploadKind, UploadProgress, UploadStart},
+    version::{ServerVersion, API_BASE},
+    worker::{Evaluation, WorkerJob},
 };
-use anyhow::{anyhow, bail, Error};
-use indicatif::{ProgressBar, ProgressStyle};
-use reqwest::{self, header::CONTENT_TYPE, Client};
+use anyhow::{anyhow, ensure, Error};
+use ed25519_dalek::SigningKey;
+use futures::stream::{self, BoxStream, FuturesUnordered, StreamExt, TryStreamExt};
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
+use itertools::Itertools;
+use phantom_zone::{gen_client_key, gen_server_key_share, set_parameter_set};
+use rand::{thread_rng, Rng};
+use rayon::prelude::*;
 use rocket::serde::msgpack;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
-    pin::Pin,
-    task::{Context, Poll},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
-use tokio::io::AsyncRead;
-use tokio_util::io::ReaderStream;
+use tokio::task::spawn_blocking;
+use tokio::time::sleep;
+use tokio_util::io::{StreamReader, SyncIoBridge};
 
-pub enum WebClient {
-    Prod {
-        url: String,
-        client: reqwest::Client,
-    },
-    Test(Box<rocket::local::asynchronous::Client>),
+/// Big enough to keep the request overhead low, small enough for Rocket's default `bytes` limit
+const UPLOAD_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+/// Consecutive failures of a chunked upload before giving up
+const UPLOAD_RETRIES: u64 = 5;
+/// Defaults of [`SimulatedParty`]
+const SIMULATION_CONCURRENCY: usize = 4;
+const SIMULATION_RUN_TIMEOUT: Duration = Duration::from_secs(600);
+/// Between the first polls of [`WebClient::wait_for`], doubled up to [`WAIT_MAX_INTERVAL`]
+const WAIT_INITIAL_INTERVAL: Duration = Duration::from_millis(100);
+const WAIT_MAX_INTERVAL: Duration = Duration::from_secs(2);
+
+/// An error response of the server. Client methods return it inside their [`Error`], so
+/// callers get it back with `err.downcast_ref::<ClientError>()` and match on its [`ErrorCode`].
+#[derive(Debug, thiserror::Error)]
+pub enum ClientError {
+    #[error("Server responded error {status}: {}", .body.message)]
+    Server { status: u16, body: ErrorBody },
+    /// A body that isn't an [`ErrorBody`], e.g. from a proxy in front of the server
+    #[error("Server responded error {status}: {body:?}")]
+    Unexpected { status: u16, body: String },
+}
+
+impl ClientError {
+    fn new(status: u16, bytes: &[u8]) -> Self {
+        match serde_json::from_slice(bytes) {
+            Ok(body) => Self::Server { status, body },
+            Err(_) => Self::Unexpected {
+                status,
+                body: String::from_utf8_lossy(bytes).into_owned(),
+            },
+        }
+    }
+
+    pub fn code(&self) -> Option<ErrorCode> {
+        match self {
+            Self::Server { body, .. } => Some(body.code),
+            Self::Unexpected { .. } => None,
+        }
+    }
+
+    /// The code of `err`, if the server answered it
+    pub fn code_of(err: &Error) -> Option<ErrorCode> {
+        err.downcast_ref::<Self>().and_then(Self::code)
+    }
+}
+
+/// How an upload went, see [`WebClient::last_upload`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UploadStats {
+    /// As sent, so compressed
+    pub bytes: u64,
+    /// From the first byte sent to the server's answer, over every chunk and retry
+    pub elapsed: Duration,
+}
+
+impl UploadStats {
+    /// Average bytes per second
+    pub fn throughput(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+impl std::fmt::Display for UploadStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} in {:.1?}, {}/s",
+            HumanBytes(self.bytes),
+            self.elapsed,
+            HumanBytes(self.throughput() as u64)
+        )
+    }
+}
+
+/// Users registered through a client, with the keys they sign submissions with
+type Registrations = Mutex<Vec<(RegisteredUser, Option<SigningKey>)>>;
+
+pub struct WebClient {
+    transport: Box<dyn Transport>,
+    room: RoomId,
+    admin_token: Option<String>,
+    /// Code the admin handed out, for rooms where registering takes one
+    invite: Option<String>,
+    /// Users registered through this client, with their tokens and signing keys
+    registered: Arc<Registrations>,
+    /// The room's dashboard as last fetched, with its `ETag`
+    dashboard: Mutex<Option<(String, Dashboard)>>,
+    last_upload: Mutex<Option<UploadStats>>,
+}
+
+/// See [`WebClient::poll_dashboard`]
+#[derive(Debug, Clone)]
+pub enum DashboardPoll {
+    Changed(Box<Dashboard>),
+    NotModified,
 }
 
 impl WebClient {
+    /// A client of room 0, the default room every server starts with, over a [`ReqwestTransport`]
     pub fn new(url: &str) -> Self {
-        Self::Prod {
+        Self::from_transport(ReqwestTransport::new(url))
+    }
+
+    /// A client over HTTP(S) with its own timeouts, proxy or headers
+    pub fn builder(url: &str) -> WebClientBuilder {
+        WebClientBuilder {
             url: url.to_string(),
-            client: Client::new(),
+            options: HttpOptions::default(),
+            tls: None,
+            upload_rate: None,
+            write_size: None,
+            retry: RetryPolicy::default(),
         }
     }
 
-    pub fn url(&self) -> String {
-        match self {
-            WebClient::Prod { url, .. } => url.to_string(),
-            WebClient::Test(_) => panic!("No url for testing"),
+    /// A client of room 0 that sends its requests over `transport`
+    pub fn from_transport(transport: impl Transport) -> Self {
+        Self {
+            transport: Box::new(transport),
+            room: 0,
+            admin_token: None,
+            invite: None,
+            registered: Default::default(),
+            dashboard: Default::default(),
+            last_upload: Default::default(),
         }
     }
 
-    fn path(&self, path: &str) -> String {
-        match self {
-            WebClient::Prod { url, .. } => format!("{}/{}", url, path),
-            WebClient::Test(_) => unreachable!(),
+    #[cfg(test)]
+    pub(crate) fn transport(&self) -> &dyn Transport {
+        self.transport.as_ref()
+    }
+
+    /// Talk to another room on the same server
+    pub fn with_room(mut self, room_id: RoomId) -> Self {
+        self.room = room_id;
+        self.dashboard = Default::default();
+        self
+    }
+
+    /// Send the admin token with every request, for `/run`, `/conclude_registration` and the admin routes
+    pub fn with_admin_token(mut self, token: &str) -> Self {
+        self.admin_token = Some(token.to_string());
+        self
+    }
+
+    /// Register with an invite code from the admin
+    pub fn with_invite(mut self, code: &str) -> Self {
+        self.invite = Some(code.to_string());
+        self
+    }
+
+    /// The admin token, and the proof of the user the request is on behalf of
+    fn authorize(&self, user: Option<&OnBehalf>) -> Vec<(&'static str, String)> {
+        let user = user.unwrap_or(&OnBehalf::NOBODY);
+        let token = user
+            .token
+            .as_ref()
+            .map(|token| ("Authorization", format!("Bearer {token}")));
+        let admin_token = self
+            .admin_token
+            .clone()
+            .map(|token| (ADMIN_TOKEN_HEADER, token));
+        let signature = user
+            .signature
+            .clone()
+            .map(|signature| (SIGNATURE_HEADER, signature));
+        admin_token
+            .into_iter()
+            .chain(token)
+            .chain(signature)
+            .collect()
+    }
+
+    /// Like [`Self::authorize`], with a fresh `Idempotency-Key` so the request can be retried
+    fn authorize_idempotent(&self, user: Option<&OnBehalf>) -> Vec<(&'static str, String)> {
+        let mut headers = self.authorize(user);
+        headers.push((IDEMPOTENCY_KEY_HEADER, idempotency_key()));
+        headers
+    }
+
+    fn registered(&self) -> &Registrations {
+        &self.registered
+    }
+
+    /// Token of the latest user registered through this client that `is_user`,
+    /// and their signature of `submission` if they registered a key
+    fn on_behalf(
+        &self,
+        is_user: impl Fn(&RegisteredUser) -> bool,
+        submission: &impl Serialize,
+    ) -> OnBehalf {
+        let registered = self.registered().lock().unwrap();
+        match registered.iter().rev().find(|(user, _)| is_user(user)) {
+            Some((user, key)) => OnBehalf {
+                token: user.token.clone(),
+                signature: key.as_ref().map(|key| sign_submission(key, submission)),
+            },
+            None => OnBehalf::NOBODY,
         }
     }
 
+    pub fn room(&self) -> RoomId {
+        self.room
+    }
+
+    /// Whether requests carry an admin token, see [`Self::with_admin_token`]
+    pub fn is_admin(&self) -> bool {
+        self.admin_token.is_some()
+    }
+
+    fn room_path(&self, path: &str) -> String {
+        format!("/rooms/{}{}", self.room(), path)
+    }
+
+    pub fn url(&self) -> String {
+        self.transport
+            .url()
+            .expect("The transport has no url")
+            .to_string()
+    }
+
+    /// Size and duration of the last upload that reached the server, e.g. of
+    /// [`Self::submit_cipher`] or [`Self::submit_inputs`], whether the server accepted it or not.
+    /// Of uploads running side by side, the last to finish.
+    pub fn last_upload(&self) -> Option<UploadStats> {
+        *self.last_upload.lock().unwrap()
+    }
+
+    /// Where the server serves `path` of the API version this client speaks
+    fn path(&self, path: &str) -> String {
+        format!("{API_BASE}{path}")
+    }
+
     async fn get<T: Send + for<'de> Deserialize<'de> + 'static>(
         &self,
         path: &str,
     ) -> Result<T, Error> {
-        match self {
-            WebClient::Prod { client, .. } => {
-                let response = client.get(self.path(path)).send().await?;
-                handle_response_prod(response).await
-            }
-            WebClient::Test(client) => {
-                let response = client.get(path).dispatch().await;
-                handle_response_test(response).await
-            }
+        self.get_on_behalf(path, None).await
+    }
+    async fn get_on_behalf<T: Send + for<'de> Deserialize<'de> + 'static>(
+        &self,
+        path: &str,
+        user: Option<&OnBehalf>,
+    ) -> Result<T, Error> {
+        let response = self
+            .transport
+            .get(&self.path(path), &self.authorize(user))
+            .await?;
+        handle_response(response).await
+    }
+    /// A msgpack body, deserialized as it downloads behind a progress bar rather than after
+    async fn get_msgpack<T: Send + DeserializeOwned + 'static>(
+        &self,
+        path: &str,
+    ) -> Result<T, Error> {
+        let mut headers = self.authorize(None);
+        headers.push(("Accept", "application/msgpack".to_string()));
+        let response = self.transport.get(&self.path(path), &headers).await?;
+        match response.status {
+            200 => read_msgpack(response).await,
+            status => Err(ClientError::new(status, &response.bytes().await?).into()),
         }
     }
+    /// The body of a msgpack route, or one that serves msgpack to clients that accept it
+    async fn get_bytes(&self, path: &str) -> Result<Vec<u8>, Error> {
+        let mut headers = self.authorize(None);
+        headers.push(("Accept", "application/msgpack".to_string()));
+        let response = self.transport.get(&self.path(path), &headers).await?;
+        response_bytes(response).await
+    }
     async fn post_nobody<T: Send + for<'de> Deserialize<'de> + 'static>(
         &self,
         path: &str,
+        user: Option<&OnBehalf>,
     ) -> Result<T, Error> {
-        match self {
-            WebClient::Prod { client, .. } => {
-                let response = client.post(self.path(path)).send().await?;
-                handle_response_prod(response).await
-            }
-            WebClient::Test(client) => {
-                let response = client.post(path).dispatch().await;
-                handle_response_test(response).await
-            }
-        }
+        let response = self
+            .transport
+            .post(&self.path(path), &self.authorize_idempotent(user), vec![])
+            .await?;
+        handle_response(response).await
     }
     async fn post<T: Send + for<'de> Deserialize<'de> + 'static>(
         &self,
         path: &str,
         body: Vec<u8>,
     ) -> Result<T, Error> {
-        match self {
-            WebClient::Prod { client, .. } => {
-                let response = client.post(self.path(path)).body(body).send().await?;
-                handle_response_prod(response).await
-            }
-            WebClient::Test(client) => {
-                let response = client.post(path).body(body).dispatch().await;
-                handle_response_test(response).await
-            }
-        }
+        let response = self
+            .transport
+            .post(&self.path(path), &self.authorize_idempotent(None), body)
+            .await?;
+        handle_response(response).await
+    }
+    async fn post_json<T: Send + for<'de> Deserialize<'de> + 'static>(
+        &self,
+        path: &str,
+        body: &impl Serialize,
+        user: Option<&OnBehalf>,
+    ) -> Result<T, Error> {
+        let mut headers = self.authorize_idempotent(user);
+        headers.push(("Content-Type", "application/json".to_string()));
+        let response = self
+            .transport
+            .post(&self.path(path), &headers, serde_json::to_vec(body)?)
+            .await?;
+        handle_response(response).await
     }
     async fn post_msgpack<T: Send + for<'de> Deserialize<'de> + 'static>(
         &self,
         path: &str,
         body: &impl Serialize,
+        user: Option<&OnBehalf>,
     ) -> Result<T, Error> {
-        match self {
-            WebClient::Prod { client, .. } => {
-                let body = msgpack::to_compact_vec(body)?;
-                let reader = ProgressReader::new(&body, 128 * 1024);
-                let stream = ReaderStream::new(reader);
-
-                let response = client
-                    .post(self.path(path))
-                    .header(CONTENT_TYPE, "application/msgpack")
-                    .body(reqwest::Body::wrap_stream(stream))
-                    .send()
-                    .await?;
-                handle_response_prod(response).await
-            }
-            WebClient::Test(client) => {
-                let response = client.post(path).msgpack(body).dispatch().await;
-                handle_response_test(response).await
+        let mut headers = self.authorize_idempotent(user);
+        headers.push(("Content-Type", "application/msgpack".to_string()));
+        headers.push(("Content-Encoding", ZSTD.to_string()));
+        let body = compress(&msgpack::to_compact_vec(body)?);
+        let bytes = body.len() as u64;
+        let started = Instant::now();
+        let response = self
+            .transport
+            .post_msgpack(&self.path(path), &headers, body)
+            .await?;
+        self.record_upload(bytes, started);
+        handle_response(response).await
+    }
+    /// One chunk of [`Self::post_chunked`], counted from `offset` on the progress bar
+    async fn put_chunk(
+        &self,
+        path: &str,
+        chunk: &[u8],
+        bar: &ProgressBar,
+        offset: u64,
+        user: Option<&OnBehalf>,
+    ) -> Result<UploadProgress, Error> {
+        let response = self
+            .transport
+            .put(&self.path(path), &self.authorize(user), chunk, bar, offset)
+            .await?;
+        handle_response(response).await
+    }
+    /// Upload the msgpack of `body`, a `kind` of `user_id`, through an upload session at `path`.
+    /// After a failed chunk, resume from the last chunk the server acknowledged.
+    async fn post_chunked<T: Send + for<'de> Deserialize<'de> + 'static>(
+        &self,
+        path: &str,
+        body: &impl Serialize,
+        kind: UploadKind,
+        user_id: UserId,
+        user: Option<&OnBehalf>,
+    ) -> Result<T, Error> {
+        let body = compress(&msgpack::to_compact_vec(body)?);
+        let start = UploadStart {
+            user_id,
+            size: body.len() as u64,
+            chunk_size: UPLOAD_CHUNK_SIZE as u64,
+            kind,
+            compressed: true,
+        };
+        let started = Instant::now();
+        let session = self
+            .post_json::<UploadProgress>(&format!("{path}/start"), &start, user)
+            .await?
+            .session;
+        let bar = upload_bar(start.size, self.transport.upload_limit());
+        let chunks = body.chunks(UPLOAD_CHUNK_SIZE).collect_vec();
+        let mut n = 0;
+        let mut failures = 0;
+        while n < chunks.len() {
+            let offset = (n * UPLOAD_CHUNK_SIZE) as u64;
+            let chunk_path = format!("{path}/{session}/chunk/{n}");
+            match self
+                .put_chunk(&chunk_path, chunks[n], &bar, offset, user)
+                .await
+            {
+                Ok(progress) => {
+                    n = progress.chunks_received as usize;
+                    failures = 0;
+                }
+                Err(err) if failures < UPLOAD_RETRIES => {
+                    failures += 1;
+                    bar.println(format!("⚠️ Chunk {n} failed, retrying: {err}"));
+                    sleep(Duration::from_secs(failures)).await;
+                    if let Ok(progress) = self
+                        .get_on_behalf::<UploadProgress>(&format!("{path}/{session}"), user)
+                        .await
+                    {
+                        n = progress.chunks_received as usize;
+                    }
+                }
+                Err(err) => return Err(err),
             }
         }
+        bar.finish_with_message("Upload complete");
+        let finished = self
+            .post_nobody(&format!("{path}/{session}/finish"), user)
+            .await;
+        self.record_upload(start.size, started);
+        finished
+    }
+
+    fn record_upload(&self, bytes: u64, started: Instant) {
+        let elapsed = started.elapsed();
+        *self.last_upload.lock().unwrap() = Some(UploadStats { bytes, elapsed });
+    }
+
+    /// Hex public key to [`Receipt::verify`] receipts with
+    pub async fn get_receipt_key(&self) -> Result<String, Error> {
+        self.get("/receipt_key").await
+    }
+
+    /// Fails unless the server is up, see `/healthz`
+    pub async fn healthz(&self) -> Result<(), Error> {
+        self.get_bytes("/healthz").await.map(|_| ())
+    }
+
+    pub async fn readyz(&self) -> Result<Readiness, Error> {
+        self.get("/readyz").await
+    }
+
+    /// What the server speaks. Fails if it's a protocol version this client doesn't, so call it
+    /// before anything else.
+    pub async fn check_version(&self) -> Result<ServerVersion, Error> {
+        let version = self.get_version().await?;
+        version.check()?;
+        Ok(version)
+    }
+
+    /// What the server speaks, even if this client doesn't, e.g. to tell the user which client
+    /// to get
+    pub async fn get_version(&self) -> Result<ServerVersion, Error> {
+        handle_response(self.transport.get("/version", &[]).await?).await
+    }
+
+    pub async fn create_room(&self) -> Result<RoomId, Error> {
+        self.post_nobody("/rooms", None).await
+    }
+
+    pub async fn list_rooms(&self) -> Result<Vec<RoomSummary>, Error> {
+        self.get("/rooms").await
+    }
+
+    /// Archive of a completed session, see [`crate::SessionArchive::from_bytes`], and the
+    /// server's signature of it. Check it with [`ArchiveSignature::verify`].
+    pub async fn fetch_archive(&self) -> Result<(Vec<u8>, ArchiveSignature), Error> {
+        let response = self
+            .transport
+            .get(
+                &self.path(&self.room_path("/archive")),
+                &self.authorize(None),
+            )
+            .await?;
+        let signature = response
+            .header(ARCHIVE_SIGNATURE_HEADER)
+            .map(serde_json::from_str::<ArchiveSignature>)
+            .transpose()?;
+        let bytes = response_bytes(response).await?;
+        let signature = signature.ok_or(anyhow!("The archive came without a signature"))?;
+        Ok((bytes, signature))
+    }
+
+    /// Tell the server the archive signed with `signature` is stored, so it drops what's left
+    /// of the round's inputs. Takes the admin token. Returns how many files were removed.
+    pub async fn acknowledge_archive(&self, signature: &ArchiveSignature) -> Result<usize, Error> {
+        self.post_json(&self.room_path("/archive"), signature, None)
+            .await
+    }
+
+    /// What the room's circuit expects of each user's scores
+    pub async fn get_circuit(&self) -> Result<InputContract, Error> {
+        self.get(&self.room_path("/circuit")).await
     }
 
     pub async fn get_seed(&self) -> Result<Seed, Error> {
-        self.get("/param").await
+        self.get(&self.room_path("/param")).await
     }
 
+    /// Register `name`. Its submissions through this client carry the token the server returns.
     pub async fn register(&self, name: &str) -> Result<RegisteredUser, Error> {
-        self.post("/register", name.as_bytes().to_vec()).await
+        self.register_with(name, None).await
+    }
+
+    /// Watch the room as `name`, without taking part
+    pub async fn register_observer(&self, name: &str) -> Result<Observer, Error> {
+        self.post(
+            &self.room_path("/register_observer"),
+            name.as_bytes().to_vec(),
+        )
+        .await
+    }
+
+    /// Like [`Self::register`], and the user signs each submission with `key`
+    pub async fn register_signed(
+        &self,
+        name: &str,
+        key: &SigningKey,
+    ) -> Result<RegisteredUser, Error> {
+        self.register_with(name, Some(key)).await
     }
+
+    async fn register_with(
+        &self,
+        name: &str,
+        key: Option<&SigningKey>,
+    ) -> Result<RegisteredUser, Error> {
+        let query = key
+            .map(|key| ("public_key", hex::encode(key.verifying_key().as_bytes())))
+            .into_iter()
+            .chain(self.invite.iter().map(|code| ("invite", code.to_string())))
+            .map(|(field, value)| format!("{field}={value}"))
+            .join("&");
+        let path = if query.is_empty() {
+            self.room_path("/register")
+        } else {
+            self.room_path(&format!("/register?{query}"))
+        };
+        // Without an `Idempotency-Key`, as the server never replays a token. A lost response
+        // isn't retried, so the user isn't registered twice.
+        let response = self
+            .transport
+            .post(
+                &self.path(&path),
+                &self.authorize(None),
+                name.as_bytes().to_vec(),
+            )
+            .await?;
+        let user: RegisteredUser = handle_response(response).await?;
+        self.registered()
+            .lock()
+            .unwrap()
+            .push((user.clone(), key.cloned()));
+        Ok(user)
+    }
+
+    /// Submit on behalf of `user`, who registered through another client, e.g. before a restart
+    pub fn resume_user(&self, user: RegisteredUser, key: Option<SigningKey>) {
+        self.registered().lock().unwrap().push((user, key));
+    }
+
+    /// `participant_id` as registered through this client, with their token
+    pub fn registered_user(&self, participant_id: &ParticipantId) -> Option<RegisteredUser> {
+        let registered = self.registered().lock().unwrap();
+        registered
+            .iter()
+            .rev()
+            .find(|(user, _)| &user.participant_id == participant_id)
+            .map(|(user, _)| user.clone())
+    }
+
+    /// The server re-indexed `participant_id` after a user was removed
+    pub fn update_user_id(&self, participant_id: &ParticipantId, user_id: UserId) {
+        let mut registered = self.registered().lock().unwrap();
+        for (user, _) in registered.iter_mut() {
+            if &user.participant_id == participant_id {
+                user.id = user_id;
+            }
+        }
+    }
+    /// The dashboard, or the one fetched before if it didn't change since
     pub async fn get_dashboard(&self) -> Result<Dashboard, Error> {
-        self.get("/dashboard").await
+        match self.poll_dashboard().await? {
+            DashboardPoll::Changed(dashboard) => Ok(*dashboard),
+            DashboardPoll::NotModified => self
+                .dashboard
+                .lock()
+                .unwrap()
+                .as_ref()
+                .map(|(_, dashboard)| dashboard.clone())
+                .ok_or_else(|| anyhow!("The server answered 304 for a dashboard never fetched")),
+        }
+    }
+
+    /// The dashboard, if it changed since this client last fetched it. The server answers an
+    /// unchanged one with an empty `304`, so polling often costs it little.
+    pub async fn poll_dashboard(&self) -> Result<DashboardPoll, Error> {
+        let mut headers = self.authorize(None);
+        let etag = self
+            .dashboard
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|(etag, _)| etag.clone());
+        if let Some(etag) = etag {
+            headers.push(("If-None-Match", etag));
+        }
+        let response = self
+            .transport
+            .get(&self.path(&self.room_path("/dashboard")), &headers)
+            .await?;
+        if response.status == 304 {
+            return Ok(DashboardPoll::NotModified);
+        }
+        let etag = response.header("ETag").map(str::to_string);
+        let dashboard: Dashboard = handle_response(response).await?;
+        *self.dashboard.lock().unwrap() = etag.map(|etag| (etag, dashboard.clone()));
+        Ok(DashboardPoll::Changed(Box::new(dashboard)))
+    }
+
+    /// The room's changes as they happen, see [`RoomEvent`]
+    pub async fn subscribe_events(&self) -> Result<BoxStream<'_, Result<RoomEvent, Error>>, Error> {
+        if let Some(events) = self
+            .transport
+            .events(&self.path(&self.room_path("/events")))
+            .await?
+        {
+            return Ok(events);
+        }
+        // Without pushed events, compare dashboards instead
+        let last = self.get_dashboard().await?;
+        let events = stream::unfold((self, last), |(client, last)| async move {
+            loop {
+                sleep(Duration::from_millis(100)).await;
+                let current = match client.get_dashboard().await {
+                    Ok(current) => current,
+                    Err(err) => return Some((vec![Err(err)], (client, last))),
+                };
+                let events = RoomEvent::diff(&last, &current);
+                if !events.is_empty() {
+                    return Some((events.into_iter().map(Ok).collect_vec(), (client, current)));
+                }
+            }
+        });
+        Ok(events.flat_map(stream::iter).boxed())
+    }
+
+    /// Poll the dashboard until the room is in `state`, a little less often each time. Fails
+    /// once `timeout` passed.
+    pub async fn wait_for(
+        &self,
+        state: ServerState,
+        timeout: Duration,
+    ) -> Result<Dashboard, Error> {
+        let deadline = Instant::now() + timeout;
+        let mut interval = WAIT_INITIAL_INTERVAL;
+        loop {
+            let dashboard = self.get_dashboard().await?;
+            if *dashboard.status() == state {
+                return Ok(dashboard);
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            ensure!(
+                !left.is_zero(),
+                "Timed out waiting for the room to be {state}, it's {}",
+                dashboard.status()
+            );
+            sleep(interval.min(left)).await;
+            interval = (interval * 2).min(WAIT_MAX_INTERVAL);
+        }
     }
 
     pub async fn conclude_registration(&self) -> Result<Dashboard, Error> {
-        self.post_nobody("/conclude_registration").await
+        self.post_nobody(&self.room_path("/conclude_registration"), None)
+            .await
     }
 
-    pub async fn submit_cipher(
+    /// Commit to `ei` before submitting it, in rooms that take commitments
+    pub async fn commit_cipher(
+        &self,
+        user_id: UserId,
+        ei: &EncryptedInput,
+    ) -> Result<Receipt, Error> {
+        let commitment = CipherCommitment {
+            user_id,
+            hash: artifact_hash(ei),
+        };
+        let user = self.on_behalf(|user| user.id == user_id, &commitment);
+        let receipt: Receipt = self
+            .post_json(&self.room_path("/commit"), &commitment, Some(&user))
+            .await?;
+        receipt.ensure_covers(ei)?;
+        Ok(receipt)
+    }
+
+    /// Submit the cipher and the server key share together
+    pub async fn submit_inputs(
         &self,
         user_id: UserId,
         ei: &EncryptedInput,
         sks: &ServerKeyShare,
-    ) -> Result<UserId, Error> {
+    ) -> Result<Receipt, Error> {
         let submission = InputSubmission {
             user_id,
             ei: ei.clone(),
             sks: sks.clone(),
         };
-        self.post_msgpack("/submit", &submission).await
+        let user = self.on_behalf(|user| user.id == user_id, &submission);
+        let receipt: Receipt = self
+            .post_chunked(
+                &self.room_path("/submit"),
+                &submission,
+                UploadKind::Inputs,
+                user_id,
+                Some(&user),
+            )
+            .await?;
+        receipt.ensure_covers(&submission)?;
+        Ok(receipt)
+    }
+
+    /// Submit the server key share alone, in chunks like [`Self::submit_inputs`]
+    pub async fn submit_key_share(
+        &self,
+        user_id: UserId,
+        sks: &ServerKeyShare,
+    ) -> Result<Receipt, Error> {
+        let submission = KeyShareSubmission {
+            user_id,
+            sks: sks.clone(),
+        };
+        let user = self.on_behalf(|user| user.id == user_id, &submission);
+        let receipt: Receipt = self
+            .post_chunked(
+                &self.room_path("/submit"),
+                &submission,
+                UploadKind::KeyShare,
+                user_id,
+                Some(&user),
+            )
+            .await?;
+        receipt.ensure_covers(&submission)?;
+        Ok(receipt)
+    }
+
+    /// Submit or replace the cipher alone. The server keeps the key share submitted before.
+    /// [`Self::last_upload`] tells how long the upload took.
+    pub async fn submit_cipher(
+        &self,
+        user_id: UserId,
+        ei: &EncryptedInput,
+    ) -> Result<Receipt, Error> {
+        let submission = CipherSubmission {
+            user_id,
+            ei: ei.clone(),
+        };
+        let user = self.on_behalf(|user| user.id == user_id, &submission);
+        let receipt: Receipt = self
+            .post_msgpack(&self.room_path("/submit_cipher"), &submission, Some(&user))
+            .await?;
+        receipt.ensure_covers(&submission)?;
+        Ok(receipt)
+    }
+
+    /// Set the submission deadline of a room that has none yet
+    pub async fn set_deadline(&self, deadline: Timestamp) -> Result<Timestamp, Error> {
+        self.post_json(&self.room_path("/deadline"), &deadline, None)
+            .await
+    }
+
+    pub async fn propose_deadline_extension(
+        &self,
+        deadline: Timestamp,
+    ) -> Result<DeadlineExtension, Error> {
+        self.post_json(&self.room_path("/deadline/propose"), &deadline, None)
+            .await
+    }
+
+    /// Returns the new deadline if the ack completed the majority
+    pub async fn ack_deadline_extension(
+        &self,
+        user_id: UserId,
+    ) -> Result<Option<Timestamp>, Error> {
+        let user = self.on_behalf(|user| user.id == user_id, &user_id);
+        self.post_nobody(
+            &self.room_path(&format!("/deadline/ack/{user_id}")),
+            Some(&user),
+        )
+        .await
     }
 
     pub async fn trigger_fhe_run(&self) -> Result<ServerState, Error> {
-        self.post_nobody("/run").await
+        self.post_nobody(&self.room_path("/run"), None).await
+    }
+
+    pub async fn cancel_fhe_run(&self) -> Result<ServerState, Error> {
+        self.post_nobody(&self.room_path("/run/cancel"), None).await
+    }
+
+    pub async fn get_run_status(&self) -> Result<JobStatus, Error> {
+        self.get(&self.room_path("/run/status")).await
     }
 
+    /// Remove a user before the run. Everyone else gets re-indexed.
+    pub async fn remove_user(&self, user_id: UserId) -> Result<Dashboard, Error> {
+        self.post_nobody(
+            &self.room_path(&format!("/admin/users/{user_id}/remove")),
+            None,
+        )
+        .await
+    }
+
+    /// Mint `count` invite codes to hand out, one per expected participant
+    pub async fn create_invites(&self, count: usize) -> Result<Vec<String>, Error> {
+        self.post_nobody(
+            &self.room_path(&format!("/admin/invites?count={count}")),
+            None,
+        )
+        .await
+    }
+
+    /// Start a new round in the room. `force` discards a running FHE computation.
+    pub async fn reset_round(&self, force: bool) -> Result<ServerState, Error> {
+        self.post_nobody(
+            &self.room_path(&format!("/admin/reset?force={force}")),
+            None,
+        )
+        .await
+    }
+
+    /// Tell peers where to fetch this participant's decryption shares, see [`crate::serve_shares`]
+    pub async fn publish_contact(
+        &self,
+        participant_id: &ParticipantId,
+        contact: &str,
+    ) -> Result<RegisteredUser, Error> {
+        let user = self.on_behalf(|user| &user.participant_id == participant_id, &contact);
+        self.post_json(
+            &self.room_path(&format!("/contact/{participant_id}")),
+            &contact,
+            Some(&user),
+        )
+        .await
+    }
+
+    pub async fn get_transitions(&self) -> Result<Vec<Transition>, Error> {
+        self.get(&self.room_path("/transitions")).await
+    }
+
+    /// Hash of every input the server accepted, to check against [`artifact_hash`] of what was sent
+    pub async fn get_transcript(&self) -> Result<Vec<TranscriptEntry>, Error> {
+        self.get(&self.room_path("/transcript")).await
+    }
+
+    /// What the server waits on from `user_id`
+    pub async fn get_user_status(&self, user_id: UserId) -> Result<UserProgress, Error> {
+        self.get(&self.room_path(&format!("/users/{user_id}/status")))
+            .await
+    }
+
+    /// The room's log from entry `since` on, see [`crate::RoomHistory`]
+    pub async fn get_log(&self, since: u64) -> Result<Vec<LogEntry>, Error> {
+        self.get(&self.room_path(&format!("/log?since={since}")))
+            .await
+    }
+
+    /// Have this server compute a share of another server's run, see the `worker_servers` config
+    pub(crate) async fn evaluate_shard(&self, job: &WorkerJob) -> Result<Evaluation, Error> {
+        self.post_msgpack("/worker/evaluate", job, None).await
+    }
+
+    /// Fails with an [`InvalidResponse`] unless there's an output word per participant
     pub async fn get_fhe_output(&self) -> Result<CircuitOutput, Error> {
-        self.get("/fhe_output").await
+        let output: CircuitOutput = self.get_msgpack(&self.room_path("/fhe_output")).await?;
+        output.validate()?;
+        Ok(output)
+    }
+
+    /// One output, available before the run completes. See [`JobStatus::ready_outputs`].
+    pub async fn get_fhe_output_word(&self, output_id: usize) -> Result<FheOutput, Error> {
+        let output: FheOutput = self
+            .get_msgpack(&self.room_path(&format!("/fhe_output/{output_id}")))
+            .await?;
+        if output.output_id != output_id {
+            return Err(InvalidResponse::OutputId {
+                expected: output_id,
+                got: output.output_id,
+            }
+            .into());
+        }
+        Ok(output)
     }
 
     pub async fn submit_decryption_shares(
         &self,
-        user_id: usize,
+        participant_id: &ParticipantId,
         decryption_shares: &[DecryptionShare],
-    ) -> Result<UserId, Error> {
+    ) -> Result<Receipt, Error> {
         let submission = DecryptionShareSubmission {
-            user_id,
+            participant_id: participant_id.clone(),
             decryption_shares: decryption_shares.to_vec(),
         };
-        self.post_msgpack("/submit_decryption_shares", &submission)
-            .await
+        let user = self.on_behalf(|user| &user.participant_id == participant_id, &submission);
+        let receipt: Receipt = self
+            .post_msgpack(
+                &self.room_path("/submit_decryption_shares"),
+                &submission,
+                Some(&user),
+            )
+            .await?;
+        receipt.ensure_covers(&submission)?;
+        Ok(receipt)
     }
 
+    /// The share `participant_id` made for decrypting output `output_id`
     pub async fn get_decryption_share(
         &self,
         output_id: usize,
-        user_id: usize,
+        participant_id: &ParticipantId,
     ) -> Result<DecryptionShare, Error> {
-        self.get(&format!("/decryption_share/{output_id}/{user_id}"))
+        self.get(&self.room_path(&format!("/decryption_share/{output_id}/{participant_id}")))
             .await
     }
-}
 
-async fn handle_response_prod<T: Send + for<'de> Deserialize<'de> + 'static>(
-    response: reqwest::Response,
-) -> Result<T, Error> {
-    match response.status().as_u16() {
-        200 => Ok(response.json::<T>().await?),
-        _ => {
-            let err = response.text().await?;
-            bail!("Server responded error: {:?}", err)
+    /// Every decryption share submitted so far, in one request
+    pub async fn get_decryption_shares(&self) -> Result<DecryptionSharesMap, Error> {
+        self.get_msgpack(&self.room_path("/decryption_shares"))
+            .await
+    }
+
+    /// Every decryption share submitted so far, except those `user_id` made
+    pub async fn get_missing_decryption_shares(
+        &self,
+        user_id: UserId,
+    ) -> Result<DecryptionSharesMap, Error> {
+        self.get_msgpack(&self.room_path(&format!("/decryption_shares/missing/{user_id}")))
+            .await
+    }
+
+    /// Download each share of `output` that `shares` lacks, up to `max_concurrency` at a time,
+    /// and merge them in. Shares that failed, or that don't fit their output word, stay
+    /// missing, and the first of the errors is returned once the others are in.
+    pub async fn fetch_missing_shares(
+        &self,
+        output: &CircuitOutput,
+        shares: &mut DecryptionSharesMap,
+        max_concurrency: usize,
+    ) -> Result<(), Error> {
+        let missing = (0..output.n())
+            .cartesian_product(output.participants())
+            .map(|(output_id, from)| (output_id, from.clone()))
+            .filter(|key| !shares.contains_key(key))
+            .collect_vec();
+        let bar = share_bar(missing.len() as u64);
+        let mut missing = missing.into_iter();
+        let mut in_flight = FuturesUnordered::new();
+        let mut failure = None;
+        loop {
+            while in_flight.len() < max_concurrency.max(1) {
+                let Some(key) = missing.next() else {
+                    break;
+                };
+                in_flight.push(async move {
+                    let share = self
+                        .get_decryption_share(key.0, &key.1)
+                        .await
+                        .and_then(|share| {
+                            output.check_share(key.0, &key.1, &share)?;
+                            Ok(share)
+                        });
+                    (key, share)
+                });
+            }
+            let Some((key, share)) = in_flight.next().await else {
+                break;
+            };
+            bar.inc(1);
+            match share {
+                Ok(share) => {
+                    shares.insert(key, share);
+                }
+                Err(err) => {
+                    failure.get_or_insert(err);
+                }
+            }
         }
+        bar.finish_with_message("Fetched decryption shares");
+        failure.map_or(Ok(()), Err)
     }
-}
 
-async fn handle_response_test<T: Send + for<'de> Deserialize<'de> + 'static>(
-    response: rocket::local::asynchronous::LocalResponse<'_>,
-) -> Result<T, Error> {
-    match response.status().code {
-        200 => response
-            .into_json::<T>()
+    /// Which decryption shares the server holds, per output and per user
+    pub async fn get_decryption_status(&self) -> Result<DecryptionStatus, Error> {
+        self.get(&self.room_path("/decryption_status")).await
+    }
+
+    /// The karma the server decrypted, if the room publishes results
+    pub async fn get_results(&self) -> Result<RoundResult, Error> {
+        self.get(&self.room_path("/results")).await
+    }
+
+    /// The results as a `name,karma` CSV table
+    pub async fn export_results_csv(&self) -> Result<String, Error> {
+        let bytes = self
+            .get_bytes(&self.room_path("/results/export?format=csv"))
+            .await?;
+        Ok(String::from_utf8(bytes)?)
+    }
+
+    /// The results signed by the server, check them with [`SignedResults::verify`]
+    pub async fn export_signed_results(&self) -> Result<SignedResults, Error> {
+        self.get(&self.room_path("/results/export?format=json"))
             .await
-            .ok_or(anyhow!("Can't parse response output")),
-        _ => {
-            let err = response
-                .into_string()
-                .await
-                .ok_or(anyhow!("Can't parse response output"))?;
-            bail!("Server responded error: {:?}", err)
+    }
+}
+
+/// A [`WebClient`] over a [`ReqwestTransport`] set up for the link, e.g. one that's slow or
+/// behind a proxy. The event socket connects directly, with the TLS trust only.
+pub struct WebClientBuilder {
+    url: String,
+    options: HttpOptions,
+    tls: Option<TlsOptions>,
+    /// Bytes per second
+    upload_rate: Option<u64>,
+    write_size: Option<usize>,
+    retry: RetryPolicy,
+}
+
+impl WebClientBuilder {
+    /// Give up on a connection the server didn't accept by then
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.options.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Give up once the server didn't answer for `timeout`. There's no limit on a whole
+    /// request, so a key share uploads as long as it takes.
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.options.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Send every request through the HTTP proxy at `url`
+    pub fn proxy(mut self, url: &str) -> Self {
+        self.options.proxy = Some(url.to_string());
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: &str) -> Self {
+        self.options.user_agent = Some(user_agent.to_string());
+        self
+    }
+
+    /// Send `name: value` with every request, e.g. the token of a gateway in front of the server
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.options
+            .headers
+            .push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// See [`ReqwestTransport::with_tls`]
+    pub fn tls(mut self, tls: TlsOptions) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// See [`ReqwestTransport::with_upload_limit`]
+    pub fn upload_limit(self, kb_per_sec: u64) -> Self {
+        self.upload_rate(kb_per_sec * 1024)
+    }
+
+    /// See [`ReqwestTransport::with_upload_rate`]
+    pub fn upload_rate(mut self, bytes_per_sec: u64) -> Self {
+        self.upload_rate = Some(bytes_per_sec);
+        self
+    }
+
+    /// See [`ReqwestTransport::with_write_size`]
+    pub fn write_size(mut self, bytes: usize) -> Self {
+        self.write_size = Some(bytes);
+        self
+    }
+
+    /// See [`ReqwestTransport::with_retry`]
+    pub fn retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
+    }
+
+    /// Fails on a header, proxy URL or TLS option that doesn't parse
+    pub fn build(self) -> Result<WebClient, Error> {
+        let mut transport = ReqwestTransport::new(&self.url)
+            .with_options(self.options)?
+            .with_retry(self.retry);
+        if let Some(tls) = &self.tls {
+            transport = transport.with_tls(tls)?;
+        }
+        if let Some(bytes_per_sec) = self.upload_rate {
+            transport = transport.with_upload_rate(bytes_per_sec);
+        }
+        if let Some(bytes) = self.write_size {
+            transport = transport.with_write_size(bytes);
         }
+        Ok(WebClient::from_transport(transport))
     }
 }
 
-struct ProgressReader {
-    inner: Vec<u8>,
-    progress_bar: ProgressBar,
-    position: usize,
-    chunk_size: usize,
+/// Many users sharing one [`WebClient`], run through a whole round together: to load test a
+/// server, or to watch the protocol work without a process per user. The client also concludes
+/// registration and triggers the run, so it carries the admin token where the server wants one.
+pub struct SimulatedParty {
+    client: WebClient,
+    names: Vec<String>,
+    concurrency: usize,
+    run_timeout: Duration,
+    /// See [`Self::with_threads`]
+    threads: Option<usize>,
 }
 
-impl ProgressReader {
-    fn new(body: &[u8], chunk_size: usize) -> Self {
-        let total_bytes = body.len() as u64;
-        println!("Total size {} B", total_bytes);
-        let bar = ProgressBar::new(total_bytes);
-        bar.set_style(
-            ProgressStyle::with_template(
-                "[{elapsed_precise}] {bar:40.cyan/blue} {percent}% {bytes_per_sec} {msg}",
-            )
-            .unwrap()
-            .progress_chars("##-"),
+impl SimulatedParty {
+    /// `users` users, named `user 0` on
+    pub fn new(client: WebClient, users: usize) -> Self {
+        Self {
+            client,
+            names: (0..users).map(|i| format!("user {i}")).collect(),
+            concurrency: SIMULATION_CONCURRENCY,
+            run_timeout: SIMULATION_RUN_TIMEOUT,
+            threads: None,
+        }
+    }
+
+    /// Uploads and downloads in flight at a time, 4 by default
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// How long to wait for the FHE run, 10 minutes by default
+    pub fn with_run_timeout(mut self, timeout: Duration) -> Self {
+        self.run_timeout = timeout;
+        self
+    }
+
+    /// Cap the rayon pool the keys and shares are made on, which has a thread per core otherwise
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = Some(threads);
+        self
+    }
+
+    pub fn client(&self) -> &WebClient {
+        &self.client
+    }
+
+    /// What each user has once user `i` gave `scores[i][j]` to user `j`: what they received
+    /// minus what they gave, wrapping like the circuit does
+    pub fn expected_balances(scores: &[Vec<Score>]) -> Vec<Score> {
+        (0..scores.len())
+            .map(|me| {
+                let given = scores[me]
+                    .iter()
+                    .fold(0, |sum: Score, s| sum.wrapping_add(*s));
+                let received = scores
+                    .iter()
+                    .fold(0, |sum: Score, row| sum.wrapping_add(row[me]));
+                received.wrapping_sub(given)
+            })
+            .collect()
+    }
+
+    /// Register every user, submit `scores` (see [`Self::expected_balances`]), run the circuit
+    /// and decrypt its outputs as each user. The client keys, ciphers, key shares and decryption
+    /// shares are made on a rayon pool. Fails unless every user decrypts the expected balances,
+    /// which it returns.
+    pub async fn run(&self, scores: &[Vec<Score>]) -> Result<Vec<Score>, Error> {
+        let total_users = self.names.len();
+        ensure!(
+            scores.len() == total_users && scores.iter().all(|row| row.len() == total_users),
+            "Expected {total_users} scores from each of the {total_users} users"
+        );
+        let seed = self.client.get_seed().await?;
+        let mut users = vec![];
+        for name in &self.names {
+            users.push(self.client.register(name).await?);
+        }
+        let dashboard = self.client.conclude_registration().await?;
+        ensure!(
+            dashboard.users().len() == total_users,
+            "The room has users besides the simulated ones"
         );
-        bar.set_message("Uploading...");
+        let parameter = dashboard.parameter_set();
+        setup(&seed, parameter);
+        let threads = self.threads;
 
-        Self {
-            inner: body.to_vec(),
-            progress_bar: bar,
-            position: 0,
-            chunk_size,
+        let rows = users.iter().map(|user| user.id).zip(scores.to_vec());
+        let rows = rows.collect_vec();
+        let keys = spawn_blocking(move || {
+            on_pool(parameter, threads, || {
+                rows.into_par_iter()
+                    .map(|(user_id, scores)| {
+                        let ck = gen_client_key();
+                        let cipher = EncryptedInput::from_plain(&ck, &scores);
+                        let sks = gen_server_key_share(user_id, total_users, &ck);
+                        (ck, cipher, sks)
+                    })
+                    .collect::<Vec<_>>()
+            })
+        })
+        .await??;
+        let (cks, inputs): (Vec<_>, Vec<_>) = keys
+            .into_iter()
+            .map(|(ck, cipher, sks)| (ck, (cipher, sks)))
+            .unzip();
+
+        if dashboard.is_taking_commitments() {
+            for (user, (cipher, _)) in users.iter().zip(&inputs) {
+                self.client.commit_cipher(user.id, cipher).await?;
+            }
+        }
+        stream::iter(users.iter().zip(&inputs))
+            .map(|(user, (cipher, sks))| self.client.submit_inputs(user.id, cipher, sks))
+            .buffer_unordered(self.concurrency)
+            .try_collect::<Vec<_>>()
+            .await?;
+        drop(inputs);
+
+        self.client.trigger_fhe_run().await?;
+        self.client
+            .wait_for(ServerState::CompletedFhe, self.run_timeout)
+            .await?;
+        let output = Arc::new(self.client.get_fhe_output().await?);
+        let cks = Arc::new(cks);
+        let my_shares = {
+            let (output, cks) = (output.clone(), cks.clone());
+            spawn_blocking(move || {
+                on_pool(parameter, threads, || {
+                    cks.par_iter()
+                        .map(|ck| output.gen_decryption_shares(ck))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .await??
+        };
+        stream::iter(users.iter().zip(&my_shares))
+            .map(|(user, shares)| {
+                self.client
+                    .submit_decryption_shares(&user.participant_id, shares)
+            })
+            .buffer_unordered(self.concurrency)
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        let mut collected = vec![];
+        for (user, shares) in users.iter().zip(my_shares) {
+            let me = &user.participant_id;
+            let mut map: DecryptionSharesMap = output.share_entries(me, &shares).collect();
+            self.client
+                .fetch_missing_shares(&output, &mut map, self.concurrency)
+                .await?;
+            let dss = output
+                .collect_shares(&map)
+                .ok_or_else(|| anyhow!("{} lacks decryption shares", user.name))?;
+            collected.push(dss);
         }
+        let decrypted = spawn_blocking(move || {
+            on_pool(parameter, threads, || {
+                cks.par_iter()
+                    .zip(collected)
+                    .map(|(ck, dss)| output.decrypt(ck, &dss))
+                    .collect::<Result<Vec<_>, _>>()
+            })
+        })
+        .await???;
+
+        let expected = Self::expected_balances(scores);
+        for (user, balances) in users.iter().zip(decrypted) {
+            ensure!(
+                balances == expected,
+                "{} decrypted {balances:?} rather than {expected:?}",
+                user.name
+            );
+        }
+        Ok(expected)
     }
 }
 
-impl AsyncRead for ProgressReader {
-    fn poll_read(
-        mut self: Pin<&mut Self>,
-        _cx: &mut Context<'_>,
-        buf: &mut tokio::io::ReadBuf<'_>,
-    ) -> Poll<tokio::io::Result<()>> {
-        let remaining = self.inner.len() - self.position;
-        let to_read = self.chunk_size.min(remaining.min(buf.remaining()));
-        let end = self.position + to_read;
-        buf.put_slice(&self.inner[self.position..end]);
-        self.position = end;
-        self.progress_bar.set_position(self.position as u64);
-
-        if to_read == 0 {
-            self.progress_bar.finish_with_message("Upload complete")
-        }
+/// Run `f` on a fresh rayon pool whose threads use the parameters of `parameter`, e.g. to
+/// generate a server key share. `threads` caps the pool, which has a thread per core otherwise.
+pub fn on_pool<R: Send>(
+    parameter: ParameterSet,
+    threads: Option<usize>,
+    f: impl FnOnce() -> R + Send,
+) -> Result<R, Error> {
+    let result = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads.unwrap_or(0))
+        .build_scoped(
+            |thread| {
+                set_parameter_set(parameter.selector());
+                thread.run()
+            },
+            |pool| pool.install(f),
+        )?;
+    Ok(result)
+}
 
-        Poll::Ready(Ok(()))
+/// Proof that a request comes from a registered user, see [`WebClient::register_signed`]
+struct OnBehalf {
+    token: Option<String>,
+    signature: Option<String>,
+}
+
+impl OnBehalf {
+    const NOBODY: Self = Self {
+        token: None,
+        signature: None,
+    };
+}
+
+/// A fresh `Idempotency-Key`, one per logical request
+fn idempotency_key() -> String {
+    hex::encode(thread_rng().gen::<[u8; 16]>())
+}
+
+async fn handle_response<T: Send + for<'de> Deserialize<'de> + 'static>(
+    response: Response,
+) -> Result<T, Error> {
+    Ok(serde_json::from_slice(&response_bytes(response).await?)?)
+}
+
+/// The body of a 200, and the [`ClientError`] of any other status
+async fn response_bytes(response: Response) -> Result<Vec<u8>, Error> {
+    match response.status {
+        200 => response.bytes().await,
+        status => Err(ClientError::new(status, &response.bytes().await?).into()),
     }
 }
+
+/// Deserialize a msgpack body from its chunks as they arrive, so the whole body is never in
+/// memory next to the value
+async fn read_msgpack<T: Send + DeserializeOwned + 'static>(
+    response: Response,
+) -> Result<T, Error> {
+    let compressed = response.compressed;
+    let bar = download_bar(response.content_length);
+    let progress = bar.clone();
+    let chunks = response
+        .body
+        .inspect_ok(move |chunk| progress.inc(chunk.len() as u64));
+    let reader = SyncIoBridge::new(StreamReader::new(chunks));
+    let value = spawn_blocking(move || -> Result<T, Error> {
+        if compressed {
+            Ok(rmp_serde::from_read(zstd::Decoder::new(reader)?)?)
+        } else {
+            Ok(rmp_serde::from_read(reader)?)
+        }
+    })
+    .await??;
+    bar.finish_with_message("Download complete");
+    Ok(value)
+}
+
+fn share_bar(total: u64) -> ProgressBar {
+    let bar = ProgressBar::new(total);
+    bar.set_style(
+        ProgressStyle::with_template("[{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len} {msg}")
+            .unwrap()
+            .progress_chars("##-"),
+    );
+    bar.set_message("Fetching decryption shares...");
+    bar
+}
+
+/// Without a `Content-Length`, a spinner that only counts bytes
+fn download_bar(total_bytes: Option<u64>) -> ProgressBar {
+    let (bar, template) = match total_bytes {
+        Some(total_bytes) => (
+            ProgressBar::new(total_bytes),
+            "[{elapsed_precise}] {bar:40.cyan/blue} {percent}% {bytes_per_sec} ETA {eta} {msg}",
+        ),
+        None => (
+            ProgressBar::new_spinner(),
+            "[{elapsed_precise}] {spinner} {bytes} {bytes_per_sec} {msg}",
+        ),
+    };
+    bar.set_style(
+        ProgressStyle::with_template(template)
+            .unwrap()
+            .progress_chars("##-"),
+    );
+    bar.set_message("Downloading...");
+    bar
+}
@@ -0,0 +1,98 @@
+use anyhow::{Context, Error};
+use rand::{thread_rng, Rng};
+use rocket::serde::{msgpack, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt::Debug;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::warn;
+
+/// Handle of a large artifact that is rarely read, like a key share.
+///
+/// With a directory, the artifact is written to disk once and only read back when needed,
+/// so the room keeps nothing big in memory and snapshots of it stay small.
+/// Without one, it stays in memory. Cloning is cheap either way.
+#[derive(Debug)]
+pub(crate) enum Cold<T> {
+    Memory(Arc<T>),
+    Disk(PathBuf),
+}
+
+impl<T> Clone for Cold<T> {
+    fn clone(&self) -> Self {
+        match self {
+            Self::Memory(value) => Self::Memory(value.clone()),
+            Self::Disk(path) => Self::Disk(path.clone()),
+        }
+    }
+}
+
+impl<T> Cold<T>
+where
+    T: Serialize + for<'de> Deserialize<'de> + Send + Sync + 'static,
+{
+    /// Serialize on a blocking thread so the caller can stash before taking any lock
+    pub(crate) async fn stash(value: T, dir: Option<&Path>, prefix: &str) -> Result<Self, Error> {
+        let Some(dir) = dir else {
+            return Ok(Self::Memory(Arc::new(value)));
+        };
+        let path = dir.join(format!(
+            "{prefix}-{}.msgpack",
+            hex::encode(thread_rng().gen::<[u8; 8]>())
+        ));
+        tokio::task::spawn_blocking(move || {
+            fs::create_dir_all(path.parent().expect("in a directory"))?;
+            fs::write(&path, msgpack::to_compact_vec(&value)?)?;
+            Ok(Self::Disk(path))
+        })
+        .await?
+    }
+
+    /// Blocking. Read the artifact back from wherever it lives.
+    pub(crate) fn load(&self) -> Result<Arc<T>, Error> {
+        match self {
+            Self::Memory(value) => Ok(value.clone()),
+            Self::Disk(path) => {
+                let bytes = fs::read(path)?;
+                let value = msgpack::from_slice(&bytes)
+                    .with_context(|| format!("Corrupted artifact {}", path.display()))?;
+                Ok(Arc::new(value))
+            }
+        }
+    }
+
+    /// Best effort, for artifacts nobody needs anymore
+    pub(crate) fn discard(&self) {
+        if let Self::Disk(path) = self {
+            if let Err(err) = fs::remove_file(path) {
+                warn!(path = %path.display(), "Failed to remove the artifact: {err}");
+            }
+        }
+    }
+}
+
+/// How a handle appears in snapshots: inline when in memory, only the path when on disk
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+enum ColdRepr<T> {
+    Memory(T),
+    Disk(PathBuf),
+}
+
+impl<T: Serialize> Serialize for Cold<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::Memory(value) => ColdRepr::Memory(value.as_ref()).serialize(serializer),
+            Self::Disk(path) => ColdRepr::<&T>::Disk(path.clone()).serialize(serializer),
+        }
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Cold<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(match ColdRepr::deserialize(deserializer)? {
+            ColdRepr::Memory(value) => Self::Memory(Arc::new(value)),
+            ColdRepr::Disk(path) => Self::Disk(path),
+        })
+    }
+}
@@ -0,0 +1,94 @@
+//! Transparent zstd `Content-Encoding` of request and response bodies. Key shares, ciphers and
+//! FHE outputs compress well, and moving them is most of what a user waits for.
+use crate::types::Error;
+use rocket::data::ByteUnit;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::{Request, Response};
+use std::io::{Cursor, Read};
+use tracing::warn;
+
+/// The `Content-Encoding` and `Accept-Encoding` token
+pub(crate) const ZSTD: &str = "zstd";
+
+/// Fast enough to keep up with the network
+const LEVEL: i32 = 3;
+
+/// Smaller responses aren't worth the trouble
+const MIN_RESPONSE_SIZE: usize = 1024;
+
+pub(crate) fn compress(bytes: &[u8]) -> Vec<u8> {
+    zstd::encode_all(bytes, LEVEL).expect("in-memory compression")
+}
+
+/// Fails rather than inflate past `limit`, so a tiny body can't exhaust memory
+pub(crate) fn decompress(bytes: &[u8], limit: ByteUnit) -> Result<Vec<u8>, Error> {
+    let invalid = |err: std::io::Error| Error::InvalidUpload {
+        reason: format!("Invalid zstd body: {err}"),
+    };
+    let mut inflated = vec![];
+    zstd::Decoder::new(bytes)
+        .map_err(invalid)?
+        .take(limit.as_u64() + 1)
+        .read_to_end(&mut inflated)
+        .map_err(invalid)?;
+    if ByteUnit::from(inflated.len()) > limit {
+        return Err(Error::PayloadTooLarge { limit });
+    }
+    Ok(inflated)
+}
+
+/// The request body is zstd compressed
+pub(crate) fn is_compressed(req: &Request<'_>) -> bool {
+    req.headers().get_one("Content-Encoding") == Some(ZSTD)
+}
+
+fn accepts_zstd(req: &Request<'_>) -> bool {
+    req.headers().get("Accept-Encoding").any(|value| {
+        value
+            .split(',')
+            .any(|coding| coding.split(';').next().map(str::trim) == Some(ZSTD))
+    })
+}
+
+/// Compresses msgpack and JSON responses for clients that send `Accept-Encoding: zstd`.
+/// Streams, like the event routes, are left alone.
+pub(crate) struct Compression;
+
+#[rocket::async_trait]
+impl Fairing for Compression {
+    fn info(&self) -> Info {
+        Info {
+            name: "zstd responses",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let compressible = res
+            .content_type()
+            .is_some_and(|content_type| content_type.is_msgpack() || content_type.is_json());
+        let large = res
+            .body()
+            .preset_size()
+            .is_some_and(|size| size >= MIN_RESPONSE_SIZE);
+        if !(compressible && large && accepts_zstd(req))
+            || res.headers().contains("Content-Encoding")
+        {
+            return;
+        }
+        let body = match res.body_mut().to_bytes().await {
+            Ok(body) => body,
+            Err(err) => {
+                warn!("Failed to read the response to compress: {err}");
+                return;
+            }
+        };
+        let compressed = tokio::task::spawn_blocking(move || compress(&body))
+            .await
+            .expect("Compression panicked");
+        res.set_header(Header::new("Content-Encoding", ZSTD));
+        res.set_header(Header::new("Vary", "Accept-Encoding"));
+        res.set_sized_body(compressed.len(), Cursor::new(compressed));
+    }
+}
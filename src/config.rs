@@ -0,0 +1,131 @@
+//! Everything the server reads from `Rocket.toml` and `ROCKET_*` environment variables
+use crate::circuit::{CircuitId, ParameterSet};
+use crate::cors::CorsConfig;
+use crate::limits::{submit_limit, RateLimitConfig};
+use crate::logging::LogFormat;
+use crate::telemetry::TelemetryConfig;
+use crate::types::{PhaseTimeouts, ResubmissionPolicy, RoomConfig, Seed};
+use rocket::config::TlsConfig;
+use rocket::data::{ByteUnit, Limits};
+use rocket::figment::Figment;
+use rocket::serde::Deserialize;
+use std::path::PathBuf;
+use tracing::{info, warn};
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(crate) struct ServerConfig {
+    /// As Rocket serves on
+    #[serde(default = "default_port")]
+    pub(crate) port: u16,
+    /// Rocket's body limits, with `submit` for the submissions, see [`submit_limit`]
+    #[serde(default)]
+    pub(crate) limits: Limits,
+    /// Submissions past this size are read through a temp file, see [`crate::limits::SpoolThreshold`]
+    #[serde(default = "default_spool_threshold")]
+    pub(crate) spool_threshold: ByteUnit,
+    /// Certificate chain and key Rocket serves HTTPS with. Rocket reads it itself, it's only
+    /// here to say in the logs whether traffic is encrypted.
+    tls: Option<TlsConfig>,
+    #[serde(default)]
+    pub(crate) telemetry: TelemetryConfig,
+    #[serde(default)]
+    pub(crate) log_format: LogFormat,
+    /// Snapshot rooms here so a restarted server resumes them
+    pub(crate) storage_dir: Option<PathBuf>,
+    /// Hex ed25519 secret key for signing receipts
+    pub(crate) receipt_key: Option<String>,
+    /// Unset, the server makes one up at launch and logs it, see [`crate::auth::AdminToken`]
+    pub(crate) admin_token: Option<String>,
+    /// Leave the admin routes open to anyone when there's no `admin_token`. For tests only.
+    #[serde(default)]
+    pub(crate) insecure_open_admin: bool,
+    pub(crate) register_rate_limit: Option<RateLimitConfig>,
+    /// Origins of browser frontends allowed to call the server, see [`crate::cors`]
+    pub(crate) cors: Option<CorsConfig>,
+    /// Program and arguments of a `worker` binary to evaluate the circuit in, see [`crate::worker`]
+    pub(crate) worker: Option<Vec<String>>,
+    /// URLs of servers to split the outputs of each run across, see [`crate::worker::shard`]
+    pub(crate) worker_servers: Option<Vec<String>>,
+    /// How long a shutdown waits for a run in progress before cancelling it
+    #[serde(default = "default_shutdown_run_wait_secs")]
+    pub(crate) shutdown_run_wait_secs: u64,
+    /// Hex seed every round starts with instead of a random one, for reproducible test deployments
+    seed: Option<String>,
+    /// Fixed FHE parameters. Unset, each room takes the smallest that fit its users.
+    parameter_set: Option<ParameterSet>,
+    /// What every room computes
+    #[serde(default)]
+    circuit: CircuitId,
+    #[serde(default)]
+    phase_timeouts: PhaseTimeouts,
+    #[serde(default)]
+    auto_run: bool,
+    #[serde(default)]
+    commit_reveal: bool,
+    #[serde(default)]
+    server_results: bool,
+    #[serde(default)]
+    resubmission: ResubmissionPolicy,
+    #[serde(default)]
+    ratings: usize,
+}
+
+fn default_port() -> u16 {
+    rocket::Config::default().port
+}
+
+fn default_spool_threshold() -> ByteUnit {
+    ByteUnit::Mebibyte(16)
+}
+
+fn default_shutdown_run_wait_secs() -> u64 {
+    10
+}
+
+impl ServerConfig {
+    /// Panics on a malformed setting, so a typo doesn't silently fall back to a default
+    pub(crate) fn from_figment(figment: &Figment) -> Self {
+        figment.extract().expect("Invalid configuration")
+    }
+
+    /// Applied to each new room
+    pub(crate) fn room_config(&self) -> RoomConfig {
+        let seed = self.seed.as_ref().map(|seed| {
+            let bytes = hex::decode(seed).expect("seed must be hex");
+            Seed::try_from(bytes).expect("seed must be 32 bytes")
+        });
+        RoomConfig {
+            timeouts: self.phase_timeouts,
+            auto_run: self.auto_run,
+            commit_reveal: self.commit_reveal,
+            server_results: self.server_results,
+            resubmission: self.resubmission,
+            ratings: self.ratings,
+            seed,
+            parameter: self.parameter_set,
+            circuit: self.circuit,
+        }
+    }
+
+    pub(crate) fn log_summary(&self) {
+        let parameter = match self.parameter_set {
+            Some(parameter) => format!("{parameter:?}"),
+            None => "by party count".to_string(),
+        };
+        info!(
+            port = self.port,
+            parameter,
+            circuit = ?self.circuit,
+            submit_limit = %submit_limit(&self.limits),
+            tls = self.tls.is_some(),
+            "Serving"
+        );
+        if self.tls.is_none() {
+            warn!("Serving plain HTTP, set `tls` or put a TLS reverse proxy in front before users connect over the internet");
+        }
+        if self.seed.is_some() {
+            warn!("Every round uses the configured seed, only do this for testing");
+        }
+    }
+}
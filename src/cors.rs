@@ -0,0 +1,111 @@
+//! Lets a frontend served from another origin call the server from a browser. Its msgpack
+//! submissions and authenticated requests aren't "simple" requests, so the browser sends an
+//! `OPTIONS` preflight first, which no route answers.
+use crate::auth::{ADMIN_TOKEN_HEADER, SIGNATURE_HEADER};
+use crate::etag::ETAG_HEADER;
+use crate::idempotency::{IDEMPOTENCY_KEY_HEADER, REPLAYED_HEADER};
+use crate::receipt::ARCHIVE_SIGNATURE_HEADER;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Header, Method, Status};
+use rocket::serde::Deserialize;
+use rocket::{Request, Response};
+use std::io::Cursor;
+
+/// Headers a browser may send unless [`CorsConfig::allowed_headers`] says otherwise.
+/// `Content-Type` covers `application/msgpack`, which isn't one a browser sends without asking.
+const DEFAULT_ALLOWED_HEADERS: [&str; 7] = [
+    "Content-Type",
+    "Content-Encoding",
+    "Authorization",
+    ADMIN_TOKEN_HEADER,
+    SIGNATURE_HEADER,
+    IDEMPOTENCY_KEY_HEADER,
+    "If-None-Match",
+];
+
+/// How long a browser may cache a preflight answer
+const MAX_AGE_SECS: u64 = 3600;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(crate) struct CorsConfig {
+    /// Origins like `https://karma.example.org`, or `*` for any
+    allowed_origins: Vec<String>,
+    #[serde(default = "default_allowed_headers")]
+    allowed_headers: Vec<String>,
+}
+
+fn default_allowed_headers() -> Vec<String> {
+    DEFAULT_ALLOWED_HEADERS.map(String::from).to_vec()
+}
+
+/// Adds the `Access-Control-*` headers for allowed origins, and answers their preflights.
+/// Without a config, browsers only reach the server from its own origin.
+pub(crate) struct Cors {
+    config: Option<CorsConfig>,
+}
+
+impl Cors {
+    pub(crate) fn new(config: Option<CorsConfig>) -> Self {
+        Self { config }
+    }
+
+    fn allows(&self, origin: &str) -> bool {
+        self.config.as_ref().is_some_and(|config| {
+            config
+                .allowed_origins
+                .iter()
+                .any(|allowed| allowed == "*" || allowed.trim_end_matches('/') == origin)
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for Cors {
+    fn info(&self) -> Info {
+        Info {
+            name: "CORS",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let Some(origin) = req.headers().get_one("Origin") else {
+            return;
+        };
+        res.adjoin_header(Header::new("Vary", "Origin"));
+        if !self.allows(origin) {
+            return;
+        }
+        // Echoed rather than `*`, which browsers reject for requests with credentials
+        res.set_header(Header::new(
+            "Access-Control-Allow-Origin",
+            origin.to_string(),
+        ));
+        res.set_header(Header::new(
+            "Access-Control-Expose-Headers",
+            format!("{REPLAYED_HEADER}, {ETAG_HEADER}, {ARCHIVE_SIGNATURE_HEADER}"),
+        ));
+        let is_preflight = req.method() == Method::Options
+            && req.headers().contains("Access-Control-Request-Method");
+        if is_preflight && res.status() == Status::NotFound {
+            let allowed_headers = self
+                .config
+                .as_ref()
+                .map(|config| config.allowed_headers.join(", "))
+                .unwrap_or_default();
+            res.set_status(Status::NoContent);
+            res.set_sized_body(0, Cursor::new(""));
+            res.remove_header("Content-Type");
+            res.set_header(Header::new(
+                "Access-Control-Allow-Methods",
+                "GET, POST, PUT, OPTIONS",
+            ));
+            res.set_header(Header::new("Access-Control-Allow-Headers", allowed_headers));
+            res.set_header(Header::new(
+                "Access-Control-Max-Age",
+                MAX_AGE_SECS.to_string(),
+            ));
+        }
+    }
+}
@@ -3,16 +3,47 @@ use rocket::serde::{Deserialize, Serialize};
 use tabled::settings::Style;
 use tabled::{Table, Tabled};
 
-use crate::types::{ServerState, UserRecord};
+use crate::circuit::ParameterSet;
+use crate::types::{
+    DeadlineExtension, Observer, ParticipantId, ResubmissionPolicy, ServerState, ServerStorage,
+    Timestamp, UserRecord, UserStorage,
+};
 use crate::UserId;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(crate = "rocket::serde")]
 pub enum UserStatus {
     IDAcquired,
-    CipherSubmitted,
+    /// Which of the cipher and the server key share are on the server
+    Submitted {
+        cipher: bool,
+        key_share: bool,
+        /// Counts accepted submissions, see [`Dashboard::resubmission`]
+        #[serde(default)]
+        version: u32,
+    },
+    Quarantined {
+        reason: String,
+        key_share: bool,
+    },
     DecryptionShareSubmitted,
 }
+
+impl UserStatus {
+    /// The server holds a key share of the user, so a new cipher is enough to change the scores
+    pub fn has_key_share(&self) -> bool {
+        matches!(
+            self,
+            Self::Submitted {
+                key_share: true,
+                ..
+            } | Self::Quarantined {
+                key_share: true,
+                ..
+            }
+        )
+    }
+}
 impl std::fmt::Display for UserStatus {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{:?}", self)
@@ -23,49 +54,310 @@ impl std::fmt::Display for UserStatus {
 #[serde(crate = "rocket::serde")]
 pub struct RegisteredUser {
     pub id: UserId,
+    pub participant_id: ParticipantId,
     pub name: String,
     pub status: UserStatus,
+    /// Where the user serves decryption shares if the server goes down
+    #[tabled(display_with = "display_contact")]
+    pub contact: Option<String>,
+    /// Committed to a cipher, in rooms that take commitments
+    #[serde(default)]
+    pub committed: bool,
+    /// Authorizes the user's submissions. Only in the response to `/register`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[tabled(skip)]
+    pub token: Option<String>,
+    /// Hex ed25519 key the user signs submissions with, see [`crate::verify_submission`]
+    #[serde(default)]
+    #[tabled(skip)]
+    pub public_key: Option<String>,
+    /// Karma over the room's counted rounds, for returning users, see [`crate::history`]
+    #[serde(default)]
+    #[tabled(rename = "lifetime karma", display_with = "display_lifetime_karma")]
+    pub lifetime_karma: Option<i64>,
+}
+
+fn display_contact(contact: &Option<String>) -> String {
+    contact.clone().unwrap_or_default()
+}
+
+fn display_lifetime_karma(karma: &Option<i64>) -> String {
+    karma.map(|karma| karma.to_string()).unwrap_or_default()
 }
 
 impl RegisteredUser {
-    pub(crate) fn new(id: UserId, name: &str) -> Self {
+    pub(crate) fn new(id: UserId, participant_id: ParticipantId, name: &str) -> Self {
         Self {
             id,
+            participant_id,
             name: name.to_string(),
             status: UserStatus::IDAcquired,
+            contact: None,
+            committed: false,
+            token: None,
+            public_key: None,
+            lifetime_karma: None,
         }
     }
 }
 impl From<&UserRecord> for RegisteredUser {
     fn from(user: &UserRecord) -> Self {
         use crate::types::UserStorage::*;
-        let status = match user.storage {
-            Empty => UserStatus::IDAcquired,
-            CipherSks(_, _) => UserStatus::CipherSubmitted,
+        let status = match &user.storage {
+            Inputs(inputs) if inputs.cipher.is_none() && inputs.sks.is_none() => {
+                UserStatus::IDAcquired
+            }
+            Inputs(inputs) => UserStatus::Submitted {
+                cipher: inputs.cipher.is_some(),
+                key_share: inputs.sks.is_some(),
+                version: inputs.version,
+            },
+            Quarantined { reason, sks } => UserStatus::Quarantined {
+                reason: reason.to_string(),
+                key_share: sks.is_some(),
+            },
             DecryptionShare(_) => UserStatus::DecryptionShareSubmitted,
         };
 
         Self {
             id: user.id,
+            participant_id: user.participant_id.clone(),
+            contact: user.contact.clone(),
             name: user.name.to_string(),
             status,
+            committed: user.commitment.is_some(),
+            token: None,
+            public_key: user.public_key.clone(),
+            lifetime_karma: None,
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// What the server waits on from a user, see [`UserProgress`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub enum NextStep {
+    /// Registration is open, the admin closes it
+    WaitForRegistration,
+    Commit,
+    /// The others haven't all committed yet
+    WaitForCommitments,
+    /// Submit whichever of the two is `true`
+    Submit {
+        cipher: bool,
+        key_share: bool,
+    },
+    /// The last cipher was rejected, submit a valid one
+    Resubmit {
+        reason: String,
+    },
+    /// Every input of the user is in, the others' and the run are not
+    WaitForRun,
+    SubmitDecryptionShares,
+    /// The user's shares are in, the others' are not
+    WaitForDecryptionShares,
+}
+
+impl std::fmt::Display for NextStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::WaitForRegistration => write!(f, "Wait for the admin to close registration"),
+            Self::Commit => write!(f, "Commit to your cipher"),
+            Self::WaitForCommitments => write!(f, "Wait for the others to commit"),
+            Self::Submit {
+                cipher: true,
+                key_share: true,
+            } => write!(f, "Submit your cipher and server key share"),
+            Self::Submit { cipher: true, .. } => write!(f, "Submit your cipher"),
+            Self::Submit { .. } => write!(f, "Submit your server key share"),
+            Self::Resubmit { reason } => {
+                write!(f, "Submit a valid cipher, yours was rejected: {reason}")
+            }
+            Self::WaitForRun => write!(f, "Wait for the others' inputs and the FHE run"),
+            Self::SubmitDecryptionShares => {
+                write!(f, "Download the outputs and submit your decryption shares")
+            }
+            Self::WaitForDecryptionShares => write!(f, "Wait for the others' decryption shares"),
+        }
+    }
+}
+
+/// What changed from one dashboard of a room to a later one, see [`Dashboard::changes_since`]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub enum DashboardChange {
+    /// The admin reset the room
+    RoundStarted {
+        round: u64,
+    },
+    /// Removed by the admin, or gone with a reset
+    UserLeft {
+        name: String,
+    },
+    UserJoined {
+        user_id: UserId,
+        name: String,
+    },
+    StatusChanged {
+        name: String,
+        from: UserStatus,
+        to: UserStatus,
+    },
+    /// In rooms that take commitments
+    Committed {
+        name: String,
+    },
+    ObserverJoined {
+        name: String,
+    },
+    StateChanged {
+        from: ServerState,
+        to: ServerState,
+    },
+}
+
+impl std::fmt::Display for DashboardChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::RoundStarted { round } => write!(f, "Round {round} started"),
+            Self::UserLeft { name } => write!(f, "{name} left the room"),
+            Self::UserJoined { user_id, name } => write!(f, "{name} joined as user #{user_id}"),
+            Self::StatusChanged { name, from, to } => write!(f, "{name}: {from} → {to}"),
+            Self::Committed { name } => write!(f, "{name} committed to a cipher"),
+            Self::ObserverJoined { name } => write!(f, "{name} watches the round"),
+            Self::StateChanged { from, to } => write!(f, "Room: {from:?} → {to:?}"),
+        }
+    }
+}
+
+/// One user's part of the round, see `/rooms/<room_id>/users/<user_id>/status`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct UserProgress {
+    pub user: RegisteredUser,
+    pub next_step: NextStep,
+    /// When the current phase closes, if it has a deadline
+    pub deadline: Option<Timestamp>,
+}
+
+impl UserProgress {
+    pub(crate) fn new(ss: &ServerStorage, user: &UserRecord) -> Self {
+        let next_step = match (&ss.state, &user.storage) {
+            (ServerState::ReadyForJoining, _) => NextStep::WaitForRegistration,
+            (ServerState::ReadyForCommitments, _) if user.commitment.is_none() => NextStep::Commit,
+            (ServerState::ReadyForCommitments, _) => NextStep::WaitForCommitments,
+            (ServerState::ReadyForInputs, UserStorage::Quarantined { reason, .. }) => {
+                NextStep::Resubmit {
+                    reason: reason.clone(),
+                }
+            }
+            // Left out of the run unless replaced before it starts
+            (ServerState::ReadyForRunning, UserStorage::Quarantined { reason, .. })
+                if ss.config.resubmission == ResubmissionPolicy::ReplaceUntilRun =>
+            {
+                NextStep::Resubmit {
+                    reason: reason.clone(),
+                }
+            }
+            (ServerState::ReadyForInputs, UserStorage::Inputs(inputs)) if !inputs.is_complete() => {
+                NextStep::Submit {
+                    cipher: inputs.cipher.is_none(),
+                    key_share: inputs.sks.is_none(),
+                }
+            }
+            (ServerState::CompletedFhe, UserStorage::DecryptionShare(None)) => {
+                NextStep::SubmitDecryptionShares
+            }
+            (ServerState::CompletedFhe, _) => NextStep::WaitForDecryptionShares,
+            _ => NextStep::WaitForRun,
+        };
+        let deadline = match ss.state {
+            ServerState::ReadyForJoining => ss.registration_deadline,
+            ServerState::ReadyForInputs => ss.deadline,
+            ServerState::CompletedFhe => ss.decryption_deadline,
+            _ => None,
+        };
+        Self {
+            user: user.into(),
+            next_step,
+            deadline,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Dashboard {
     status: ServerState,
+    /// Bumped every time the room is reset
+    round: u64,
     users: Vec<RegisteredUser>,
+    registration_deadline: Option<Timestamp>,
+    deadline: Option<Timestamp>,
+    deadline_extension: Option<DeadlineExtension>,
+    decryption_deadline: Option<Timestamp>,
+    /// Names of users removed by the admin or dropped at the inputs deadline
+    removed_users: Vec<String>,
+    /// Watching without taking part
+    #[serde(default)]
+    observers: Vec<Observer>,
+    /// The run starts on its own once every cipher arrives
+    auto_run: bool,
+    /// Whether users may correct an input they already submitted
+    #[serde(default)]
+    resubmission: ResubmissionPolicy,
+    /// Unused invite codes, in rooms where registering takes one
+    #[serde(default)]
+    invites_left: Option<usize>,
+    /// FHE parameters of the round, settled once registration closes
+    #[serde(default)]
+    parameter_set: ParameterSet,
+    /// Anyone can fetch the decrypted karma from `/results` once every decryption share is in
+    #[serde(default)]
+    server_results: bool,
+    /// Ratings of the round carried so far, still encrypted
+    #[serde(default)]
+    rating: usize,
+    /// Ratings per round, see `ratings` in Rocket.toml. 0 and 1 decrypt every run.
+    #[serde(default)]
+    ratings: usize,
+    /// Changes with every dashboard the room publishes, for the `ETag` of `/dashboard`
+    #[serde(skip)]
+    pub(crate) version: u64,
 }
 impl Dashboard {
-    pub(crate) fn new(status: &ServerState, users: &[RegisteredUser]) -> Self {
+    pub(crate) fn new(ss: &ServerStorage) -> Self {
         Self {
-            status: status.clone(),
-            users: users.to_vec(),
+            status: ss.state.clone(),
+            round: ss.round,
+            users: ss
+                .users
+                .iter()
+                .map(|user| RegisteredUser {
+                    lifetime_karma: ss.karma.lifetime_karma(&user.participant_id),
+                    ..user.into()
+                })
+                .collect_vec(),
+            registration_deadline: ss.registration_deadline,
+            deadline: ss.deadline,
+            deadline_extension: ss.deadline_extension.clone(),
+            decryption_deadline: ss.decryption_deadline,
+            removed_users: ss.removed_users.clone(),
+            observers: ss.observers.clone(),
+            auto_run: ss.config.auto_run,
+            resubmission: ss.config.resubmission,
+            invites_left: ss.invites_left(),
+            parameter_set: ss.parameter,
+            server_results: ss.config.server_results,
+            rating: ss.rating,
+            ratings: ss.config.ratings,
+            version: 0,
         }
     }
 
+    pub(crate) fn version(&self) -> u64 {
+        self.version
+    }
+
     pub fn get_names(&self) -> Vec<String> {
         self.users
             .iter()
@@ -73,17 +365,219 @@ impl Dashboard {
             .collect_vec()
     }
 
+    pub fn status(&self) -> &ServerState {
+        &self.status
+    }
+
+    pub fn round(&self) -> u64 {
+        self.round
+    }
+
+    /// Ratings of the round carried so far, and how many the round takes
+    pub fn rating(&self) -> (usize, usize) {
+        (self.rating, self.ratings.max(1))
+    }
+
+    /// A later rating of the round takes scores, and this user hasn't sent theirs yet
+    pub fn awaits_scores(&self, participant_id: &ParticipantId) -> bool {
+        let taking_scores = matches!(
+            self.status,
+            ServerState::ReadyForCommitments | ServerState::ReadyForInputs
+        );
+        self.rating > 0
+            && taking_scores
+            && self.users.iter().any(|user| {
+                &user.participant_id == participant_id
+                    && !matches!(user.status, UserStatus::Submitted { cipher: true, .. })
+            })
+    }
+
+    pub fn users(&self) -> &[RegisteredUser] {
+        &self.users
+    }
+
     /// An API for client to check server state
     pub fn is_concluded(&self) -> bool {
-        self.status == ServerState::ReadyForInputs
+        matches!(
+            self.status,
+            ServerState::ReadyForCommitments | ServerState::ReadyForInputs
+        )
+    }
+
+    /// Users commit to their cipher now, and submit it once everyone has
+    pub fn is_taking_commitments(&self) -> bool {
+        self.status == ServerState::ReadyForCommitments
     }
 
     pub fn is_fhe_complete(&self) -> bool {
         self.status == ServerState::CompletedFhe
     }
 
+    pub fn registration_deadline(&self) -> Option<Timestamp> {
+        self.registration_deadline
+    }
+
+    pub fn deadline(&self) -> Option<Timestamp> {
+        self.deadline
+    }
+
+    pub fn decryption_deadline(&self) -> Option<Timestamp> {
+        self.decryption_deadline
+    }
+
+    pub fn is_auto_run(&self) -> bool {
+        self.auto_run
+    }
+
+    pub fn resubmission(&self) -> ResubmissionPolicy {
+        self.resubmission
+    }
+
+    /// A user may submit again to replace their cipher or key share
+    pub fn takes_replacements(&self) -> bool {
+        self.resubmission == ResubmissionPolicy::ReplaceUntilRun
+            && matches!(
+                self.status,
+                ServerState::ReadyForInputs | ServerState::ReadyForRunning
+            )
+    }
+
+    /// Pass to [`crate::setup`] before generating keys, once [`Self::is_concluded`]
+    pub fn parameter_set(&self) -> ParameterSet {
+        self.parameter_set
+    }
+
+    pub fn invites_left(&self) -> Option<usize> {
+        self.invites_left
+    }
+
+    /// Whether `/results` serves the decrypted karma, see `server_results` in Rocket.toml
+    pub fn publishes_results(&self) -> bool {
+        self.server_results
+    }
+
+    pub fn observers(&self) -> &[Observer] {
+        &self.observers
+    }
+
+    pub fn removed_users(&self) -> &[String] {
+        &self.removed_users
+    }
+
+    /// Find a user's current ID, which changes when the admin removes someone
+    pub fn user_id_of(&self, participant_id: &ParticipantId) -> Option<UserId> {
+        self.users
+            .iter()
+            .find(|user| &user.participant_id == participant_id)
+            .map(|user| user.id)
+    }
+
+    /// The proposed deadline still waiting for a majority of acks
+    pub fn pending_deadline_extension(&self) -> Option<&DeadlineExtension> {
+        self.deadline_extension.as_ref()
+    }
+
+    /// What happened since `earlier`: the round, users leaving, then the others joining or
+    /// moving on in ID order, and the room's phase last, as their progress moves it
+    pub fn changes_since(&self, earlier: &Dashboard) -> Vec<DashboardChange> {
+        let find = |users: &'_ [RegisteredUser], participant_id: &ParticipantId| {
+            users
+                .iter()
+                .find(|user| &user.participant_id == participant_id)
+                .cloned()
+        };
+        let mut changes = vec![];
+        if self.round != earlier.round {
+            changes.push(DashboardChange::RoundStarted { round: self.round });
+        }
+        for user in &earlier.users {
+            if find(&self.users, &user.participant_id).is_none() {
+                changes.push(DashboardChange::UserLeft {
+                    name: user.name.clone(),
+                });
+            }
+        }
+        for user in &self.users {
+            let Some(before) = find(&earlier.users, &user.participant_id) else {
+                changes.push(DashboardChange::UserJoined {
+                    user_id: user.id,
+                    name: user.name.clone(),
+                });
+                continue;
+            };
+            if user.status != before.status {
+                changes.push(DashboardChange::StatusChanged {
+                    name: user.name.clone(),
+                    from: before.status,
+                    to: user.status.clone(),
+                });
+            }
+            if user.committed && !before.committed {
+                changes.push(DashboardChange::Committed {
+                    name: user.name.clone(),
+                });
+            }
+        }
+        for observer in &self.observers {
+            if !earlier.observers.contains(observer) {
+                changes.push(DashboardChange::ObserverJoined {
+                    name: observer.name.clone(),
+                });
+            }
+        }
+        if self.status != earlier.status {
+            changes.push(DashboardChange::StateChanged {
+                from: earlier.status.clone(),
+                to: self.status.clone(),
+            });
+        }
+        changes
+    }
+
     pub fn print_presentation(&self) {
         println!("🤖🧠 {}", self.status);
+        if self.is_concluded() {
+            println!("🔑 Parameters: {:?}", self.parameter_set);
+        }
+        if self.auto_run {
+            println!("🏃 The FHE run starts once every cipher is submitted");
+        }
+        if self.resubmission == ResubmissionPolicy::Reject {
+            println!("🔒 Inputs can't be replaced once submitted");
+        }
+        if self.ratings > 1 {
+            println!("🔁 Rating {} of {}", self.rating + 1, self.ratings);
+        }
+        if let Some(left) = self.invites_left {
+            println!("🎟️ Invite codes left: {}", left);
+        }
+        if let Some(deadline) = self.registration_deadline {
+            println!("⏰ Registration deadline: {} (unix time)", deadline);
+        }
+        if let Some(deadline) = self.deadline {
+            println!("⏰ Submission deadline: {} (unix time)", deadline);
+        }
+        if let Some(deadline) = self.decryption_deadline {
+            println!("⏰ Decryption share deadline: {} (unix time)", deadline);
+        }
+        if let Some(ext) = &self.deadline_extension {
+            println!(
+                "📨 Proposed deadline {} acked by {}/{} users",
+                ext.deadline,
+                ext.acks.len(),
+                self.users.len()
+            );
+        }
+        if !self.observers.is_empty() {
+            let names = self.observers.iter().map(|o| o.name.as_str()).join(", ");
+            println!("👀 Watching: {}", names);
+        }
+        if !self.removed_users.is_empty() {
+            println!(
+                "🚪 Removed from the round: {}",
+                self.removed_users.join(", ")
+            );
+        }
         let users = Table::new(&self.users)
             .with(Style::ascii_rounded())
             .to_string();
@@ -0,0 +1,69 @@
+//! Lets clients poll `/dashboard` cheaply: the response carries an `ETag` naming the version of
+//! the dashboard, and a request whose `If-None-Match` names the current one gets an empty
+//! `304 Not Modified` rather than the whole user table again
+use rocket::http::{Header, Status};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::{self, Responder, Response};
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use std::convert::Infallible;
+
+pub(crate) const ETAG_HEADER: &str = "ETag";
+
+/// The `If-None-Match` of the request, if any
+pub(crate) struct IfNoneMatch(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IfNoneMatch {
+    type Error = Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let tags = req.headers().get_one("If-None-Match").map(str::to_string);
+        Outcome::Success(Self(tags))
+    }
+}
+
+impl IfNoneMatch {
+    /// Whether the client holds the representation tagged `etag` already
+    fn matches(&self, etag: &str) -> bool {
+        let Some(tags) = &self.0 else {
+            return false;
+        };
+        tags.split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == "*" || tag == etag)
+    }
+
+    /// `value` tagged with `version`, or nothing if the client has it
+    pub(crate) fn respond<T>(&self, version: u64, value: impl FnOnce() -> T) -> Conditional<T> {
+        let etag = format!("\"{version:x}\"");
+        if self.matches(&etag) {
+            Conditional::NotModified { etag }
+        } else {
+            Conditional::Fresh {
+                etag,
+                body: Json(value()),
+            }
+        }
+    }
+}
+
+/// A JSON response with an `ETag`, see [`IfNoneMatch::respond`]
+pub(crate) enum Conditional<T> {
+    Fresh { etag: String, body: Json<T> },
+    NotModified { etag: String },
+}
+
+impl<'r, T: Serialize> Responder<'r, 'static> for Conditional<T> {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        match self {
+            Self::Fresh { etag, body } => Response::build_from(body.respond_to(req)?)
+                .header(Header::new(ETAG_HEADER, etag))
+                .ok(),
+            Self::NotModified { etag } => Response::build()
+                .status(Status::NotModified)
+                .header(Header::new(ETAG_HEADER, etag))
+                .ok(),
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use crate::dashboard::{Dashboard, RegisteredUser};
+use crate::types::ServerState;
+use futures::{SinkExt, StreamExt};
+use rocket::data::{IoHandler, IoStream};
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::{self, Responder, Response};
+use rocket::serde::{json, Deserialize, Serialize};
+use std::io;
+use std::pin::Pin;
+use tokio::sync::watch;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+/// A change in a room, pushed as JSON over `/rooms/<room_id>/events`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub enum RoomEvent {
+    /// The room moved to another phase
+    StateChanged { from: ServerState, to: ServerState },
+    /// A user joined, or their status, ID or commitment changed
+    UserChanged(RegisteredUser),
+    /// The admin removed a user
+    UserRemoved { name: String },
+}
+
+impl RoomEvent {
+    /// What changed between two dashboards of the same room.
+    /// Changes that were undone in between don't show up.
+    pub fn diff(old: &Dashboard, new: &Dashboard) -> Vec<Self> {
+        let mut events = vec![];
+        if old.status() != new.status() {
+            events.push(Self::StateChanged {
+                from: old.status().clone(),
+                to: new.status().clone(),
+            });
+        }
+        for user in new.users() {
+            let before = old
+                .users()
+                .iter()
+                .find(|before| before.participant_id == user.participant_id);
+            if !before.is_some_and(|before| {
+                before.id == user.id
+                    && before.status == user.status
+                    && before.committed == user.committed
+            }) {
+                events.push(Self::UserChanged(user.clone()));
+            }
+        }
+        if let Some(removed) = new.removed_users().get(old.removed_users().len()..) {
+            events.extend(removed.iter().map(|name| Self::UserRemoved {
+                name: name.to_string(),
+            }));
+        }
+        events
+    }
+}
+
+/// The `Sec-WebSocket-Accept` of a WebSocket handshake request
+pub(crate) struct WebSocketUpgrade {
+    accept: String,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for WebSocketUpgrade {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let has = |header: &str, value: &str| {
+            req.headers()
+                .get(header)
+                .flat_map(|h| h.split(','))
+                .any(|v| v.trim().eq_ignore_ascii_case(value))
+        };
+        let key = req.headers().get_one("Sec-WebSocket-Key");
+        match key {
+            Some(key)
+                if has("Connection", "upgrade")
+                    && has("Upgrade", "websocket")
+                    && has("Sec-WebSocket-Version", "13") =>
+            {
+                Outcome::Success(Self {
+                    accept: derive_accept_key(key.as_bytes()),
+                })
+            }
+            _ => Outcome::Error((Status::UpgradeRequired, ())),
+        }
+    }
+}
+
+/// Streams a room's [`RoomEvent`]s to one subscriber until either side hangs up
+pub(crate) struct EventChannel {
+    upgrade: WebSocketUpgrade,
+    dashboard: watch::Receiver<Dashboard>,
+}
+
+impl EventChannel {
+    pub(crate) fn new(upgrade: WebSocketUpgrade, dashboard: watch::Receiver<Dashboard>) -> Self {
+        Self { upgrade, dashboard }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for EventChannel {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        Response::build()
+            .raw_header("Sec-WebSocket-Version", "13")
+            .raw_header("Sec-WebSocket-Accept", self.upgrade.accept.clone())
+            .upgrade("websocket", self)
+            .ok()
+    }
+}
+
+#[rocket::async_trait]
+impl IoHandler for EventChannel {
+    async fn io(self: Pin<Box<Self>>, io: IoStream) -> io::Result<()> {
+        let mut dashboard = Pin::into_inner(self).dashboard;
+        let mut socket = WebSocketStream::from_raw_socket(io, Role::Server, None).await;
+        let mut last = dashboard.borrow_and_update().clone();
+        loop {
+            tokio::select! {
+                changed = dashboard.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+                    let current = dashboard.borrow_and_update().clone();
+                    for event in RoomEvent::diff(&last, &current) {
+                        let text = json::to_string(&event).map_err(io::Error::other)?;
+                        socket.send(Message::Text(text)).await.map_err(io::Error::other)?;
+                    }
+                    last = current;
+                }
+                message = socket.next() => match message {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    // Subscribers have nothing to say
+                    Some(Ok(_)) => {}
+                },
+            }
+        }
+        Ok(())
+    }
+}
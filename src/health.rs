@@ -0,0 +1,37 @@
+//! Probes for orchestrators and `cli doctor`: `/healthz` answers as long as the process does,
+//! `/readyz` tells what the rooms are up to
+use crate::room::RoomSummary;
+use crate::types::ServerState;
+use rocket::serde::{Deserialize, Serialize};
+
+/// Body of `/readyz`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Readiness {
+    /// Phase and registered users of every room
+    pub rooms: Vec<RoomSummary>,
+    /// Some room is in [`ServerState::RunningFhe`], so expect the CPU to be busy
+    pub running: bool,
+    /// Resident memory of the server process. `None` where the OS doesn't tell us.
+    pub memory_bytes: Option<u64>,
+}
+
+impl Readiness {
+    pub(crate) fn new(rooms: Vec<RoomSummary>) -> Self {
+        Self {
+            running: rooms
+                .iter()
+                .any(|room| room.status == ServerState::RunningFhe),
+            rooms,
+            memory_bytes: resident_memory(),
+        }
+    }
+}
+
+/// The `VmRSS` line of `/proc/self/status`, only on Linux
+fn resident_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
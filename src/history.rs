@@ -0,0 +1,193 @@
+//! Append-only audit log of the milestones of a room, across rounds: who joined or left,
+//! commitments, accepted inputs by hash, phase changes, deadlines and new rounds. The server
+//! applies each through [`crate::types::ServerStorage::apply`] and keeps the log with the room
+//! snapshot. It is a record, not the source of truth: tokens, invites, deadline proposals,
+//! contacts and the stored submissions change outside it, and a restarted server resumes from
+//! the snapshot rather than by replaying the log.
+//! Observers fetch it from `/rooms/<room_id>/log?since=<seq>` and fold it with [`RoomHistory`].
+//!
+//! The room also keeps each participant's karma across rounds in a [`KarmaLedger`].
+use crate::types::{
+    ParticipantId, Score, Seed, ServerState, Timestamp, TranscriptArtifact, UserId,
+};
+use anyhow::{ensure, Error};
+use rocket::serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// A change to a room. Tokens and submissions stay out of the log, only their hashes go in.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub enum RoomChange {
+    UserJoined {
+        user_id: UserId,
+        participant_id: ParticipantId,
+        name: String,
+    },
+    /// Someone started watching the round, see [`crate::Observer`]
+    ObserverJoined {
+        name: String,
+    },
+    /// The admin removed a user, the ones after moved down an ID
+    UserRemoved {
+        user_id: UserId,
+        name: String,
+    },
+    /// The user missed the inputs deadline and was removed, the ones after moved down an ID
+    UserDropped {
+        user_id: UserId,
+    },
+    /// The user committed to the hash of their cipher
+    Committed {
+        user_id: UserId,
+        hash: String,
+    },
+    /// The server accepted an input, see [`crate::TranscriptEntry`]
+    InputAccepted {
+        participant_id: ParticipantId,
+        artifact: TranscriptArtifact,
+        hash: String,
+        #[serde(default)]
+        bytes: u64,
+    },
+    StateChanged {
+        from: ServerState,
+        to: ServerState,
+    },
+    /// The admin set the input deadline of a rating that had none
+    DeadlineSet {
+        deadline: Timestamp,
+    },
+    /// A majority of users agreed to a later input deadline
+    DeadlineExtended {
+        deadline: Timestamp,
+    },
+    /// The admin reset the room
+    RoundStarted {
+        round: u64,
+        seed: Seed,
+    },
+    /// The run's outputs were kept encrypted for the next rating, and users send new scores.
+    /// See [`crate::types::RoomConfig::ratings`].
+    RatingCarried {
+        rating: usize,
+    },
+    /// The server decrypted the round's results and added them to the [`KarmaLedger`]
+    ResultsCounted {
+        round: u64,
+        karma: Vec<(ParticipantId, Score)>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct LogEntry {
+    /// Position in the log, starting at 0
+    pub seq: u64,
+    /// The round the change happened in. A [`RoomChange::RoundStarted`] is in the new round.
+    pub round: u64,
+    pub at: Timestamp,
+    pub change: RoomChange,
+}
+
+/// What an observer knows of a room from its log alone
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoomHistory {
+    pub round: u64,
+    pub state: ServerState,
+    /// Names in [`UserId`] order
+    pub users: Vec<String>,
+    pub observers: Vec<String>,
+    /// Inputs accepted this rating
+    pub inputs: usize,
+    /// Where to continue reading the log from
+    pub next_seq: u64,
+}
+
+impl Default for RoomHistory {
+    fn default() -> Self {
+        Self {
+            round: 0,
+            state: ServerState::ReadyForJoining,
+            users: vec![],
+            observers: vec![],
+            inputs: 0,
+            next_seq: 0,
+        }
+    }
+}
+
+impl RoomHistory {
+    /// Fold `entries` into a fresh history
+    pub fn replay(entries: &[LogEntry]) -> Result<Self, Error> {
+        let mut history = Self::default();
+        for entry in entries {
+            history.apply(entry)?;
+        }
+        Ok(history)
+    }
+
+    /// Fails on a gap in the log, so a missed entry can't go unnoticed
+    pub fn apply(&mut self, entry: &LogEntry) -> Result<(), Error> {
+        ensure!(
+            entry.seq == self.next_seq,
+            "Expected log entry #{}, got #{}",
+            self.next_seq,
+            entry.seq
+        );
+        self.next_seq += 1;
+        match &entry.change {
+            RoomChange::UserJoined { name, .. } => self.users.push(name.clone()),
+            RoomChange::ObserverJoined { name } => self.observers.push(name.clone()),
+            RoomChange::UserRemoved { user_id, .. } | RoomChange::UserDropped { user_id } => {
+                ensure!(*user_id < self.users.len(), "Unknown user #{user_id}");
+                self.users.remove(*user_id);
+                // Everyone submits again for the new party
+                self.inputs = 0;
+            }
+            RoomChange::InputAccepted { .. } => self.inputs += 1,
+            RoomChange::RatingCarried { .. } => self.inputs = 0,
+            RoomChange::StateChanged { to, .. } => self.state = to.clone(),
+            RoomChange::RoundStarted { round, .. } => {
+                *self = Self {
+                    round: *round,
+                    next_seq: self.next_seq,
+                    ..Self::default()
+                }
+            }
+            RoomChange::Committed { .. }
+            | RoomChange::DeadlineSet { .. }
+            | RoomChange::DeadlineExtended { .. }
+            | RoomChange::ResultsCounted { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+/// Karma each participant accumulated over the rounds of a room. Only rounds the server
+/// decrypted itself are counted, see [`crate::types::RoomConfig::server_results`].
+/// Users who register with the same public key keep their [`ParticipantId`], and their total.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(crate) struct KarmaLedger {
+    totals: HashMap<ParticipantId, i64>,
+    /// Rounds counted so far, so fetching the results again doesn't count them twice
+    rounds: Vec<u64>,
+}
+
+impl KarmaLedger {
+    pub(crate) fn is_counted(&self, round: u64) -> bool {
+        self.rounds.contains(&round)
+    }
+
+    pub(crate) fn count(&mut self, round: u64, karma: &[(ParticipantId, Score)]) {
+        for (participant_id, score) in karma {
+            *self.totals.entry(participant_id.clone()).or_default() += *score as i64;
+        }
+        self.rounds.push(round);
+    }
+
+    /// `None` for a participant none of the counted rounds had
+    pub(crate) fn lifetime_karma(&self, participant_id: &ParticipantId) -> Option<i64> {
+        self.totals.get(participant_id).copied()
+    }
+}
@@ -0,0 +1,100 @@
+//! Lets a client retry a mutating request whose response got lost, without the server doing it
+//! twice: a second request with the same `Idempotency-Key` gets the first response again
+use crate::auth::ADMIN_TOKEN_HEADER;
+use rocket::http::{ContentType, Header};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::{self, Responder, Response};
+use rocket::serde::json::{self, Json};
+use rocket::serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::io::Cursor;
+
+/// Header a client sends a fresh random key in with each mutating request, and again on its retries
+pub(crate) const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+/// Header marking a response served again for a retried request
+pub(crate) const REPLAYED_HEADER: &str = "Idempotent-Replayed";
+
+/// How many responses a room remembers. Retries come within seconds, so a few recent ones do.
+const CAPACITY: usize = 256;
+
+/// The `Idempotency-Key` of the request, if any, scoped to its path so a key can't replay
+/// the response of another route, and to the hash of the caller's credentials so only the
+/// caller who sent it can replay a response
+pub(crate) struct IdempotencyKey(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IdempotencyKey {
+    type Error = Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let headers = req.headers();
+        let key = headers.get_one(IDEMPOTENCY_KEY_HEADER).map(|key| {
+            let mut caller = Sha256::new();
+            for name in ["Authorization", ADMIN_TOKEN_HEADER] {
+                caller.update(headers.get_one(name).unwrap_or_default());
+                caller.update([0]);
+            }
+            let caller = hex::encode(caller.finalize());
+            format!("{} {} {}", req.uri().path(), caller, key)
+        });
+        Outcome::Success(Self(key))
+    }
+}
+
+/// Successful responses of a room's recent keyed requests, as JSON. Errors aren't remembered,
+/// so a retry after one runs the request again. The cache is saved with the room snapshot, so
+/// never remember a response holding a secret, e.g. a user's token.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(crate) struct IdempotencyCache {
+    responses: VecDeque<(String, String)>,
+}
+
+impl IdempotencyCache {
+    /// The response to replay, if a request with this key succeeded before
+    pub(crate) fn replay<T>(&self, key: &IdempotencyKey) -> Option<Idempotent<T>> {
+        let key = key.0.as_ref()?;
+        self.responses
+            .iter()
+            .find(|(seen, _)| seen == key)
+            .map(|(_, body)| Idempotent::Replayed(body.clone()))
+    }
+
+    /// Remember `value` as the response to `key`, and respond with it
+    pub(crate) fn remember<T: Serialize>(
+        &mut self,
+        key: &IdempotencyKey,
+        value: T,
+    ) -> Idempotent<T> {
+        if let Some(key) = &key.0 {
+            if self.responses.len() == CAPACITY {
+                self.responses.pop_front();
+            }
+            let body = json::to_string(&value).expect("Responses serialize");
+            self.responses.push_back((key.clone(), body));
+        }
+        Idempotent::Fresh(Json(value))
+    }
+}
+
+/// A JSON response that may be replayed, see [`IdempotencyCache`]
+pub(crate) enum Idempotent<T> {
+    Fresh(Json<T>),
+    Replayed(String),
+}
+
+impl<'r, T: Serialize> Responder<'r, 'static> for Idempotent<T> {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        match self {
+            Self::Fresh(json) => json.respond_to(req),
+            Self::Replayed(body) => Response::build()
+                .header(ContentType::JSON)
+                .header(Header::new(REPLAYED_HEADER, "true"))
+                .sized_body(body.len(), Cursor::new(body))
+                .ok(),
+        }
+    }
+}
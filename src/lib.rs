@@ -1,28 +1,95 @@
+mod archive;
+mod auth;
+mod bench;
+mod checkpoint;
 mod circuit;
 mod client;
+mod cold;
 mod compiled;
+mod compression;
+mod config;
+mod cors;
 mod dashboard;
+mod etag;
+mod events;
+mod health;
+mod history;
+mod idempotency;
+mod limits;
+mod logging;
+mod outbox;
+mod p2p;
+mod participant;
+mod persist;
+mod receipt;
+mod report;
+mod results;
+mod retry;
+mod room;
 mod server;
+mod session;
+mod telemetry;
+mod tls;
+mod transport;
 mod types;
+mod upload;
+mod version;
+mod worker;
 
-pub use client::WebClient;
-pub use server::{rocket, setup};
+pub use archive::{read_index, ArchiveEntry, ArchiveMeta, SessionArchive};
+pub use bench::{bench, BenchReport, BenchStep};
+pub use circuit::{CircuitId, InputContract, ParameterSet, SelfScorePolicy};
+pub use client::{
+    on_pool, ClientError, DashboardPoll, SimulatedParty, UploadStats, WebClient, WebClientBuilder,
+};
+pub use dashboard::{
+    Dashboard, DashboardChange, NextStep, RegisteredUser, UserProgress, UserStatus,
+};
+pub use events::RoomEvent;
+pub use health::Readiness;
+pub use history::{LogEntry, RoomChange, RoomHistory};
+pub use logging::init_tracing;
+pub use p2p::{fetch_peer_shares, serve_shares, PeerShares};
+pub use participant::{Participant, RunOutcome, SubmissionQueued};
+pub use receipt::{
+    artifact_hash, sign_submission, verify_submission, ArchiveSignature, Receipt, ReceiptBody,
+    SignedResults,
+};
+pub use report::{KarmaDiff, RoundResult, Trend};
+pub use retry::{NetworkError, RetryPolicy};
+pub use room::{RoomId, RoomSummary};
+pub use server::{local_rocket, rocket, setup};
+pub use session::Session;
+pub use tls::{parse_pin, TlsOptions};
+pub use transport::{
+    EventStream, Headers, ReqwestTransport, Response, RocketLocalTransport, Transport,
+};
 pub use types::{
-    recover, u64_to_binary, EncryptedInput, CircuitOutput, ClientKey, DecryptionSharesMap, Score,
-    ServerState, UserId,
+    recover, u64_to_binary, CircuitOutput, ClientKey, DeadlineExtension, DecryptionSharesMap,
+    DecryptionStatus, EncryptedInput, ErrorBody, ErrorCode, FheOutput, InvalidResponse, JobStatus,
+    Observer, ParticipantId, ResubmissionPolicy, Score, ServerKeyShare, ServerState, Timestamp,
+    TranscriptArtifact, TranscriptEntry, Transition, UserId, UserShareStatus, SCORE_BITS,
 };
+pub use version::{ServerVersion, PROTOCOL_VERSION};
+pub use worker::run_worker;
 
 #[cfg(test)]
 mod tests;
 
-/// Utility to time a long running function
+/// For [`time!`] in crates that don't depend on `tracing` themselves
+#[doc(hidden)]
+pub use tracing;
+
+/// Utility to time a long running function. It runs in a `timed` span with the label,
+/// and logs the elapsed time when done, so any `tracing` subscriber can pick it up.
 #[macro_export]
 macro_rules! time {
     ($block:expr, $label:expr) => {{
+        let span = $crate::tracing::info_span!("timed", label = %$label);
+        let _entered = span.enter();
         let start = std::time::Instant::now();
-        print!("{}", $label);
         let result = $block();
-        println!(" | elapsed: {:.2?}", start.elapsed());
+        $crate::tracing::info!(elapsed_ms = start.elapsed().as_millis() as u64, "{}", $label);
         result
     }};
 }
@@ -0,0 +1,244 @@
+//! Keeps a misbehaving client from spamming registrations or sending submissions that exhaust memory
+use crate::compression::{decompress, is_compressed};
+use crate::types::Error;
+use rocket::data::{self, ByteUnit, Data, DataStream, FromData, Limits};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Method, Status};
+use rocket::request::{self, FromRequest, Request};
+use rocket::serde::{msgpack, Deserialize, DeserializeOwned};
+use std::collections::HashMap;
+use std::io::{BufReader, Read, Seek};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::debug;
+
+/// At most `requests` registrations from one IP in any `per_secs` seconds
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(crate) struct RateLimitConfig {
+    requests: usize,
+    per_secs: u64,
+}
+
+/// Counts each IP's recent `/register` requests. Without a config, nobody is limited.
+pub(crate) struct RegisterRateLimit {
+    config: Option<RateLimitConfig>,
+    hits: Mutex<HashMap<IpAddr, Vec<Instant>>>,
+}
+
+/// How long the fairing told a registration to wait, for [`RegisterQuota`] to read
+struct Throttled(Option<Duration>);
+
+impl RegisterRateLimit {
+    pub(crate) fn new(config: Option<RateLimitConfig>) -> Self {
+        Self {
+            config,
+            hits: Mutex::default(),
+        }
+    }
+
+    /// Record a request from `ip`, unless it's one too many. Then it says how long to wait.
+    fn hit(&self, ip: IpAddr, now: Instant) -> Option<Duration> {
+        let RateLimitConfig { requests, per_secs } = self.config?;
+        let window = Duration::from_secs(per_secs);
+        let mut hits = self.hits.lock().unwrap();
+        hits.retain(|_, times| {
+            times.retain(|time| now.duration_since(*time) < window);
+            !times.is_empty()
+        });
+        let times = hits.entry(ip).or_default();
+        if times.len() >= requests {
+            return Some(window.saturating_sub(now.duration_since(times[0])));
+        }
+        times.push(now);
+        None
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for RegisterRateLimit {
+    fn info(&self) -> Info {
+        Info {
+            name: "Registration rate limit",
+            kind: Kind::Request,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        let is_register =
+            req.method() == Method::Post && req.uri().path().segments().last() == Some("register");
+        if let (true, Some(ip)) = (is_register, req.client_ip()) {
+            let wait = self.hit(ip, Instant::now());
+            req.local_cache(|| Throttled(wait));
+        }
+    }
+}
+
+/// The rate limit let the registration through. Take it as `Result<RegisterQuota, Error>` to answer 429 with the error.
+pub(crate) struct RegisterQuota;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RegisterQuota {
+    type Error = Error;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        match req.local_cache(|| Throttled(None)).0 {
+            Some(wait) => request::Outcome::Error((
+                Status::TooManyRequests,
+                Error::TooManyRequests {
+                    retry_after: wait.as_secs().max(1),
+                },
+            )),
+            None => request::Outcome::Success(Self),
+        }
+    }
+}
+
+/// The `submit` limit of `Rocket.toml`, or the `msgpack` one if unset
+pub(crate) fn submit_limit(limits: &Limits) -> ByteUnit {
+    limits
+        .get("submit")
+        .or_else(|| limits.get("msgpack"))
+        .unwrap_or(Limits::MESSAGE_PACK)
+}
+
+/// Submission bodies past this size are spooled to a temp file while they're read, rather than
+/// held in memory next to their deserialized value. Set as `spool_threshold` in `Rocket.toml`.
+pub(crate) struct SpoolThreshold(pub(crate) ByteUnit);
+
+/// A msgpack submission no bigger than [`submit_limit`], zstd compressed or not.
+/// Bodies past the [`SpoolThreshold`] go through a file in Rocket's `temp_dir`, so several large
+/// key shares arriving at once don't each take their size in memory twice.
+/// Take it as `Result<Submission<T>, Error>` to answer 413 with the error.
+pub(crate) struct Submission<T>(pub(crate) T);
+
+type Rejection = (Status, Error);
+
+fn invalid(status: Status, reason: impl ToString) -> Rejection {
+    (
+        status,
+        Error::InvalidUpload {
+            reason: reason.to_string(),
+        },
+    )
+}
+
+fn storage_failed(err: std::io::Error) -> Rejection {
+    (
+        Status::InternalServerError,
+        Error::ArtifactStorage {
+            reason: err.to_string(),
+        },
+    )
+}
+
+#[rocket::async_trait]
+impl<'r, T: Send + DeserializeOwned + 'static> FromData<'r> for Submission<T> {
+    type Error = Error;
+
+    async fn from_data(req: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        match read_submission(req, data).await {
+            Ok(value) => data::Outcome::Success(Self(value)),
+            Err(rejection) => data::Outcome::Error(rejection),
+        }
+    }
+}
+
+async fn read_submission<T: Send + DeserializeOwned + 'static>(
+    req: &Request<'_>,
+    data: Data<'_>,
+) -> Result<T, Rejection> {
+    let limit = submit_limit(req.limits());
+    let threshold = req
+        .rocket()
+        .state::<SpoolThreshold>()
+        .map_or(limit, |threshold| threshold.0)
+        .min(limit);
+    let mut stream = data.open(limit);
+    let mut head = vec![];
+    (&mut stream)
+        .take(threshold.as_u64())
+        .read_to_end(&mut head)
+        .await
+        .map_err(|err| invalid(Status::BadRequest, err))?;
+    if ByteUnit::from(head.len()) < threshold {
+        return decode_bytes(req, head, limit);
+    }
+    let file = spool(req, head, stream, limit).await?;
+    let compressed = is_compressed(req);
+    tokio::task::spawn_blocking(move || decode_file(file, compressed, limit))
+        .await
+        .expect("Decoding panicked")
+}
+
+fn decode_bytes<T: DeserializeOwned>(
+    req: &Request<'_>,
+    mut bytes: Vec<u8>,
+    limit: ByteUnit,
+) -> Result<T, Rejection> {
+    let received = bytes.len();
+    if is_compressed(req) {
+        bytes = match decompress(&bytes, limit) {
+            Ok(bytes) => bytes,
+            Err(err @ Error::PayloadTooLarge { .. }) => return Err((Status::PayloadTooLarge, err)),
+            Err(err) => return Err((Status::BadRequest, err)),
+        };
+    }
+    debug!(
+        path = %req.uri().path(),
+        received,
+        bytes = bytes.len(),
+        "Submission body read"
+    );
+    msgpack::from_slice(&bytes).map_err(|err| invalid(Status::UnprocessableEntity, err))
+}
+
+/// Write `head` and the rest of the body to an anonymous temp file, and rewind it
+async fn spool(
+    req: &Request<'_>,
+    head: Vec<u8>,
+    stream: DataStream<'_>,
+    limit: ByteUnit,
+) -> Result<std::fs::File, Rejection> {
+    let dir = req.rocket().config().temp_dir.relative();
+    let file = tempfile::tempfile_in(dir).map_err(storage_failed)?;
+    let mut file = tokio::fs::File::from_std(file);
+    file.write_all(&head).await.map_err(storage_failed)?;
+    let rest = stream.stream_to(&mut file).await.map_err(storage_failed)?;
+    if !rest.complete {
+        return Err((Status::PayloadTooLarge, Error::PayloadTooLarge { limit }));
+    }
+    file.flush().await.map_err(storage_failed)?;
+    let mut file = file.into_std().await;
+    file.rewind().map_err(storage_failed)?;
+    debug!(
+        path = %req.uri().path(),
+        received = head.len() as u64 + rest.written,
+        "Submission body spooled to disk"
+    );
+    Ok(file)
+}
+
+/// Decode a body spooled to `file`, also for [`crate::upload::Upload`]
+pub(crate) fn decode_file<T: DeserializeOwned>(
+    file: std::fs::File,
+    compressed: bool,
+    limit: ByteUnit,
+) -> Result<T, Rejection> {
+    let decoded = if compressed {
+        let decoder = zstd::Decoder::new(file)
+            .map_err(|err| invalid(Status::BadRequest, format!("Invalid zstd body: {err}")))?;
+        // Like `decompress`, stop rather than inflate past the limit
+        let mut inflated = decoder.take(limit.as_u64() + 1);
+        let decoded = rmp_serde::from_read(&mut inflated);
+        if inflated.limit() == 0 {
+            return Err((Status::PayloadTooLarge, Error::PayloadTooLarge { limit }));
+        }
+        decoded
+    } else {
+        rmp_serde::from_read(BufReader::new(file))
+    };
+    decoded.map_err(|err| invalid(Status::UnprocessableEntity, err))
+}
@@ -0,0 +1,37 @@
+//! The server logs through `tracing`, so deployments can pick the format and downstream users
+//! can hook their own subscriber
+use crate::config::ServerConfig;
+use rocket::serde::Deserialize;
+use rocket::Config;
+use tracing_subscriber::EnvFilter;
+
+/// How the `log_format` config prints events
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(crate) enum LogFormat {
+    /// One human readable line per event
+    #[default]
+    Pretty,
+    /// One JSON object per event, with the fields of its spans, for log collectors
+    Json,
+}
+
+/// Print events at `info` and above, or as the `RUST_LOG` environment variable says.
+/// Does nothing if a subscriber is already set.
+fn init(format: LogFormat) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    let result = match format {
+        LogFormat::Pretty => builder.try_init(),
+        LogFormat::Json => builder.json().with_current_span(true).try_init(),
+    };
+    if let Err(err) = result {
+        tracing::debug!("Keeping the existing subscriber: {err}");
+    }
+}
+
+/// Log in the `log_format` of `Rocket.toml`. The server binary calls this before launching,
+/// a binary that embeds the server may set its own subscriber instead.
+pub fn init_tracing() {
+    init(ServerConfig::from_figment(&Config::figment()).log_format);
+}
@@ -0,0 +1,45 @@
+//! A submission [`crate::Participant`] made while the server was unreachable, kept on disk so
+//! the encryption and the key share are made once, and sent when the link is back. Neither is
+//! secret, so the file isn't encrypted like a [`crate::Session`].
+use crate::room::RoomId;
+use crate::types::{EncryptedInput, ParticipantId, Score, ServerKeyShare, UserId};
+use anyhow::Error;
+use rocket::serde::msgpack;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct PendingSubmission {
+    pub room: RoomId,
+    pub participant_id: ParticipantId,
+    /// Who the key share was made for: the user's ID, and how many users there were
+    pub user_id: UserId,
+    pub total_users: usize,
+    /// Of the dashboard the scores are for
+    pub names: Vec<String>,
+    pub scores: Vec<Score>,
+    pub cipher: EncryptedInput,
+    /// `None` where the server kept the key share of an earlier submission
+    pub sks: Option<ServerKeyShare>,
+}
+
+impl PendingSubmission {
+    /// Replace the file only once the new one is complete
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, msgpack::to_vec(self)?)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// `None` if nothing is queued at `path`
+    pub fn load(path: &Path) -> Result<Option<Self>, Error> {
+        match fs::read(path) {
+            Ok(bytes) => Ok(Some(msgpack::from_slice(&bytes)?)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
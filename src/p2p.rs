@@ -0,0 +1,131 @@
+//! Fallback for a server that goes away after publishing the outputs. Peers hand their
+//! decryption shares to each other over plain TCP, at the contact they published in the dashboard.
+//! Whoever serves at a contact is checked against what the peer submitted to the server.
+use crate::receipt::{artifact_hash, sign_submission, verify_submission};
+use crate::types::{
+    DecryptionShare, DecryptionShareSubmission, ParticipantId, TranscriptArtifact, TranscriptEntry,
+};
+use anyhow::{bail, ensure, Context, Error};
+use ed25519_dalek::SigningKey;
+use rocket::serde::{msgpack, Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+
+/// Refuse anything bigger, a peer's shares are a few KB per output
+const MAX_PEER_SHARES_BYTES: u64 = 64 * 1024 * 1024;
+
+/// A peer that says nothing for this long is given up on
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A peer's decryption shares, one per output by output ID
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct PeerShares {
+    pub participant_id: ParticipantId,
+    pub decryption_shares: Vec<DecryptionShare>,
+    /// The peer's signature of the shares, as they submitted them to the server, if they
+    /// registered a key
+    #[serde(default)]
+    pub signature: Option<String>,
+}
+
+impl PeerShares {
+    /// Shares of `participant_id`, signed with `key` like [`crate::WebClient::submit_decryption_shares`] signs them
+    pub fn new(
+        participant_id: ParticipantId,
+        decryption_shares: Vec<DecryptionShare>,
+        key: Option<&SigningKey>,
+    ) -> Self {
+        let signature = key.map(|key| {
+            sign_submission(
+                key,
+                &DecryptionShareSubmission {
+                    participant_id: participant_id.clone(),
+                    decryption_shares: decryption_shares.clone(),
+                },
+            )
+        });
+        Self {
+            participant_id,
+            decryption_shares,
+            signature,
+        }
+    }
+
+    /// Check these are the `outputs` shares `from` submitted to the server: signed with the
+    /// `public_key` they registered, or else hashed as in the room's `transcript`
+    pub fn verify(
+        &self,
+        from: &ParticipantId,
+        outputs: usize,
+        public_key: Option<&str>,
+        transcript: &[TranscriptEntry],
+    ) -> Result<(), Error> {
+        ensure!(
+            &self.participant_id == from,
+            "The peer served the shares of {} instead of {from}",
+            self.participant_id
+        );
+        ensure!(
+            self.decryption_shares.len() == outputs,
+            "{from} served {} shares for {outputs} outputs",
+            self.decryption_shares.len()
+        );
+        if let Some(public_key) = public_key {
+            let submission = DecryptionShareSubmission {
+                participant_id: from.clone(),
+                decryption_shares: self.decryption_shares.clone(),
+            };
+            let signature = self.signature.as_deref().unwrap_or_default();
+            return verify_submission(public_key, &artifact_hash(&submission), signature)
+                .with_context(|| format!("{from} served shares they didn't sign"));
+        }
+        let submitted = transcript.iter().rev().find(|entry| {
+            &entry.participant_id == from && entry.artifact == TranscriptArtifact::DecryptionShares
+        });
+        match submitted {
+            Some(entry) if entry.hash == artifact_hash(&self.decryption_shares) => Ok(()),
+            Some(_) => bail!("{from} served other shares than they submitted to the server"),
+            None => bail!("{from} registered no key and the transcript has none of their shares, so theirs can't be checked"),
+        }
+    }
+}
+
+/// Answer every connection with `shares`, until the task is dropped
+pub async fn serve_shares(listener: TcpListener, shares: PeerShares) -> Result<(), Error> {
+    let bytes = Arc::new(msgpack::to_compact_vec(&shares)?);
+    loop {
+        let (mut socket, _) = listener.accept().await?;
+        let bytes = bytes.clone();
+        tokio::spawn(async move {
+            // Best effort, the peer asks again if it missed them
+            let _ = async {
+                socket.write_u64(bytes.len() as u64).await?;
+                socket.write_all(&bytes).await?;
+                socket.shutdown().await
+            }
+            .await;
+        });
+    }
+}
+
+/// Download the shares a peer serves at `contact`, unchecked, see [`PeerShares::verify`]
+pub async fn fetch_peer_shares(contact: &str) -> Result<PeerShares, Error> {
+    let exchange = async {
+        let mut socket = TcpStream::connect(contact).await?;
+        let len = socket.read_u64().await?;
+        ensure!(
+            len <= MAX_PEER_SHARES_BYTES,
+            "Peer at {contact} sent {len} B of shares"
+        );
+        let mut bytes = vec![0; len as usize];
+        socket.read_exact(&mut bytes).await?;
+        Ok(msgpack::from_slice(&bytes)?)
+    };
+    timeout(PEER_TIMEOUT, exchange)
+        .await
+        .with_context(|| format!("The peer at {contact} didn't answer in time"))?
+}
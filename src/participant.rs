@@ -0,0 +1,404 @@
+//! The whole protocol for one user, for apps that embed it rather than call each route in turn.
+//! Each step waits for the room where it has to, so an app only calls them in order:
+//! [`Participant::join`], [`Participant::rate`], [`Participant::submit`],
+//! [`Participant::finalize`] and [`Participant::reveal`].
+use crate::circuit::ParameterSet;
+use crate::client::WebClient;
+use crate::dashboard::{Dashboard, RegisteredUser};
+use crate::outbox::PendingSubmission;
+use crate::receipt::Receipt;
+use crate::report::RoundResult;
+use crate::retry::NetworkError;
+use crate::server::setup;
+use crate::session::Session;
+use crate::types::{
+    CircuitOutput, ClientKey, DecryptionSharesMap, EncryptedInput, ParticipantId, Score, Seed,
+    ServerKeyShare, UserId,
+};
+use anyhow::{anyhow, bail, ensure, Error};
+use ed25519_dalek::SigningKey;
+use futures::StreamExt;
+use phantom_zone::{gen_client_key, gen_server_key_share};
+use std::fs;
+use std::path::PathBuf;
+
+/// How a run ended for this user, see [`Participant::finalize`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunOutcome {
+    /// The outputs are in, and this user's decryption shares are submitted
+    Decryptable,
+    /// The run only added up a rating of the round. Rate and submit again.
+    NextRating,
+}
+
+/// [`Participant::submit`] couldn't reach the server, and left the submission for
+/// [`Participant::flush_pending`]
+#[derive(Debug, thiserror::Error)]
+#[error("The server is unreachable, so the submission waits in {}", .path.display())]
+pub struct SubmissionQueued {
+    pub path: PathBuf,
+}
+
+pub struct Participant {
+    client: WebClient,
+    /// Signs every submission, and makes the participant ID the same in every round
+    identity: Option<SigningKey>,
+    user: Option<RegisteredUser>,
+    crs: Option<(Seed, ParameterSet)>,
+    ck: Option<ClientKey>,
+    /// The names the scores are for, as of [`Self::rate`]
+    names: Vec<String>,
+    scores: Option<Vec<Score>>,
+    /// Committed to in rooms that take commitments, so it's submitted as it is
+    cipher: Option<EncryptedInput>,
+    /// Made for a user ID out of a number of users, and kept until the server accepts it
+    sks: Option<(UserId, usize, ServerKeyShare)>,
+    /// The room as last seen, for what to queue while the server is unreachable
+    dashboard: Option<Dashboard>,
+    /// See [`Self::with_outbox`]
+    outbox: Option<PathBuf>,
+    fhe_output: Option<CircuitOutput>,
+    decryption_shares: DecryptionSharesMap,
+}
+
+impl Participant {
+    pub fn new(client: WebClient) -> Self {
+        Self {
+            client,
+            identity: None,
+            user: None,
+            crs: None,
+            ck: None,
+            names: vec![],
+            scores: None,
+            cipher: None,
+            sks: None,
+            dashboard: None,
+            outbox: None,
+            fhe_output: None,
+            decryption_shares: Default::default(),
+        }
+    }
+
+    /// Register with `key`, see [`WebClient::register_signed`]
+    pub fn with_identity(mut self, key: SigningKey) -> Self {
+        self.identity = Some(key);
+        self
+    }
+
+    /// Where [`Self::submit`] leaves the submission when the server is unreachable, for
+    /// [`Self::flush_pending`] to send later
+    pub fn with_outbox(mut self, path: PathBuf) -> Self {
+        self.outbox = Some(path);
+        self
+    }
+
+    /// Pick up a [`Session`] saved with [`Self::session`], e.g. after a restart
+    pub fn resume(client: WebClient, session: Session, identity: Option<SigningKey>) -> Self {
+        if let Some(user) = &session.user {
+            client.resume_user(user.clone(), identity.clone());
+        }
+        if let Some((seed, parameter)) = &session.crs {
+            setup(seed, *parameter);
+        }
+        Self {
+            client,
+            identity,
+            user: session.user,
+            crs: session.crs,
+            ck: session.ck,
+            names: vec![],
+            scores: session.scores,
+            cipher: None,
+            sks: None,
+            dashboard: None,
+            outbox: None,
+            fhe_output: session.fhe_output,
+            decryption_shares: session.decryption_shares,
+        }
+    }
+
+    /// What [`Self::resume`] takes to come back to this point
+    pub fn session(&self) -> Session {
+        let mut session = Session::new(self.name(), self.client.room());
+        session.user = self.user.clone();
+        session.crs = self.crs;
+        session.ck = self.ck.clone();
+        session.scores = self.scores.clone();
+        session.fhe_output = self.fhe_output.clone();
+        session.decryption_shares = self.decryption_shares.clone();
+        session
+    }
+
+    pub fn client(&self) -> &WebClient {
+        &self.client
+    }
+
+    pub fn name(&self) -> &str {
+        self.user.as_ref().map_or("", |user| user.name.as_str())
+    }
+
+    pub fn participant_id(&self) -> Option<&ParticipantId> {
+        self.user.as_ref().map(|user| &user.participant_id)
+    }
+
+    /// Register in the client's room as `name`
+    pub async fn join(&mut self, name: &str) -> Result<&RegisteredUser, Error> {
+        ensure!(self.user.is_none(), "Already joined as {}", self.name());
+        let user = match &self.identity {
+            Some(key) => self.client.register_signed(name, key).await?,
+            None => self.client.register(name).await?,
+        };
+        Ok(self.user.insert(user))
+    }
+
+    /// Score every user, in the order of the dashboard's names. Fails while registration is
+    /// still open. The first call makes the client key, once the room settled its parameters.
+    pub async fn rate(&mut self, scores: &[Score]) -> Result<(), Error> {
+        let dashboard = self.client.get_dashboard().await?;
+        ensure!(dashboard.is_concluded(), "Registration is still open");
+        self.user_id(&dashboard)?;
+        self.client.get_circuit().await?.validate(scores)?;
+        if self.ck.is_none() {
+            let seed = self.client.get_seed().await?;
+            let parameter = dashboard.parameter_set();
+            setup(&seed, parameter);
+            self.crs = Some((seed, parameter));
+            self.ck = Some(gen_client_key());
+        }
+        if self.scores.as_deref() != Some(scores) || self.names != dashboard.get_names() {
+            self.cipher = None;
+        }
+        self.names = dashboard.get_names();
+        self.scores = Some(scores.to_vec());
+        self.dashboard = Some(dashboard);
+        Ok(())
+    }
+
+    /// Encrypt the scores and submit them with the server key share. In rooms that take
+    /// commitments, commit first and wait until everyone has. With an outbox, a server that's
+    /// unreachable fails it with [`SubmissionQueued`] once the submission is on disk.
+    pub async fn submit(&mut self) -> Result<Receipt, Error> {
+        let ck = self.ck.as_ref().ok_or(anyhow!("Rate before submitting"))?;
+        let scores = self
+            .scores
+            .as_ref()
+            .ok_or(anyhow!("Rate before submitting"))?;
+        self.cipher
+            .get_or_insert_with(|| EncryptedInput::from_plain(ck, scores));
+        match self.send().await {
+            Err(err) if self.outbox.is_some() && NetworkError::of_error(&err).is_some() => {
+                Err(self.queue()?.into())
+            }
+            result => result,
+        }
+    }
+
+    /// Send the submission [`Self::submit`] left in the outbox, if any. It stays there until
+    /// the server accepts it. A key share made for other users is made again.
+    pub async fn flush_pending(&mut self) -> Result<Option<Receipt>, Error> {
+        let Some(path) = self.outbox.clone() else {
+            return Ok(None);
+        };
+        let Some(pending) = PendingSubmission::load(&path)? else {
+            return Ok(None);
+        };
+        ensure!(
+            pending.room == self.client.room()
+                && self.participant_id() == Some(&pending.participant_id),
+            "{} holds the submission of another user or room",
+            path.display()
+        );
+        self.names = pending.names;
+        self.scores = Some(pending.scores);
+        self.cipher = Some(pending.cipher);
+        self.sks = pending
+            .sks
+            .map(|sks| (pending.user_id, pending.total_users, sks));
+        let receipt = self.send().await?;
+        fs::remove_file(&path)?;
+        Ok(Some(receipt))
+    }
+
+    /// Write the submission to the outbox, with the key share made now unless the server kept
+    /// one, so nothing but the upload is left
+    fn queue(&mut self) -> Result<SubmissionQueued, Error> {
+        let path = self.outbox.clone().ok_or(anyhow!("No outbox"))?;
+        let me = self.participant_id().ok_or(anyhow!("Join first"))?.clone();
+        let dashboard = self
+            .dashboard
+            .as_ref()
+            .ok_or(anyhow!("Rate before submitting"))?;
+        let user_id = self.user_id(dashboard)?;
+        let total_users = dashboard.users().len();
+        let key_share_kept = dashboard
+            .users()
+            .iter()
+            .any(|user| user.id == user_id && user.status.has_key_share());
+        let sks = match self.sks.take() {
+            Some((id, total, sks)) if id == user_id && total == total_users => Some(sks),
+            _ if key_share_kept => None,
+            _ => {
+                let ck = self.ck.as_ref().ok_or(anyhow!("Rate before submitting"))?;
+                Some(gen_server_key_share(user_id, total_users, ck))
+            }
+        };
+        let pending = PendingSubmission {
+            room: self.client.room(),
+            participant_id: me,
+            user_id,
+            total_users,
+            names: self.names.clone(),
+            scores: self.scores.clone().unwrap_or_default(),
+            cipher: self
+                .cipher
+                .clone()
+                .ok_or(anyhow!("Rate before submitting"))?,
+            sks,
+        };
+        pending.save(&path)?;
+        self.sks = pending
+            .sks
+            .map(|sks| (pending.user_id, pending.total_users, sks));
+        Ok(SubmissionQueued { path })
+    }
+
+    /// Submit the cipher, with the key share unless the server kept one
+    async fn send(&mut self) -> Result<Receipt, Error> {
+        let cipher = self
+            .cipher
+            .clone()
+            .ok_or(anyhow!("Rate before submitting"))?;
+        let mut dashboard = self.client.get_dashboard().await?;
+        let mut user_id = self.user_id(&dashboard)?;
+        if dashboard.is_taking_commitments() {
+            self.client.commit_cipher(user_id, &cipher).await?;
+            let names = self.names.clone();
+            self.wait_until(|dashboard| {
+                ensure!(
+                    dashboard.get_names() == names,
+                    "Users changed to {:?}, rate them again",
+                    dashboard.get_names()
+                );
+                Ok(!dashboard.is_taking_commitments())
+            })
+            .await?;
+            dashboard = self.client.get_dashboard().await?;
+            user_id = self.user_id(&dashboard)?;
+        }
+        let total_users = dashboard.users().len();
+        // After a rejected cipher, or in a later rating, the server keeps the key share
+        let key_share_kept = dashboard
+            .users()
+            .iter()
+            .any(|user| user.id == user_id && user.status.has_key_share());
+        self.dashboard = Some(dashboard);
+        if key_share_kept {
+            return self.client.submit_cipher(user_id, &cipher).await;
+        }
+        let sks = match self.sks.take() {
+            Some((id, total, sks)) if id == user_id && total == total_users => sks,
+            _ => {
+                let ck = self.ck.as_ref().ok_or(anyhow!("Rate before submitting"))?;
+                gen_server_key_share(user_id, total_users, ck)
+            }
+        };
+        let receipt = self.client.submit_inputs(user_id, &cipher, &sks).await;
+        if receipt.is_err() {
+            self.sks = Some((user_id, total_users, sks));
+        }
+        receipt
+    }
+
+    /// Wait for the run to end. Once the outputs are in, download them and submit this user's
+    /// decryption shares.
+    pub async fn finalize(&mut self) -> Result<RunOutcome, Error> {
+        let ck = self.ck.as_ref().ok_or(anyhow!("Rate before finalizing"))?;
+        let me = self
+            .participant_id()
+            .ok_or(anyhow!("Join before finalizing"))?
+            .clone();
+        self.wait_until(
+            |dashboard| Ok(dashboard.is_fhe_complete() || dashboard.awaits_scores(&me)),
+        )
+        .await?;
+        if self.client.get_run_status().await?.carried {
+            self.scores = None;
+            self.cipher = None;
+            return Ok(RunOutcome::NextRating);
+        }
+        let fhe_output = self.client.get_fhe_output().await?;
+        let my_shares = fhe_output.gen_decryption_shares(ck);
+        self.client
+            .submit_decryption_shares(&me, &my_shares)
+            .await?;
+        self.decryption_shares
+            .extend(fhe_output.share_entries(&me, &my_shares));
+        self.fhe_output = Some(fhe_output);
+        Ok(RunOutcome::Decryptable)
+    }
+
+    /// Wait for everyone's decryption shares, and decrypt the karma of every user
+    pub async fn reveal(&mut self) -> Result<RoundResult, Error> {
+        let fhe_output = self
+            .fhe_output
+            .as_ref()
+            .ok_or(anyhow!("Finalize before revealing"))?;
+        let ck = self
+            .ck
+            .as_ref()
+            .ok_or(anyhow!("Finalize before revealing"))?;
+        let mut events = self.client.subscribe_events().await?;
+        let dss = loop {
+            let dashboard = self.client.get_dashboard().await?;
+            let user_id = self.user_id(&dashboard)?;
+            let missing = self.client.get_missing_decryption_shares(user_id).await?;
+            fhe_output.check_shares(&missing)?;
+            for (key, share) in missing {
+                self.decryption_shares.entry(key).or_insert(share);
+            }
+            if let Some(dss) = fhe_output.collect_shares(&self.decryption_shares) {
+                break dss;
+            }
+            match events.next().await {
+                Some(event) => event.map(|_| ())?,
+                None => bail!("The server closed the event stream"),
+            }
+        };
+        let dashboard = self.client.get_dashboard().await?;
+        Ok(RoundResult {
+            room: self.client.room(),
+            round: dashboard.round(),
+            names: dashboard.get_names(),
+            balances: fhe_output.decrypt(ck, &dss)?,
+        })
+    }
+
+    /// This user's ID, which changes when the admin removes someone
+    fn user_id(&self, dashboard: &Dashboard) -> Result<UserId, Error> {
+        let me = self.participant_id().ok_or(anyhow!("Join first"))?;
+        let user_id = dashboard
+            .user_id_of(me)
+            .ok_or(anyhow!("You were removed from the room"))?;
+        self.client.update_user_id(me, user_id);
+        Ok(user_id)
+    }
+
+    /// Follow the room's events until its dashboard satisfies `done`
+    async fn wait_until(
+        &self,
+        mut done: impl FnMut(&Dashboard) -> Result<bool, Error>,
+    ) -> Result<(), Error> {
+        // Subscribe before checking, so the change can't slip in between
+        let mut events = self.client.subscribe_events().await?;
+        loop {
+            if done(&self.client.get_dashboard().await?)? {
+                return Ok(());
+            }
+            match events.next().await {
+                Some(event) => event.map(|_| ())?,
+                None => bail!("The server closed the event stream"),
+            }
+        }
+    }
+}
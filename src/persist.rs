@@ -0,0 +1,223 @@
+use crate::room::RoomId;
+use crate::types::{ServerState, ServerStorage, UserId, UserRecord, UserStorage};
+use anyhow::{Context, Error};
+use futures::future::join_all;
+use rocket::serde::{msgpack, Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::runtime::Handle;
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+/// Where rooms are snapshotted so a restarted server can resume them.
+///
+/// Users' submissions are saved apart from the rest of the room, so accepting one upload
+/// doesn't rewrite everyone else's key shares.
+pub(crate) trait Persistence: Send + Sync + Debug {
+    /// Store a snapshot serialized as msgpack. Blocking.
+    fn write(&self, room_id: RoomId, snapshot: Snapshot, bytes: &[u8]) -> Result<(), Error>;
+    /// Rooms saved so far, ordered by [`RoomId`]
+    fn load(&self) -> Result<Vec<(RoomId, ServerStorage)>, Error>;
+    /// Where the room's large artifacts go. `None` keeps them in memory.
+    fn artifact_dir(&self, _room_id: RoomId) -> Option<PathBuf> {
+        None
+    }
+}
+
+/// What a snapshot holds: everything in the room except users' submissions, or one user's
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Snapshot {
+    Room,
+    User(UserId),
+}
+
+/// Persistence handle of one room, kept inside its [`ServerStorage`]
+#[derive(Debug, Clone)]
+pub(crate) struct RoomStore {
+    backend: Arc<dyn Persistence>,
+    room_id: RoomId,
+    writes: Arc<Writes>,
+}
+
+/// Snapshots on their way to disk. The room is serialized under its lock, and written on a
+/// blocking thread once the handler is done with it, so no request waits on the disk.
+#[derive(Debug, Default)]
+struct Writes {
+    /// How many snapshots were taken, to order them
+    taken: AtomicU64,
+    /// The latest snapshot written of each file. Held while writing, so an older snapshot
+    /// that comes late never overwrites a newer one.
+    written: Mutex<HashMap<Snapshot, u64>>,
+    /// Writes that may still be running, see [`RoomStore::flush`]
+    running: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl RoomStore {
+    pub(crate) fn new(backend: Arc<dyn Persistence>, room_id: RoomId) -> Self {
+        Self {
+            backend,
+            room_id,
+            writes: Arc::default(),
+        }
+    }
+
+    /// Best effort, the round goes on even if the disk fails us
+    pub(crate) fn save_room(&self, ss: &ServerStorage) {
+        // Ciphers and key shares are consumed by the run. Keep the last snapshot from
+        // `ReadyForRunning`, so a restart during the run can run again.
+        if ss.state == ServerState::RunningFhe {
+            return;
+        }
+        self.save(Snapshot::Room, msgpack::to_compact_vec(ss));
+    }
+
+    pub(crate) fn save_user(&self, user: &UserRecord) {
+        self.save(
+            Snapshot::User(user.id),
+            msgpack::to_compact_vec(&user.storage),
+        );
+    }
+
+    fn save(&self, snapshot: Snapshot, bytes: Result<Vec<u8>, rmp_serde::encode::Error>) {
+        let room_id = self.room_id;
+        let bytes = match bytes {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                warn!(
+                    room_id,
+                    ?snapshot,
+                    "Failed to serialize the snapshot: {:?}",
+                    err
+                );
+                return;
+            }
+        };
+        let order = self.writes.taken.fetch_add(1, Ordering::SeqCst);
+        let (backend, writes) = (self.backend.clone(), self.writes.clone());
+        let write = move || {
+            let mut written = writes.written.lock().unwrap();
+            if written.get(&snapshot).is_some_and(|&latest| latest > order) {
+                return;
+            }
+            match backend.write(room_id, snapshot, &bytes) {
+                Ok(()) => {
+                    written.insert(snapshot, order);
+                }
+                Err(err) => warn!(room_id, ?snapshot, "Failed to save the snapshot: {:?}", err),
+            }
+        };
+        // Outside a runtime, e.g. loading at startup, nothing waits on us anyway
+        match Handle::try_current() {
+            Ok(runtime) => {
+                let handle = runtime.spawn_blocking(write);
+                let mut running = self.writes.running.lock().unwrap();
+                running.retain(|handle| !handle.is_finished());
+                running.push(handle);
+            }
+            Err(_) => write(),
+        }
+    }
+
+    /// Wait for the snapshots taken so far to reach the disk
+    pub(crate) async fn flush(&self) {
+        let running = std::mem::take(&mut *self.writes.running.lock().unwrap());
+        join_all(running).await;
+    }
+}
+
+/// Snapshots as msgpack files: `<dir>/room-<id>/room.msgpack` and `<dir>/room-<id>/user-<id>.msgpack`
+#[derive(Debug)]
+pub(crate) struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    pub(crate) fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    fn room_dir(&self, room_id: RoomId) -> PathBuf {
+        self.dir.join(format!("room-{room_id}"))
+    }
+}
+
+impl Persistence for FileStore {
+    fn write(&self, room_id: RoomId, snapshot: Snapshot, bytes: &[u8]) -> Result<(), Error> {
+        let file = match snapshot {
+            Snapshot::Room => "room.msgpack".to_string(),
+            Snapshot::User(user_id) => format!("user-{user_id}.msgpack"),
+        };
+        write_bytes_atomic(&self.room_dir(room_id).join(file), bytes)
+    }
+
+    fn load(&self) -> Result<Vec<(RoomId, ServerStorage)>, Error> {
+        let mut rooms = vec![];
+        if !self.dir.exists() {
+            return Ok(rooms);
+        }
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let Some(room_id) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_prefix("room-"))
+                .and_then(|id| id.parse::<RoomId>().ok())
+            else {
+                continue;
+            };
+            let room_file = path.join("room.msgpack");
+            if !room_file.exists() {
+                continue;
+            }
+            let mut ss: ServerStorage = read(&room_file)?;
+            for user in ss.users.iter_mut() {
+                let user_file = path.join(format!("user-{}.msgpack", user.id));
+                if user_file.exists() {
+                    user.storage = read(&user_file)?;
+                }
+            }
+            resume(&mut ss);
+            rooms.push((room_id, ss));
+        }
+        rooms.sort_by_key(|(room_id, _)| *room_id);
+        Ok(rooms)
+    }
+
+    fn artifact_dir(&self, room_id: RoomId) -> Option<PathBuf> {
+        Some(self.room_dir(room_id).join("artifacts"))
+    }
+}
+
+pub(crate) fn write_atomic(path: &Path, value: &impl Serialize) -> Result<(), Error> {
+    write_bytes_atomic(path, &msgpack::to_compact_vec(value)?)
+}
+
+fn write_bytes_atomic(path: &Path, bytes: &[u8]) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, bytes)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+pub(crate) fn read<T: for<'de> Deserialize<'de>>(path: &Path) -> Result<T, Error> {
+    let bytes = fs::read(path)?;
+    msgpack::from_slice(&bytes).with_context(|| format!("Corrupted snapshot {}", path.display()))
+}
+
+/// Fix up a loaded snapshot whose user files may be older than the room file
+fn resume(ss: &mut ServerStorage) {
+    if ss.state == ServerState::CompletedFhe {
+        for user in ss.users.iter_mut() {
+            if matches!(user.storage, UserStorage::Inputs(..)) {
+                user.storage = UserStorage::DecryptionShare(None);
+            }
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use crate::report::RoundResult;
+use crate::room::RoomId;
+use crate::types::{now, ParticipantId, ServerState, Timestamp};
+use anyhow::{anyhow, ensure, Error};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::{thread_rng, RngCore};
+use rocket::serde::{msgpack, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// What the server attests to: who delivered which artifact, when, and in which phase
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ReceiptBody {
+    pub room_id: RoomId,
+    pub participant_id: ParticipantId,
+    pub phase: ServerState,
+    /// Hex SHA-256 of the msgpack encoded submission
+    pub artifact_hash: String,
+    pub timestamp: Timestamp,
+}
+
+/// Proof of delivery, signed by the server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Receipt {
+    pub body: ReceiptBody,
+    /// Hex ed25519 signature over the msgpack encoded body
+    pub signature: String,
+}
+
+impl Receipt {
+    /// Check the signature against the server's hex public key, see `/receipt_key`
+    pub fn verify(&self, public_key: &str) -> Result<(), Error> {
+        parse_public_key(public_key)?.verify(
+            &signed_bytes(&self.body),
+            &parse_signature(&self.signature)?,
+        )?;
+        Ok(())
+    }
+
+    /// Check the receipt covers exactly this submission
+    pub fn ensure_covers(&self, submission: &impl Serialize) -> Result<(), Error> {
+        ensure!(
+            self.body.artifact_hash == artifact_hash(submission),
+            "Receipt doesn't match the submission"
+        );
+        Ok(())
+    }
+}
+
+/// The outcome of a round, signed by the server so participants can archive it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SignedResults {
+    pub result: RoundResult,
+    pub timestamp: Timestamp,
+    /// Hex ed25519 signature over the msgpack encoded result and timestamp
+    pub signature: String,
+}
+
+impl SignedResults {
+    /// Check the signature against the server's hex public key, see `/receipt_key`
+    pub fn verify(&self, public_key: &str) -> Result<(), Error> {
+        parse_public_key(public_key)?.verify(
+            &results_bytes(&self.result, self.timestamp),
+            &parse_signature(&self.signature)?,
+        )?;
+        Ok(())
+    }
+}
+
+fn results_bytes(result: &RoundResult, timestamp: Timestamp) -> Vec<u8> {
+    msgpack::to_compact_vec(&(result, timestamp)).expect("serializable")
+}
+
+/// Header the archive comes with its [`ArchiveSignature`] in, as JSON
+pub(crate) const ARCHIVE_SIGNATURE_HEADER: &str = "Archive-Signature";
+
+/// The server's signature of an archive it served, so a group can show the record is the
+/// server's long after the room is gone
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ArchiveSignature {
+    pub room_id: RoomId,
+    /// Hex SHA-256 of the archive
+    pub digest: String,
+    pub timestamp: Timestamp,
+    /// Hex ed25519 signature over the msgpack encoded room ID, digest and timestamp
+    pub signature: String,
+}
+
+impl ArchiveSignature {
+    /// Check the signature against the server's hex public key, see `/receipt_key`, and that
+    /// it covers `archive`
+    pub fn verify(&self, public_key: &str, archive: &[u8]) -> Result<(), Error> {
+        ensure!(
+            self.digest == hex::encode(Sha256::digest(archive)),
+            "Signature doesn't match the archive"
+        );
+        self.verify_signature(public_key)
+    }
+
+    /// Check only that the server signed the room ID, digest and timestamp
+    pub(crate) fn verify_signature(&self, public_key: &str) -> Result<(), Error> {
+        parse_public_key(public_key)?.verify(
+            &archive_bytes(self.room_id, &self.digest, self.timestamp),
+            &parse_signature(&self.signature)?,
+        )?;
+        Ok(())
+    }
+}
+
+fn archive_bytes(room_id: RoomId, digest: &str, timestamp: Timestamp) -> Vec<u8> {
+    msgpack::to_compact_vec(&(room_id, digest, timestamp)).expect("serializable")
+}
+
+/// Hex SHA-256 of the msgpack encoding, as the client sends it
+pub fn artifact_hash(submission: &impl Serialize) -> String {
+    artifact_digest(submission).0
+}
+
+/// [`artifact_hash`] and the length of the msgpack encoding, from one encoding
+pub(crate) fn artifact_digest(submission: &impl Serialize) -> (String, u64) {
+    let bytes = msgpack::to_compact_vec(submission).expect("serializable");
+    (hex::encode(Sha256::digest(&bytes)), bytes.len() as u64)
+}
+
+/// The author's hex ed25519 signature of a submission. It signs the SHA-256 of the msgpack
+/// encoding, so it covers the same [`artifact_hash`] as the server's receipt.
+pub fn sign_submission(key: &SigningKey, submission: &impl Serialize) -> String {
+    let digest = hex::decode(artifact_hash(submission)).expect("hex");
+    hex::encode(key.sign(&digest).to_bytes())
+}
+
+/// Check the author's signature of the submission hashed to `artifact`
+pub fn verify_submission(public_key: &str, artifact: &str, signature: &str) -> Result<(), Error> {
+    parse_public_key(public_key)?.verify(&hex::decode(artifact)?, &parse_signature(signature)?)?;
+    Ok(())
+}
+
+/// A hex ed25519 public key
+pub(crate) fn parse_public_key(public_key: &str) -> Result<VerifyingKey, Error> {
+    let key: [u8; 32] = hex::decode(public_key)?
+        .try_into()
+        .map_err(|_| anyhow!("Public key should be 32 bytes"))?;
+    Ok(VerifyingKey::from_bytes(&key)?)
+}
+
+fn parse_signature(signature: &str) -> Result<Signature, Error> {
+    let signature: [u8; 64] = hex::decode(signature)?
+        .try_into()
+        .map_err(|_| anyhow!("Signature should be 64 bytes"))?;
+    Ok(Signature::from_bytes(&signature))
+}
+
+fn signed_bytes(body: &ReceiptBody) -> Vec<u8> {
+    msgpack::to_compact_vec(body).expect("serializable")
+}
+
+/// The server's receipt signing key. Set `receipt_key` in Rocket.toml to keep it across restarts.
+pub(crate) struct ReceiptSigner {
+    key: SigningKey,
+}
+
+impl ReceiptSigner {
+    /// From a hex secret key, or a fresh one if `None`
+    pub(crate) fn new(secret: Option<&str>) -> Result<Self, Error> {
+        let secret = match secret {
+            Some(secret) => hex::decode(secret)?
+                .try_into()
+                .map_err(|_| anyhow!("Receipt key should be 32 bytes"))?,
+            None => {
+                let mut secret = [0u8; 32];
+                thread_rng().fill_bytes(&mut secret);
+                secret
+            }
+        };
+        Ok(Self {
+            key: SigningKey::from_bytes(&secret),
+        })
+    }
+
+    pub(crate) fn public_key(&self) -> String {
+        hex::encode(self.key.verifying_key().as_bytes())
+    }
+
+    pub(crate) fn sign(
+        &self,
+        room_id: RoomId,
+        participant_id: ParticipantId,
+        phase: ServerState,
+        artifact_hash: String,
+    ) -> Receipt {
+        let body = ReceiptBody {
+            room_id,
+            participant_id,
+            phase,
+            artifact_hash,
+            timestamp: now(),
+        };
+        let signature = self.key.sign(&signed_bytes(&body));
+        Receipt {
+            body,
+            signature: hex::encode(signature.to_bytes()),
+        }
+    }
+
+    pub(crate) fn sign_results(&self, result: RoundResult) -> SignedResults {
+        let timestamp = now();
+        let signature = self.key.sign(&results_bytes(&result, timestamp));
+        SignedResults {
+            result,
+            timestamp,
+            signature: hex::encode(signature.to_bytes()),
+        }
+    }
+
+    pub(crate) fn sign_archive(&self, room_id: RoomId, archive: &[u8]) -> ArchiveSignature {
+        let digest = hex::encode(Sha256::digest(archive));
+        let timestamp = now();
+        let signature = self.key.sign(&archive_bytes(room_id, &digest, timestamp));
+        ArchiveSignature {
+            room_id,
+            digest,
+            timestamp,
+            signature: hex::encode(signature.to_bytes()),
+        }
+    }
+}
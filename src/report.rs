@@ -0,0 +1,107 @@
+use crate::room::RoomId;
+use crate::types::Score;
+use itertools::Itertools;
+use rocket::serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use tabled::Tabled;
+
+/// A decrypted round, as kept by the client
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct RoundResult {
+    pub room: RoomId,
+    pub round: u64,
+    pub names: Vec<String>,
+    /// Net karma in the order of `names`
+    pub balances: Vec<Score>,
+}
+
+impl RoundResult {
+    /// The latest earlier round of the same room in `history`
+    pub fn previous<'a>(&self, history: &'a [RoundResult]) -> Option<&'a RoundResult> {
+        history
+            .iter()
+            .filter(|result| result.room == self.room && result.round < self.round)
+            .max_by_key(|result| result.round)
+    }
+
+    /// One `name,karma` row per user under a header. Names with commas or quotes are quoted.
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("name,karma\n");
+        for (name, karma) in self.names.iter().zip(&self.balances) {
+            let name = if name.contains([',', '"', '\n']) {
+                format!("\"{}\"", name.replace('"', "\"\""))
+            } else {
+                name.to_string()
+            };
+            csv.push_str(&format!("{name},{karma}\n"));
+        }
+        csv
+    }
+
+    /// Each user's net karma against `previous`. Users are matched by name.
+    pub fn diff(&self, previous: Option<&RoundResult>) -> Vec<KarmaDiff> {
+        self.names
+            .iter()
+            .zip(&self.balances)
+            .map(|(name, &karma)| {
+                let before = previous.and_then(|previous| {
+                    let position = previous.names.iter().position(|n| n == name)?;
+                    Some(previous.balances[position])
+                });
+                KarmaDiff {
+                    name: name.to_string(),
+                    karma,
+                    previous: before,
+                    trend: Trend::new(karma, before),
+                }
+            })
+            .collect_vec()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub enum Trend {
+    Up(Score),
+    Down(Score),
+    Flat,
+    /// Wasn't in the previous round
+    New,
+}
+
+impl Trend {
+    fn new(karma: Score, previous: Option<Score>) -> Self {
+        match previous.map(|previous| karma.wrapping_sub(previous)) {
+            None => Self::New,
+            Some(0) => Self::Flat,
+            Some(delta) if delta > 0 => Self::Up(delta),
+            Some(delta) => Self::Down(delta),
+        }
+    }
+}
+
+impl Display for Trend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Up(delta) => write!(f, "↑ +{delta}"),
+            Self::Down(delta) => write!(f, "↓ {delta}"),
+            Self::Flat => write!(f, "→ 0"),
+            Self::New => write!(f, "new"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Tabled, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct KarmaDiff {
+    pub name: String,
+    pub karma: Score,
+    #[tabled(display_with = "display_previous")]
+    pub previous: Option<Score>,
+    pub trend: Trend,
+}
+
+fn display_previous(previous: &Option<Score>) -> String {
+    previous.map(|p| p.to_string()).unwrap_or_default()
+}
@@ -0,0 +1,89 @@
+//! Plaintext karma the server decrypts by itself once every decryption share is in,
+//! for light clients that would rather not download all the shares. See [`crate::types::RoomConfig::server_results`].
+use crate::circuit::ParameterSet;
+use crate::report::RoundResult;
+use crate::room::RoomId;
+use crate::types::{
+    CircuitOutput, DecryptionShare, Error, InvalidResponse, ParticipantId, ServerStorage,
+};
+use phantom_zone::{gen_client_key, set_parameter_set};
+use std::sync::Arc;
+
+/// Everything needed to decrypt a round, taken from the room so decryption runs without its lock
+pub(crate) struct ResultsJob {
+    room: RoomId,
+    round: u64,
+    names: Vec<String>,
+    parameter: ParameterSet,
+    output: Arc<CircuitOutput>,
+    /// For each output, the share of every participant
+    shares: Vec<Vec<DecryptionShare>>,
+}
+
+impl ResultsJob {
+    pub(crate) fn new(ss: &ServerStorage, room: RoomId) -> Result<Self, Error> {
+        if !ss.config.server_results {
+            return Err(Error::ResultsDisabled);
+        }
+        let output = ss.fhe_outputs.clone().ok_or(Error::OutputNotReady)?;
+        let submitted = ss.get_decryption_shares(None)?;
+        let shares = (0..output.n())
+            .map(|output_id| {
+                output
+                    .participants()
+                    .iter()
+                    .map(|from| {
+                        submitted
+                            .get(&(output_id, from.clone()))
+                            .cloned()
+                            .ok_or_else(|| Error::DecryptionShareNotFound {
+                                output_id,
+                                participant_id: from.clone(),
+                            })
+                    })
+                    .collect()
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            room,
+            round: ss.round,
+            names: (0..output.n())
+                .map(|output_id| match output.owner(output_id) {
+                    Some(participant_id) => ss
+                        .users
+                        .iter()
+                        .find(|user| &user.participant_id == participant_id)
+                        .map(|user| user.name.clone())
+                        .ok_or_else(|| Error::UnknownParticipant {
+                            participant_id: participant_id.clone(),
+                        }),
+                    None => Ok(format!("Output {output_id}")),
+                })
+                .collect::<Result<_, _>>()?,
+            parameter: ss.parameter,
+            output,
+            shares,
+        })
+    }
+
+    /// Whose karma the results are, in output order, see [`CircuitOutput::owner`]
+    pub(crate) fn owners(&self) -> Vec<Option<ParticipantId>> {
+        (0..self.output.n())
+            .map(|output_id| self.output.owner(output_id).cloned())
+            .collect()
+    }
+
+    /// Long running, call it from a blocking task
+    pub(crate) fn decrypt(self) -> Result<RoundResult, InvalidResponse> {
+        set_parameter_set(self.parameter.selector());
+        // Aggregating shares only reads the parameters off the key, so a throwaway key will do.
+        // It can't decrypt anything by itself.
+        let ck = gen_client_key();
+        Ok(RoundResult {
+            room: self.room,
+            round: self.round,
+            names: self.names,
+            balances: self.output.decrypt(&ck, &self.shares)?,
+        })
+    }
+}
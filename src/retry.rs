@@ -0,0 +1,133 @@
+//! When [`crate::WebClient`] sends a request again after it failed on the way, so a flaky link
+//! doesn't cost a participant their upload. Only requests the server can't act on twice are
+//! retried: reads, and mutating requests that carry an `Idempotency-Key`.
+use rand::{thread_rng, Rng};
+use reqwest::header::RETRY_AFTER;
+use std::time::Duration;
+
+/// Ways a request can fail before the server answers it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetworkError {
+    /// No connection to the server, e.g. it's restarting
+    Connect,
+    /// The request or the response took longer than the client's timeout
+    Timeout,
+    /// The connection dropped while sending the request or reading the response
+    Interrupted,
+}
+
+impl NetworkError {
+    /// How a request of [`crate::WebClient`] failed on the way, if it did rather than by the
+    /// server's answer, e.g. to tell an unreachable server from one that refused
+    pub fn of_error(err: &anyhow::Error) -> Option<Self> {
+        err.downcast_ref::<reqwest::Error>().and_then(Self::of)
+    }
+
+    fn of(err: &reqwest::Error) -> Option<Self> {
+        if is_tls(err) {
+            // An untrusted certificate stays untrusted
+            None
+        } else if err.is_connect() {
+            Some(Self::Connect)
+        } else if err.is_timeout() {
+            Some(Self::Timeout)
+        } else if err.is_request() || err.is_body() {
+            Some(Self::Interrupted)
+        } else {
+            None
+        }
+    }
+}
+
+/// A failed handshake, by the default TLS or by the one of [`crate::TlsOptions`]
+fn is_tls(err: &reqwest::Error) -> bool {
+    let mut source = std::error::Error::source(err);
+    while let Some(err) = source {
+        if err.is::<rustls::Error>() || err.is::<native_tls::Error>() {
+            return true;
+        }
+        // An `io::Error` skips the error it wraps in its sources
+        source = match err.downcast_ref::<std::io::Error>() {
+            Some(io) => io.get_ref().map(|inner| inner as _),
+            None => err.source(),
+        };
+    }
+    false
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Including the first, so 1 never retries
+    pub max_attempts: u32,
+    /// Wait before the first retry. Each retry waits twice as long as the previous one, up to
+    /// `max_backoff`, and a random part of it so clients that failed together don't retry together.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Responses worth another try. A `Retry-After` longer than `max_backoff` ends the retries.
+    pub statuses: Vec<u16>,
+    pub network_errors: Vec<NetworkError>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 4,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            statuses: vec![408, 429, 502, 503, 504],
+            network_errors: vec![
+                NetworkError::Connect,
+                NetworkError::Timeout,
+                NetworkError::Interrupted,
+            ],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Every request is sent once
+    pub fn never() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Self::default()
+        }
+    }
+
+    /// How long to wait before attempt `attempt + 1` after `response`, or `None` to give up
+    pub(crate) fn after_response(
+        &self,
+        attempt: u32,
+        response: &reqwest::Response,
+    ) -> Option<Duration> {
+        if attempt >= self.max_attempts || !self.statuses.contains(&response.status().as_u16()) {
+            return None;
+        }
+        let backoff = self.backoff(attempt);
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok()?.parse().ok())
+            .map(Duration::from_secs);
+        match retry_after {
+            Some(retry_after) if retry_after > self.max_backoff => None,
+            Some(retry_after) => Some(retry_after.max(backoff)),
+            None => Some(backoff),
+        }
+    }
+
+    /// How long to wait before attempt `attempt + 1` after `err`, or `None` to give up
+    pub(crate) fn after_error(&self, attempt: u32, err: &reqwest::Error) -> Option<Duration> {
+        let retryable =
+            NetworkError::of(err).is_some_and(|kind| self.network_errors.contains(&kind));
+        (attempt < self.max_attempts && retryable).then(|| self.backoff(attempt))
+    }
+
+    /// Half the exponential backoff, plus up to the other half at random
+    fn backoff(&self, attempt: u32) -> Duration {
+        let full = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_backoff);
+        full / 2 + full.mul_f64(thread_rng().gen::<f64>() / 2.0)
+    }
+}
@@ -0,0 +1,289 @@
+use crate::dashboard::Dashboard;
+use crate::persist::{Persistence, RoomStore};
+use crate::server::JobManager;
+use crate::types::{now, Error, MutexServerStorage, RoomConfig, Seed, ServerState, ServerStorage};
+use crate::upload::Uploads;
+use crate::worker::Evaluator;
+use futures::future::join_all;
+use itertools::Itertools;
+use rand::{thread_rng, RngCore};
+use rocket::serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, Mutex};
+use tracing::{info, warn};
+
+pub type RoomId = usize;
+
+/// An independent karma session with its own seed, users and state machine
+#[derive(Clone)]
+pub(crate) struct Room {
+    pub(crate) storage: MutexServerStorage,
+    /// Updated on every change of the storage, so polls never wait on its lock
+    pub(crate) dashboard: watch::Receiver<Dashboard>,
+    pub(crate) jobs: Arc<JobManager>,
+    /// Where submissions are stashed, see [`crate::cold::Cold`]
+    pub(crate) cold_dir: Option<PathBuf>,
+    /// Chunked submissions in progress
+    pub(crate) uploads: Arc<Mutex<Uploads>>,
+    /// Set for every room at once when the server shuts down, see [`Lobby::shutdown`]
+    closing: Arc<AtomicBool>,
+}
+
+impl Room {
+    fn new(
+        mut ss: ServerStorage,
+        cold_dir: Option<PathBuf>,
+        evaluator: Evaluator,
+        closing: Arc<AtomicBool>,
+    ) -> Self {
+        let mut first = ss.get_dashboard();
+        // So an `ETag` of before a restart doesn't match
+        first.version = thread_rng().next_u64();
+        let (published, dashboard) = watch::channel(first);
+        ss.published = Some(published);
+        let jobs = JobManager::new(evaluator);
+        if ss.state == ServerState::CompletedFhe {
+            jobs.mark_completed();
+        }
+        Self {
+            storage: MutexServerStorage::new(Mutex::new(ss)),
+            dashboard,
+            jobs: Arc::new(jobs),
+            cold_dir,
+            uploads: Arc::default(),
+            closing,
+        }
+    }
+
+    /// Fails once the server is shutting down, so nothing new is taken in or started
+    pub(crate) fn ensure_open(&self) -> Result<(), Error> {
+        if self.closing.load(Ordering::SeqCst) {
+            return Err(Error::ShuttingDown);
+        }
+        Ok(())
+    }
+
+    /// Once the completed round is archived, drop whatever is left of its inputs: uploads that
+    /// never finished, ciphers and key shares still under `cold_dir`, and the outputs carried
+    /// from earlier ratings. The run discards the inputs it read already. Returns how many
+    /// files were removed.
+    pub(crate) async fn drop_inputs(&self, ss: &mut ServerStorage) -> usize {
+        self.uploads.lock().await.clear();
+        if !ss.carried.is_empty() {
+            ss.carried = vec![];
+            ss.save();
+        }
+        let Some(dir) = self.cold_dir.clone() else {
+            return 0;
+        };
+        let removed = tokio::task::spawn_blocking(move || -> std::io::Result<usize> {
+            let mut removed = 0;
+            for entry in std::fs::read_dir(dir)? {
+                let path = entry?.path();
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                if name.starts_with("cipher-") || name.starts_with("key-share-") {
+                    std::fs::remove_file(&path)?;
+                    removed += 1;
+                }
+            }
+            Ok(removed)
+        })
+        .await;
+        match removed {
+            Ok(Ok(removed)) => {
+                info!(removed, "Dropped the inputs left of the archived round");
+                removed
+            }
+            // Nothing was ever stashed
+            Ok(Err(err)) if err.kind() == std::io::ErrorKind::NotFound => 0,
+            Ok(Err(err)) => {
+                warn!("Failed to drop the inputs of the archived round: {err}");
+                0
+            }
+            Err(err) => {
+                warn!("Failed to drop the inputs of the archived round: {err}");
+                0
+            }
+        }
+    }
+}
+
+pub(crate) fn fresh_seed() -> Seed {
+    let mut seed = [0u8; 32];
+    thread_rng().fill_bytes(&mut seed);
+    seed
+}
+
+/// Lobby listing entry
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct RoomSummary {
+    pub id: RoomId,
+    pub status: ServerState,
+    pub users: usize,
+}
+
+/// All rooms hosted by the server. Rooms are never removed, so a [`RoomId`] is an index.
+#[derive(Clone)]
+pub(crate) struct Lobby {
+    rooms: Arc<Mutex<Vec<Room>>>,
+    persistence: Option<Arc<dyn Persistence>>,
+    /// Applied to new rooms. Resumed rooms keep their own.
+    config: RoomConfig,
+    evaluator: Evaluator,
+    closing: Arc<AtomicBool>,
+}
+
+impl Lobby {
+    /// Resume the saved rooms if any. Otherwise room 0 is created so a single-session deployment needs no setup.
+    pub(crate) fn new(
+        persistence: Option<Arc<dyn Persistence>>,
+        config: RoomConfig,
+        evaluator: Evaluator,
+    ) -> Self {
+        let saved = match &persistence {
+            Some(backend) => backend.load().unwrap_or_else(|err| {
+                warn!("Failed to resume saved rooms: {:?}", err);
+                vec![]
+            }),
+            None => vec![],
+        };
+        let lobby = Self {
+            rooms: Arc::new(Mutex::new(vec![])),
+            persistence,
+            config,
+            evaluator,
+            closing: Arc::new(AtomicBool::new(false)),
+        };
+        let mut rooms = vec![];
+        for (room_id, ss) in saved {
+            // Fill the gaps of rooms that were never saved
+            while rooms.len() < room_id {
+                rooms.push(lobby.new_room(rooms.len()));
+            }
+            info!(room_id, state = %ss.state, "Resumed room");
+            rooms.push(Room::new(
+                lobby.attach_store(ss, room_id),
+                lobby.artifact_dir(room_id),
+                lobby.evaluator.clone(),
+                lobby.closing.clone(),
+            ));
+        }
+        if rooms.is_empty() {
+            rooms.push(lobby.new_room(0));
+        }
+        Self {
+            rooms: Arc::new(Mutex::new(rooms)),
+            ..lobby
+        }
+    }
+
+    fn attach_store(&self, ss: ServerStorage, room_id: RoomId) -> ServerStorage {
+        match &self.persistence {
+            Some(backend) => ss.with_store(RoomStore::new(backend.clone(), room_id)),
+            None => ss,
+        }
+    }
+
+    fn new_room(&self, room_id: RoomId) -> Room {
+        let parameter = self.config.parameter.unwrap_or_default();
+        let ss = ServerStorage::new(self.config.next_seed(), parameter).with_config(self.config);
+        Room::new(
+            self.attach_store(ss, room_id),
+            self.artifact_dir(room_id),
+            self.evaluator.clone(),
+            self.closing.clone(),
+        )
+    }
+
+    fn artifact_dir(&self, room_id: RoomId) -> Option<PathBuf> {
+        self.persistence.as_ref()?.artifact_dir(room_id)
+    }
+
+    pub(crate) async fn create(&self) -> RoomId {
+        let mut rooms = self.rooms.lock().await;
+        let room_id = rooms.len();
+        rooms.push(self.new_room(room_id));
+        room_id
+    }
+
+    pub(crate) async fn get(&self, room_id: RoomId) -> Result<Room, Error> {
+        self.rooms
+            .lock()
+            .await
+            .get(room_id)
+            .cloned()
+            .ok_or(Error::RoomNotFound { room_id })
+    }
+
+    /// A room to take inputs or start a run in
+    pub(crate) async fn open(&self, room_id: RoomId) -> Result<Room, Error> {
+        let room = self.get(room_id).await?;
+        room.ensure_open()?;
+        Ok(room)
+    }
+
+    /// Refuse new inputs, give runs in progress `wait` to complete before cancelling them,
+    /// and snapshot every room, so a restart resumes where the server stopped
+    pub(crate) async fn shutdown(&self, wait: Duration) {
+        self.closing.store(true, Ordering::SeqCst);
+        let rooms = self.rooms.lock().await.clone();
+        join_all(rooms.iter().enumerate().map(|(room_id, room)| async move {
+            // Requests that got the lock before we closed have started their run by now
+            drop(room.storage.lock().await);
+            room.jobs.drain(wait).await;
+            let store = {
+                let ss = room.storage.lock().await;
+                ss.save();
+                ss.store.clone()
+            };
+            if let Some(store) = store {
+                store.flush().await;
+            }
+            info!(room_id, "Room saved for shutdown");
+        }))
+        .await;
+    }
+
+    /// Check every room's phase deadline each second, forever
+    pub(crate) async fn enforce_deadlines(self) {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            let rooms = self.rooms.lock().await.clone();
+            for room in rooms {
+                let mut ss = room.storage.lock().await;
+                let (round, users) = (ss.round, ss.users.len());
+                if !ss.enforce_deadlines(now()) {
+                    continue;
+                }
+                // Uploads in progress are of user IDs that moved or are gone
+                if ss.round != round || ss.users.len() != users {
+                    room.uploads.lock().await.clear();
+                }
+                if ss.round != round {
+                    room.jobs.reset();
+                }
+            }
+        }
+    }
+
+    pub(crate) async fn summaries(&self) -> Vec<RoomSummary> {
+        let rooms = self.rooms.lock().await;
+        rooms
+            .iter()
+            .enumerate()
+            .map(|(id, room)| {
+                let dashboard = room.dashboard.borrow();
+                RoomSummary {
+                    id,
+                    status: dashboard.status().clone(),
+                    users: dashboard.users().len(),
+                }
+            })
+            .collect_vec()
+    }
+}
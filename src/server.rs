@@ -1,128 +1,1108 @@
-use crate::circuit::{derive_server_key, evaluate_circuit, PARAMETER};
-use crate::dashboard::{Dashboard, RegisteredUser};
-use crate::time;
+use crate::auth::{AdminGuard, AdminToken, UserAuth};
+use crate::checkpoint::Checkpoint;
+use crate::circuit::{CircuitId, InputContract, ParameterSet};
+use crate::cold::Cold;
+use crate::compression::Compression;
+use crate::config::ServerConfig;
+use crate::cors::Cors;
+use crate::dashboard::{Dashboard, RegisteredUser, UserProgress};
+use crate::etag::{Conditional, IfNoneMatch};
+use crate::events::{EventChannel, WebSocketUpgrade};
+use crate::health::Readiness;
+use crate::history::{LogEntry, RoomChange};
+use crate::idempotency::{IdempotencyKey, Idempotent};
+use crate::limits::{submit_limit, RegisterQuota, RegisterRateLimit, SpoolThreshold, Submission};
+use crate::persist::{FileStore, Persistence};
+use crate::receipt::{
+    artifact_digest, artifact_hash, parse_public_key, ArchiveSignature, Receipt, ReceiptSigner,
+    SignedResults, ARCHIVE_SIGNATURE_HEADER,
+};
+use crate::report::RoundResult;
+use crate::results::ResultsJob;
+use crate::room::{Lobby, Room, RoomId, RoomSummary};
+use crate::telemetry::{RunStats, Telemetry};
 use crate::types::{
-    CircuitOutput, DecryptionShare, DecryptionShareSubmission, Error, ErrorResponse,
-    InputSubmission, MutexServerStorage, Seed, ServerState, ServerStorage, UserId, UserStorage,
+    CipherCommitment, CipherSubmission, CircuitOutput, DeadlineExtension, DecryptionShare,
+    DecryptionShareSubmission, DecryptionSharesMap, DecryptionStatus, EncryptedInput, Error,
+    ErrorCode, ErrorResponse, FheOutput, InputParts, InputSubmission, JobStatus,
+    KeyShareSubmission, MutexServerStorage, Observer, ParticipantId, Seed, ServerKeyShare,
+    ServerState, ServerStorage, Timestamp, TranscriptArtifact, TranscriptEntry, Transition, UserId,
+    UserInputs, UserStorage, Word,
 };
-use itertools::Itertools;
+use crate::upload::{Upload, UploadKind, UploadProgress, UploadStart};
+use crate::version::{ServerVersion, API_BASE};
+use crate::worker::{evaluate, Evaluation, Evaluator, WorkerJob};
 use phantom_zone::{set_common_reference_seed, set_parameter_set};
-use rand::{thread_rng, RngCore};
-use rocket::serde::json::Json;
+use rocket::data::{Data, Limits};
+use rocket::fairing::AdHoc;
+use rocket::figment::Figment;
+use rocket::http::{Header, Status, StatusClass};
+use rocket::response::content::RawHtml;
+use rocket::response::stream::{Event, EventStream};
+use rocket::response::{self, Responder};
+use rocket::serde::json::{self, Json};
 use rocket::serde::msgpack::MsgPack;
-use rocket::{get, post, routes};
-use rocket::{Build, Rocket, State};
-use tokio::sync::Mutex;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{catch, catchers, get, post, put, routes, FromFormField};
+use rocket::{Build, Config, Request, Rocket, Shutdown, State};
+use std::iter::zip;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::task::{JoinError, JoinHandle};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, info_span, instrument, warn, Instrument};
+
+/// Which API the server speaks, see [`ServerVersion`]. It's the one route outside [`API_BASE`].
+#[get("/version")]
+async fn get_version(version: &State<ServerVersion>) -> Json<ServerVersion> {
+    Json(version.inner().clone())
+}
+
+/// Clients from before the API moved under [`API_BASE`] show this in their error
+#[catch(404)]
+fn not_found(req: &Request<'_>) -> ErrorResponse {
+    let mut message = format!("No route for {} {}", req.method(), req.uri().path());
+    if !req.uri().path().starts_with(API_BASE) {
+        message += &format!(". The API is under {API_BASE}, update the client.");
+    }
+    ErrorResponse::new(Status::NotFound, ErrorCode::NoSuchRoute, message)
+}
+
+/// Requests Rocket refused before a route ran get the same JSON [`ErrorBody`] as route errors
+#[catch(default)]
+fn default_catcher(status: Status, req: &Request<'_>) -> ErrorResponse {
+    let code = match status.class() {
+        StatusClass::ClientError => ErrorCode::BadRequest,
+        _ => ErrorCode::Internal,
+    };
+    let message = format!("{} {} failed: {status}", req.method(), req.uri().path());
+    ErrorResponse::new(status, code, message)
+}
+
+/// Hex public key that verifies submission receipts
+#[get("/receipt_key")]
+async fn get_receipt_key(signer: &State<ReceiptSigner>) -> Json<String> {
+    Json(signer.public_key())
+}
+
+/// A page to supervise a room from the browser, at `/v1/admin?room=<room_id>`. It reads the room's
+/// public routes, and takes the admin token for its buttons.
+#[get("/admin")]
+async fn admin_page() -> RawHtml<&'static str> {
+    RawHtml(include_str!("admin.html"))
+}
+
+/// Liveness probe, answers as long as the server does
+#[get("/healthz")]
+async fn healthz() -> &'static str {
+    "OK"
+}
 
-#[get("/param")]
-async fn get_param(ss: &State<MutexServerStorage>) -> Json<Seed> {
-    let ss = ss.lock().await;
-    Json(ss.seed)
+/// Readiness probe with what every room is up to. It never waits on a room's storage lock,
+/// so a long submission or run doesn't make the server look stuck.
+#[get("/readyz")]
+async fn readyz(lobby: &State<Lobby>) -> Json<Readiness> {
+    Json(Readiness::new(lobby.summaries().await))
 }
 
-/// A user registers a name and get an ID
-#[post("/register", data = "<name>")]
+/// Open a new room with a fresh seed
+#[post("/rooms")]
+async fn create_room(
+    admin: Result<AdminGuard, Error>,
+    lobby: &State<Lobby>,
+) -> Result<Json<RoomId>, ErrorResponse> {
+    admin?;
+    let room_id = lobby.create().await;
+    info!(room_id, "Room created");
+    Ok(Json(room_id))
+}
+
+/// Lobby listing of all rooms
+#[get("/rooms")]
+async fn list_rooms(lobby: &State<Lobby>) -> Json<Vec<RoomSummary>> {
+    Json(lobby.summaries().await)
+}
+
+#[get("/rooms/<room_id>/param")]
+async fn get_param(room_id: RoomId, lobby: &State<Lobby>) -> Result<Json<Seed>, ErrorResponse> {
+    let room = lobby.get(room_id).await?;
+    let ss = room.storage.lock().await;
+    Ok(Json(ss.seed))
+}
+
+/// What the circuit expects of each user's scores, for frontends to validate and prompt with
+#[get("/rooms/<room_id>/circuit")]
+async fn get_circuit(
+    room_id: RoomId,
+    lobby: &State<Lobby>,
+) -> Result<Json<InputContract>, ErrorResponse> {
+    let room = lobby.get(room_id).await?;
+    let ss = room.storage.lock().await;
+    Ok(Json(
+        ss.config.circuit.circuit().input_contract(ss.users.len()),
+    ))
+}
+
+/// A user registers a name and get an ID. With a hex ed25519 `public_key`, they must sign their submissions,
+/// and they get the same participant ID whenever they register with it again.
+/// Once the admin created invite codes, registering takes an unused `invite`.
+/// The response holds the user's token, so it is never remembered for an `Idempotency-Key`.
+#[post("/rooms/<room_id>/register?<public_key>&<invite>", data = "<name>")]
 async fn register(
     name: &str,
-    ss: &State<MutexServerStorage>,
+    public_key: Option<&str>,
+    invite: Option<&str>,
+    room_id: RoomId,
+    lobby: &State<Lobby>,
+    quota: Result<RegisterQuota, Error>,
 ) -> Result<Json<RegisteredUser>, ErrorResponse> {
-    let mut ss = ss.lock().await;
+    quota?;
+    if let Some(public_key) = public_key {
+        parse_public_key(public_key).map_err(|err| Error::InvalidPublicKey {
+            reason: err.to_string(),
+        })?;
+    }
+    let room = lobby.open(room_id).await?;
+    let mut ss = room.storage.lock().await;
     ss.ensure(ServerState::ReadyForJoining)?;
-    let user = ss.add_user(name);
-    println!("{name} just joined!");
-
+    ss.redeem_invite(invite)?;
+    let user = match public_key {
+        Some(public_key) => ss.add_returning_user(name, public_key)?,
+        None => ss.add_user(name),
+    };
+    info!(room_id, user_id = user.id, name, "User joined");
+    ss.save();
     Ok(Json(user))
 }
 
-#[post("/conclude_registration")]
+/// Someone registers a name to watch the round. Observers read what anyone can, and their
+/// names are on the dashboard, but they send no inputs and aren't counted as users.
+#[post("/rooms/<room_id>/register_observer", data = "<name>")]
+async fn register_observer(
+    name: &str,
+    room_id: RoomId,
+    lobby: &State<Lobby>,
+    quota: Result<RegisterQuota, Error>,
+    key: IdempotencyKey,
+) -> Result<Idempotent<Observer>, ErrorResponse> {
+    quota?;
+    let room = lobby.open(room_id).await?;
+    let mut ss = room.storage.lock().await;
+    if let Some(replayed) = ss.idempotency.replay(&key) {
+        return Ok(replayed);
+    }
+    let observer = ss.add_observer(name);
+    info!(room_id, name, "Observer joined");
+    let response = ss.idempotency.remember(&key, observer);
+    ss.save();
+    Ok(response)
+}
+
+#[post("/rooms/<room_id>/conclude_registration")]
 async fn conclude_registration(
-    ss: &State<MutexServerStorage>,
+    room_id: RoomId,
+    lobby: &State<Lobby>,
+    admin: Result<AdminGuard, Error>,
 ) -> Result<Json<Dashboard>, ErrorResponse> {
-    let mut ss = ss.lock().await;
-    ss.ensure(ServerState::ReadyForJoining)?;
-    ss.transit(ServerState::ReadyForInputs);
-    println!("Registration closed!");
+    admin?;
+    let room = lobby.get(room_id).await?;
+    let mut ss = room.storage.lock().await;
+    ss.close_registration()?;
+    info!(room_id, "Registration closed");
     let dashboard = ss.get_dashboard();
     Ok(Json(dashboard))
 }
 
-#[get("/dashboard")]
-async fn get_dashboard(ss: &State<MutexServerStorage>) -> Json<Dashboard> {
-    let dashboard = ss.lock().await.get_dashboard();
-    Json(dashboard)
+/// Tagged with its version, so a poll with `If-None-Match` gets `304` until it changes
+#[get("/rooms/<room_id>/dashboard")]
+async fn get_dashboard(
+    room_id: RoomId,
+    lobby: &State<Lobby>,
+    if_none_match: IfNoneMatch,
+) -> Result<Conditional<Dashboard>, ErrorResponse> {
+    let room = lobby.get(room_id).await?;
+    let dashboard = room.dashboard.borrow();
+    Ok(if_none_match.respond(dashboard.version(), || dashboard.clone()))
 }
 
-/// The user submits the ciphertext
-#[post("/submit", data = "<submission>", format = "msgpack")]
+/// Server-sent events for browsers: the dashboard now, then again on every change
+#[get("/rooms/<room_id>/dashboard/events")]
+async fn dashboard_events(
+    room_id: RoomId,
+    lobby: &State<Lobby>,
+    mut shutdown: Shutdown,
+) -> Result<EventStream![], ErrorResponse> {
+    let room = lobby.get(room_id).await?;
+    let mut dashboard = room.dashboard.clone();
+    Ok(EventStream! {
+        loop {
+            let snapshot = dashboard.borrow_and_update().clone();
+            yield Event::json(&snapshot);
+            tokio::select! {
+                changed = dashboard.changed() => if changed.is_err() { break },
+                _ = &mut shutdown => break,
+            }
+        }
+    })
+}
+
+/// The user commits to the hash of their cipher. Ciphers are accepted once everyone has.
+#[post("/rooms/<room_id>/commit", data = "<commitment>")]
+async fn commit(
+    commitment: Json<CipherCommitment>,
+    room_id: RoomId,
+    auth: UserAuth,
+    lobby: &State<Lobby>,
+    signer: &State<ReceiptSigner>,
+    key: IdempotencyKey,
+) -> Result<Idempotent<Receipt>, ErrorResponse> {
+    let room = lobby.open(room_id).await?;
+    let mut ss = room.storage.lock().await;
+    let CipherCommitment { user_id, hash } = commitment.into_inner();
+    let user = ss.get_user(user_id)?;
+    user.authorize(&auth)?;
+    let participant_id = user.participant_id.clone();
+    if let Some(replayed) = ss.idempotency.replay(&key) {
+        return Ok(replayed);
+    }
+    ss.ensure(ServerState::ReadyForCommitments)?;
+    let state = ss.state.clone();
+    info!(room_id, user_id, "User committed to a cipher");
+    ss.apply(RoomChange::Committed {
+        user_id,
+        hash: hash.clone(),
+    });
+    let receipt = signer.sign(room_id, participant_id, state, hash);
+    let response = ss.idempotency.remember(&key, receipt);
+    ss.save();
+    if ss.check_commitments() {
+        info!(room_id, "Every user committed, accepting ciphers");
+        ss.transit(ServerState::ReadyForInputs)?;
+    }
+    Ok(response)
+}
+
+/// The user submits the ciphertext and the server key share, within the `submit` limit
+#[post("/rooms/<room_id>/submit", data = "<submission>", format = "msgpack")]
 async fn submit(
-    submission: MsgPack<InputSubmission>,
-    ss: &State<MutexServerStorage>,
-) -> Result<Json<UserId>, ErrorResponse> {
-    let mut ss = ss.lock().await;
+    submission: Result<Submission<InputSubmission>, Error>,
+    room_id: RoomId,
+    auth: UserAuth,
+    lobby: &State<Lobby>,
+    signer: &State<ReceiptSigner>,
+    telemetry: &State<Telemetry>,
+    key: IdempotencyKey,
+) -> Result<Idempotent<Receipt>, ErrorResponse> {
+    let Submission(submission) = submission?;
+    let room = lobby.open(room_id).await?;
+    let artifact = artifact_hash(&submission);
+    let parts = submission.into();
+    accept_submission(
+        &room, room_id, artifact, parts, &auth, signer, telemetry, &key,
+    )
+    .await
+}
+
+/// The user submits the server key share alone. The cipher may come before or after.
+#[post(
+    "/rooms/<room_id>/submit_key_share",
+    data = "<submission>",
+    format = "msgpack"
+)]
+async fn submit_key_share(
+    submission: Result<Submission<KeyShareSubmission>, Error>,
+    room_id: RoomId,
+    auth: UserAuth,
+    lobby: &State<Lobby>,
+    signer: &State<ReceiptSigner>,
+    telemetry: &State<Telemetry>,
+    key: IdempotencyKey,
+) -> Result<Idempotent<Receipt>, ErrorResponse> {
+    let Submission(submission) = submission?;
+    let room = lobby.open(room_id).await?;
+    let artifact = artifact_hash(&submission);
+    let parts = submission.into();
+    accept_submission(
+        &room, room_id, artifact, parts, &auth, signer, telemetry, &key,
+    )
+    .await
+}
 
-    ss.ensure(ServerState::ReadyForInputs)?;
+/// The user submits or replaces the ciphertext alone, keeping the key share on the server
+#[post(
+    "/rooms/<room_id>/submit_cipher",
+    data = "<submission>",
+    format = "msgpack"
+)]
+async fn submit_cipher(
+    submission: Result<Submission<CipherSubmission>, Error>,
+    room_id: RoomId,
+    auth: UserAuth,
+    lobby: &State<Lobby>,
+    signer: &State<ReceiptSigner>,
+    telemetry: &State<Telemetry>,
+    key: IdempotencyKey,
+) -> Result<Idempotent<Receipt>, ErrorResponse> {
+    let Submission(submission) = submission?;
+    let room = lobby.open(room_id).await?;
+    let artifact = artifact_hash(&submission);
+    let parts = submission.into();
+    accept_submission(
+        &room, room_id, artifact, parts, &auth, signer, telemetry, &key,
+    )
+    .await
+}
 
-    let InputSubmission { user_id, ei, sks } = submission.0;
+/// Open a session to upload the msgpack of an [`InputSubmission`] or a [`KeyShareSubmission`] in chunks.
+/// The session is the user's, and the chunks are spooled to Rocket's `temp_dir`.
+#[post("/rooms/<room_id>/submit/start", data = "<start>")]
+async fn start_upload(
+    start: Json<UploadStart>,
+    room_id: RoomId,
+    limits: &Limits,
+    config: &rocket::Config,
+    auth: UserAuth,
+    lobby: &State<Lobby>,
+) -> Result<Json<UploadProgress>, ErrorResponse> {
+    let room = lobby.open(room_id).await?;
+    {
+        let mut ss = room.storage.lock().await;
+        ss.ensure(ServerState::ReadyForInputs)?;
+        ss.get_user(start.user_id)?.authorize(&auth)?;
+    }
+    let file = tempfile::tempfile_in(config.temp_dir.relative()).map_err(|err| {
+        Error::ArtifactStorage {
+            reason: err.to_string(),
+        }
+    })?;
+    let now = Instant::now();
+    let upload = Upload::new(start.0, submit_limit(limits), file, now)?;
+    let mut uploads = room.uploads.lock().await;
+    let session = uploads.open(upload, now);
+    Ok(Json(uploads.get_mut(&session, now)?.progress(&session)))
+}
 
-    let user = ss.get_user(user_id)?;
-    println!("{} submited data", user.name);
-    user.storage = UserStorage::CipherSks(ei, Box::new(sks));
+/// Check the request is from the user who opened the upload `session`
+async fn authorize_upload(room: &Room, session: &str, auth: &UserAuth) -> Result<(), Error> {
+    let user_id = room
+        .uploads
+        .lock()
+        .await
+        .get_mut(session, Instant::now())?
+        .user_id();
+    room.storage.lock().await.get_user(user_id)?.authorize(auth)
+}
+
+/// Where to resume an interrupted upload
+#[get("/rooms/<room_id>/submit/<session>")]
+async fn get_upload(
+    session: &str,
+    room_id: RoomId,
+    auth: UserAuth,
+    lobby: &State<Lobby>,
+) -> Result<Json<UploadProgress>, ErrorResponse> {
+    let room = lobby.get(room_id).await?;
+    authorize_upload(&room, session, &auth).await?;
+    let mut uploads = room.uploads.lock().await;
+    let upload = uploads.get_mut(session, Instant::now())?;
+    Ok(Json(upload.progress(session)))
+}
+
+#[put("/rooms/<room_id>/submit/<session>/chunk/<n>", data = "<chunk>")]
+#[allow(clippy::too_many_arguments)]
+async fn put_upload_chunk(
+    chunk: Data<'_>,
+    session: &str,
+    n: u64,
+    room_id: RoomId,
+    limits: &Limits,
+    auth: UserAuth,
+    lobby: &State<Lobby>,
+) -> Result<Json<UploadProgress>, ErrorResponse> {
+    let room = lobby.open(room_id).await?;
+    authorize_upload(&room, session, &auth).await?;
+    let slot = room
+        .uploads
+        .lock()
+        .await
+        .get_mut(session, Instant::now())?
+        .slot(n)
+        .await?;
+    // Chunks are far bigger than Rocket's default `bytes` limit, and only the slot caps them
+    let slot = match slot {
+        Some(mut slot) => {
+            slot.write(chunk.open(submit_limit(limits))).await?;
+            Some(slot)
+        }
+        None => None,
+    };
+    let mut uploads = room.uploads.lock().await;
+    let now = Instant::now();
+    let upload = uploads.get_mut(session, now)?;
+    if let Some(slot) = slot {
+        upload.commit(slot, now);
+    }
+    Ok(Json(upload.progress(session)))
+}
+
+/// Submit the assembled upload, as `/submit` or `/submit_key_share` would
+#[post("/rooms/<room_id>/submit/<session>/finish")]
+#[allow(clippy::too_many_arguments)]
+async fn finish_upload(
+    session: &str,
+    room_id: RoomId,
+    limits: &Limits,
+    auth: UserAuth,
+    lobby: &State<Lobby>,
+    signer: &State<ReceiptSigner>,
+    telemetry: &State<Telemetry>,
+    key: IdempotencyKey,
+) -> Result<Idempotent<Receipt>, ErrorResponse> {
+    let room = lobby.open(room_id).await?;
+    authorize_upload(&room, session, &auth).await?;
+    if let Some(replayed) = room.storage.lock().await.idempotency.replay(&key) {
+        return Ok(replayed);
+    }
+    let upload = {
+        let mut uploads = room.uploads.lock().await;
+        uploads
+            .get_mut(session, Instant::now())?
+            .ensure_complete()?;
+        uploads.remove(session).expect("just checked")
+    };
+    let user_id = upload.user_id();
+    let limit = submit_limit(limits);
+    let (artifact, parts): (String, InputParts) = match upload.kind() {
+        UploadKind::Inputs => {
+            let submission: InputSubmission = upload.decode(limit).await?;
+            (artifact_hash(&submission), submission.into())
+        }
+        UploadKind::KeyShare => {
+            let submission: KeyShareSubmission = upload.decode(limit).await?;
+            (artifact_hash(&submission), submission.into())
+        }
+    };
+    if parts.user_id != user_id {
+        return Err(Error::InvalidUpload {
+            reason: format!(
+                "Session of user #{user_id} holds a submission of #{}",
+                parts.user_id
+            ),
+        }
+        .into());
+    }
+    accept_submission(
+        &room, room_id, artifact, parts, &auth, signer, telemetry, &key,
+    )
+    .await
+}
+
+/// Store whichever of a user's cipher and key share arrived, starting the run if they completed the inputs.
+/// `artifact` is the hash of the submission as sent.
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip_all, fields(room_id, user_id = parts.user_id))]
+async fn accept_submission(
+    room: &Room,
+    room_id: RoomId,
+    artifact: String,
+    parts: InputParts,
+    auth: &UserAuth,
+    signer: &ReceiptSigner,
+    telemetry: &Telemetry,
+    key: &IdempotencyKey,
+) -> Result<Idempotent<Receipt>, ErrorResponse> {
+    let (has_cipher, has_sks) = (parts.ei.is_some(), parts.sks.is_some());
+    let (contract, commitment, signed) = {
+        let mut ss = room.storage.lock().await;
+        let user = ss.get_user(parts.user_id)?;
+        user.authorize(auth)?;
+        if let Some(replayed) = ss.idempotency.replay(key) {
+            return Ok(replayed);
+        }
+        ss.ensure_accepts_inputs(parts.user_id, has_cipher, has_sks)?;
+        ss.ensure_before_deadline()?;
+        let user = ss.get_user(parts.user_id)?;
+        let signed = user.check_signature(&artifact, auth)?;
+        let commitment = user.commitment.clone();
+        let contract = ss.config.circuit.circuit().input_contract(ss.users.len());
+        (contract, commitment, signed)
+    };
+
+    let validation = parts.validate(&contract, commitment.as_deref());
+    let InputParts { user_id, ei, sks } = parts;
+    let cipher_digest = ei.as_ref().map(artifact_digest);
+    let sks_digest = sks.as_ref().map(artifact_digest);
+    // Stash the big parts before locking, so dashboard polls don't wait on the write.
+    // A bad cipher is dropped, but the key share is kept for the resubmission.
+    let newer = UserInputs {
+        cipher: match (ei, &validation) {
+            (Some(ei), Ok(())) => Some(stash(ei, room, "cipher").await?),
+            _ => None,
+        },
+        sks: match sks {
+            Some(sks) => Some(stash(sks, room, "key-share").await?),
+            None => None,
+        },
+        version: 0,
+    };
+
+    let mut ss = room.storage.lock().await;
+    // The room may have moved on while stashing
+    if let Err(err) = ss
+        .ensure_accepts_inputs(user_id, has_cipher, has_sks)
+        .and_then(|_| ss.ensure_before_deadline())
+    {
+        newer.discard();
+        return Err(err.into());
+    }
+    // Once every cipher is in, a bad replacement must not take the user out of the run
+    if let (Err(reason), ServerState::ReadyForRunning) = (&validation, &ss.state) {
+        newer.discard();
+        return Err(Error::ReplacementRejected {
+            user_id,
+            reason: reason.clone(),
+        }
+        .into());
+    }
+    let user = match ss.get_user(user_id) {
+        Ok(user) => user,
+        Err(err) => {
+            newer.discard();
+            return Err(err.into());
+        }
+    };
+    let mut inputs = user.storage.get_inputs();
+    inputs.update(newer);
+    if let Err(reason) = validation {
+        warn!(%reason, "Quarantined a bad submission");
+        inputs.cipher.take().inspect(Cold::discard);
+        user.storage = UserStorage::Quarantined {
+            reason: reason.clone(),
+            sks: inputs.sks,
+        };
+        let participant_id = user.participant_id.clone();
+        // The key share is kept, so is the signature of the submission it came in
+        if let Some(digest) = sks_digest {
+            user.signatures.extend(signed);
+            ss.record(&participant_id, TranscriptArtifact::ServerKeyShare, digest);
+        }
+        ss.save_user(user_id);
+        // The run goes on without the cipher if the key share is in
+        start_when_complete(room, &mut ss, telemetry)?;
+        return Err(Error::Quarantined { user_id, reason }.into());
+    }
+    info!(
+        cipher = cipher_digest.is_some(),
+        key_share = sks_digest.is_some(),
+        "Accepted submission"
+    );
+    user.storage = UserStorage::Inputs(inputs);
+    user.signatures.extend(signed);
+    let participant_id = user.participant_id.clone();
+    let receipt = signer.sign(room_id, participant_id.clone(), ss.state.clone(), artifact);
+    let response = ss.idempotency.remember(key, receipt);
+    let digests = [
+        (TranscriptArtifact::Cipher, cipher_digest),
+        (TranscriptArtifact::ServerKeyShare, sks_digest),
+    ];
+    for (artifact, digest) in digests {
+        if let Some(digest) = digest {
+            ss.record(&participant_id, artifact, digest);
+        }
+    }
+    ss.save_user(user_id);
+    start_when_complete(room, &mut ss, telemetry)?;
+
+    Ok(response)
+}
+
+/// Move on to `ReadyForRunning` once every user is ready to run, and start the run in rooms
+/// with `auto_run`
+fn start_when_complete(
+    room: &Room,
+    ss: &mut ServerStorage,
+    telemetry: &Telemetry,
+) -> Result<(), Error> {
+    if ss.state == ServerState::ReadyForInputs && ss.check_cipher_submission() {
+        ss.transit(ServerState::ReadyForRunning)?;
+        if ss.config.auto_run {
+            info!("Every cipher arrived, starting the FHE run");
+            start_run(room, ss, telemetry)?;
+        }
+    }
+    Ok(())
+}
+
+async fn stash<T>(value: T, room: &Room, prefix: &str) -> Result<Cold<T>, Error>
+where
+    T: Serialize + for<'de> Deserialize<'de> + Send + Sync + 'static,
+{
+    Cold::stash(value, room.cold_dir.as_deref(), prefix)
+        .await
+        .map_err(|err| Error::ArtifactStorage {
+            reason: err.to_string(),
+        })
+}
+
+/// The admin sets the submission deadline, if the room has none yet
+#[post("/rooms/<room_id>/deadline", data = "<deadline>")]
+async fn set_deadline(
+    deadline: Json<Timestamp>,
+    room_id: RoomId,
+    lobby: &State<Lobby>,
+    admin: Result<AdminGuard, Error>,
+) -> Result<Json<Timestamp>, ErrorResponse> {
+    admin?;
+    let room = lobby.get(room_id).await?;
+    room.storage.lock().await.set_deadline(deadline.0)?;
+    info!(room_id, deadline = deadline.0, "Deadline set");
+    Ok(deadline)
+}
+
+/// The admin proposes a new submission deadline
+#[post("/rooms/<room_id>/deadline/propose", data = "<deadline>")]
+async fn propose_deadline_extension(
+    deadline: Json<Timestamp>,
+    room_id: RoomId,
+    lobby: &State<Lobby>,
+    admin: Result<AdminGuard, Error>,
+) -> Result<Json<DeadlineExtension>, ErrorResponse> {
+    admin?;
+    let room = lobby.get(room_id).await?;
+    let mut ss = room.storage.lock().await;
+    let extension = ss.propose_deadline_extension(deadline.0)?.clone();
+    info!(
+        room_id,
+        deadline = extension.deadline,
+        "Deadline extension proposed"
+    );
+    Ok(Json(extension))
+}
+
+/// A user consents to the proposed deadline. The new deadline applies once a majority acks.
+#[post("/rooms/<room_id>/deadline/ack/<user_id>")]
+async fn ack_deadline_extension(
+    user_id: UserId,
+    room_id: RoomId,
+    lobby: &State<Lobby>,
+    auth: UserAuth,
+) -> Result<Json<Option<Timestamp>>, ErrorResponse> {
+    let room = lobby.get(room_id).await?;
+    let mut ss = room.storage.lock().await;
+    ss.get_user(user_id)?.authorize(&auth)?;
+    let applied = ss.ack_deadline_extension(user_id)?;
+    if let Some(deadline) = applied {
+        info!(room_id, deadline, "Deadline extended");
+    }
+    Ok(Json(applied))
+}
+
+/// Compute the outputs `job.output_ids` for a coordinator that lists this server in `worker_servers`
+#[post("/worker/evaluate", data = "<job>", format = "msgpack")]
+async fn evaluate_shard(
+    job: Result<Submission<WorkerJob>, Error>,
+    admin: Result<AdminGuard, Error>,
+) -> Result<Json<Evaluation>, ErrorResponse> {
+    admin?;
+    let Submission(job) = job?;
+    let users = job.encrypted_inputs.len();
+    let outputs = job.circuit.circuit().output_count(users);
+    if let Some(output_id) = job
+        .output_ids
+        .iter()
+        .find(|&&output_id| output_id >= outputs)
+    {
+        return Err(Error::ShardFailed {
+            reason: format!("No output #{output_id} among {outputs} of {users} users"),
+        }
+        .into());
+    }
+    if !job.carried.is_empty() && job.carried.len() != outputs {
+        return Err(Error::ShardFailed {
+            reason: format!("{} earlier outputs for {outputs}", job.carried.len()),
+        }
+        .into());
+    }
+    info!(outputs = job.output_ids.len(), "Evaluating a shard");
+    let evaluation = tokio::task::spawn_blocking(move || {
+        evaluate(job, &CancellationToken::new(), |_| {}, |_, _| {})
+    })
+    .await
+    .map_err(|err| Error::ShardFailed {
+        reason: err.to_string(),
+    })?
+    .expect("Never cancelled");
+    Ok(Json(evaluation))
+}
+
+/// Owns the background FHE run and reports its progress
+pub(crate) struct JobManager {
+    progress: Arc<watch::Sender<JobStatus>>,
+    /// Token of the latest run
+    cancel: std::sync::Mutex<CancellationToken>,
+    /// The task storing the outcome of the latest run, until someone drains it
+    running: std::sync::Mutex<Option<JoinHandle<()>>>,
+    evaluator: Evaluator,
+}
+
+/// How a run ended. A cancelled or failed run hands its inputs back so the room can run again.
+enum RunOutcome {
+    Completed(Vec<Word>, RunStats),
+    Cancelled,
+    /// The evaluation panicked, with this message
+    Failed(String),
+}
+
+impl JobManager {
+    pub(crate) fn new(evaluator: Evaluator) -> Self {
+        let (progress, _) = watch::channel(JobStatus::default());
+        Self {
+            progress: Arc::new(progress),
+            cancel: std::sync::Mutex::new(CancellationToken::new()),
+            running: std::sync::Mutex::new(None),
+            evaluator,
+        }
+    }
+
+    /// For a room resumed after its run completed
+    pub(crate) fn mark_completed(&self) {
+        self.progress.send_modify(|status| status.completed = true);
+    }
+
+    pub(crate) fn status(&self) -> JobStatus {
+        self.progress.borrow().clone()
+    }
+
+    /// Forget the progress of the previous round
+    pub(crate) fn reset(&self) {
+        self.cancel();
+        self.progress.send_replace(JobStatus::default());
+    }
+
+    /// Ask the running job to stop after the outputs in progress
+    pub(crate) fn cancel(&self) {
+        self.cancel.lock().unwrap().cancel();
+    }
+
+    /// Wait up to `wait` for the run to store its outcome, then cancel it and wait for the
+    /// outputs in progress, which the checkpoint keeps for the next start
+    pub(crate) async fn drain(&self, wait: Duration) {
+        let Some(mut handle) = self.running.lock().unwrap().take() else {
+            return;
+        };
+        if tokio::time::timeout(wait, &mut handle).await.is_ok() {
+            return;
+        }
+        info!("Cancelling the FHE run to shut down");
+        self.cancel();
+        if let Err(err) = handle.await {
+            warn!("The FHE run failed while shutting down: {err}");
+        }
+    }
 
-    if ss.check_cipher_submission() {
-        ss.transit(ServerState::ReadyForRunning);
+    /// Evaluate the circuit on a blocking thread and store the output once it arrives,
+    /// unless the room was reset away from `round` in the meantime.
+    /// Outputs already in `checkpoint` are taken from there, new ones are added to it.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn start(
+        &self,
+        ss: MutexServerStorage,
+        round: u64,
+        parameter: ParameterSet,
+        circuit: CircuitId,
+        ciphers_and_sks: Vec<UserInputs>,
+        carried: Vec<Word>,
+        checkpoint: Option<Checkpoint>,
+        telemetry: Telemetry,
+    ) {
+        let total_outputs = circuit.circuit().output_count(ciphers_and_sks.len());
+        self.progress.send_replace(JobStatus {
+            total_outputs,
+            ..Default::default()
+        });
+        let cancel = CancellationToken::new();
+        *self.cancel.lock().unwrap() = cancel.clone();
+        let progress = self.progress.clone();
+        let measure_sizes = telemetry.is_enabled();
+        let handles = ciphers_and_sks.clone();
+        let partial = ss.clone();
+        let evaluator = self.evaluator.clone();
+        let saved = checkpoint.clone();
+        let span = info_span!("fhe_run", round, users = ciphers_and_sks.len());
+        let run_span = span.clone();
+        let task = tokio::task::spawn_blocking(move || {
+            let _entered = run_span.enter();
+            // Publish each output early, so users can start decrypting. Outputs carried to
+            // the next rating have no slot, and stay unpublished.
+            let publish = |output_id: usize, output: &Word| {
+                let mut ss = partial.blocking_lock();
+                let mut published = false;
+                if ss.round == round {
+                    if let Some(slot) = ss.partial_outputs.get_mut(output_id) {
+                        *slot = Some(output.clone());
+                        published = true;
+                    }
+                }
+                drop(ss);
+                progress.send_modify(|status| {
+                    status.outputs_computed += 1;
+                    if published {
+                        status.ready_outputs.push(output_id);
+                    }
+                })
+            };
+            let mut outputs = match &saved {
+                Some(checkpoint) => checkpoint.load(total_outputs),
+                None => vec![None; total_outputs],
+            };
+            let resumed = outputs.iter().flatten().count();
+            if resumed > 0 {
+                info!(resumed, "Resuming the FHE run from the checkpoint");
+            }
+            for (output_id, output) in outputs.iter().enumerate() {
+                if let Some(output) = output {
+                    publish(output_id, output);
+                }
+            }
+            let output_ids = (0..outputs.len())
+                .filter(|&output_id| outputs[output_id].is_none())
+                .collect::<Vec<_>>();
+
+            let mut stats = RunStats {
+                party_count: ciphers_and_sks.len(),
+                parameter_set: format!("{:?}", parameter),
+                key_aggregation_ms: 0,
+                evaluation_ms: 0,
+                cipher_bytes: 0,
+                server_key_share_bytes: 0,
+            };
+            if !output_ids.is_empty() {
+                let (server_key_shares, encrypted_inputs) = match load_all(&ciphers_and_sks) {
+                    Ok(loaded) => loaded,
+                    Err(err) => {
+                        warn!("Failed to load the submissions: {:?}", err);
+                        return RunOutcome::Cancelled;
+                    }
+                };
+                drop(ciphers_and_sks);
+                info!("Begin FHE run");
+                if measure_sizes {
+                    stats.cipher_bytes = serialized_size(&encrypted_inputs);
+                    stats.server_key_share_bytes = serialized_size(&server_key_shares);
+                }
+                let job = WorkerJob {
+                    parameter,
+                    circuit,
+                    output_ids: output_ids.clone(),
+                    server_key_shares,
+                    encrypted_inputs,
+                    carried,
+                };
+                let evaluation = evaluator.run(
+                    job,
+                    &cancel,
+                    |ms| {
+                        info!(elapsed_ms = ms as u64, "Server key aggregated");
+                        progress.send_modify(|status| status.keys_aggregated = true);
+                    },
+                    |output_id, output| {
+                        if let Some(checkpoint) = &saved {
+                            checkpoint.save(output_id, output);
+                        }
+                        publish(output_id, output);
+                    },
+                );
+                let Some(evaluation) = evaluation else {
+                    return RunOutcome::Cancelled;
+                };
+                info!(
+                    elapsed_ms = evaluation.evaluation_ms as u64,
+                    "Circuit evaluated"
+                );
+                stats.key_aggregation_ms = evaluation.key_aggregation_ms;
+                stats.evaluation_ms = evaluation.evaluation_ms;
+                for (output_id, output) in output_ids.into_iter().zip(evaluation.outputs) {
+                    outputs[output_id] = Some(output);
+                }
+            }
+            let outputs = outputs
+                .into_iter()
+                .collect::<Option<Vec<_>>>()
+                .expect("Every output is resumed or evaluated");
+            RunOutcome::Completed(outputs, stats)
+        });
+        let progress = self.progress.clone();
+        let running = tokio::spawn(
+            async move {
+                let outcome = task
+                    .await
+                    .unwrap_or_else(|err| RunOutcome::Failed(panic_message(err)));
+                let mut ss = ss.lock().await;
+                if ss.round != round {
+                    info!(round, "Discarding the FHE output of a stale round");
+                    return;
+                }
+                match outcome {
+                    RunOutcome::Completed(output, _) if ss.ratings_left() > 0 => {
+                        handles.iter().for_each(UserInputs::discard);
+                        checkpoint.iter().for_each(Checkpoint::discard);
+                        ss.partial_outputs.clear();
+                        ss.carry_rating(output)
+                            .expect("Only the job leaves RunningFhe");
+                        drop(ss);
+                        progress.send_modify(|status| status.carried = true);
+                        info!(round, "FHE output carried to the next rating");
+                    }
+                    RunOutcome::Completed(output, stats) => {
+                        // Nobody reads the submissions or the checkpoint again after a successful run
+                        handles.iter().for_each(UserInputs::discard);
+                        checkpoint.iter().for_each(Checkpoint::discard);
+                        ss.partial_outputs.clear();
+                        ss.fhe_outputs = Some(Arc::new(CircuitOutput::new(
+                            output,
+                            ss.participant_ids(),
+                            circuit,
+                        )));
+                        ss.transit(ServerState::CompletedFhe)
+                            .expect("Only the job leaves RunningFhe");
+                        drop(ss);
+                        progress.send_modify(|status| status.completed = true);
+                        info!(round, "FHE computation completed");
+                        if telemetry.is_enabled() {
+                            telemetry.report(&stats).await;
+                        }
+                    }
+                    RunOutcome::Cancelled => {
+                        ss.partial_outputs.clear();
+                        ss.restore_ciphers_and_sks(handles);
+                        ss.transit(ServerState::ReadyForRunning)
+                            .expect("Only the job leaves RunningFhe");
+                        progress.send_replace(JobStatus {
+                            cancelled: true,
+                            ..Default::default()
+                        });
+                        info!(round, "FHE computation cancelled");
+                    }
+                    RunOutcome::Failed(reason) => {
+                        ss.partial_outputs.clear();
+                        ss.restore_ciphers_and_sks(handles);
+                        ss.transit(ServerState::ReadyForRunning)
+                            .expect("Only the job leaves RunningFhe");
+                        warn!(round, reason, "FHE computation failed");
+                        progress.send_replace(JobStatus {
+                            cancelled: true,
+                            failed: Some(reason),
+                            ..Default::default()
+                        });
+                    }
+                }
+            }
+            .instrument(span),
+        );
+        *self.running.lock().unwrap() = Some(running);
     }
+}
+
+/// Blocking. Load the stashed inputs into the key shares and ciphers of the run.
+fn load_all(
+    ciphers_and_sks: &[UserInputs],
+) -> Result<(Vec<ServerKeyShare>, Vec<Option<EncryptedInput>>), anyhow::Error> {
+    let mut server_key_shares = vec![];
+    let mut encrypted_inputs = vec![];
+    for inputs in ciphers_and_sks {
+        let Some(sks) = &inputs.sks else {
+            anyhow::bail!("Incomplete inputs");
+        };
+        // A quarantined cipher is left out of the run
+        let cipher = match &inputs.cipher {
+            Some(cipher) => Some(Arc::unwrap_or_clone(cipher.load()?)),
+            None => None,
+        };
+        encrypted_inputs.push(cipher);
+        server_key_shares.push(Arc::unwrap_or_clone(sks.load()?));
+    }
+    Ok((server_key_shares, encrypted_inputs))
+}
 
-    Ok(Json(user_id))
+fn serialized_size<T: Serialize>(value: &T) -> u64 {
+    bincode::serialized_size(value).unwrap_or_default()
+}
+
+/// What a task panicked with, or why else it didn't finish
+pub(crate) fn panic_message(err: JoinError) -> String {
+    match err.try_into_panic() {
+        Ok(panic) => panic
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "Unknown panic".to_string()),
+        Err(err) => err.to_string(),
+    }
+}
+
+/// A blocking `task` of a request that panicked, logged and answered as an error
+pub(crate) fn task_failed(task: &'static str, err: JoinError) -> Error {
+    let reason = panic_message(err);
+    warn!(task, reason, "Task failed");
+    Error::TaskFailed { task, reason }
+}
+
+/// Hand the ciphers and key shares to the room's job
+fn start_run(room: &Room, ss: &mut ServerStorage, telemetry: &Telemetry) -> Result<(), Error> {
+    room.ensure_open()?;
+    let ciphers_and_sks = ss.get_ciphers_and_sks()?;
+    // Only the last rating's outputs are decrypted, so only they are published early
+    if ss.ratings_left() == 0 {
+        ss.partial_outputs = vec![
+            None;
+            ss.config
+                .circuit
+                .circuit()
+                .output_count(ciphers_and_sks.len())
+        ];
+    }
+    let checkpoint = room
+        .cold_dir
+        .as_deref()
+        .map(|dir| Checkpoint::new(dir, ss.round, &ciphers_and_sks));
+    room.jobs.start(
+        room.storage.clone(),
+        ss.round,
+        ss.parameter,
+        ss.config.circuit,
+        ciphers_and_sks,
+        ss.carried.clone(),
+        checkpoint,
+        telemetry.clone(),
+    );
+    ss.transit(ServerState::RunningFhe)
 }
 
 /// The admin runs the fhe computation
-#[post("/run")]
-async fn run(ss: &State<MutexServerStorage>) -> Result<Json<ServerState>, ErrorResponse> {
-    let s2 = (*ss).clone();
-    let mut ss = ss.lock().await;
+#[post("/rooms/<room_id>/run")]
+async fn run(
+    room_id: RoomId,
+    lobby: &State<Lobby>,
+    telemetry: &State<Telemetry>,
+    admin: Result<AdminGuard, Error>,
+    key: IdempotencyKey,
+) -> Result<Idempotent<ServerState>, ErrorResponse> {
+    admin?;
+    let room = lobby.open(room_id).await?;
+    let mut ss = room.storage.lock().await;
+    if let Some(replayed) = ss.idempotency.replay(&key) {
+        return Ok(replayed);
+    }
 
     match &ss.state {
         ServerState::ReadyForRunning => {
-            let (server_key_shares, encrypted_inputs) = ss.get_ciphers_and_sks()?;
-
-            tokio::task::spawn_blocking(move || {
-                rayon::ThreadPoolBuilder::new()
-                    .build_scoped(
-                        // Initialize thread-local storage parameters
-                        |thread| {
-                            set_parameter_set(PARAMETER);
-                            thread.run()
-                        },
-                        // Run parallel code under this pool
-                        |pool| {
-                            pool.install(|| {
-                                println!("Begin FHE run");
-                                // Long running, global variable change
-                                derive_server_key(&server_key_shares);
-
-                                // Unpack to get circuit inputs
-                                let cis = encrypted_inputs
-                                    .iter()
-                                    .enumerate()
-                                    .map(|(user_id, ei)| ei.unpack(user_id))
-                                    .collect_vec();
-
-                                // Long running
-                                let output = time!(|| evaluate_circuit(&cis), "Evaluating Circuit");
-                                let mut ss = s2.blocking_lock();
-                                ss.fhe_outputs = Some(output);
-                                ss.transit(ServerState::CompletedFhe);
-                                println!("FHE computation completed");
-                            })
-                        },
-                    )
-                    .unwrap();
-            });
-            ss.transit(ServerState::RunningFhe);
-            Ok(Json(ServerState::RunningFhe))
-        }
-        ServerState::RunningFhe => Ok(Json(ServerState::RunningFhe)),
-        ServerState::CompletedFhe => Ok(Json(ServerState::CompletedFhe)),
+            let response = ss.idempotency.remember(&key, ServerState::RunningFhe);
+            start_run(&room, &mut ss, telemetry)?;
+            Ok(response)
+        }
+        ServerState::RunningFhe => Ok(Idempotent::Fresh(Json(ServerState::RunningFhe))),
+        ServerState::CompletedFhe => Ok(Idempotent::Fresh(Json(ServerState::CompletedFhe))),
         _ => Err(Error::WrongServerState {
             expect: ServerState::ReadyForRunning.to_string(),
             got: ss.state.to_string(),
@@ -131,82 +1111,644 @@ async fn run(ss: &State<MutexServerStorage>) -> Result<Json<ServerState>, ErrorR
     }
 }
 
-#[get("/fhe_output")]
+/// The admin aborts the running FHE computation. The room returns to `ReadyForRunning`
+/// once the outputs in progress finish, see `/run/status`.
+#[post("/rooms/<room_id>/run/cancel")]
+async fn cancel_run(
+    room_id: RoomId,
+    lobby: &State<Lobby>,
+    admin: Result<AdminGuard, Error>,
+) -> Result<Json<ServerState>, ErrorResponse> {
+    admin?;
+    let room = lobby.get(room_id).await?;
+    let ss = room.storage.lock().await;
+    ss.ensure(ServerState::RunningFhe)?;
+    room.jobs.cancel();
+    info!(room_id, "Cancelling the FHE run");
+    Ok(Json(ss.state.clone()))
+}
+
+/// Push the room's changes as they happen, so clients don't have to poll `/dashboard`
+#[get("/rooms/<room_id>/events")]
+async fn subscribe_events(
+    room_id: RoomId,
+    upgrade: WebSocketUpgrade,
+    lobby: &State<Lobby>,
+) -> Result<EventChannel, ErrorResponse> {
+    let room = lobby.get(room_id).await?;
+    Ok(EventChannel::new(upgrade, room.dashboard.clone()))
+}
+
+/// A user publishes where peers can fetch their decryption shares if the server goes down
+#[post("/rooms/<room_id>/contact/<participant_id>", data = "<contact>")]
+async fn publish_contact(
+    contact: Json<String>,
+    participant_id: ParticipantId,
+    room_id: RoomId,
+    auth: UserAuth,
+    lobby: &State<Lobby>,
+) -> Result<Json<RegisteredUser>, ErrorResponse> {
+    let room = lobby.get(room_id).await?;
+    let mut ss = room.storage.lock().await;
+    let user = ss.get_participant(&participant_id)?;
+    user.authorize(&auth)?;
+    user.contact = Some(contact.0);
+    let registered = RegisteredUser::from(&*user);
+    ss.save();
+    Ok(Json(registered))
+}
+
+/// Every state change of the room, for auditing
+#[get("/rooms/<room_id>/transitions")]
+async fn get_transitions(
+    room_id: RoomId,
+    lobby: &State<Lobby>,
+) -> Result<Json<Vec<Transition>>, ErrorResponse> {
+    let room = lobby.get(room_id).await?;
+    let transitions = room.storage.lock().await.transitions.clone();
+    Ok(Json(transitions))
+}
+
+/// The user's status, what the server waits on from them, and until when
+#[get("/rooms/<room_id>/users/<user_id>/status")]
+async fn get_user_status(
+    room_id: RoomId,
+    user_id: UserId,
+    lobby: &State<Lobby>,
+) -> Result<Json<UserProgress>, ErrorResponse> {
+    let room = lobby.get(room_id).await?;
+    let ss = room.storage.lock().await;
+    let user = ss
+        .users
+        .get(user_id)
+        .ok_or(Error::UnregisteredUser { user_id })?;
+    Ok(Json(UserProgress::new(&ss, user)))
+}
+
+/// Every change to the room from `since` on, across rounds. Observers poll with the
+/// `next_seq` of their [`crate::RoomHistory`] to catch up.
+#[get("/rooms/<room_id>/log?<since>")]
+async fn get_log(
+    since: Option<u64>,
+    room_id: RoomId,
+    lobby: &State<Lobby>,
+) -> Result<Json<Vec<LogEntry>>, ErrorResponse> {
+    let room = lobby.get(room_id).await?;
+    let ss = room.storage.lock().await;
+    let since = since.unwrap_or_default().min(ss.log.len() as u64) as usize;
+    Ok(Json(ss.log[since..].to_vec()))
+}
+
+/// Hash of every cipher, key share and decryption shares the server accepted, across rounds.
+/// Users check theirs against [`crate::artifact_hash`] of what they sent.
+#[get("/rooms/<room_id>/transcript")]
+async fn get_transcript(
+    room_id: RoomId,
+    lobby: &State<Lobby>,
+) -> Result<Json<Vec<TranscriptEntry>>, ErrorResponse> {
+    let room = lobby.get(room_id).await?;
+    let transcript = room.storage.lock().await.transcript.clone();
+    Ok(Json(transcript))
+}
+
+/// The admin mints `count` invite codes to hand out. From then on, registering takes one.
+#[post("/rooms/<room_id>/admin/invites?<count>")]
+async fn create_invites(
+    count: usize,
+    room_id: RoomId,
+    lobby: &State<Lobby>,
+    admin: Result<AdminGuard, Error>,
+    key: IdempotencyKey,
+) -> Result<Idempotent<Vec<String>>, ErrorResponse> {
+    admin?;
+    let room = lobby.get(room_id).await?;
+    let mut ss = room.storage.lock().await;
+    if let Some(replayed) = ss.idempotency.replay(&key) {
+        return Ok(replayed);
+    }
+    let codes = ss.create_invites(count)?;
+    info!(room_id, count, "Invite codes created");
+    let response = ss.idempotency.remember(&key, codes);
+    ss.save();
+    Ok(response)
+}
+
+/// The admin removes a user who registered and disappeared. The remaining users are re-indexed
+/// and have to submit again if they already did.
+#[post("/rooms/<room_id>/admin/users/<user_id>/remove")]
+async fn remove_user(
+    user_id: UserId,
+    room_id: RoomId,
+    lobby: &State<Lobby>,
+    admin: Result<AdminGuard, Error>,
+    key: IdempotencyKey,
+) -> Result<Idempotent<Dashboard>, ErrorResponse> {
+    admin?;
+    let room = lobby.get(room_id).await?;
+    let mut ss = room.storage.lock().await;
+    if let Some(replayed) = ss.idempotency.replay(&key) {
+        return Ok(replayed);
+    }
+    let name = ss.remove_user(user_id)?;
+    info!(room_id, name, "User removed");
+    let dashboard = ss.get_dashboard();
+    let response = ss.idempotency.remember(&key, dashboard);
+    ss.save();
+    Ok(response)
+}
+
+/// The admin starts a new round in the room with a fresh seed.
+/// A running FHE computation is only discarded with `?force=true`.
+#[post("/rooms/<room_id>/admin/reset?<force>")]
+async fn reset(
+    force: bool,
+    room_id: RoomId,
+    lobby: &State<Lobby>,
+    admin: Result<AdminGuard, Error>,
+    key: IdempotencyKey,
+) -> Result<Idempotent<ServerState>, ErrorResponse> {
+    admin?;
+    let room = lobby.get(room_id).await?;
+    let mut ss = room.storage.lock().await;
+    if let Some(replayed) = ss.idempotency.replay(&key) {
+        return Ok(replayed);
+    }
+    if ss.state == ServerState::RunningFhe && !force {
+        return Err(Error::RunInProgress.into());
+    }
+    let seed = ss.config.next_seed();
+    ss.reset(seed);
+    room.jobs.reset();
+    room.uploads.lock().await.clear();
+    info!(room_id, round = ss.round, "Room reset");
+    let state = ss.state.clone();
+    let response = ss.idempotency.remember(&key, state);
+    ss.save();
+    Ok(response)
+}
+
+/// Progress of the FHE run. The server completes on its own, no need to re-trigger `/run`.
+#[get("/rooms/<room_id>/run/status")]
+async fn get_run_status(
+    room_id: RoomId,
+    lobby: &State<Lobby>,
+) -> Result<Json<JobStatus>, ErrorResponse> {
+    let room = lobby.get(room_id).await?;
+    Ok(Json(room.jobs.status()))
+}
+
+/// Every output once the run completes, as msgpack if the client accepts it
+#[get("/rooms/<room_id>/fhe_output")]
 async fn get_fhe_output(
-    ss: &State<MutexServerStorage>,
-) -> Result<Json<CircuitOutput>, ErrorResponse> {
-    let ss = ss.lock().await;
-    ss.ensure(ServerState::CompletedFhe)?;
-    let output = ss
-        .fhe_outputs
-        .clone()
-        .expect("Should exist after CompletedFhe");
-    Ok(Json(output))
-}
-
-/// The user submits the ciphertext
-#[post("/submit_decryption_shares", data = "<submission>", format = "msgpack")]
+    room_id: RoomId,
+    lobby: &State<Lobby>,
+) -> Result<Negotiated<CircuitOutput>, ErrorResponse> {
+    let room = lobby.get(room_id).await?;
+    let output = {
+        let ss = room.storage.lock().await;
+        ss.ensure(ServerState::CompletedFhe)?;
+        ss.fhe_outputs
+            .clone()
+            .expect("Should exist after CompletedFhe")
+    };
+    Ok(Negotiated(Arc::unwrap_or_clone(output)))
+}
+
+/// One output, as soon as it's computed. `partial` tells whether the run is still going.
+#[get("/rooms/<room_id>/fhe_output/<output_id>")]
+async fn get_fhe_output_word(
+    output_id: usize,
+    room_id: RoomId,
+    lobby: &State<Lobby>,
+) -> Result<Negotiated<FheOutput>, ErrorResponse> {
+    let room = lobby.get(room_id).await?;
+    let output = room.storage.lock().await.get_fhe_output(output_id)?;
+    Ok(Negotiated(output))
+}
+
+/// msgpack for clients that prefer it in `Accept`, JSON otherwise. Ciphertexts are far
+/// smaller and faster to parse as msgpack, JSON is there for debugging.
+struct Negotiated<T>(T);
+
+impl<'r, T: Serialize> Responder<'r, 'static> for Negotiated<T> {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let msgpack = req
+            .accept()
+            .is_some_and(|accept| accept.preferred().media_type().is_msgpack());
+        if msgpack {
+            MsgPack(self.0).respond_to(req)
+        } else {
+            Json(self.0).respond_to(req)
+        }
+    }
+}
+
+/// The user submits decryption shares for all outputs
+#[post(
+    "/rooms/<room_id>/submit_decryption_shares",
+    data = "<submission>",
+    format = "msgpack"
+)]
 async fn submit_decryption_shares(
-    submission: MsgPack<DecryptionShareSubmission>,
-    ss: &State<MutexServerStorage>,
-) -> Result<Json<UserId>, ErrorResponse> {
-    let user_id = submission.user_id;
-    let mut ss = ss.lock().await;
-    let decryption_shares = ss
-        .get_user(user_id)?
+    submission: Result<Submission<DecryptionShareSubmission>, Error>,
+    room_id: RoomId,
+    auth: UserAuth,
+    lobby: &State<Lobby>,
+    signer: &State<ReceiptSigner>,
+    key: IdempotencyKey,
+) -> Result<Idempotent<Receipt>, ErrorResponse> {
+    let Submission(submission) = submission?;
+    let artifact = artifact_hash(&submission);
+    let DecryptionShareSubmission {
+        participant_id,
+        decryption_shares,
+    } = submission;
+    let shares_digest = artifact_digest(&decryption_shares);
+    let room = lobby.open(room_id).await?;
+    let mut ss = room.storage.lock().await;
+    let user = ss.get_participant(&participant_id)?;
+    user.authorize(&auth)?;
+    if let Some(replayed) = ss.idempotency.replay(&key) {
+        return Ok(replayed);
+    }
+    let user = ss.get_participant(&participant_id)?;
+    let signed = user.check_signature(&artifact, &auth)?;
+    let user_id = user.id;
+    let slot = user
         .storage
         .get_mut_decryption_shares()
         .ok_or(Error::OutputNotReady)?;
-    *decryption_shares = Some(submission.decryption_shares.to_vec());
-    Ok(Json(user_id))
+    *slot = Some(decryption_shares);
+    user.signatures.extend(signed);
+    let receipt = signer.sign(room_id, participant_id.clone(), ss.state.clone(), artifact);
+    let response = ss.idempotency.remember(&key, receipt);
+    ss.record(
+        &participant_id,
+        TranscriptArtifact::DecryptionShares,
+        shares_digest,
+    );
+    ss.save_user(user_id);
+    Ok(response)
 }
 
-#[get("/decryption_share/<fhe_output_id>/<user_id>")]
+/// The share `participant_id` made for decrypting output `output_id`
+#[get("/rooms/<room_id>/decryption_share/<output_id>/<participant_id>")]
 async fn get_decryption_share(
-    fhe_output_id: usize,
-    user_id: UserId,
-    ss: &State<MutexServerStorage>,
+    output_id: usize,
+    participant_id: ParticipantId,
+    room_id: RoomId,
+    lobby: &State<Lobby>,
 ) -> Result<Json<DecryptionShare>, ErrorResponse> {
-    let mut ss: tokio::sync::MutexGuard<ServerStorage> = ss.lock().await;
-    let decryption_shares = ss
-        .get_user(user_id)?
+    let room = lobby.get(room_id).await?;
+    let mut ss = room.storage.lock().await;
+    ss.fhe_outputs.as_ref().ok_or(Error::OutputNotReady)?;
+    let decryption_share = ss
+        .get_participant(&participant_id)?
         .storage
         .get_mut_decryption_shares()
-        .cloned()
         .ok_or(Error::OutputNotReady)?
+        .as_ref()
+        .and_then(|shares| shares.get(output_id))
         .ok_or(Error::DecryptionShareNotFound {
-            output_id: fhe_output_id,
-            user_id,
+            output_id,
+            participant_id,
+        })?
+        .clone();
+    Ok(Json(decryption_share))
+}
+
+/// Every decryption share submitted so far in one msgpack map, instead of a request per share
+#[get("/rooms/<room_id>/decryption_shares")]
+async fn get_decryption_shares(
+    room_id: RoomId,
+    lobby: &State<Lobby>,
+) -> Result<MsgPack<DecryptionSharesMap>, ErrorResponse> {
+    let room = lobby.get(room_id).await?;
+    let shares = room.storage.lock().await.get_decryption_shares(None)?;
+    Ok(MsgPack(shares))
+}
+
+/// Like `/decryption_shares`, without the shares `user_id` made and already has
+#[get("/rooms/<room_id>/decryption_shares/missing/<user_id>")]
+async fn get_missing_decryption_shares(
+    user_id: UserId,
+    room_id: RoomId,
+    lobby: &State<Lobby>,
+) -> Result<MsgPack<DecryptionSharesMap>, ErrorResponse> {
+    let room = lobby.get(room_id).await?;
+    let shares = room
+        .storage
+        .lock()
+        .await
+        .get_decryption_shares(Some(user_id))?;
+    Ok(MsgPack(shares))
+}
+
+/// Which decryption shares are in, per output and per user, so clients can tell who they're waiting for
+#[get("/rooms/<room_id>/decryption_status")]
+async fn get_decryption_status(
+    room_id: RoomId,
+    lobby: &State<Lobby>,
+) -> Result<Json<DecryptionStatus>, ErrorResponse> {
+    let room = lobby.get(room_id).await?;
+    let status = room.storage.lock().await.get_decryption_status()?;
+    Ok(Json(status))
+}
+
+/// The decrypted karma of every user, once all decryption shares are in. Off unless `server_results` is set.
+#[get("/rooms/<room_id>/results")]
+async fn get_results(
+    room_id: RoomId,
+    lobby: &State<Lobby>,
+) -> Result<Json<RoundResult>, ErrorResponse> {
+    Ok(Json(decrypt_results(room_id, lobby).await?))
+}
+
+#[derive(FromFormField)]
+enum ExportFormat {
+    Csv,
+    Json,
+}
+
+#[derive(Responder)]
+enum Export {
+    #[response(content_type = "text/csv")]
+    Csv(String),
+    Json(Json<SignedResults>),
+}
+
+/// The results as a `name,karma` table, or as JSON signed with the receipt key to archive
+#[get("/rooms/<room_id>/results/export?<format>")]
+async fn export_results(
+    room_id: RoomId,
+    format: ExportFormat,
+    lobby: &State<Lobby>,
+    signer: &State<ReceiptSigner>,
+) -> Result<Export, ErrorResponse> {
+    let result = decrypt_results(room_id, lobby).await?;
+    Ok(match format {
+        ExportFormat::Csv => Export::Csv(result.to_csv()),
+        ExportFormat::Json => Export::Json(Json(signer.sign_results(result))),
+    })
+}
+
+/// Decrypt the results and count them in the room's lifetime karma
+async fn decrypt_results(room_id: RoomId, lobby: &Lobby) -> Result<RoundResult, Error> {
+    let room = lobby.get(room_id).await?;
+    let job = ResultsJob::new(&*room.storage.lock().await, room_id)?;
+    let owners = job.owners();
+    let result = tokio::task::spawn_blocking(move || job.decrypt())
+        .await
+        .map_err(|err| task_failed("Decrypting the results", err))?
+        .map_err(|err| Error::TaskFailed {
+            task: "Decrypting the results",
+            reason: err.to_string(),
+        })?;
+    let karma = zip(owners, result.balances.iter().copied())
+        .filter_map(|(owner, balance)| Some((owner?, balance)))
+        .collect();
+    room.storage.lock().await.count_results(result.round, karma);
+    Ok(result)
+}
+
+/// An archive and its [`ArchiveSignature`]
+#[derive(Responder)]
+#[response(content_type = "binary")]
+struct SignedArchive {
+    bytes: Vec<u8>,
+    signature: Header<'static>,
+}
+
+/// Download the record of a completed session in the archival format, signed with the receipt
+/// key. It changes nothing, see [`acknowledge_archive`] for dropping the inputs.
+#[get("/rooms/<room_id>/archive")]
+async fn archive(
+    room_id: RoomId,
+    lobby: &State<Lobby>,
+    signer: &State<ReceiptSigner>,
+) -> Result<SignedArchive, ErrorResponse> {
+    let room = lobby.get(room_id).await?;
+    let archive = room.storage.lock().await.get_archive()?;
+    info!(room_id, "Archiving room");
+    // Compress without holding the lock
+    let bytes = tokio::task::spawn_blocking(move || archive.to_bytes())
+        .await
+        .map_err(|err| task_failed("Archiving", err))?;
+    let signature = signer.sign_archive(room_id, &bytes);
+    Ok(SignedArchive {
+        bytes,
+        signature: Header::new(
+            ARCHIVE_SIGNATURE_HEADER,
+            json::to_string(&signature).expect("serializable"),
+        ),
+    })
+}
+
+/// The admin has stored the archive signed with `signature`, which is the record of the round
+/// from then on, so the server drops what's left of its inputs. Returns how many cipher and
+/// key share files were removed.
+#[post("/rooms/<room_id>/archive", data = "<signature>")]
+async fn acknowledge_archive(
+    room_id: RoomId,
+    signature: Json<ArchiveSignature>,
+    lobby: &State<Lobby>,
+    signer: &State<ReceiptSigner>,
+    admin: Result<AdminGuard, Error>,
+) -> Result<Json<usize>, ErrorResponse> {
+    admin?;
+    if signature.room_id != room_id {
+        return Err(Error::UnknownArchive {
+            reason: format!("It is of room #{}", signature.room_id),
+        }
+        .into());
+    }
+    signature
+        .verify_signature(&signer.public_key())
+        .map_err(|err| Error::UnknownArchive {
+            reason: err.to_string(),
         })?;
-    Ok(Json(decryption_shares[fhe_output_id].clone()))
+    let room = lobby.get(room_id).await?;
+    let mut ss = room.storage.lock().await;
+    if ss.state != ServerState::CompletedFhe {
+        return Err(Error::WrongServerState {
+            expect: ServerState::CompletedFhe.to_string(),
+            got: ss.state.to_string(),
+        }
+        .into());
+    }
+    Ok(Json(room.drop_inputs(&mut ss).await))
 }
 
-pub fn setup(seed: &Seed) {
-    set_parameter_set(PARAMETER);
+/// Ready this thread for the room's FHE, with the seed from `/param` and [`Dashboard::parameter_set`].
+/// The parameters are only settled once registration closes, so generate the client key after.
+pub fn setup(seed: &Seed, parameter: ParameterSet) {
+    set_parameter_set(parameter.selector());
     set_common_reference_seed(*seed);
 }
 
 pub fn rocket() -> Rocket<Build> {
-    let mut seed = [0u8; 32];
-    thread_rng().fill_bytes(&mut seed);
-    setup(&seed);
-
-    rocket::build()
-        .manage(MutexServerStorage::new(Mutex::new(ServerStorage::new(
-            seed,
-        ))))
+    rocket_from(Config::figment())
+}
+
+/// A server of its own on `port` of localhost, with every setting at its default but
+/// `admin_token` and no logs, so `Rocket.toml` and the environment can't point it at real rooms,
+/// e.g. for a demo
+pub fn local_rocket(port: u16, admin_token: &str) -> Rocket<Build> {
+    rocket_from(
+        Figment::from(rocket::Config::default())
+            .merge(("address", "127.0.0.1"))
+            .merge(("port", port))
+            .merge(("admin_token", admin_token))
+            .merge(("log_level", "off")),
+    )
+}
+
+/// The server configured by `figment` rather than `Rocket.toml` and the environment
+pub(crate) fn rocket_from(figment: Figment) -> Rocket<Build> {
+    let rocket = rocket::custom(figment);
+    let server_config = ServerConfig::from_figment(rocket.figment());
+    server_config.log_summary();
+    let config = server_config.room_config();
+    let ServerConfig {
+        telemetry,
+        storage_dir,
+        receipt_key,
+        admin_token,
+        insecure_open_admin,
+        register_rate_limit,
+        cors,
+        worker,
+        worker_servers,
+        shutdown_run_wait_secs,
+        spool_threshold,
+        ..
+    } = server_config;
+    let admin_token = match (admin_token, insecure_open_admin) {
+        (Some(token), _) => Some(token),
+        (None, true) => {
+            warn!("insecure_open_admin is set, anyone may run the admin routes");
+            None
+        }
+        (None, false) => {
+            let token = UserAuth::new_secret();
+            warn!(
+                admin_token = token,
+                "No admin_token set, the admin routes take this one until the server stops"
+            );
+            Some(token)
+        }
+    };
+    let signer = ReceiptSigner::new(receipt_key.as_deref()).expect("Invalid receipt_key");
+    let evaluator = match (worker, worker_servers) {
+        (Some(_), Some(_)) => panic!("Set either `worker` or `worker_servers`"),
+        (Some(command), None) => {
+            info!(
+                command = command.join(" "),
+                "Evaluating in a worker process"
+            );
+            Evaluator::Worker(command)
+        }
+        (None, Some(urls)) => {
+            info!(
+                workers = urls.join(", "),
+                "Sharding the outputs across worker servers"
+            );
+            Evaluator::Workers {
+                urls,
+                admin_token: admin_token.clone(),
+            }
+        }
+        (None, None) => Evaluator::InProcess,
+    };
+    // phantom_zone sets the server key once per process, and each rating derives its own
+    assert!(
+        config.ratings <= 1 || matches!(evaluator, Evaluator::Worker(_)),
+        "`ratings` above 1 need a `worker` process to evaluate each rating in"
+    );
+    let persistence = storage_dir.map(|dir| {
+        info!(dir = %dir.display(), "Persisting rooms");
+        Arc::new(FileStore::new(dir)) as Arc<dyn Persistence>
+    });
+
+    rocket
+        .manage(Lobby::new(persistence, config, evaluator))
+        .attach(AdHoc::on_liftoff("Phase deadlines", move |rocket| {
+            Box::pin(async move {
+                if config.timeouts.is_enabled() {
+                    let lobby = rocket.state::<Lobby>().expect("managed").clone();
+                    tokio::spawn(lobby.enforce_deadlines());
+                }
+            })
+        }))
+        .attach(AdHoc::on_shutdown("Save rooms", move |rocket| {
+            Box::pin(async move {
+                let lobby = rocket.state::<Lobby>().expect("managed");
+                lobby
+                    .shutdown(Duration::from_secs(shutdown_run_wait_secs))
+                    .await;
+            })
+        }))
+        .manage(Telemetry::new(telemetry))
+        .manage(signer)
+        .manage(AdminToken(admin_token))
+        .manage(SpoolThreshold(spool_threshold))
+        .manage(ServerVersion::new(config.parameter))
+        .attach(RegisterRateLimit::new(register_rate_limit))
+        .attach(Compression)
+        .attach(Cors::new(cors))
+        .mount("/", routes![get_version])
+        .register("/", catchers![not_found, default_catcher])
         .mount(
-            "/",
+            API_BASE,
             routes![
+                admin_page,
+                healthz,
+                readyz,
+                get_receipt_key,
+                create_room,
+                list_rooms,
                 get_param,
+                get_circuit,
                 register,
+                register_observer,
                 conclude_registration,
                 get_dashboard,
+                dashboard_events,
+                commit,
                 submit,
+                submit_key_share,
+                submit_cipher,
+                start_upload,
+                get_upload,
+                put_upload_chunk,
+                finish_upload,
+                set_deadline,
+                propose_deadline_extension,
+                ack_deadline_extension,
                 run,
+                get_run_status,
+                cancel_run,
+                create_invites,
+                remove_user,
+                reset,
+                get_transitions,
+                get_transcript,
+                get_log,
+                get_user_status,
+                evaluate_shard,
+                publish_contact,
+                subscribe_events,
                 get_fhe_output,
+                get_fhe_output_word,
                 submit_decryption_shares,
                 get_decryption_share,
+                get_decryption_shares,
+                get_missing_decryption_shares,
+                get_decryption_status,
+                get_results,
+                export_results,
+                archive,
+                acknowledge_archive,
             ],
         )
 }
@@ -0,0 +1,109 @@
+//! What a client holds between the steps of a round, saved so its user can quit and come back
+//! with the same client key. The key decrypts the user's share of every output, so the file is
+//! encrypted under a passphrase.
+use crate::circuit::ParameterSet;
+use crate::dashboard::RegisteredUser;
+use crate::room::RoomId;
+use crate::types::{CircuitOutput, ClientKey, DecryptionSharesMap, Score, Seed};
+use anyhow::{anyhow, bail, Error};
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::{thread_rng, Rng};
+use rocket::serde::msgpack;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+/// Starts every session file, so another file is told apart from a wrong passphrase
+const MAGIC: &[u8; 8] = b"KARMAS01";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct Session {
+    pub name: String,
+    pub room: RoomId,
+    /// As registered, with the token the server wants on the user's submissions. Hand it back
+    /// to a new client with [`crate::WebClient::resume_user`].
+    pub user: Option<RegisteredUser>,
+    /// What [`crate::setup`] takes again before `ck` is of any use
+    pub crs: Option<(Seed, ParameterSet)>,
+    pub ck: Option<ClientKey>,
+    /// As submitted
+    pub scores: Option<Vec<Score>>,
+    pub fhe_output: Option<CircuitOutput>,
+    /// Collected so far, from the server or from peers
+    pub decryption_shares: DecryptionSharesMap,
+    /// Secret key the user registered and signs with, in a session exported to another machine
+    #[serde(default)]
+    pub identity: Option<[u8; 32]>,
+}
+
+impl Session {
+    pub fn new(name: &str, room: RoomId) -> Self {
+        Self {
+            name: name.to_string(),
+            room,
+            ..Default::default()
+        }
+    }
+
+    /// Encrypt the session under `passphrase`, replacing the file only once the new one is
+    /// complete. Only the owner can read it.
+    pub fn save(&self, path: &Path, passphrase: &str) -> Result<(), Error> {
+        let salt: [u8; SALT_LEN] = thread_rng().gen();
+        let nonce: [u8; NONCE_LEN] = thread_rng().gen();
+        let cipher = ChaCha20Poly1305::new(&derive_key(passphrase, &salt)?);
+        // With field names, as `RegisteredUser` skips its token when there's none
+        let plain = msgpack::to_vec(self)?;
+        let sealed = cipher
+            .encrypt(Nonce::from_slice(&nonce), plain.as_slice())
+            .map_err(|_| anyhow!("Failed to encrypt the session"))?;
+
+        let tmp = path.with_extension("tmp");
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&tmp)?;
+        for part in [&MAGIC[..], &salt, &nonce, &sealed] {
+            file.write_all(part)?;
+        }
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path, passphrase: &str) -> Result<Self, Error> {
+        let bytes = fs::read(path)?;
+        let Some(rest) = bytes.strip_prefix(MAGIC) else {
+            bail!("{} isn't a session file", path.display());
+        };
+        if rest.len() < SALT_LEN + NONCE_LEN {
+            bail!("{} is truncated", path.display());
+        }
+        let (salt, rest) = rest.split_at(SALT_LEN);
+        let (nonce, sealed) = rest.split_at(NONCE_LEN);
+        let cipher = ChaCha20Poly1305::new(&derive_key(passphrase, salt)?);
+        let plain = cipher
+            .decrypt(Nonce::from_slice(nonce), sealed)
+            .map_err(|_| {
+                anyhow!(
+                    "Wrong passphrase for {}, or the file was altered",
+                    path.display()
+                )
+            })?;
+        Ok(msgpack::from_slice(&plain)?)
+    }
+}
+
+/// Argon2id, so guessing passphrases of a stolen file is slow
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<Key, Error> {
+    let mut key = Key::default();
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|err| anyhow!("Failed to derive the session key: {err}"))?;
+    Ok(key)
+}
@@ -0,0 +1,64 @@
+use rocket::serde::{json, Deserialize, Serialize};
+use std::{fs::OpenOptions, io::Write, path::PathBuf};
+use tracing::warn;
+
+/// Opt-in via the `telemetry` key in Rocket.toml. Nothing is recorded when both are unset.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(crate) struct TelemetryConfig {
+    /// Append stats as JSON lines to this file
+    file: Option<PathBuf>,
+    /// POST stats as JSON to this URL
+    endpoint: Option<String>,
+}
+
+/// Anonymous performance numbers of one FHE run. No names, seeds or ciphertexts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(crate) struct RunStats {
+    pub(crate) party_count: usize,
+    pub(crate) parameter_set: String,
+    pub(crate) key_aggregation_ms: u128,
+    pub(crate) evaluation_ms: u128,
+    pub(crate) cipher_bytes: u64,
+    pub(crate) server_key_share_bytes: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Telemetry {
+    config: TelemetryConfig,
+}
+
+impl Telemetry {
+    pub(crate) fn new(config: TelemetryConfig) -> Self {
+        Self { config }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.config.file.is_some() || self.config.endpoint.is_some()
+    }
+
+    /// Best effort. Failures are logged and never affect the round.
+    pub(crate) async fn report(&self, stats: &RunStats) {
+        if let Some(file) = &self.config.file {
+            if let Err(err) = append_line(file, stats) {
+                warn!(file = %file.display(), "Telemetry: failed to write: {err}");
+            }
+        }
+        if let Some(endpoint) = &self.config.endpoint {
+            let result = reqwest::Client::new()
+                .post(endpoint)
+                .json(stats)
+                .send()
+                .await;
+            if let Err(err) = result {
+                warn!(endpoint, "Telemetry: failed to post: {err}");
+            }
+        }
+    }
+}
+
+fn append_line(file: &PathBuf, stats: &RunStats) -> std::io::Result<()> {
+    let mut f = OpenOptions::new().create(true).append(true).open(file)?;
+    writeln!(f, "{}", json::to_string(stats).expect("serializable"))
+}
@@ -1,5 +1,10 @@
+use crate::auth::AdminToken;
 use crate::circuit::*;
+use crate::receipt::ReceiptSigner;
+use crate::results::ResultsJob;
+use crate::server::{panic_message, rocket_from, task_failed};
 use crate::types::*;
+use crate::worker::{shard, Evaluator, WorkerJob};
 use crate::*;
 use anyhow::Error;
 use futures::future::join_all;
@@ -7,12 +12,22 @@ use itertools::Itertools;
 use phantom_zone::{gen_client_key, gen_server_key_share, set_parameter_set};
 use rayon::iter::{IntoParallelRefMutIterator, ParallelIterator};
 use rocket::{
+    figment::Figment,
     serde::{msgpack, Deserialize, Serialize},
     Build, Rocket,
 };
-use std::{collections::HashMap, time::Duration};
+use std::{any::Any, collections::HashMap, path::Path, sync::Arc, time::Duration};
 use tokio::time::sleep;
 
+/// `Rocket.toml` and the environment, with the admin routes open unless a test sets `admin_token`
+fn test_figment() -> Figment {
+    rocket::Config::figment().merge(("insecure_open_admin", true))
+}
+
+fn rocket() -> Rocket<Build> {
+    rocket_from(test_figment())
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 // We're not sending the User struct in rockets. This macro is here just for Serde reasons
 #[serde(crate = "rocket::serde")]
@@ -24,6 +39,7 @@ struct User {
     ck: Option<ClientKey>,
     // step 1: get userID
     id: Option<UserId>,
+    participant_id: Option<ParticipantId>,
     total_users: Option<usize>,
     // step 2: assign scores
     scores: Option<Vec<Score>>,
@@ -43,6 +59,7 @@ impl User {
             seed: None,
             ck: None,
             id: None,
+            participant_id: None,
             total_users: None,
             scores: None,
             server_key: None,
@@ -61,8 +78,9 @@ impl User {
         self
     }
 
-    fn set_id(&mut self, id: usize) -> &mut Self {
+    fn set_id(&mut self, id: usize, participant_id: ParticipantId) -> &mut Self {
         self.id = Some(id);
+        self.participant_id = Some(participant_id);
         self
     }
 
@@ -102,23 +120,21 @@ impl User {
     fn gen_decryption_shares(&mut self) -> &mut Self {
         let ck = self.ck.as_ref().expect("already exists");
         let fhe_out = self.fhe_out.as_ref().expect("exists");
-        let my_id = self.id.expect("exists");
+        let me = self.participant_id.as_ref().expect("exists");
 
         let my_decryption_shares = fhe_out.gen_decryption_shares(ck);
-        for (out_id, share) in my_decryption_shares.iter().enumerate() {
-            self.decryption_shares
-                .insert((out_id, my_id), share.to_vec());
-        }
+        self.decryption_shares
+            .extend(fhe_out.share_entries(me, &my_decryption_shares));
         self
     }
 
     fn get_my_shares(&self) -> Vec<DecryptionShare> {
-        let total_users = self.total_users.expect("exist");
-        let my_id = self.id.expect("exists");
-        (0..total_users)
+        let fhe_out = self.fhe_out.as_ref().expect("exists");
+        let me = self.participant_id.as_ref().expect("exists");
+        (0..fhe_out.n())
             .map(|output_id| {
                 self.decryption_shares
-                    .get(&(output_id, my_id))
+                    .get(&(output_id, me.clone()))
                     .expect("exists")
                     .to_owned()
             })
@@ -126,30 +142,28 @@ impl User {
     }
 
     fn decrypt_everything(&self) -> Vec<Score> {
-        let total_users = self.total_users.expect("exist");
         let ck = self.ck.as_ref().expect("already exists");
         let co = self.fhe_out.as_ref().expect("exists");
 
-        let dss = (0..co.n())
-            .map(|output_id| {
-                (0..total_users)
-                    .map(|user_id| {
-                        self.decryption_shares
-                            .get(&(output_id, user_id))
-                            .expect("exists")
-                            .to_owned()
-                    })
-                    .collect_vec()
-            })
-            .collect_vec();
-        co.decrypt(ck, &dss)
+        let dss = co.collect_shares(&self.decryption_shares).expect("exists");
+        co.decrypt(ck, &dss).expect("decrypts")
     }
 }
 
 impl WebClient {
     pub(crate) async fn new_test(rocket: Rocket<Build>) -> Result<Self, Error> {
-        let client = rocket::local::asynchronous::Client::tracked(rocket).await?;
-        Ok(Self::Test(Box::new(client)))
+        let client = Self::from_transport(RocketLocalTransport::new(rocket).await?);
+        client.check_version().await?;
+        Ok(client)
+    }
+
+    /// The Rocket client under a client of [`Self::new_test`], for the server's state
+    fn local(&self) -> &rocket::local::asynchronous::Client {
+        let transport: &dyn Any = self.transport();
+        transport
+            .downcast_ref::<RocketLocalTransport>()
+            .expect("Not a test client")
+            .client()
     }
 }
 
@@ -166,7 +180,6 @@ async fn run_flow_with_n_users(total_users: usize) -> Result<(), Error> {
     for user in users.iter_mut() {
         let seed = client.get_seed().await.unwrap();
         user.assign_seed(seed);
-        user.gen_client_key();
     }
 
     println!("register users");
@@ -174,34 +187,44 @@ async fn run_flow_with_n_users(total_users: usize) -> Result<(), Error> {
     // Register
     for user in users.iter_mut() {
         let reg = client.register(&user.name).await.unwrap();
-        user.set_id(reg.id);
+        user.set_id(reg.id, reg.participant_id);
     }
-    // Conclude the registration
-    client.conclude_registration().await.unwrap();
+    // Conclude the registration, which settles the parameters for the client keys
+    let parameter = client
+        .conclude_registration()
+        .await
+        .unwrap()
+        .parameter_set();
 
     for user in users.iter_mut() {
         let dashboard = client.get_dashboard().await.unwrap();
         user.set_total_users(dashboard.get_names().len());
+        setup(user.seed.as_ref().unwrap(), parameter);
+        user.gen_client_key();
     }
 
-    // Assign scores
-    for user in users.iter_mut() {
-        let scores: Vec<Score> = (0..total_users.try_into().unwrap()).collect_vec();
-        user.assign_scores(&scores);
-    }
-
-    let mut correct_output = vec![];
-    for (my_id, me) in users.iter().enumerate() {
-        let given_out = me.scores.as_ref().unwrap().iter().sum::<Score>();
-        let mut received = 0;
-        for other in users.iter() {
-            received += other.scores.as_ref().unwrap()[my_id];
-        }
-        correct_output.push(received.wrapping_sub(given_out))
+    // Assign scores. Each user's differ, and they add up past `Score::MAX`, so the sums wrap.
+    let scores = (0..total_users)
+        .map(|i| {
+            (0..total_users)
+                .map(|j| {
+                    if i == j {
+                        0
+                    } else {
+                        Score::MAX - (i * total_users + j) as Score
+                    }
+                })
+                .collect_vec()
+        })
+        .collect_vec();
+    for (user, scores) in users.iter_mut().zip(&scores) {
+        user.assign_scores(scores);
     }
+    // What each user received minus what they sent, in plaintext
+    let correct_output = SimulatedParty::expected_balances(&scores);
 
     users.par_iter_mut().for_each(|user| {
-        set_parameter_set(PARAMETER);
+        set_parameter_set(parameter.selector());
         println!("{} Gen cipher", user.name);
         user.gen_cipher();
         time!(
@@ -223,11 +246,9 @@ async fn run_flow_with_n_users(total_users: usize) -> Result<(), Error> {
             println!("cipher_text size {}", cipher_text.len());
             println!("sks size {}", sks.len());
         }
-        println!("Submit cipher and server key");
-        client
-            .submit_cipher(user_id, &cipher_text, &sks)
-            .await
-            .unwrap();
+        println!("Submit server key, then cipher");
+        client.submit_key_share(user_id, sks).await.unwrap();
+        client.submit_cipher(user_id, cipher_text).await.unwrap();
         // Drop here to save mem
         user.server_key = None;
     }
@@ -236,9 +257,10 @@ async fn run_flow_with_n_users(total_users: usize) -> Result<(), Error> {
 
     // Admin runs the FHE computation
     client.trigger_fhe_run().await.unwrap();
-    while client.trigger_fhe_run().await.unwrap() != ServerState::CompletedFhe {
-        sleep(Duration::from_secs(1)).await
-    }
+    client
+        .wait_for(ServerState::CompletedFhe, Duration::from_secs(600))
+        .await
+        .unwrap();
 
     // Users get FHE output, generate decryption shares, and submit decryption shares
     for user in users.iter_mut() {
@@ -248,27 +270,31 @@ async fn run_flow_with_n_users(total_users: usize) -> Result<(), Error> {
         user.gen_decryption_shares();
 
         client
-            .submit_decryption_shares(user.id.expect("exist now"), &user.get_my_shares())
+            .submit_decryption_shares(
+                user.participant_id.as_ref().expect("exist now"),
+                &user.get_my_shares(),
+            )
             .await
             .unwrap();
     }
     // Users acquire all decryption shares they want
     for user in users.iter_mut() {
-        for (output_id, user_id) in (0..total_users).cartesian_product(0..total_users) {
-            if user.decryption_shares.get(&(output_id, user_id)).is_none() {
-                let ds = client
-                    .get_decryption_share(output_id, user_id)
-                    .await
-                    .unwrap();
-                user.decryption_shares.insert((output_id, user_id), ds);
-            }
-        }
+        client
+            .fetch_missing_shares(
+                user.fhe_out.as_ref().unwrap(),
+                &mut user.decryption_shares,
+                4,
+            )
+            .await
+            .unwrap();
     }
     // Users decrypt everything
     println!("Users decrypt everything");
+    let names: Vec<String> = users.iter().map(|user| user.name.clone()).collect();
     for user in users {
         let decrypted_outs = user.decrypt_everything();
-        println!("{} sees {:?}", user.name, decrypted_outs);
+        let balances: Vec<_> = names.iter().zip(&decrypted_outs).collect();
+        println!("{} sees {:?}", user.name, balances);
         assert_eq!(decrypted_outs, correct_output);
     }
     Ok(())
@@ -281,3 +307,2840 @@ async fn full_flow() {
     // run_flow_with_n_users(3).await.unwrap();
     run_flow_with_n_users(4).await.unwrap();
 }
+
+#[test]
+fn deadline_extension_needs_majority() {
+    let mut ss = ServerStorage::new([0u8; 32], ParameterSet::default());
+    for name in ["alice", "bob", "carlos"] {
+        ss.add_user(name);
+    }
+    ss.propose_deadline_extension(100).unwrap();
+    assert_eq!(ss.ack_deadline_extension(0).unwrap(), None);
+    // Acking twice doesn't count twice
+    assert_eq!(ss.ack_deadline_extension(0).unwrap(), None);
+    assert_eq!(ss.ack_deadline_extension(2).unwrap(), Some(100));
+    assert_eq!(ss.deadline, Some(100));
+    assert!(ss.deadline_extension.is_none());
+    // Deadlines only move later
+    assert!(ss.propose_deadline_extension(50).is_err());
+}
+
+#[rocket::async_test]
+async fn deadlines_are_set_once_and_acked_by_their_users() {
+    use rocket::http::{Header, Status};
+
+    let client = WebClient::new_test(rocket()).await.unwrap();
+    let alice = client.register("alice").await.unwrap();
+    client.register("bob").await.unwrap();
+    // Only the phase the deadline closes takes one
+    assert!(client.set_deadline(now() + 100).await.is_err());
+    client.conclude_registration().await.unwrap();
+    assert!(client.set_deadline(now() - 1).await.is_err());
+    let deadline = now() + 100;
+    client.set_deadline(deadline).await.unwrap();
+    assert_eq!(
+        client.get_dashboard().await.unwrap().deadline(),
+        Some(deadline)
+    );
+    let err = client.set_deadline(deadline + 100).await.unwrap_err();
+    assert_eq!(
+        ClientError::code_of(&err),
+        Some(ErrorCode::DeadlineAlreadySet)
+    );
+
+    client
+        .propose_deadline_extension(deadline + 100)
+        .await
+        .unwrap();
+    // Alice can't ack for Bob
+    let response = client
+        .local()
+        .post("/v1/rooms/0/deadline/ack/1")
+        .header(Header::new(
+            "Authorization",
+            format!("Bearer {}", alice.token.as_deref().unwrap()),
+        ))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Forbidden);
+    assert_eq!(client.ack_deadline_extension(0).await.unwrap(), None);
+    assert_eq!(
+        client.ack_deadline_extension(1).await.unwrap(),
+        Some(deadline + 100)
+    );
+}
+
+#[rocket::async_test]
+async fn rooms_are_independent() {
+    let client = WebClient::new_test(rocket()).await.unwrap();
+    let room_id = client.create_room().await.unwrap();
+    assert_eq!(room_id, 1);
+    let other = WebClient::new_test(rocket())
+        .await
+        .unwrap()
+        .with_room(room_id);
+    // A fresh rocket has only room 0
+    assert!(other.get_dashboard().await.is_err());
+
+    client.register("alice").await.unwrap();
+    client.conclude_registration().await.unwrap();
+    let client = client.with_room(room_id);
+    let bob = client.register("bob").await.unwrap();
+    assert_eq!(bob.id, 0);
+    assert_ne!(
+        client.get_seed().await.unwrap(),
+        client.with_room(0).get_seed().await.unwrap()
+    );
+}
+
+#[rocket::async_test]
+async fn archives_are_signed_and_drop_the_inputs_once_acknowledged() {
+    use crate::room::Lobby;
+
+    let dir = tempfile::tempdir().unwrap();
+    let figment = test_figment()
+        .merge(("storage_dir", dir.path()))
+        .merge(("admin_token", "s3cret"));
+    let client = WebClient::new_test(rocket_from(figment)).await.unwrap();
+    client.register("alice").await.unwrap();
+    assert!(client.fetch_archive().await.is_err());
+
+    let local_client = client.local();
+    let lobby = local_client.rocket().state::<Lobby>().unwrap();
+    let room = lobby.get(0).await.unwrap();
+    let cold_dir = room.cold_dir.clone().unwrap();
+    std::fs::create_dir_all(&cold_dir).unwrap();
+    let leftover = cold_dir.join("key-share-0123.msgpack");
+    std::fs::write(&leftover, b"key share").unwrap();
+    {
+        let mut ss = room.storage.lock().await;
+        ss.fhe_outputs = Some(Arc::new(CircuitOutput::new(
+            vec![vec![]],
+            ss.participant_ids(),
+            CircuitId::Karma,
+        )));
+        ss.state = ServerState::CompletedFhe;
+    }
+
+    let (bytes, signature) = client.fetch_archive().await.unwrap();
+    let public_key = client.get_receipt_key().await.unwrap();
+    signature.verify(&public_key, &bytes).unwrap();
+    assert_eq!(signature.room_id, 0);
+    assert!(signature.verify(&public_key, &bytes[1..]).is_err());
+    assert_eq!(
+        SessionArchive::from_bytes(&bytes).unwrap().meta.users.len(),
+        1
+    );
+    // Downloading changes nothing
+    assert!(leftover.exists());
+
+    // Only the admin drops the inputs, with an archive the server signed for the room
+    assert!(client.acknowledge_archive(&signature).await.is_err());
+    let admin = client.with_admin_token("s3cret");
+    let forged = ArchiveSignature {
+        timestamp: signature.timestamp + 1,
+        ..signature.clone()
+    };
+    assert!(admin.acknowledge_archive(&forged).await.is_err());
+    let other_room = ArchiveSignature {
+        room_id: 1,
+        ..signature.clone()
+    };
+    assert!(admin.acknowledge_archive(&other_room).await.is_err());
+    assert!(leftover.exists());
+    assert_eq!(admin.acknowledge_archive(&signature).await.unwrap(), 1);
+    assert!(!leftover.exists());
+}
+
+/// Complete a run of two users from a checkpoint that has every output, so no FHE is needed
+async fn run_from_checkpoint(figment: rocket::figment::Figment) -> WebClient {
+    use crate::checkpoint::Checkpoint;
+    use crate::cold::Cold;
+    use crate::room::Lobby;
+
+    let client = WebClient::new_test(rocket_from(figment)).await.unwrap();
+    client.register("alice").await.unwrap();
+    client.register("bob").await.unwrap();
+    let local_client = client.local();
+    let lobby = local_client.rocket().state::<Lobby>().unwrap();
+    let room = lobby.get(0).await.unwrap();
+    let cold_dir = room.cold_dir.clone().unwrap();
+    {
+        let mut ss = room.storage.lock().await;
+        for user in ss.users.iter_mut() {
+            user.storage = UserStorage::Inputs(UserInputs {
+                cipher: Some(Cold::Disk(cold_dir.join("cipher"))),
+                sks: Some(Cold::Disk(cold_dir.join("key-share"))),
+                version: 1,
+            });
+        }
+        let inputs = ss
+            .users
+            .iter()
+            .map(|user| match &user.storage {
+                UserStorage::Inputs(inputs) => inputs.clone(),
+                _ => unreachable!(),
+            })
+            .collect_vec();
+        let checkpoint = Checkpoint::new(&cold_dir, ss.round, &inputs);
+        checkpoint.save(0, &vec![]);
+        checkpoint.save(1, &vec![]);
+        ss.state = ServerState::ReadyForRunning;
+    }
+
+    client.trigger_fhe_run().await.unwrap();
+    for _ in 0..50 {
+        if client.get_run_status().await.unwrap().completed {
+            break;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    assert!(client.get_run_status().await.unwrap().completed);
+    // Write the snapshots before the test removes the directory
+    lobby.shutdown(Duration::ZERO).await;
+    client
+}
+
+#[rocket::async_test]
+async fn telemetry_reports_runs_only_when_enabled() {
+    use crate::telemetry::{RunStats, Telemetry};
+
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("telemetry.jsonl");
+    let figment = test_figment()
+        .merge(("storage_dir", dir.path().join("on")))
+        .merge(("telemetry.file", &file));
+    let client = run_from_checkpoint(figment).await;
+    assert!(client
+        .local()
+        .rocket()
+        .state::<Telemetry>()
+        .unwrap()
+        .is_enabled());
+    let lines = std::fs::read_to_string(&file).unwrap();
+    let stats = lines
+        .lines()
+        .map(|line| rocket::serde::json::from_str::<RunStats>(line).unwrap())
+        .collect_vec();
+    assert_eq!(stats.len(), 1);
+    assert_eq!(stats[0].party_count, 2);
+    assert_eq!(
+        stats[0].parameter_set,
+        format!("{:?}", ParameterSet::default())
+    );
+    // Both outputs came from the checkpoint, so nothing was aggregated or evaluated
+    assert_eq!(stats[0].key_aggregation_ms, 0);
+    assert_eq!(stats[0].evaluation_ms, 0);
+    // No names, seeds or ciphertexts
+    assert!(!lines.contains("alice"));
+
+    std::fs::remove_file(&file).unwrap();
+    let figment = test_figment().merge(("storage_dir", dir.path().join("off")));
+    let client = run_from_checkpoint(figment).await;
+    assert!(!client
+        .local()
+        .rocket()
+        .state::<Telemetry>()
+        .unwrap()
+        .is_enabled());
+    assert!(!file.exists());
+}
+
+#[test]
+fn archive_round_trip() {
+    let archive = SessionArchive {
+        meta: ArchiveMeta {
+            seed: [7u8; 32],
+            parameter: ParameterSet::default(),
+            archived_at: 1_700_000_000,
+            users: vec![(0, "alice".to_string()), (1, "bob".to_string())],
+        },
+        fhe_output: CircuitOutput::new(vec![], vec![], CircuitId::Karma),
+        decryption_shares: vec![Some(vec![vec![1, 2, 3]]), None],
+    };
+    let bytes = archive.to_bytes();
+    let names = archive::read_index(&bytes)
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect_vec();
+    assert_eq!(names, ["meta", "fhe_output", "decryption_shares"]);
+
+    let decoded = SessionArchive::from_bytes(&bytes).unwrap();
+    assert_eq!(decoded.meta.users, archive.meta.users);
+    assert_eq!(decoded.decryption_shares, archive.decryption_shares);
+    assert!(SessionArchive::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+}
+
+#[test]
+fn tree_reduce_keeps_order() {
+    let concat = |a: &String, b: &String| format!("{a}{b}");
+    for n in 1..=11 {
+        let letters = ('a'..).take(n).map(String::from).collect_vec();
+        assert_eq!(tree_reduce(&letters, concat), Some(letters.concat()));
+    }
+    assert_eq!(tree_reduce(&[] as &[String], concat), None);
+}
+
+#[test]
+fn words_round_trip_at_every_width() {
+    for score in [0i16, 1, 1000, -1, -1000, i16::MIN, i16::MAX] {
+        let bits = u64_to_binary::<SCORE_BITS>(score as u16 as u64);
+        assert_eq!(recover(&bits) as u16 as Score, score);
+    }
+    assert_eq!(recover(&u64_to_binary::<8>(200)), 200);
+    assert_eq!(recover(&u64_to_binary::<32>(70_000)), 70_000);
+    assert_eq!(u64_to_binary::<4>(0b0110), [false, true, true, false]);
+}
+
+#[rocket::async_test]
+async fn rooms_resume_from_disk() {
+    use crate::persist::{FileStore, Persistence};
+    use crate::room::Lobby;
+    use std::sync::Arc;
+
+    let dir = std::env::temp_dir().join(format!("karma-persist-{}", std::process::id()));
+    let backend = || Some(Arc::new(FileStore::new(dir.clone())) as Arc<dyn Persistence>);
+    let config = RoomConfig::default();
+
+    let lobby = Lobby::new(backend(), config, Evaluator::InProcess);
+    let room = lobby.get(0).await.unwrap();
+    let seed = {
+        let mut ss = room.storage.lock().await;
+        ss.add_user("alice");
+        ss.add_user("bob");
+        ss.transit(ServerState::ReadyForInputs).unwrap();
+        ss.seed
+    };
+    lobby.create().await;
+    // Snapshots are written off the lock, and all of them by the time the server stops
+    lobby.shutdown(Duration::ZERO).await;
+
+    let resumed = Lobby::new(backend(), config, Evaluator::InProcess);
+    assert_eq!(resumed.summaries().await.len(), 2);
+    {
+        let ss = resumed.get(0).await.unwrap();
+        let ss = ss.storage.lock().await;
+        assert_eq!(ss.seed, seed);
+        assert_eq!(ss.state, ServerState::ReadyForInputs);
+        assert_eq!(ss.users.len(), 2);
+    }
+    // Resuming saves the rooms again
+    resumed.shutdown(Duration::ZERO).await;
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[rocket::async_test]
+async fn snapshots_land_on_disk_in_order() {
+    use crate::persist::{FileStore, Persistence, RoomStore};
+
+    let dir = tempfile::tempdir().unwrap();
+    let backend = Arc::new(FileStore::new(dir.path().to_path_buf()));
+    let store = RoomStore::new(backend.clone(), 0);
+    let mut ss = ServerStorage::new([0u8; 32], ParameterSet::default()).with_store(store.clone());
+    for i in 0..20 {
+        ss.add_user(&format!("user {i}"));
+    }
+    store.flush().await;
+    let rooms = backend.load().unwrap();
+    assert_eq!(rooms[0].1.users.len(), 20);
+}
+
+#[rocket::async_test]
+async fn shutdown_refuses_new_inputs() {
+    use crate::room::Lobby;
+
+    let lobby = Lobby::new(None, RoomConfig::default(), Evaluator::InProcess);
+    lobby.open(0).await.unwrap();
+    lobby.shutdown(Duration::ZERO).await;
+    assert!(matches!(
+        lobby.open(0).await,
+        Err(crate::types::Error::ShuttingDown)
+    ));
+    // Outputs and the dashboard stay readable until the server is gone
+    lobby.get(0).await.unwrap();
+}
+
+#[test]
+fn illegal_transitions_are_rejected() {
+    let mut ss = ServerStorage::new([0u8; 32], ParameterSet::default());
+    assert!(ss.transit(ServerState::RunningFhe).is_err());
+    ss.transit(ServerState::ReadyForInputs).unwrap();
+    ss.transit(ServerState::ReadyForRunning).unwrap();
+    assert!(ss.transit(ServerState::ReadyForInputs).is_err());
+
+    let log = ss
+        .transitions
+        .iter()
+        .map(|t| (t.from.clone(), t.to.clone()))
+        .collect_vec();
+    assert_eq!(
+        log,
+        vec![
+            (ServerState::ReadyForJoining, ServerState::ReadyForInputs),
+            (ServerState::ReadyForInputs, ServerState::ReadyForRunning),
+        ]
+    );
+}
+
+#[rocket::async_test]
+async fn reset_starts_a_new_round() {
+    let client = WebClient::new_test(rocket()).await.unwrap();
+    let seed = client.get_seed().await.unwrap();
+    client.register("alice").await.unwrap();
+    client.conclude_registration().await.unwrap();
+
+    let state = client.reset_round(false).await.unwrap();
+    assert_eq!(state, ServerState::ReadyForJoining);
+    assert_ne!(client.get_seed().await.unwrap(), seed);
+    assert!(client.get_dashboard().await.unwrap().get_names().is_empty());
+    assert_eq!(client.register("bob").await.unwrap().id, 0);
+}
+
+#[test]
+fn phase_deadlines_are_enforced() {
+    use crate::cold::Cold;
+    use std::path::PathBuf;
+
+    let timeouts = PhaseTimeouts {
+        registration: Some(10),
+        inputs: Some(10),
+        decryption: Some(10),
+    };
+    let room = |commit_reveal| {
+        ServerStorage::new([0u8; 32], ParameterSet::default()).with_config(RoomConfig {
+            timeouts,
+            commit_reveal,
+            ..Default::default()
+        })
+    };
+    let complete = || {
+        UserStorage::Inputs(UserInputs {
+            cipher: Some(Cold::Disk(PathBuf::from("cipher"))),
+            sks: Some(Cold::Disk(PathBuf::from("key-share"))),
+            version: 1,
+        })
+    };
+    let mut ss = room(false);
+    let registration_deadline = ss.registration_deadline.unwrap();
+    // Nobody to run with, registration stays open
+    assert!(!ss.enforce_deadlines(registration_deadline + 1));
+    for name in ["alice", "bob", "carol"] {
+        ss.add_user(name);
+    }
+    assert!(!ss.enforce_deadlines(registration_deadline));
+    assert!(ss.enforce_deadlines(registration_deadline + 1));
+    assert_eq!(ss.state, ServerState::ReadyForInputs);
+
+    let deadline = ss.deadline.unwrap();
+    ss.users[0].storage = UserStorage::Quarantined {
+        reason: "bad".to_string(),
+        sks: None,
+    };
+    ss.users[2].storage = complete();
+    assert!(!ss.enforce_deadlines(deadline));
+    assert!(ss.enforce_deadlines(deadline + 1));
+    // Bob and alice are out, from the last ID, and carol submits again for the new party before a fresh deadline
+    assert_eq!(ss.get_dashboard().get_names(), ["carol"]);
+    assert_eq!(ss.get_dashboard().removed_users(), ["bob", "alice"]);
+    assert_eq!(ss.state, ServerState::ReadyForInputs);
+    assert!(matches!(ss.users[0].storage, UserStorage::Inputs(_)));
+    assert!(!ss.users[0].storage.has_complete_inputs());
+    assert!(ss.deadline.unwrap() >= deadline);
+    ss.ensure_before_deadline().unwrap();
+    assert!(!ss.enforce_deadlines(ss.deadline.unwrap()));
+
+    // The room moves on once the ones left are in
+    ss.users[0].storage = complete();
+    assert!(ss.check_cipher_submission());
+    ss.transit(ServerState::ReadyForRunning).unwrap();
+    ss.get_ciphers_and_sks().unwrap();
+    ss.transit(ServerState::RunningFhe).unwrap();
+    ss.transit(ServerState::CompletedFhe).unwrap();
+
+    // Without everyone's decryption shares the round fails and the next one starts
+    let round = ss.round;
+    let decryption_deadline = ss.decryption_deadline.unwrap();
+    assert!(!ss.enforce_deadlines(decryption_deadline));
+    assert!(ss.enforce_deadlines(decryption_deadline + 1));
+    assert_eq!(ss.round, round + 1);
+    assert_eq!(ss.state, ServerState::ReadyForJoining);
+    assert!(ss.users.is_empty());
+
+    // So does a round nobody submitted to
+    ss.add_user("dave");
+    ss.close_registration().unwrap();
+    assert!(ss.enforce_deadlines(ss.deadline.unwrap() + 1));
+    assert_eq!(ss.round, round + 2);
+    assert_eq!(ss.state, ServerState::ReadyForJoining);
+
+    // Commitments depend on the party too, so they reopen
+    let mut ss = room(true);
+    for name in ["alice", "bob"] {
+        ss.add_user(name);
+    }
+    ss.close_registration().unwrap();
+    ss.transit(ServerState::ReadyForInputs).unwrap();
+    ss.users[1].storage = complete();
+    assert!(ss.enforce_deadlines(ss.deadline.unwrap() + 1));
+    assert_eq!(ss.state, ServerState::ReadyForCommitments);
+    assert_eq!(ss.get_dashboard().get_names(), ["bob"]);
+    assert!(ss.deadline.is_none());
+}
+
+#[test]
+fn receipts_verify_against_the_server_key() {
+    let signer = ReceiptSigner::new(None).unwrap();
+    let submission = vec![1u64, 2, 3];
+    let receipt = signer.sign(
+        0,
+        ParticipantId::random(),
+        ServerState::ReadyForInputs,
+        artifact_hash(&submission),
+    );
+    receipt.verify(&signer.public_key()).unwrap();
+    receipt.ensure_covers(&submission).unwrap();
+    assert!(receipt.ensure_covers(&vec![1u64, 2]).is_err());
+
+    let other = ReceiptSigner::new(None).unwrap();
+    assert!(receipt.verify(&other.public_key()).is_err());
+    let mut forged = receipt.clone();
+    forged.body.timestamp += 1;
+    assert!(forged.verify(&signer.public_key()).is_err());
+}
+
+#[test]
+fn removing_a_user_reindexes_the_rest() {
+    let mut ss = ServerStorage::new([0u8; 32], ParameterSet::default());
+    for name in ["alice", "bob", "carlos"] {
+        ss.add_user(name);
+    }
+    let carlos = ss.users[2].participant_id.clone();
+    ss.transit(ServerState::ReadyForInputs).unwrap();
+    ss.propose_deadline_extension(100).unwrap();
+    ss.ack_deadline_extension(2).unwrap();
+    ss.users[0].storage = UserStorage::Quarantined {
+        reason: "bad".to_string(),
+        sks: None,
+    };
+
+    assert_eq!(ss.remove_user(1).unwrap(), "bob");
+    assert_eq!(ss.users.len(), 2);
+    assert_eq!(ss.get_participant(&carlos).unwrap().id, 1);
+    assert!(!ss.users[0].storage.get_inputs().is_complete());
+    assert_eq!(ss.get_dashboard().users()[0].status, UserStatus::IDAcquired);
+    assert_eq!(ss.deadline_extension.as_ref().unwrap().acks, vec![1]);
+    assert_eq!(ss.get_dashboard().removed_users(), ["bob"]);
+    assert!(ss.remove_user(5).is_err());
+}
+
+#[test]
+fn dashboard_changes_tell_what_happened_in_between() {
+    let mut ss = ServerStorage::new([0u8; 32], ParameterSet::default());
+    for name in ["alice", "bob"] {
+        ss.add_user(name);
+    }
+    let before = ss.get_dashboard();
+    ss.add_user("carlos");
+    ss.remove_user(1).unwrap();
+    ss.add_observer("olivia");
+    ss.transit(ServerState::ReadyForInputs).unwrap();
+    ss.users[0].storage = UserStorage::Quarantined {
+        reason: "bad".to_string(),
+        sks: None,
+    };
+
+    let after = ss.get_dashboard();
+    assert_eq!(
+        after.changes_since(&before),
+        vec![
+            DashboardChange::UserLeft {
+                name: "bob".to_string()
+            },
+            DashboardChange::StatusChanged {
+                name: "alice".to_string(),
+                from: UserStatus::IDAcquired,
+                to: UserStatus::Quarantined {
+                    reason: "bad".to_string(),
+                    key_share: false
+                }
+            },
+            DashboardChange::UserJoined {
+                user_id: 1,
+                name: "carlos".to_string()
+            },
+            DashboardChange::ObserverJoined {
+                name: "olivia".to_string()
+            },
+            DashboardChange::StateChanged {
+                from: ServerState::ReadyForJoining,
+                to: ServerState::ReadyForInputs
+            },
+        ]
+    );
+    assert!(after.changes_since(&after).is_empty());
+}
+
+#[rocket::async_test]
+async fn dashboard_reads_skip_the_storage_lock() {
+    use crate::room::Lobby;
+
+    let lobby = Lobby::new(None, RoomConfig::default(), Evaluator::InProcess);
+    let room = lobby.get(0).await.unwrap();
+    let mut ss = room.storage.lock().await;
+    ss.add_user("alice");
+    ss.transit(ServerState::ReadyForInputs).unwrap();
+
+    // Still holding the lock
+    let dashboard = room.dashboard.borrow().clone();
+    assert_eq!(dashboard.get_names(), vec!["alice"]);
+    assert!(dashboard.is_concluded());
+    let summaries = lobby.summaries().await;
+    assert_eq!(summaries[0].users, 1);
+    assert_eq!(summaries[0].status, ServerState::ReadyForInputs);
+}
+
+#[rocket::async_test]
+async fn events_follow_the_room() {
+    use futures::StreamExt;
+
+    let client = WebClient::new_test(rocket()).await.unwrap();
+    let mut events = client.subscribe_events().await.unwrap();
+    client.register("alice").await.unwrap();
+    match events.next().await.unwrap().unwrap() {
+        RoomEvent::UserChanged(user) => assert_eq!(user.name, "alice"),
+        event => panic!("Unexpected {:?}", event),
+    }
+    client.conclude_registration().await.unwrap();
+    assert!(matches!(
+        events.next().await.unwrap().unwrap(),
+        RoomEvent::StateChanged {
+            from: ServerState::ReadyForJoining,
+            to: ServerState::ReadyForInputs
+        }
+    ));
+}
+
+async fn next_sse_dashboard(
+    stream: &mut rocket::local::asynchronous::LocalResponse<'_>,
+) -> crate::dashboard::Dashboard {
+    use tokio::io::AsyncReadExt;
+
+    let mut buf = vec![];
+    while !buf.ends_with(b"\n\n") {
+        buf.push(stream.read_u8().await.unwrap());
+    }
+    let event = String::from_utf8(buf).unwrap();
+    // Rocket's heartbeat comments can land in the same event
+    let data = event
+        .lines()
+        .find_map(|line| line.strip_prefix("data:"))
+        .unwrap();
+    rocket::serde::json::from_str(data).unwrap()
+}
+
+#[rocket::async_test]
+async fn dashboard_events_stream_snapshots() {
+    let client = rocket::local::asynchronous::Client::tracked(rocket())
+        .await
+        .unwrap();
+    let mut stream = client.get("/v1/rooms/0/dashboard/events").dispatch().await;
+    assert!(next_sse_dashboard(&mut stream).await.get_names().is_empty());
+
+    client
+        .post("/v1/rooms/0/register")
+        .body("alice")
+        .dispatch()
+        .await;
+    let dashboard = next_sse_dashboard(&mut stream).await;
+    assert_eq!(dashboard.get_names(), vec!["alice"]);
+}
+
+/// Send chunk `n` of an upload, as `/submit/<session>/chunk/<n>` does
+async fn append(
+    upload: &mut crate::upload::Upload,
+    n: u64,
+    chunk: &[u8],
+    now: std::time::Instant,
+) -> Result<(), types::Error> {
+    if let Some(mut slot) = upload.slot(n).await? {
+        slot.write(chunk).await?;
+        upload.commit(slot, now);
+    }
+    Ok(())
+}
+
+#[rocket::async_test]
+async fn uploads_resume_from_the_last_chunk() {
+    use crate::upload::{Upload, UploadStart};
+    use std::time::Instant;
+
+    let body = rocket::serde::msgpack::to_compact_vec(&(0..10u8).collect_vec()).unwrap();
+    let start = UploadStart {
+        user_id: 0,
+        size: body.len() as u64,
+        chunk_size: 4,
+        kind: Default::default(),
+        compressed: false,
+    };
+    let now = Instant::now();
+    let file = || tempfile::tempfile().unwrap();
+    assert!(matches!(
+        Upload::new(start, (body.len() - 1).into(), file(), now),
+        Err(types::Error::PayloadTooLarge { .. })
+    ));
+    let mut upload = Upload::new(start, body.len().into(), file(), now).unwrap();
+    assert_eq!(upload.progress("s").total_chunks, 3);
+    append(&mut upload, 0, &body[..4], now).await.unwrap();
+    // Chunks can't skip ahead, and resending one is harmless
+    assert!(append(&mut upload, 2, &body[8..], now).await.is_err());
+    append(&mut upload, 0, &body[..4], now).await.unwrap();
+    assert_eq!(upload.progress("s").chunks_received, 1);
+    assert!(upload.ensure_complete().is_err());
+
+    assert!(append(&mut upload, 1, &body[4..7], now).await.is_err());
+    assert!(append(&mut upload, 1, &body[4..9], now).await.is_err());
+    append(&mut upload, 1, &body[4..8], now).await.unwrap();
+    append(&mut upload, 2, &body[8..], now).await.unwrap();
+    upload.ensure_complete().unwrap();
+    let decoded: Vec<u8> = upload.decode(body.len().into()).await.unwrap();
+    assert_eq!(decoded, (0..10u8).collect_vec());
+}
+
+#[test]
+fn uploads_are_capped_per_user_and_dropped_when_idle() {
+    use crate::upload::{Upload, UploadStart, Uploads, MAX_UPLOADS_PER_USER, UPLOAD_IDLE_TIMEOUT};
+    use std::time::{Duration, Instant};
+
+    let upload = |user_id, now| {
+        let start = UploadStart {
+            user_id,
+            size: 1,
+            chunk_size: 1,
+            kind: Default::default(),
+            compressed: false,
+        };
+        Upload::new(start, 1.into(), tempfile::tempfile().unwrap(), now).unwrap()
+    };
+    let mut uploads = Uploads::default();
+    let now = Instant::now();
+    let sessions = (0..=MAX_UPLOADS_PER_USER as u64)
+        .map(|i| uploads.open(upload(0, now + Duration::from_secs(i)), now))
+        .collect_vec();
+    let bob = uploads.open(upload(1, now), now);
+    // Alice's oldest session made room for her newest, and bob's is untouched
+    assert!(uploads.get_mut(&sessions[0], now).is_err());
+    for session in sessions.iter().skip(1).chain([&bob]) {
+        assert!(uploads.get_mut(session, now).is_ok());
+    }
+    let later = now + UPLOAD_IDLE_TIMEOUT + Duration::from_secs(1);
+    assert!(uploads.get_mut(&bob, later).is_err());
+}
+
+#[rocket::async_test]
+async fn uploads_need_the_user_token() {
+    use crate::upload::{UploadProgress, UploadStart};
+    use rocket::http::{Header, Status};
+
+    let client = WebClient::new_test(rocket()).await.unwrap();
+    let alice = client.register("alice").await.unwrap();
+    client.register("bob").await.unwrap();
+    client.conclude_registration().await.unwrap();
+
+    let local_client = client.local();
+    let bearer = Header::new(
+        "Authorization",
+        format!("Bearer {}", alice.token.as_deref().unwrap()),
+    );
+    let start_as = |user_id, auth: Option<Header<'static>>| {
+        let start = UploadStart {
+            user_id,
+            size: 10,
+            chunk_size: 4,
+            kind: Default::default(),
+            compressed: false,
+        };
+        let request = local_client.post("/v1/rooms/0/submit/start").json(&start);
+        match auth {
+            Some(auth) => request.header(auth),
+            None => request,
+        }
+        .dispatch()
+    };
+    assert_eq!(start_as(0, None).await.status(), Status::Forbidden);
+    assert_eq!(
+        start_as(1, Some(bearer.clone())).await.status(),
+        Status::Forbidden
+    );
+    let progress: UploadProgress = start_as(0, Some(bearer.clone()))
+        .await
+        .into_json()
+        .await
+        .unwrap();
+    // Only alice may send chunks to her session
+    let chunk = format!("/v1/rooms/0/submit/{}/chunk/0", progress.session);
+    let response = local_client.put(chunk.clone()).body([0u8; 4]).dispatch();
+    assert_eq!(response.await.status(), Status::Forbidden);
+    let response = local_client.put(chunk).header(bearer).body([0u8; 4]);
+    assert_eq!(response.dispatch().await.status(), Status::Ok);
+}
+
+#[rocket::async_test]
+async fn chunks_of_several_mib_upload_over_http() {
+    use crate::upload::{UploadProgress, UploadStart};
+    use rocket::http::{Header, Status};
+
+    let client = WebClient::new_test(rocket()).await.unwrap();
+    let alice = client.register("alice").await.unwrap();
+    client.register("bob").await.unwrap();
+    client.conclude_registration().await.unwrap();
+
+    let local_client = client.local();
+    let bearer = Header::new(
+        "Authorization",
+        format!("Bearer {}", alice.token.as_deref().unwrap()),
+    );
+    let chunk_size = 4 << 20;
+    let start = UploadStart {
+        user_id: 0,
+        size: chunk_size + 1000,
+        chunk_size,
+        kind: Default::default(),
+        compressed: false,
+    };
+    let progress: UploadProgress = local_client
+        .post("/v1/rooms/0/submit/start")
+        .header(bearer.clone())
+        .json(&start)
+        .dispatch()
+        .await
+        .into_json()
+        .await
+        .unwrap();
+    let put = |n, body: Vec<u8>| {
+        local_client
+            .put(format!("/v1/rooms/0/submit/{}/chunk/{n}", progress.session))
+            .header(bearer.clone())
+            .body(body)
+            .dispatch()
+    };
+    // A chunk longer than its slot is turned away
+    let response = put(0, vec![1; chunk_size as usize + 1]).await;
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+    let response = put(0, vec![1; chunk_size as usize]).await;
+    assert_eq!(response.status(), Status::Ok);
+    let progress: UploadProgress = put(1, vec![2; 1000]).await.into_json().await.unwrap();
+    assert_eq!(progress.chunks_received, 2);
+    assert_eq!(progress.total_chunks, 2);
+}
+
+#[rocket::async_test]
+async fn bodies_are_zstd_compressed_both_ways() -> Result<(), Error> {
+    use crate::compression::{compress, decompress, ZSTD};
+    use rocket::http::Header;
+
+    let body = vec![0u8; 100_000];
+    let compressed = compress(&body);
+    assert!(compressed.len() < 1000);
+    assert_eq!(decompress(&compressed, 100_000.into())?, body);
+    // A small body can't inflate past the limit
+    assert!(matches!(
+        decompress(&compressed, 99_999.into()),
+        Err(types::Error::PayloadTooLarge { .. })
+    ));
+
+    let client = rocket::local::asynchronous::Client::tracked(rocket()).await?;
+    for _ in 0..30 {
+        client.post("/v1/rooms").dispatch().await;
+    }
+    let plain = client.get("/v1/rooms").dispatch().await;
+    assert_eq!(plain.headers().get_one("Content-Encoding"), None);
+    let plain = plain.into_bytes().await.unwrap();
+    let response = client
+        .get("/v1/rooms")
+        .header(Header::new("Accept-Encoding", "gzip, zstd"))
+        .dispatch()
+        .await;
+    assert_eq!(response.headers().get_one("Content-Encoding"), Some(ZSTD));
+    let inflated = zstd::decode_all(&response.into_bytes().await.unwrap()[..])?;
+    assert_eq!(inflated, plain);
+    Ok(())
+}
+
+#[rocket::async_test]
+async fn peers_serve_shares_at_their_contact() {
+    use crate::p2p::{fetch_peer_shares, serve_shares, PeerShares};
+
+    use ed25519_dalek::SigningKey;
+    use rocket::http::Status;
+
+    let client = WebClient::new_test(rocket()).await.unwrap();
+    let key = SigningKey::from_bytes(&[7u8; 32]);
+    let alice = client.register_signed("alice", &key).await.unwrap();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let contact = listener.local_addr().unwrap().to_string();
+    let shares = PeerShares::new(
+        alice.participant_id.clone(),
+        vec![vec![1, 2], vec![3]],
+        Some(&key),
+    );
+    tokio::spawn(serve_shares(listener, shares.clone()));
+    // Only alice may say where her shares are
+    let response = client
+        .local()
+        .post(format!("/v1/rooms/0/contact/{}", alice.participant_id))
+        .json(&"127.0.0.1:1")
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Forbidden);
+    client
+        .publish_contact(&alice.participant_id, &contact)
+        .await
+        .unwrap();
+
+    let dashboard = client.get_dashboard().await.unwrap();
+    let published = &dashboard.users()[0];
+    let public_key = published.public_key.as_deref();
+    let fetched = fetch_peer_shares(published.contact.as_ref().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(fetched.decryption_shares, shares.decryption_shares);
+    fetched
+        .verify(&alice.participant_id, 2, public_key, &[])
+        .unwrap();
+
+    // Shares alice didn't sign, or of another count, are refused
+    let mut forged = fetched.clone();
+    forged.decryption_shares[1] = vec![4];
+    let err = forged
+        .verify(&alice.participant_id, 2, public_key, &[])
+        .unwrap_err();
+    assert!(err.to_string().contains("didn't sign"));
+    assert!(fetched
+        .verify(&alice.participant_id, 3, public_key, &[])
+        .is_err());
+    // Without a key, the transcript's hash of what alice submitted decides
+    let transcript = [TranscriptEntry {
+        round: 0,
+        participant_id: alice.participant_id.clone(),
+        artifact: TranscriptArtifact::DecryptionShares,
+        hash: artifact_hash(&shares.decryption_shares),
+        bytes: 0,
+        at: 0,
+    }];
+    fetched
+        .verify(&alice.participant_id, 2, None, &transcript)
+        .unwrap();
+    assert!(forged
+        .verify(&alice.participant_id, 2, None, &transcript)
+        .is_err());
+    assert!(fetched.verify(&alice.participant_id, 2, None, &[]).is_err());
+}
+
+#[test]
+fn rounds_diff_against_the_latest_earlier_round() {
+    let result = |room, round, names: &[&str], balances: &[Score]| RoundResult {
+        room,
+        round,
+        names: names.iter().map(|n| n.to_string()).collect_vec(),
+        balances: balances.to_vec(),
+    };
+    let history = vec![
+        result(0, 1, &["alice", "bob"], &[5, -5]),
+        result(0, 2, &["alice", "bob"], &[2, -2]),
+        result(1, 3, &["alice", "bob"], &[9, -9]),
+    ];
+    let current = result(0, 4, &["bob", "alice", "carlos"], &[1, 2, -3]);
+    let previous = current.previous(&history).unwrap();
+    assert_eq!(previous.round, 2);
+
+    let trends = current
+        .diff(Some(previous))
+        .into_iter()
+        .map(|diff| diff.trend)
+        .collect_vec();
+    assert_eq!(trends, vec![Trend::Up(3), Trend::Flat, Trend::New]);
+}
+
+#[test]
+fn key_share_and_cipher_are_replaced_independently() {
+    use crate::cold::Cold;
+
+    let dir = std::env::temp_dir().join(format!("karma-inputs-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let [old_sks, new_sks, cipher] = ["old-key-share", "new-key-share", "cipher"].map(|name| {
+        let path = dir.join(name);
+        std::fs::write(&path, b"").unwrap();
+        path
+    });
+
+    let mut inputs = UserInputs {
+        cipher: None,
+        sks: Some(Cold::Disk(old_sks.clone())),
+        version: 0,
+    };
+    assert!(!inputs.is_complete());
+    inputs.update(UserInputs {
+        cipher: Some(Cold::Disk(cipher.clone())),
+        sks: None,
+        version: 0,
+    });
+    assert!(inputs.is_complete() && old_sks.exists());
+    inputs.update(UserInputs {
+        cipher: None,
+        sks: Some(Cold::Disk(new_sks.clone())),
+        version: 0,
+    });
+    assert!(!old_sks.exists() && new_sks.exists() && cipher.exists());
+    assert_eq!(inputs.version, 2);
+    inputs.discard();
+    assert!(!new_sks.exists() && !cipher.exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn checkpoints_follow_the_inputs() {
+    use crate::checkpoint::Checkpoint;
+    use crate::cold::Cold;
+
+    let dir = std::env::temp_dir().join(format!("karma-checkpoint-{}", std::process::id()));
+    let inputs = |cipher: &str| {
+        vec![
+            UserInputs {
+                cipher: Some(Cold::Disk(dir.join(cipher))),
+                sks: Some(Cold::Disk(dir.join("sks"))),
+                version: 0,
+            };
+            2
+        ]
+    };
+    let checkpoint = Checkpoint::new(&dir, 0, &inputs("cipher"));
+    checkpoint.save(1, &vec![]);
+    assert_eq!(checkpoint.load(2), vec![None, Some(vec![])]);
+    // Same inputs after a restart, same checkpoint
+    let resumed = Checkpoint::new(&dir, 0, &inputs("cipher"));
+    assert_eq!(resumed.load(2), vec![None, Some(vec![])]);
+    // A replaced input or a new round starts over
+    assert_eq!(
+        Checkpoint::new(&dir, 0, &inputs("other")).load(2),
+        vec![None, None]
+    );
+    assert_eq!(
+        Checkpoint::new(&dir, 1, &inputs("cipher")).load(2),
+        vec![None, None]
+    );
+    resumed.discard();
+    assert_eq!(checkpoint.load(2), vec![None, None]);
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn status_tracks_each_input() {
+    use crate::cold::Cold;
+    use std::path::PathBuf;
+
+    let mut ss = ServerStorage::new([0u8; 32], ParameterSet::default());
+    ss.add_user("alice");
+    ss.users[0].storage = UserStorage::Inputs(UserInputs {
+        cipher: None,
+        sks: Some(Cold::Disk(PathBuf::from("key-share"))),
+        version: 1,
+    });
+    let status = ss.get_dashboard().users()[0].status.clone();
+    assert_eq!(
+        status,
+        UserStatus::Submitted {
+            cipher: false,
+            key_share: true,
+            version: 1
+        }
+    );
+    assert!(status.has_key_share());
+    assert!(!ss.check_cipher_submission());
+
+    ss.users[0].storage = UserStorage::Quarantined {
+        reason: "bad".to_string(),
+        sks: Some(Cold::Disk(PathBuf::from("key-share"))),
+    };
+    assert!(ss.get_dashboard().users()[0].status.has_key_share());
+    assert!(!ss.check_cipher_submission());
+    assert!(matches!(
+        ss.get_ciphers_and_sks(),
+        Err(types::Error::AllQuarantined)
+    ));
+}
+
+#[test]
+fn quarantined_ciphers_are_left_out_of_the_run() {
+    use crate::cold::Cold;
+    use std::path::PathBuf;
+
+    let mut ss = ServerStorage::new([0u8; 32], ParameterSet::default());
+    for name in ["alice", "bob", "carlos"] {
+        ss.add_user(name);
+    }
+    ss.transit(ServerState::ReadyForInputs).unwrap();
+    let complete = || {
+        UserStorage::Inputs(UserInputs {
+            cipher: Some(Cold::Disk(PathBuf::from("cipher"))),
+            sks: Some(Cold::Disk(PathBuf::from("key-share"))),
+            version: 1,
+        })
+    };
+    let quarantined = |sks: Option<Cold<ServerKeyShare>>| UserStorage::Quarantined {
+        reason: "bad".to_string(),
+        sks,
+    };
+    ss.users[0].storage = complete();
+    ss.users[1].storage = complete();
+    // The server key needs every key share
+    ss.users[2].storage = quarantined(None);
+    assert!(!ss.check_cipher_submission());
+    assert!(ss.get_ciphers_and_sks().is_err());
+    ss.users[2].storage = quarantined(Some(Cold::Disk(PathBuf::from("key-share"))));
+    assert!(ss.check_cipher_submission());
+    // Their key share is in, so the deadline doesn't drop them
+    ss.deadline = Some(1);
+    assert!(!ss.enforce_deadlines(2));
+    ss.transit(ServerState::ReadyForRunning).unwrap();
+
+    let inputs = ss.get_ciphers_and_sks().unwrap();
+    assert_eq!(
+        inputs
+            .iter()
+            .map(|inputs| inputs.cipher.is_some())
+            .collect_vec(),
+        [true, true, false]
+    );
+    assert!(inputs.iter().all(|inputs| inputs.sks.is_some()));
+    assert!(matches!(
+        ss.users[0].storage,
+        UserStorage::DecryptionShare(None)
+    ));
+    assert!(matches!(
+        ss.users[2].storage,
+        UserStorage::Quarantined { .. }
+    ));
+
+    // A cancelled run gives the quarantined user another chance
+    ss.restore_ciphers_and_sks(inputs);
+    assert!(ss.users[0].storage.has_complete_inputs());
+    assert!(matches!(
+        ss.users[2].storage,
+        UserStorage::Quarantined { .. }
+    ));
+
+    // They still decrypt, as their key is part of the server key
+    ss.get_ciphers_and_sks().unwrap();
+    ss.transit(ServerState::RunningFhe).unwrap();
+    ss.transit(ServerState::CompletedFhe).unwrap();
+    assert!(ss
+        .users
+        .iter()
+        .all(|user| matches!(user.storage, UserStorage::DecryptionShare(None))));
+}
+
+#[test]
+fn resubmission_follows_the_policy() {
+    use crate::cold::Cold;
+    use std::path::PathBuf;
+
+    let mut ss = ServerStorage::new([0u8; 32], ParameterSet::default());
+    ss.add_user("alice");
+    ss.state = ServerState::ReadyForInputs;
+    ss.users[0].storage = UserStorage::Inputs(UserInputs {
+        cipher: Some(Cold::Disk(PathBuf::from("cipher"))),
+        sks: None,
+        version: 1,
+    });
+    assert!(ss.ensure_accepts_inputs(0, true, false).is_ok());
+    ss.state = ServerState::ReadyForRunning;
+    assert!(ss.ensure_accepts_inputs(0, true, true).is_ok());
+    assert!(ss.get_dashboard().takes_replacements());
+    ss.state = ServerState::RunningFhe;
+    assert!(matches!(
+        ss.ensure_accepts_inputs(0, true, false),
+        Err(types::Error::WrongServerState { .. })
+    ));
+
+    ss.config.resubmission = ResubmissionPolicy::Reject;
+    ss.state = ServerState::ReadyForInputs;
+    assert!(!ss.get_dashboard().takes_replacements());
+    assert!(matches!(
+        ss.ensure_accepts_inputs(0, true, false),
+        Err(types::Error::AlreadySubmitted { user_id: 0 })
+    ));
+    // The key share is still missing
+    assert!(ss.ensure_accepts_inputs(0, false, true).is_ok());
+    ss.state = ServerState::ReadyForRunning;
+    assert!(ss.ensure_accepts_inputs(0, false, true).is_err());
+}
+
+#[test]
+fn parameters_fit_the_users() {
+    assert_eq!(
+        ParameterSet::for_parties(2),
+        Some(ParameterSet::NonInteractiveLTE2Party)
+    );
+    assert_eq!(
+        ParameterSet::for_parties(3),
+        Some(ParameterSet::NonInteractiveLTE4Party)
+    );
+    assert_eq!(ParameterSet::for_parties(41), None);
+
+    let mut ss = ServerStorage::new([0u8; 32], ParameterSet::default());
+    for name in ["alice", "bob", "carlos", "dave", "eve"] {
+        ss.add_user(name);
+    }
+    ss.close_registration().unwrap();
+    assert_eq!(ss.parameter, ParameterSet::NonInteractiveLTE8Party);
+    assert_eq!(
+        ss.get_dashboard().parameter_set(),
+        ParameterSet::NonInteractiveLTE8Party
+    );
+
+    // Fixed parameters must fit the users too
+    let mut ss = ServerStorage::new([0u8; 32], ParameterSet::default()).with_config(RoomConfig {
+        parameter: Some(ParameterSet::NonInteractiveLTE2Party),
+        ..Default::default()
+    });
+    for name in ["alice", "bob", "carlos"] {
+        ss.add_user(name);
+    }
+    assert!(matches!(
+        ss.close_registration(),
+        Err(types::Error::TooManyUsers { users: 3 })
+    ));
+    assert_eq!(ss.state, ServerState::ReadyForJoining);
+}
+
+#[test]
+fn commitments_come_before_ciphers() {
+    let mut ss = ServerStorage::new([0u8; 32], ParameterSet::default()).with_config(RoomConfig {
+        commit_reveal: true,
+        ..Default::default()
+    });
+    for name in ["alice", "bob", "carlos"] {
+        ss.add_user(name);
+    }
+    ss.close_registration().unwrap();
+    assert_eq!(ss.state, ServerState::ReadyForCommitments);
+    assert!(ss.get_dashboard().is_concluded());
+
+    ss.users[0].commitment = Some("a".to_string());
+    ss.users[2].commitment = Some("c".to_string());
+    assert!(!ss.check_commitments());
+    // The party changed, so everyone commits again
+    ss.remove_user(1).unwrap();
+    assert!(ss.users.iter().all(|user| user.commitment.is_none()));
+
+    for user in ss.users.iter_mut() {
+        user.commitment = Some(user.name.clone());
+    }
+    assert!(ss.check_commitments());
+    assert!(ss.get_dashboard().users().iter().all(|user| user.committed));
+    ss.transit(ServerState::ReadyForInputs).unwrap();
+    assert!(ss.remove_user(0).is_err());
+}
+
+#[rocket::async_test]
+async fn outputs_come_as_msgpack_when_accepted() {
+    use crate::room::Lobby;
+    use rocket::http::{Accept, ContentType};
+
+    let client = WebClient::new_test(rocket()).await.unwrap();
+    client.register("alice").await.unwrap();
+    let local_client = client.local();
+    let lobby = local_client.rocket().state::<Lobby>().unwrap();
+    let room = lobby.get(0).await.unwrap();
+    {
+        let mut ss = room.storage.lock().await;
+        ss.fhe_outputs = Some(Arc::new(CircuitOutput::new(
+            vec![vec![]],
+            ss.participant_ids(),
+            CircuitId::Karma,
+        )));
+        ss.state = ServerState::CompletedFhe;
+    }
+
+    let response = local_client
+        .get("/v1/rooms/0/fhe_output")
+        .header(Accept::MsgPack)
+        .dispatch()
+        .await;
+    assert_eq!(response.content_type(), Some(ContentType::MsgPack));
+    // JSON stays as a fallback for debugging
+    let response = local_client
+        .get("/v1/rooms/0/fhe_output/0")
+        .dispatch()
+        .await;
+    assert_eq!(response.content_type(), Some(ContentType::JSON));
+
+    // The output parses, but words without bits aren't scores
+    let err = client.get_fhe_output().await.unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<InvalidResponse>(),
+        Some(&InvalidResponse::WordLength {
+            output_id: 0,
+            bits: 0,
+            expected: SCORE_BITS,
+        })
+    );
+    assert!(!client.get_fhe_output_word(0).await.unwrap().partial);
+}
+
+#[test]
+fn outputs_are_served_as_they_complete() {
+    let mut ss = ServerStorage::new([0u8; 32], ParameterSet::default());
+    ss.add_user("alice");
+    ss.add_user("bob");
+    assert!(ss.get_fhe_output(0).is_err());
+
+    ss.partial_outputs = vec![None, Some(vec![])];
+    assert!(ss.get_fhe_output(0).is_err());
+    let output = ss.get_fhe_output(1).unwrap();
+    assert!(output.partial);
+    assert_eq!(
+        output.participant_id,
+        Some(ss.users[1].participant_id.clone())
+    );
+
+    ss.fhe_outputs = Some(Arc::new(CircuitOutput::new(
+        vec![vec![], vec![]],
+        ss.participant_ids(),
+        CircuitId::Karma,
+    )));
+    assert!(!ss.get_fhe_output(0).unwrap().partial);
+    assert!(ss.get_fhe_output(2).is_err());
+}
+
+#[rocket::async_test]
+async fn panicked_tasks_become_errors() {
+    let err = tokio::task::spawn_blocking(|| panic!("Out of memory"))
+        .await
+        .unwrap_err();
+    let err = task_failed("Decrypting the results", err);
+    assert_eq!(err.code(), ErrorCode::TaskFailed);
+    assert_eq!(
+        err.to_string(),
+        "Decrypting the results failed: Out of memory"
+    );
+    let output = 3;
+    let err = tokio::task::spawn_blocking(move || panic!("Output {output}"))
+        .await
+        .unwrap_err();
+    assert_eq!(panic_message(err), "Output 3");
+}
+
+#[test]
+fn a_failed_worker_cancels_the_run() {
+    let job = WorkerJob {
+        parameter: ParameterSet::default(),
+        circuit: CircuitId::Karma,
+        server_key_shares: vec![],
+        encrypted_inputs: vec![],
+        output_ids: vec![],
+        carried: vec![],
+    };
+    let evaluator = Evaluator::Worker(vec!["false".to_string()]);
+    let evaluation = evaluator.run(
+        job,
+        &Default::default(),
+        |_| panic!("No keys were aggregated"),
+        |_, _| panic!("No output was computed"),
+    );
+    assert!(evaluation.is_none());
+}
+
+#[test]
+fn shards_cover_every_output_once() {
+    let output_ids = (0..10).collect_vec();
+    let shards = shard(&output_ids, 3);
+    assert_eq!(shards, vec![vec![0, 1, 2, 3], vec![4, 5, 6, 7], vec![8, 9]]);
+    // Never more shards than outputs
+    assert_eq!(shard(&output_ids[..2], 3), vec![vec![0], vec![1]]);
+    assert!(shard(&[], 3).is_empty());
+}
+
+#[rocket::async_test]
+async fn worker_route_checks_the_shard() -> Result<(), Error> {
+    let client = WebClient::new_test(rocket()).await?;
+    let job = |output_ids| WorkerJob {
+        parameter: ParameterSet::default(),
+        circuit: CircuitId::Karma,
+        server_key_shares: vec![],
+        encrypted_inputs: vec![],
+        output_ids,
+        carried: vec![],
+    };
+    let evaluation = client.evaluate_shard(&job(vec![])).await?;
+    assert!(evaluation.outputs.is_empty());
+    let err = client.evaluate_shard(&job(vec![0])).await.unwrap_err();
+    assert!(err.to_string().contains("No output #0 among 0"));
+    Ok(())
+}
+
+#[test]
+fn decryption_shares_come_in_one_map() {
+    let mut ss = ServerStorage::new([0u8; 32], ParameterSet::default());
+    for name in ["alice", "bob", "carlos"] {
+        ss.add_user(name);
+    }
+    assert!(ss.get_decryption_shares(None).is_err());
+    let participants = ss.participant_ids();
+    ss.fhe_outputs = Some(Arc::new(CircuitOutput::new(
+        vec![vec![]; 3],
+        participants.clone(),
+        CircuitId::Karma,
+    )));
+    ss.users[0].storage = UserStorage::DecryptionShare(Some(vec![vec![1], vec![2], vec![3]]));
+    ss.users[1].storage = UserStorage::DecryptionShare(Some(vec![vec![4], vec![5], vec![6]]));
+    ss.users[2].storage = UserStorage::DecryptionShare(None);
+
+    let all = ss.get_decryption_shares(None).unwrap();
+    assert_eq!(all.len(), 6);
+    assert_eq!(all[&(2, participants[1].clone())], vec![6]);
+    let status = ss.get_decryption_status().unwrap();
+    assert_eq!(
+        status.outputs,
+        participants.iter().cloned().map(Some).collect_vec()
+    );
+    assert_eq!(status.users[0].submitted, vec![true; 3]);
+    assert_eq!(status.users[2].submitted, vec![false; 3]);
+    let pending = status.pending();
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].name, "carlos");
+
+    let missing = ss.get_decryption_shares(Some(0)).unwrap();
+    assert_eq!(missing.len(), 3);
+    assert!(missing.keys().all(|(_, from)| from == &participants[1]));
+    assert!(ss.get_decryption_shares(Some(3)).is_err());
+
+    let bytes = msgpack::to_vec(&missing).unwrap();
+    let decoded: DecryptionSharesMap = msgpack::from_slice(&bytes).unwrap();
+    assert_eq!(decoded, missing);
+}
+
+#[rocket::async_test]
+async fn circuit_describes_the_expected_scores() {
+    let client = WebClient::new_test(rocket()).await.unwrap();
+    client.register("alice").await.unwrap();
+    client.register("bob").await.unwrap();
+    let contract = client.get_circuit().await.unwrap();
+    assert_eq!(contract.scores_expected, 2);
+    assert_eq!(contract.self_score_policy, SelfScorePolicy::Ignored);
+
+    let (min, max) = contract.value_range;
+    contract.validate(&[min, max]).unwrap();
+    assert!(contract.validate(&[min]).is_err());
+    assert!(contract.check_input(&vec![vec![]; 2]).is_err());
+    assert!(contract.check_input(&vec![]).is_err());
+
+    // The most anyone can receive still fits in a score with the most users
+    let parties = ParameterSet::NonInteractiveLTE40PartyExperimental.max_parties();
+    let contract = InputContract::new(parties);
+    let (_, max) = contract.value_range;
+    let mut scores = vec![max; parties];
+    scores[0] = 0;
+    contract.validate(&scores).unwrap();
+    assert!(max.checked_mul(parties as Score - 1).is_some());
+    scores[1] = max + 1;
+    assert!(contract.validate(&scores).is_err());
+}
+
+#[test]
+fn ratings_carry_the_outputs_until_the_last() {
+    let mut ss = ServerStorage::new([0u8; 32], ParameterSet::default());
+    ss.config.ratings = 2;
+    for name in ["alice", "bob"] {
+        ss.add_user(name);
+    }
+    ss.close_registration().unwrap();
+    assert_eq!(ss.ratings_left(), 1);
+    let alice = ss.users[0].participant_id.clone();
+    ss.users[0].storage = UserStorage::DecryptionShare(None);
+    ss.state = ServerState::RunningFhe;
+
+    ss.carry_rating(vec![vec![], vec![]]).unwrap();
+    assert_eq!(ss.state, ServerState::ReadyForInputs);
+    assert_eq!(ss.carried.len(), 2);
+    assert_eq!(ss.ratings_left(), 0);
+    assert!(matches!(&ss.users[0].storage, UserStorage::Inputs(inputs) if !inputs.is_complete()));
+    let dashboard = ss.get_dashboard();
+    assert_eq!(dashboard.rating(), (1, 2));
+    assert!(dashboard.awaits_scores(&alice));
+
+    // A new round starts from scratch
+    ss.reset([1u8; 32]);
+    assert!(ss.carried.is_empty());
+    assert_eq!(ss.ratings_left(), 1);
+}
+
+#[test]
+#[should_panic(expected = "need a `worker`")]
+fn ratings_need_a_worker_process() {
+    rocket_from(test_figment().merge(("ratings", 2)));
+}
+
+/// The `worker` binary next to the test binary, which `cargo build --bin worker` puts there
+fn worker_binary() -> String {
+    let exe = std::env::current_exe().unwrap();
+    let path = exe.parent().and_then(Path::parent).unwrap().join("worker");
+    assert!(
+        path.exists(),
+        "Build the worker first: cargo build --bin worker"
+    );
+    path.display().to_string()
+}
+
+/// Two ratings, each evaluated in a worker process of its own, and only their sum decrypted
+#[rocket::async_test]
+async fn full_flow_with_two_ratings() {
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let figment = test_figment()
+        .merge(("address", "127.0.0.1"))
+        .merge(("port", port))
+        .merge(("ratings", 2))
+        .merge(("worker", [worker_binary()]));
+    let server = rocket_from(figment).ignite().await.unwrap();
+    let shutdown = server.shutdown();
+    tokio::spawn(server.launch());
+    let url = format!("http://127.0.0.1:{port}");
+    let admin = WebClient::new(&url);
+
+    let mut alice = Participant::new(WebClient::new(&url));
+    let mut bob = Participant::new(WebClient::new(&url));
+    alice.join("alice").await.unwrap();
+    bob.join("bob").await.unwrap();
+    admin.conclude_registration().await.unwrap();
+    let ratings = [([0, 3], [5, 0]), ([0, 4], [1, 0])];
+    for (rating, (alice_scores, bob_scores)) in ratings.into_iter().enumerate() {
+        alice.rate(&alice_scores).await.unwrap();
+        bob.rate(&bob_scores).await.unwrap();
+        for participant in [&mut alice, &mut bob] {
+            participant.submit().await.unwrap();
+        }
+        admin.trigger_fhe_run().await.unwrap();
+        let expected = match rating {
+            0 => RunOutcome::NextRating,
+            _ => RunOutcome::Decryptable,
+        };
+        for participant in [&mut alice, &mut bob] {
+            assert_eq!(participant.finalize().await.unwrap(), expected);
+        }
+    }
+    // Alice received 5 + 1 and sent 3 + 4
+    for participant in [&mut alice, &mut bob] {
+        let result = participant.reveal().await.unwrap();
+        assert_eq!(result.balances, vec![-1, 1]);
+    }
+    shutdown.notify();
+}
+
+#[test]
+fn results_wait_for_every_share() {
+    let mut ss = ServerStorage::new([0u8; 32], ParameterSet::default());
+    for name in ["alice", "bob"] {
+        ss.add_user(name);
+    }
+    ss.fhe_outputs = Some(Arc::new(CircuitOutput::new(
+        vec![vec![]; 2],
+        ss.participant_ids(),
+        CircuitId::Karma,
+    )));
+    ss.users[0].storage = UserStorage::DecryptionShare(Some(vec![vec![1], vec![2]]));
+    ss.users[1].storage = UserStorage::DecryptionShare(None);
+    assert!(matches!(
+        ResultsJob::new(&ss, 0),
+        Err(types::Error::ResultsDisabled)
+    ));
+
+    ss.config.server_results = true;
+    let bob = ss.users[1].participant_id.clone();
+    assert!(matches!(
+        ResultsJob::new(&ss, 0),
+        Err(types::Error::DecryptionShareNotFound { participant_id, .. }) if participant_id == bob
+    ));
+    ss.users[1].storage = UserStorage::DecryptionShare(Some(vec![vec![3], vec![4]]));
+    assert!(ResultsJob::new(&ss, 0).is_ok());
+}
+
+#[test]
+fn exported_results_are_signed() {
+    let result = RoundResult {
+        room: 0,
+        round: 2,
+        names: vec!["alice".to_string(), "bob, jr".to_string()],
+        balances: vec![3, -3],
+    };
+    assert_eq!(result.to_csv(), "name,karma\nalice,3\n\"bob, jr\",-3\n");
+
+    let signer = ReceiptSigner::new(None).unwrap();
+    let signed = signer.sign_results(result);
+    signed.verify(&signer.public_key()).unwrap();
+    let mut forged = signed.clone();
+    forged.result.balances[1] = 3;
+    assert!(forged.verify(&signer.public_key()).is_err());
+}
+
+#[rocket::async_test]
+async fn registration_takes_an_invite_once_there_are_any() {
+    let client = WebClient::new_test(rocket()).await.unwrap();
+    client.register("alice").await.unwrap();
+    assert_eq!(client.get_dashboard().await.unwrap().invites_left(), None);
+
+    let codes = client.create_invites(2).await.unwrap();
+    assert_eq!(codes.len(), 2);
+    assert_eq!(
+        client.get_dashboard().await.unwrap().invites_left(),
+        Some(2)
+    );
+    assert!(client.register("mallory").await.is_err());
+
+    let client = client.with_invite(&codes[0]);
+    client.register("bob").await.unwrap();
+    // Each code admits one user
+    assert!(client.register("carlos").await.is_err());
+    let dashboard = client.get_dashboard().await.unwrap();
+    assert_eq!(dashboard.invites_left(), Some(1));
+    assert_eq!(dashboard.get_names(), vec!["alice", "bob"]);
+}
+
+#[rocket::async_test]
+async fn registrations_and_submissions_are_limited() {
+    use rocket::http::{ContentType, Status};
+    use std::collections::HashMap;
+
+    let figment = test_figment()
+        .merge((
+            "register_rate_limit",
+            HashMap::from([("requests", 2), ("per_secs", 60)]),
+        ))
+        .merge(("limits.submit", 64));
+    let client = WebClient::new_test(rocket_from(figment)).await.unwrap();
+    let local_client = client.local();
+    let register_from = |ip: &str, name: &'static str| {
+        local_client
+            .post("/v1/rooms/0/register")
+            .remote(format!("{ip}:1234").parse().unwrap())
+            .body(name)
+            .dispatch()
+    };
+    assert_eq!(
+        register_from("10.0.0.1", "alice").await.status(),
+        Status::Ok
+    );
+    assert_eq!(register_from("10.0.0.1", "bob").await.status(), Status::Ok);
+    let response = register_from("10.0.0.1", "mallory").await;
+    assert_eq!(response.status(), Status::TooManyRequests);
+    assert!(response.into_string().await.unwrap().contains("retry in"));
+    // Others aren't held back by one address
+    assert_eq!(
+        register_from("10.0.0.2", "carlos").await.status(),
+        Status::Ok
+    );
+
+    let response = local_client
+        .post("/v1/rooms/0/submit")
+        .header(ContentType::MsgPack)
+        .body(vec![0u8; 65])
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::PayloadTooLarge);
+    assert!(response
+        .into_string()
+        .await
+        .unwrap()
+        .contains("limits.submit"));
+}
+
+#[rocket::async_test]
+async fn transcript_keeps_every_accepted_input() {
+    use crate::room::Lobby;
+
+    let client = WebClient::new_test(rocket()).await.unwrap();
+    let alice = client.register("alice").await.unwrap();
+    assert!(client.get_transcript().await.unwrap().is_empty());
+
+    let local_client = client.local();
+    let lobby = local_client.rocket().state::<Lobby>().unwrap();
+    let room = lobby.get(0).await.unwrap();
+    {
+        let mut ss = room.storage.lock().await;
+        ss.users[0].storage = UserStorage::DecryptionShare(None);
+    }
+    let shares = vec![vec![1u64, 2], vec![3]];
+    client
+        .submit_decryption_shares(&alice.participant_id, &shares)
+        .await
+        .unwrap();
+    room.storage.lock().await.reset([1u8; 32]);
+
+    // The reset starts a new round, but the transcript stays
+    let transcript = client.get_transcript().await.unwrap();
+    assert_eq!(transcript.len(), 1);
+    assert_eq!(transcript[0].round, 0);
+    assert_eq!(transcript[0].participant_id, alice.participant_id);
+    assert_eq!(transcript[0].artifact, TranscriptArtifact::DecryptionShares);
+    assert_eq!(transcript[0].hash, artifact_hash(&shares));
+    assert_eq!(
+        transcript[0].bytes,
+        msgpack::to_compact_vec(&shares).unwrap().len() as u64
+    );
+}
+
+#[rocket::async_test]
+async fn the_log_replays_to_the_room() {
+    let client = WebClient::new_test(rocket()).await.unwrap();
+    client.register("alice").await.unwrap();
+    client.register("bob").await.unwrap();
+    client.register("carol").await.unwrap();
+    client.remove_user(1).await.unwrap();
+    client.conclude_registration().await.unwrap();
+
+    let log = client.get_log(0).await.unwrap();
+    let history = RoomHistory::replay(&log).unwrap();
+    assert_eq!(history.state, ServerState::ReadyForInputs);
+    assert_eq!(history.users, ["alice", "carol"]);
+    assert_eq!(history.next_seq, log.len() as u64);
+
+    // An observer catches up from where it stopped, and a gap is caught
+    client.reset_round(false).await.unwrap();
+    let mut caught_up = history.clone();
+    for entry in client.get_log(history.next_seq).await.unwrap() {
+        caught_up.apply(&entry).unwrap();
+    }
+    assert_eq!(caught_up.round, 1);
+    assert!(caught_up.users.is_empty());
+    assert!(history.clone().apply(&log[0]).is_err());
+}
+
+#[rocket::async_test]
+async fn retries_with_the_same_key_are_replayed() {
+    use rocket::http::{Header, Status};
+
+    let figment = test_figment().merge(("commit_reveal", true));
+    let client = WebClient::new_test(rocket_from(figment)).await.unwrap();
+    let local_client = client.local();
+    let register = |name: &'static str| {
+        local_client
+            .post("/v1/rooms/0/register")
+            .header(Header::new("Idempotency-Key", "k0"))
+            .body(name)
+            .dispatch()
+    };
+    // The response holds the user's token, so registering never replays
+    let alice: RegisteredUser = register("alice").await.into_json().await.unwrap();
+    let retry = register("bob").await;
+    assert!(retry.headers().get_one("Idempotent-Replayed").is_none());
+    let bob: RegisteredUser = retry.into_json().await.unwrap();
+    assert_ne!(alice.token, bob.token);
+    client.conclude_registration().await.unwrap();
+
+    let bearer = |user: &RegisteredUser| {
+        Header::new(
+            "Authorization",
+            format!("Bearer {}", user.token.as_deref().unwrap()),
+        )
+    };
+    let commit = |auth: Option<Header<'static>>, key: &'static str| {
+        let commitment = CipherCommitment {
+            user_id: 0,
+            hash: "ab".to_string(),
+        };
+        let request = local_client
+            .post("/v1/rooms/0/commit")
+            .header(Header::new("Idempotency-Key", key))
+            .json(&commitment);
+        match auth {
+            Some(auth) => request.header(auth),
+            None => request,
+        }
+        .dispatch()
+    };
+    let first = commit(Some(bearer(&alice)), "k1").await;
+    assert!(first.headers().get_one("Idempotent-Replayed").is_none());
+    let first = first.into_string().await.unwrap();
+    let retry = commit(Some(bearer(&alice)), "k1").await;
+    assert_eq!(retry.headers().get_one("Idempotent-Replayed"), Some("true"));
+    assert_eq!(retry.into_string().await.unwrap(), first);
+
+    // Only alice replays her response
+    assert_eq!(commit(None, "k1").await.status(), Status::Forbidden);
+    let response = commit(Some(bearer(&bob)), "k1").await;
+    assert_eq!(response.status(), Status::Forbidden);
+
+    // Another key is another request
+    let again = commit(Some(bearer(&alice)), "k2").await;
+    assert!(again.headers().get_one("Idempotent-Replayed").is_none());
+}
+
+#[rocket::async_test]
+async fn a_fixed_seed_makes_rounds_reproducible() {
+    let seed = [9u8; 32];
+    let figment = test_figment().merge(("seed", hex::encode(seed)));
+    let client = WebClient::new_test(rocket_from(figment)).await.unwrap();
+    assert_eq!(client.get_seed().await.unwrap(), seed);
+    client.reset_round(false).await.unwrap();
+    assert_eq!(client.get_seed().await.unwrap(), seed);
+
+    let room = client.create_room().await.unwrap();
+    let client = client.with_room(room);
+    assert_eq!(client.get_seed().await.unwrap(), seed);
+}
+
+#[rocket::async_test]
+async fn readiness_reports_each_room() {
+    let client = WebClient::new_test(rocket()).await.unwrap();
+    client.healthz().await.unwrap();
+    client.register("alice").await.unwrap();
+    client.register("bob").await.unwrap();
+    client.conclude_registration().await.unwrap();
+
+    let readiness = client.readyz().await.unwrap();
+    assert!(!readiness.running);
+    let room = &readiness.rooms[0];
+    assert_eq!(room.users, 2);
+    assert_eq!(room.status, ServerState::ReadyForInputs);
+    if cfg!(target_os = "linux") {
+        assert!(readiness.memory_bytes.is_some_and(|bytes| bytes > 0));
+    }
+}
+
+#[rocket::async_test]
+async fn admin_routes_need_the_token() {
+    let figment = test_figment().merge(("admin_token", "s3cret"));
+    let client = WebClient::new_test(rocket_from(figment)).await.unwrap();
+    client.register("alice").await.unwrap();
+    let err = client.conclude_registration().await.unwrap_err();
+    assert!(err.to_string().contains("Only the admin"));
+    assert!(client.trigger_fhe_run().await.is_err());
+
+    let client = client.with_admin_token("wrong");
+    assert!(client.conclude_registration().await.is_err());
+    let client = client.with_admin_token("s3cret");
+    client.conclude_registration().await.unwrap();
+
+    // Without a token set, the server makes one up rather than leaving the routes open
+    let client = WebClient::new_test(rocket_from(rocket::Config::figment()))
+        .await
+        .unwrap();
+    client.register("alice").await.unwrap();
+    let err = client.conclude_registration().await.unwrap_err();
+    assert_eq!(ClientError::code_of(&err), Some(ErrorCode::Unauthorized));
+    let rocket = client.local().rocket();
+    let token = rocket.state::<AdminToken>().unwrap().0.clone().unwrap();
+    let client = client.with_admin_token(&token);
+    client.conclude_registration().await.unwrap();
+}
+
+#[rocket::async_test]
+async fn submissions_need_the_user_token() {
+    use rocket::http::{Header, Status};
+
+    let client = WebClient::new_test(rocket()).await.unwrap();
+    let alice = client.register("alice").await.unwrap();
+    let bob = client.register("bob").await.unwrap();
+    let dashboard = client.get_dashboard().await.unwrap();
+    assert!(dashboard.users().iter().all(|user| user.token.is_none()));
+
+    let local_client = client.local();
+    let submission = DecryptionShareSubmission {
+        participant_id: alice.participant_id.clone(),
+        decryption_shares: vec![],
+    };
+    let submit_as = |token: &Option<String>| {
+        local_client
+            .post("/v1/rooms/0/submit_decryption_shares")
+            .header(Header::new(
+                "Authorization",
+                format!("Bearer {}", token.as_deref().unwrap()),
+            ))
+            .msgpack(&submission)
+            .dispatch()
+    };
+    assert_eq!(submit_as(&bob.token).await.status(), Status::Forbidden);
+    // Alice's token gets her past the check, to find the outputs aren't ready
+    assert_eq!(submit_as(&alice.token).await.status(), Status::NotFound);
+    let err = client
+        .submit_decryption_shares(&alice.participant_id, &[])
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("Output not ready"));
+}
+
+#[rocket::async_test]
+async fn signed_submissions_are_verified_and_kept() {
+    use crate::room::Lobby;
+    use ed25519_dalek::SigningKey;
+    use rocket::http::{Header, Status};
+
+    let client = WebClient::new_test(rocket()).await.unwrap();
+    let key = SigningKey::from_bytes(&[7u8; 32]);
+    let alice = client.register_signed("alice", &key).await.unwrap();
+    let public_key = hex::encode(key.verifying_key().as_bytes());
+    assert_eq!(alice.public_key.as_ref(), Some(&public_key));
+
+    let local_client = client.local();
+    let response = local_client
+        .post("/v1/rooms/0/register?public_key=00")
+        .body("mallory")
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+
+    // Alice's token alone doesn't do, the submission must be signed
+    let submission = DecryptionShareSubmission {
+        participant_id: alice.participant_id.clone(),
+        decryption_shares: vec![vec![1]],
+    };
+    let response = local_client
+        .post("/v1/rooms/0/submit_decryption_shares")
+        .header(Header::new(
+            "Authorization",
+            format!("Bearer {}", alice.token.as_deref().unwrap()),
+        ))
+        .msgpack(&submission)
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Forbidden);
+
+    // The client signs, so the server gets on to find the outputs aren't ready
+    let err = client
+        .submit_decryption_shares(&alice.participant_id, &[vec![1]])
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("Output not ready"));
+    // A rejected submission leaves no signature behind, an accepted one does
+    let lobby = local_client.rocket().state::<Lobby>().unwrap();
+    let room = lobby.get(0).await.unwrap();
+    {
+        let mut ss = room.storage.lock().await;
+        assert!(ss.users[0].signatures.is_empty());
+        ss.users[0].storage = UserStorage::DecryptionShare(None);
+    }
+    client
+        .submit_decryption_shares(&alice.participant_id, &[vec![1]])
+        .await
+        .unwrap();
+    let ss = room.storage.lock().await;
+    let signed = &ss.users[0].signatures;
+    assert_eq!(signed.len(), 1);
+    assert_eq!(signed[0].artifact_hash, artifact_hash(&submission));
+    verify_submission(&public_key, &signed[0].artifact_hash, &signed[0].signature).unwrap();
+}
+
+#[rocket::async_test]
+async fn user_status_tells_the_next_step() {
+    let client = WebClient::new_test(rocket()).await.unwrap();
+    client.register("alice").await.unwrap();
+    client.register("bob").await.unwrap();
+
+    let progress = client.get_user_status(1).await.unwrap();
+    assert_eq!(progress.user.name, "bob");
+    assert_eq!(progress.next_step, NextStep::WaitForRegistration);
+
+    let dashboard = client.conclude_registration().await.unwrap();
+    let progress = client.get_user_status(1).await.unwrap();
+    assert_eq!(
+        progress.next_step,
+        NextStep::Submit {
+            cipher: true,
+            key_share: true
+        }
+    );
+    assert_eq!(progress.deadline, dashboard.deadline());
+    assert!(client.get_user_status(2).await.is_err());
+}
+
+#[rocket::async_test]
+async fn returning_users_keep_their_identity_and_karma() {
+    use crate::room::Lobby;
+    use ed25519_dalek::SigningKey;
+
+    let client = WebClient::new_test(rocket()).await.unwrap();
+    let key = SigningKey::from_bytes(&[7u8; 32]);
+    let alice = client.register_signed("alice", &key).await.unwrap();
+    client.register("bob").await.unwrap();
+    assert_eq!(alice.lifetime_karma, None);
+    assert!(client.register_signed("alice again", &key).await.is_err());
+
+    let local_client = client.local();
+    let lobby = local_client.rocket().state::<Lobby>().unwrap();
+    let room = lobby.get(0).await.unwrap();
+    {
+        let mut ss = room.storage.lock().await;
+        let bob = ss.users[1].participant_id.clone();
+        let karma = vec![(alice.participant_id.clone(), 5), (bob, -5)];
+        ss.count_results(0, karma.clone());
+        // Fetching the results again doesn't count them twice
+        ss.count_results(0, karma);
+    }
+
+    client.reset_round(false).await.unwrap();
+    let returning = client.register_signed("alice", &key).await.unwrap();
+    assert_eq!(returning.participant_id, alice.participant_id);
+    assert_eq!(returning.lifetime_karma, Some(5));
+    let newcomer = client.register("bob").await.unwrap();
+    let dashboard = client.get_dashboard().await.unwrap();
+    assert_eq!(dashboard.users()[0].lifetime_karma, Some(5));
+    // Without a key, bob is someone new every round
+    assert_eq!(dashboard.users()[newcomer.id].lifetime_karma, None);
+}
+
+#[rocket::async_test]
+async fn observers_watch_without_counting() {
+    let client = WebClient::new_test(rocket()).await.unwrap();
+    client.register("alice").await.unwrap();
+    let observer = client.register_observer("olivia").await.unwrap();
+    assert_eq!(observer.name, "olivia");
+    client.register("bob").await.unwrap();
+    assert_eq!(client.get_circuit().await.unwrap().scores_expected, 2);
+
+    let dashboard = client.conclude_registration().await.unwrap();
+    assert_eq!(dashboard.get_names(), vec!["alice", "bob"]);
+    assert_eq!(
+        dashboard.parameter_set(),
+        ParameterSet::NonInteractiveLTE2Party
+    );
+    // Observers may join after registration closed
+    client.register_observer("oscar").await.unwrap();
+    let dashboard = client.get_dashboard().await.unwrap();
+    assert_eq!(dashboard.observers().len(), 2);
+    let history = RoomHistory::replay(&client.get_log(0).await.unwrap()).unwrap();
+    assert_eq!(history.observers, ["olivia", "oscar"]);
+}
+
+#[rocket::async_test]
+async fn the_admin_page_is_served() {
+    use rocket::http::{ContentType, Status};
+
+    let client = WebClient::new_test(rocket()).await.unwrap();
+    let local_client = client.local();
+    let response = local_client.get("/v1/admin?room=0").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::HTML));
+    let page = response.into_string().await.unwrap();
+    assert!(page.contains("/conclude_registration"));
+}
+
+#[rocket::async_test]
+async fn browsers_on_allowed_origins_pass_cors() -> Result<(), Error> {
+    use rocket::http::{Header, Method, Status};
+
+    let origin = "https://karma.example.org";
+    let figment = test_figment().merge(("cors.allowed_origins", [origin]));
+    let client = rocket::local::asynchronous::Client::tracked(rocket_from(figment)).await?;
+    let preflight = client
+        .req(Method::Options, "/v1/rooms/0/submit")
+        .header(Header::new("Origin", origin))
+        .header(Header::new("Access-Control-Request-Method", "POST"))
+        .header(Header::new(
+            "Access-Control-Request-Headers",
+            "content-type",
+        ))
+        .dispatch()
+        .await;
+    assert_eq!(preflight.status(), Status::NoContent);
+    let headers = preflight.headers();
+    assert_eq!(headers.get_one("Access-Control-Allow-Origin"), Some(origin));
+    assert!(headers
+        .get_one("Access-Control-Allow-Methods")
+        .is_some_and(|methods| methods.contains("POST")));
+    assert!(headers
+        .get_one("Access-Control-Allow-Headers")
+        .is_some_and(|allowed| allowed.contains("Content-Type")));
+
+    let response = client
+        .get("/v1/rooms/0/dashboard")
+        .header(Header::new("Origin", origin))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(
+        response.headers().get_one("Access-Control-Allow-Origin"),
+        Some(origin)
+    );
+
+    // Other origins get no CORS headers, so their browser blocks the response
+    let response = client
+        .get("/v1/rooms/0/dashboard")
+        .header(Header::new("Origin", "https://elsewhere.example"))
+        .dispatch()
+        .await;
+    assert_eq!(
+        response.headers().get_one("Access-Control-Allow-Origin"),
+        None
+    );
+    Ok(())
+}
+
+#[rocket::async_test]
+async fn https_clients_trust_a_custom_ca_or_a_pinned_certificate() {
+    use crate::tls::{parse_pin, TlsOptions};
+    use futures::StreamExt;
+
+    // A certificate for localhost signed by a test CA, both made with `openssl`
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/src/testdata");
+    let ca_cert = std::fs::read(format!("{dir}/ca-cert.pem")).unwrap();
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let figment = test_figment()
+        .merge(("address", "127.0.0.1"))
+        .merge(("port", port))
+        .merge(("tls.certs", format!("{dir}/localhost-cert.pem")))
+        .merge(("tls.key", format!("{dir}/localhost-key.pem")));
+    let server = rocket_from(figment).ignite().await.unwrap();
+    let shutdown = server.shutdown();
+    tokio::spawn(server.launch());
+
+    let url = format!("https://localhost:{port}");
+    let ca = TlsOptions {
+        root_ca: Some(ca_cert),
+        pinned_cert: None,
+    };
+    let trusting = WebClient::from_transport(ReqwestTransport::new(&url).with_tls(&ca).unwrap());
+    for _ in 0..50 {
+        if trusting.healthz().await.is_ok() {
+            break;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    trusting.healthz().await.unwrap();
+    // No public CA signed it
+    assert!(WebClient::new(&url).healthz().await.is_err());
+
+    // `openssl x509 -in localhost-cert.pem -noout -fingerprint -sha256`
+    let pin = "85:26:C7:03:36:3B:35:E5:F5:33:2E:2E:95:AA:82:C9:51:62:16:CF:24:18:55:BA:10:4B:A9:D4:8F:E7:7E:B6";
+    let pinned = TlsOptions {
+        root_ca: None,
+        pinned_cert: Some(parse_pin(pin).unwrap()),
+    };
+    let pinning = WebClient::from_transport(ReqwestTransport::new(&url).with_tls(&pinned).unwrap());
+    pinning.healthz().await.unwrap();
+    // The event socket goes over `wss` with the same trust
+    let mut events = pinning.subscribe_events().await.unwrap();
+    pinning.register("alice").await.unwrap();
+    let joined = events.next().await.unwrap().unwrap();
+    assert!(matches!(joined, RoomEvent::UserChanged(user) if user.name == "alice"));
+    drop(events);
+    let wrong = TlsOptions {
+        root_ca: None,
+        pinned_cert: Some([0; 32]),
+    };
+    let pinning_another =
+        WebClient::from_transport(ReqwestTransport::new(&url).with_tls(&wrong).unwrap());
+    assert!(pinning_another.healthz().await.is_err());
+    assert!(parse_pin("70:B8").is_err());
+    shutdown.notify();
+}
+
+#[rocket::async_test]
+async fn clients_check_the_protocol_version() {
+    use crate::version::ServerVersion;
+    use rocket::http::Status;
+
+    let client = WebClient::new_test(rocket()).await.unwrap();
+    let version = client.check_version().await.unwrap();
+    assert_eq!(version.protocol, PROTOCOL_VERSION);
+    assert_eq!(version.crate_version, env!("CARGO_PKG_VERSION"));
+
+    let future = ServerVersion {
+        protocol: PROTOCOL_VERSION + 1,
+        ..version
+    };
+    let err = future.check().unwrap_err();
+    assert!(err.to_string().contains("protocol version 2"));
+
+    // A client from before `/v1` is told why its route is gone
+    let local_client = client.local();
+    let response = local_client.get("/rooms/0/dashboard").dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+    let message = response.into_string().await.unwrap();
+    assert!(message.contains("under /v1"));
+}
+
+#[rocket::async_test]
+async fn large_submissions_are_spooled_to_disk() {
+    use crate::compression::{compress, ZSTD};
+    use rocket::http::{ContentType, Header, Status};
+
+    let figment = test_figment()
+        .merge(("spool_threshold", 64))
+        .merge(("limits.submit", 4096));
+    let client = WebClient::new_test(rocket_from(figment)).await.unwrap();
+    let alice = client.register("alice").await.unwrap();
+    let local_client = client.local();
+    let submission = DecryptionShareSubmission {
+        participant_id: alice.participant_id.clone(),
+        decryption_shares: vec![vec![1; 300]],
+    };
+    let body = rocket::serde::msgpack::to_compact_vec(&submission).unwrap();
+    assert!(body.len() > 64);
+    let submit = |body: Vec<u8>, compressed: bool| {
+        let mut request = local_client
+            .post("/v1/rooms/0/submit_decryption_shares")
+            .header(ContentType::MsgPack)
+            .header(Header::new(
+                "Authorization",
+                format!("Bearer {}", alice.token.as_deref().unwrap()),
+            ))
+            .body(body);
+        if compressed {
+            request = request.header(Header::new("Content-Encoding", ZSTD));
+        }
+        request.dispatch()
+    };
+    // Read from the file, the route gets on to find the outputs aren't ready
+    assert_eq!(submit(body.clone(), false).await.status(), Status::NotFound);
+    assert_eq!(
+        submit(compress(&body), true).await.status(),
+        Status::NotFound
+    );
+    let garbage = submit(vec![0xc1; 100], false).await;
+    assert!(garbage
+        .into_string()
+        .await
+        .unwrap()
+        .contains("Invalid upload"));
+    assert_eq!(
+        submit(vec![0; 5000], false).await.status(),
+        Status::PayloadTooLarge
+    );
+    // Compressed below the threshold, it's decoded in memory, and still can't inflate past the limit
+    let bomb = compress(&vec![0; 100_000]);
+    assert!(bomb.len() < 64);
+    assert_eq!(submit(bomb, true).await.status(), Status::PayloadTooLarge);
+    // Past the threshold, the inflated stream is cut at the limit
+    let noise: String = (0..200)
+        .map(|_| rand::random::<u8>() % 26 + b'a')
+        .map(char::from)
+        .collect();
+    let long_id = noise + &"a".repeat(100_000);
+    let big = rocket::serde::msgpack::to_compact_vec(&(long_id, Vec::<Vec<u64>>::new())).unwrap();
+    let compressed = compress(&big);
+    assert!(compressed.len() > 64);
+    assert_eq!(
+        submit(compressed, true).await.status(),
+        Status::PayloadTooLarge
+    );
+}
+
+#[rocket::async_test]
+async fn errors_have_machine_readable_codes() {
+    use rocket::http::{ContentType, Status};
+    use std::collections::HashMap;
+
+    let figment = test_figment().merge((
+        "register_rate_limit",
+        HashMap::from([("requests", 2), ("per_secs", 60)]),
+    ));
+    let client = WebClient::new_test(rocket_from(figment)).await.unwrap();
+    let client = client.with_room(7);
+    let err = client.get_dashboard().await.unwrap_err();
+    let Some(ClientError::Server { status, body }) = err.downcast_ref::<ClientError>() else {
+        panic!("Expect an error response, got {err}")
+    };
+    assert_eq!(*status, 404);
+    assert_eq!(body.code, ErrorCode::RoomNotFound);
+    assert_eq!(body.message, "Room #7 not found");
+
+    let client = client.with_room(0);
+    let err = client.get_fhe_output().await.unwrap_err();
+    assert_eq!(
+        ClientError::code_of(&err),
+        Some(ErrorCode::WrongServerState)
+    );
+    let local_client = client.local();
+    for name in ["alice", "bob"] {
+        local_client
+            .post("/v1/rooms/0/register")
+            .remote("10.0.0.1:1234".parse().unwrap())
+            .body(name)
+            .dispatch()
+            .await;
+    }
+    let response = local_client
+        .post("/v1/rooms/0/register")
+        .remote("10.0.0.1:1234".parse().unwrap())
+        .body("mallory")
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::TooManyRequests);
+    let retry_after = response
+        .headers()
+        .get_one("Retry-After")
+        .unwrap()
+        .to_string();
+    let body: ErrorBody = response.into_json().await.unwrap();
+    assert_eq!(body.code, ErrorCode::TooManyRequests);
+    assert_eq!(
+        body.retry_after.map(|secs| secs.to_string()),
+        Some(retry_after)
+    );
+
+    // Requests no route took get the same body
+    let response = local_client.get("/v1/nowhere").dispatch().await;
+    let body: ErrorBody = response.into_json().await.unwrap();
+    assert_eq!(body.code, ErrorCode::NoSuchRoute);
+    let response = local_client
+        .post("/v1/rooms/0/commit")
+        .header(ContentType::JSON)
+        .body("{")
+        .dispatch()
+        .await;
+    assert_eq!(response.status().class(), Status::BadRequest.class());
+    let body: ErrorBody = response.into_json().await.unwrap();
+    assert_eq!(body.code, ErrorCode::BadRequest);
+}
+
+#[rocket::async_test]
+async fn requests_are_retried_while_the_server_comes_up() {
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let url = format!("http://127.0.0.1:{port}");
+    let policy = RetryPolicy {
+        max_attempts: 20,
+        initial_backoff: Duration::from_millis(50),
+        max_backoff: Duration::from_millis(200),
+        ..RetryPolicy::default()
+    };
+    let patient = WebClient::from_transport(ReqwestTransport::new(&url).with_retry(policy));
+    let impatient =
+        WebClient::from_transport(ReqwestTransport::new(&url).with_retry(RetryPolicy::never()));
+    assert!(impatient.healthz().await.is_err());
+
+    let figment = test_figment()
+        .merge(("address", "127.0.0.1"))
+        .merge(("port", port));
+    let server = rocket_from(figment).ignite().await.unwrap();
+    let shutdown = server.shutdown();
+    tokio::spawn(async move {
+        sleep(Duration::from_millis(300)).await;
+        server.launch().await
+    });
+    // A POST with an idempotency key goes through once the server listens
+    let observer = patient.register_observer("alice").await.unwrap();
+    assert_eq!(observer.name, "alice");
+    assert_eq!(patient.get_dashboard().await.unwrap().observers().len(), 1);
+    shutdown.notify();
+}
+
+#[rocket::async_test]
+async fn msgpack_downloads_are_deserialized_as_they_arrive() {
+    use crate::room::Lobby;
+
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let figment = test_figment()
+        .merge(("address", "127.0.0.1"))
+        .merge(("port", port));
+    let server = rocket_from(figment).ignite().await.unwrap();
+    let lobby = server.state::<Lobby>().unwrap();
+    let room = lobby.get(0).await.unwrap();
+    let participants = {
+        // Big enough to come in many chunks
+        let mut ss = room.storage.lock().await;
+        for n in 0..500 {
+            ss.add_user(&format!("user {n}"));
+        }
+        ss.fhe_outputs = Some(Arc::new(CircuitOutput::new(
+            vec![vec![]; 500],
+            ss.participant_ids(),
+            CircuitId::Karma,
+        )));
+        ss.state = ServerState::CompletedFhe;
+        ss.participant_ids()
+    };
+    let shutdown = server.shutdown();
+    tokio::spawn(server.launch());
+
+    let client = WebClient::new(&format!("http://127.0.0.1:{port}"));
+    // Every word arrives, as the count is checked first, but words without bits aren't scores
+    let err = client.get_fhe_output().await.unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<InvalidResponse>(),
+        Some(&InvalidResponse::WordLength {
+            output_id: 0,
+            bits: 0,
+            expected: SCORE_BITS,
+        })
+    );
+    let word = client.get_fhe_output_word(499).await.unwrap();
+    assert_eq!(word.participant_id.as_ref(), participants.last());
+    assert!(client.get_decryption_shares().await.unwrap().is_empty());
+    let err = client.get_fhe_output_word(500).await.unwrap_err();
+    assert_eq!(ClientError::code_of(&err), Some(ErrorCode::OutputNotReady));
+    shutdown.notify();
+}
+
+#[test]
+fn sessions_come_back_only_with_their_passphrase() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("alice.session");
+    let mut ss = ServerStorage::new([7u8; 32], ParameterSet::default());
+    ss.add_user("alice");
+    let mut session = Session::new("alice", 3);
+    let client = WebClient::new("http://localhost:5566");
+    let user = ss.get_dashboard().users()[0].clone();
+    client.resume_user(user.clone(), None);
+    session.user = client.registered_user(&user.participant_id);
+    session.crs = Some(([7u8; 32], ParameterSet::default()));
+    session.ck = Some(phantom_zone::gen_client_key());
+    session.scores = Some(vec![1, 2]);
+    session.fhe_output = Some(CircuitOutput::new(
+        vec![vec![]],
+        ss.participant_ids(),
+        CircuitId::Karma,
+    ));
+    session.identity = Some([9u8; 32]);
+    session.save(&path, "correct horse").unwrap();
+
+    let loaded = Session::load(&path, "correct horse").unwrap();
+    assert_eq!(loaded.name, "alice");
+    assert_eq!(loaded.room, 3);
+    assert_eq!(loaded.user.unwrap().participant_id, user.participant_id);
+    assert_eq!(loaded.crs, session.crs);
+    assert!(loaded.ck.is_some());
+    assert_eq!(loaded.scores, Some(vec![1, 2]));
+    assert_eq!(loaded.fhe_output.unwrap().participants().len(), 1);
+    assert_eq!(loaded.identity, Some([9u8; 32]));
+    // Nothing readable without the passphrase
+    let bytes = std::fs::read(&path).unwrap();
+    assert!(!bytes.windows(5).any(|window| window == b"alice"));
+    assert!(Session::load(&path, "wrong horse").is_err());
+    std::fs::write(&path, b"not a session").unwrap();
+    assert!(Session::load(&path, "correct horse").is_err());
+}
+
+#[rocket::async_test]
+async fn participants_take_the_steps_in_order() {
+    let client = WebClient::new_test(rocket()).await.unwrap();
+    let mut alice = Participant::new(client);
+    assert!(alice.rate(&[1, 2]).await.is_err());
+    let user = alice.join("alice").await.unwrap();
+    assert_eq!(user.name, "alice");
+    assert!(alice.join("alice again").await.is_err());
+    alice.client().register("bob").await.unwrap();
+    assert!(alice
+        .rate(&[1, 2])
+        .await
+        .unwrap_err()
+        .to_string()
+        .contains("Registration is still open"));
+    assert!(alice.reveal().await.is_err());
+
+    alice.client().conclude_registration().await.unwrap();
+    // One score per user
+    assert!(alice.rate(&[1]).await.is_err());
+    alice.rate(&[1, 2]).await.unwrap();
+    let session = alice.session();
+    assert_eq!(session.name, "alice");
+    assert!(session.ck.is_some());
+    assert_eq!(session.scores, Some(vec![1, 2]));
+    assert_eq!(
+        session.user.unwrap().participant_id,
+        *alice.participant_id().unwrap()
+    );
+    assert!(alice.reveal().await.is_err());
+}
+
+#[rocket::async_test]
+async fn queued_submissions_wait_for_the_server() {
+    use crate::outbox::PendingSubmission;
+
+    // A server that isn't there fails on the way, rather than by an answer
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let offline = WebClient::builder(&format!("http://127.0.0.1:{port}"))
+        .retry(RetryPolicy::never())
+        .build()
+        .unwrap();
+    let err = offline.healthz().await.unwrap_err();
+    assert_eq!(NetworkError::of_error(&err), Some(NetworkError::Connect));
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("outbox");
+    let client = WebClient::new_test(rocket()).await.unwrap();
+    let mut alice = Participant::new(client).with_outbox(path.clone());
+    assert!(alice.flush_pending().await.unwrap().is_none());
+    alice.join("alice").await.unwrap();
+
+    let mut pending = PendingSubmission {
+        room: 0,
+        participant_id: ParticipantId::random(),
+        user_id: 0,
+        total_users: 1,
+        names: vec!["alice".to_string()],
+        scores: vec![],
+        cipher: EncryptedInput::from_plain(&gen_client_key(), &[]),
+        sks: None,
+    };
+    pending.save(&path).unwrap();
+    let err = alice.flush_pending().await.unwrap_err();
+    assert!(err.to_string().contains("another user"));
+
+    // Turned down for now, so it stays queued
+    pending.participant_id = alice.participant_id().unwrap().clone();
+    pending.save(&path).unwrap();
+    assert!(alice.flush_pending().await.is_err());
+    assert!(path.exists());
+}
+
+/// Path and headers of a request
+type Sent = (String, Vec<(&'static str, String)>);
+
+/// Answers from memory, and keeps every request
+#[derive(Default)]
+struct MockTransport {
+    sent: std::sync::Mutex<Vec<Sent>>,
+}
+
+#[async_trait::async_trait]
+impl Transport for MockTransport {
+    async fn get(&self, path: &str, headers: &Headers) -> Result<transport::Response, Error> {
+        self.sent
+            .lock()
+            .unwrap()
+            .push((path.to_string(), headers.to_vec()));
+        let response = match path {
+            "/version" => {
+                transport::Response::new(200, serde_json::to_vec(&ServerVersion::new(None))?, false)
+            }
+            _ => {
+                let body = ErrorBody {
+                    code: ErrorCode::RoomNotFound,
+                    message: "No room 5".to_string(),
+                    retry_after: None,
+                };
+                transport::Response::new(404, serde_json::to_vec(&body)?, false)
+            }
+        };
+        Ok(response)
+    }
+
+    async fn post(
+        &self,
+        path: &str,
+        headers: &Headers,
+        _body: Vec<u8>,
+    ) -> Result<transport::Response, Error> {
+        self.sent
+            .lock()
+            .unwrap()
+            .push((path.to_string(), headers.to_vec()));
+        Ok(transport::Response::new(200, b"5".to_vec(), false))
+    }
+
+    async fn put(
+        &self,
+        _path: &str,
+        _headers: &Headers,
+        _chunk: &[u8],
+        _progress: &indicatif::ProgressBar,
+        _offset: u64,
+    ) -> Result<transport::Response, Error> {
+        unimplemented!()
+    }
+}
+
+#[rocket::async_test]
+async fn clients_send_through_any_transport() {
+    let client = WebClient::from_transport(MockTransport::default())
+        .with_admin_token("secret")
+        .with_room(5);
+    client.check_version().await.unwrap();
+    assert_eq!(client.create_room().await.unwrap(), 5);
+    let err = client.get_seed().await.unwrap_err();
+    assert_eq!(ClientError::code_of(&err), Some(ErrorCode::RoomNotFound));
+    assert!(err.to_string().contains("No room 5"));
+
+    let transport: &dyn Any = client.transport();
+    let sent = transport
+        .downcast_ref::<MockTransport>()
+        .unwrap()
+        .sent
+        .lock()
+        .unwrap()
+        .clone();
+    let paths = sent.iter().map(|(path, _)| path.as_str()).collect_vec();
+    assert_eq!(paths, ["/version", "/v1/rooms", "/v1/rooms/5/param"]);
+    let header = |n: usize, name: &str| {
+        sent[n]
+            .1
+            .iter()
+            .find(|(header, _)| *header == name)
+            .map(|(_, value)| value.clone())
+    };
+    assert_eq!(header(1, "X-Admin-Token").as_deref(), Some("secret"));
+    // Only writes need a key to be retried safely
+    assert!(header(1, "Idempotency-Key").is_some());
+    assert!(header(2, "Idempotency-Key").is_none());
+}
+
+#[rocket::async_test]
+async fn missing_shares_are_fetched_side_by_side() {
+    use crate::room::Lobby;
+
+    let client = WebClient::new_test(rocket()).await.unwrap();
+    for name in ["alice", "bob", "carol"] {
+        client.register(name).await.unwrap();
+    }
+    let room = client
+        .local()
+        .rocket()
+        .state::<Lobby>()
+        .unwrap()
+        .get(0)
+        .await
+        .unwrap();
+    // Words without bits, so each share has no parts
+    let output = {
+        let mut ss = room.storage.lock().await;
+        let output = CircuitOutput::new(vec![vec![]; 3], ss.participant_ids(), CircuitId::Karma);
+        ss.fhe_outputs = Some(Arc::new(output.clone()));
+        ss.users[0].storage = UserStorage::DecryptionShare(Some(vec![vec![]; 3]));
+        ss.users[1].storage = UserStorage::DecryptionShare(Some(vec![vec![]; 3]));
+        ss.users[2].storage = UserStorage::DecryptionShare(None);
+        output
+    };
+    let participants = output.participants();
+    let mut shares = DecryptionSharesMap::from([((0, participants[0].clone()), vec![])]);
+
+    // Carol hasn't submitted, so her shares stay missing
+    let err = client
+        .fetch_missing_shares(&output, &mut shares, 2)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        ClientError::code_of(&err),
+        Some(ErrorCode::DecryptionShareNotFound)
+    );
+    assert_eq!(shares.len(), 6);
+
+    // A share that doesn't fit its output is caught before decryption
+    room.storage.lock().await.users[2].storage =
+        UserStorage::DecryptionShare(Some(vec![vec![8], vec![], vec![]]));
+    let err = client
+        .fetch_missing_shares(&output, &mut shares, 2)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<InvalidResponse>(),
+        Some(&InvalidResponse::ShareLength {
+            output_id: 0,
+            from: participants[2].clone(),
+            len: 1,
+            expected: 0,
+        })
+    );
+    assert_eq!(shares.len(), 8);
+    assert!(output.check_shares(&shares).is_ok());
+
+    let truncated = CircuitOutput::new(vec![vec![]; 2], participants.to_vec(), CircuitId::Karma);
+    assert_eq!(
+        truncated.validate(),
+        Err(InvalidResponse::OutputCount {
+            outputs: 2,
+            expected: 3,
+            participants: 3
+        })
+    );
+    // Decryption takes only words as wide as a score
+    assert_eq!(
+        output.validate(),
+        Err(InvalidResponse::WordLength {
+            output_id: 0,
+            bits: 0,
+            expected: SCORE_BITS,
+        })
+    );
+}
+
+#[rocket::async_test]
+async fn clients_wait_for_the_room_to_move_on() {
+    let client = WebClient::new_test(rocket()).await.unwrap();
+    client.register("alice").await.unwrap();
+    let err = client
+        .wait_for(ServerState::ReadyForInputs, Duration::from_millis(300))
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("Timed out"));
+
+    let (dashboard, concluded) = tokio::join!(
+        client.wait_for(ServerState::ReadyForInputs, Duration::from_secs(10)),
+        async {
+            sleep(Duration::from_millis(300)).await;
+            client.conclude_registration().await
+        }
+    );
+    concluded.unwrap();
+    assert!(dashboard.unwrap().is_concluded());
+}
+
+#[rocket::async_test]
+async fn unchanged_dashboards_are_not_sent_again() {
+    use rocket::http::{Header, Status};
+    let client = WebClient::new_test(rocket()).await.unwrap();
+    let local_client = client.local();
+    let response = local_client.get("/v1/rooms/0/dashboard").dispatch().await;
+    let etag = response.headers().get_one("ETag").unwrap().to_string();
+    let response = local_client
+        .get("/v1/rooms/0/dashboard")
+        .header(Header::new("If-None-Match", etag.clone()))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NotModified);
+    assert_eq!(response.headers().get_one("ETag"), Some(etag.as_str()));
+
+    assert!(matches!(
+        client.poll_dashboard().await.unwrap(),
+        DashboardPoll::Changed(_)
+    ));
+    assert!(matches!(
+        client.poll_dashboard().await.unwrap(),
+        DashboardPoll::NotModified
+    ));
+    // The client answers with the dashboard it has
+    assert!(client.get_dashboard().await.unwrap().get_names().is_empty());
+
+    client.register("alice").await.unwrap();
+    let response = local_client
+        .get("/v1/rooms/0/dashboard")
+        .header(Header::new("If-None-Match", etag))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    match client.poll_dashboard().await.unwrap() {
+        DashboardPoll::Changed(dashboard) => assert_eq!(dashboard.get_names(), ["alice"]),
+        DashboardPoll::NotModified => panic!("The dashboard changed"),
+    }
+}
+
+#[rocket::async_test]
+async fn simulated_parties_expect_received_minus_given() {
+    let scores = vec![vec![0, 3, 1], vec![5, 0, 0], vec![2, 2, 0]];
+    assert_eq!(SimulatedParty::expected_balances(&scores), [3, 0, -3]);
+    // Like the circuit, the sums wrap around
+    let wrapping = vec![
+        vec![0, Score::MAX, Score::MAX],
+        vec![1, 0, 0],
+        vec![0, 0, 0],
+    ];
+    assert_eq!(
+        SimulatedParty::expected_balances(&wrapping),
+        [3, Score::MAX - 1, Score::MAX]
+    );
+
+    let client = WebClient::new_test(rocket()).await.unwrap();
+    let party = SimulatedParty::new(client, 2);
+    let err = party.run(&scores).await.unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("2 scores from each of the 2 users"));
+    // Anyone else in the room would throw off the balances
+    party.client().register("mallory").await.unwrap();
+    let err = party.run(&[vec![0, 1], vec![1, 0]]).await.unwrap_err();
+    assert!(err.to_string().contains("besides the simulated ones"));
+}
+
+#[rocket::async_test]
+async fn built_clients_send_their_headers_and_use_their_proxy() {
+    let free_port = || {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    };
+    let port = free_port();
+    let figment = test_figment()
+        .merge(("address", "127.0.0.1"))
+        .merge(("port", port))
+        .merge(("admin_token", "s3cret"));
+    let server = rocket_from(figment).ignite().await.unwrap();
+    let shutdown = server.shutdown();
+    tokio::spawn(server.launch());
+    let url = format!("http://127.0.0.1:{port}");
+
+    // The admin token goes with every request as an extra header
+    let admin = WebClient::builder(&url)
+        .header("X-Admin-Token", "s3cret")
+        .user_agent("karma-test")
+        .connect_timeout(Duration::from_secs(5))
+        .read_timeout(Duration::from_secs(30))
+        .build()
+        .unwrap();
+    admin.register("alice").await.unwrap();
+    assert!(admin.conclude_registration().await.is_ok());
+
+    // Nothing listens where the proxy should be
+    let proxied = WebClient::builder(&url)
+        .proxy(&format!("http://127.0.0.1:{}", free_port()))
+        .retry(RetryPolicy::never())
+        .build()
+        .unwrap();
+    assert!(proxied.healthz().await.is_err());
+    assert!(WebClient::new(&url).healthz().await.is_ok());
+
+    assert!(WebClient::builder(&url)
+        .header("Not a header", "value")
+        .build()
+        .is_err());
+    shutdown.notify();
+}
+
+#[rocket::async_test]
+async fn throttled_uploads_report_their_throughput() {
+    use rand::Rng;
+
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let figment = test_figment()
+        .merge(("address", "127.0.0.1"))
+        .merge(("port", port));
+    let server = rocket_from(figment).ignite().await.unwrap();
+    let shutdown = server.shutdown();
+    tokio::spawn(server.launch());
+
+    let rate = 64 * 1024;
+    let client = WebClient::builder(&format!("http://127.0.0.1:{port}"))
+        .upload_rate(rate)
+        .write_size(1024)
+        .build()
+        .unwrap();
+    assert!(client.last_upload().is_none());
+    let alice = client.register("alice").await.unwrap();
+    // Random, so compression leaves it as big
+    let share = (0..4096).map(|_| rand::thread_rng().gen()).collect_vec();
+    // The server turns it down, but only once it's all uploaded
+    client
+        .submit_decryption_shares(&alice.participant_id, &[share])
+        .await
+        .unwrap_err();
+    let stats = client.last_upload().unwrap();
+    assert!(stats.bytes > 32 * 1024);
+    assert!(stats.elapsed >= Duration::from_secs_f64(stats.bytes as f64 / rate as f64 * 0.9));
+    assert!(stats.throughput() <= rate as f64 * 1.1);
+    shutdown.notify();
+}
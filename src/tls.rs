@@ -0,0 +1,129 @@
+//! How [`crate::WebClient`] decides to trust a server whose certificate no public CA signed,
+//! e.g. one a participant runs for a round between parties who don't trust each other
+use anyhow::{bail, Error};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::{
+    ring, verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms,
+};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+/// Without either, the client trusts the usual public CAs
+#[derive(Debug, Clone, Default)]
+pub struct TlsOptions {
+    /// PEM certificates of the CAs to trust instead of the public ones
+    pub root_ca: Option<Vec<u8>>,
+    /// SHA-256 of the server's certificate in DER, as `openssl x509 -noout -fingerprint -sha256`
+    /// prints it. Only a server with exactly this certificate is trusted, and if it's
+    /// self-signed no CA needs to vouch for it.
+    pub pinned_cert: Option<[u8; 32]>,
+}
+
+impl TlsOptions {
+    /// `None` when the defaults do
+    pub(crate) fn client_config(&self) -> Result<Option<ClientConfig>, Error> {
+        let provider = Arc::new(ring::default_provider());
+        let roots = match &self.root_ca {
+            Some(pem) => Some(Arc::new(root_store(pem)?)),
+            None => None,
+        };
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let config = match (roots, self.pinned_cert) {
+            (None, None) => return Ok(None),
+            (Some(roots), None) => builder.with_root_certificates(roots),
+            (roots, Some(pin)) => {
+                let ca = match roots {
+                    Some(roots) => Some(
+                        WebPkiServerVerifier::builder_with_provider(roots, provider.clone())
+                            .build()?,
+                    ),
+                    None => None,
+                };
+                let verifier = PinnedCert {
+                    pin,
+                    ca,
+                    algorithms: provider.signature_verification_algorithms,
+                };
+                builder
+                    .dangerous()
+                    .with_custom_certificate_verifier(Arc::new(verifier))
+            }
+        };
+        Ok(Some(config.with_no_client_auth()))
+    }
+}
+
+/// Parse a pin in hex, with or without the colons `openssl` separates the bytes with
+pub fn parse_pin(pin: &str) -> Result<[u8; 32], Error> {
+    let bytes = hex::decode(pin.replace(':', ""))?;
+    match <[u8; 32]>::try_from(bytes) {
+        Ok(pin) => Ok(pin),
+        Err(bytes) => bail!("A SHA-256 pin is 32 bytes, got {}", bytes.len()),
+    }
+}
+
+fn root_store(pem: &[u8]) -> Result<RootCertStore, Error> {
+    let mut roots = RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut &pem[..]) {
+        roots.add(cert?)?;
+    }
+    if roots.is_empty() {
+        bail!("No certificate in the root CA file");
+    }
+    Ok(roots)
+}
+
+/// Accepts the pinned certificate, after the CAs, if any, accepted it too
+#[derive(Debug)]
+struct PinnedCert {
+    pin: [u8; 32],
+    ca: Option<Arc<WebPkiServerVerifier>>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for PinnedCert {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(ca) = &self.ca {
+            ca.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+        }
+        if Sha256::digest(end_entity)[..] != self.pin {
+            return Err(rustls::Error::General(
+                "The server's certificate isn't the pinned one".to_string(),
+            ));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
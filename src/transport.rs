@@ -0,0 +1,637 @@
+//! How a [`crate::WebClient`] reaches its server. The client builds each request's path,
+//! headers and body, and a [`Transport`] carries it: [`ReqwestTransport`] over HTTP(S), and
+//! [`RocketLocalTransport`] to a Rocket instance in the same process. Other links, or mocks in
+//! tests, implement the trait and go in with [`crate::WebClient::from_transport`].
+use crate::{
+    compression::ZSTD, events::RoomEvent, idempotency::IDEMPOTENCY_KEY_HEADER, retry::RetryPolicy,
+    tls::TlsOptions,
+};
+use anyhow::{anyhow, Error};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::{self, BoxStream, StreamExt, TryStreamExt};
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING},
+    Client,
+};
+use rocket::http::Header;
+use rocket::local::asynchronous::LocalResponse;
+use rocket::{Build, Rocket};
+use rustls::pki_types::ServerName;
+use std::{
+    any::Any,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, Sleep};
+use tokio_rustls::{client::TlsStream, TlsConnector};
+use tokio_tungstenite::{client_async, connect_async, tungstenite::Message, WebSocketStream};
+use tokio_util::io::ReaderStream;
+use tracing::{debug, info};
+
+/// Bytes an upload hands to the connection at a time, unless a throttle wants fewer
+const UPLOAD_WRITE_SIZE: usize = 128 * 1024;
+
+/// Request headers, as `(name, value)`
+pub type Headers = [(&'static str, String)];
+
+/// The room's changes, see [`Transport::events`]
+pub type EventStream = BoxStream<'static, Result<RoomEvent, Error>>;
+
+/// A response of the server, whatever its status
+pub struct Response {
+    pub status: u16,
+    /// As `(name, value)`, for the ones a client reads, e.g. `ETag`
+    pub headers: Vec<(String, String)>,
+    pub content_length: Option<u64>,
+    /// Whether `body` is zstd, see [`crate::compression::Compression`]
+    pub compressed: bool,
+    /// As it arrives
+    pub body: BoxStream<'static, Result<Bytes, std::io::Error>>,
+}
+
+impl Response {
+    /// A body that's all there already
+    pub fn new(status: u16, body: Vec<u8>, compressed: bool) -> Self {
+        Self {
+            status,
+            headers: vec![],
+            content_length: Some(body.len() as u64),
+            compressed,
+            body: stream::once(async { Ok(Bytes::from(body)) }).boxed(),
+        }
+    }
+
+    /// The value of the header `name`, in any case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The whole body, inflated if the server compressed it
+    pub async fn bytes(self) -> Result<Vec<u8>, Error> {
+        let bytes = self
+            .body
+            .try_fold(Vec::new(), |mut bytes, chunk| async move {
+                bytes.extend_from_slice(&chunk);
+                Ok(bytes)
+            })
+            .await?;
+        if self.compressed {
+            Ok(zstd::decode_all(&bytes[..])?)
+        } else {
+            Ok(bytes)
+        }
+    }
+
+    fn from_reqwest(response: reqwest::Response) -> Self {
+        let compressed = response
+            .headers()
+            .get(CONTENT_ENCODING)
+            .is_some_and(|encoding| encoding == ZSTD);
+        let headers = response
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect();
+        Self {
+            status: response.status().as_u16(),
+            headers,
+            content_length: response.content_length(),
+            compressed,
+            body: response
+                .bytes_stream()
+                .map_err(std::io::Error::other)
+                .boxed(),
+        }
+    }
+}
+
+/// Carries the requests of a [`crate::WebClient`]. Paths start with `/`, and include the API
+/// version prefix where the route has one.
+#[async_trait]
+pub trait Transport: Any + Send + Sync {
+    async fn get(&self, path: &str, headers: &Headers) -> Result<Response, Error>;
+
+    async fn post(&self, path: &str, headers: &Headers, body: Vec<u8>) -> Result<Response, Error>;
+
+    /// A compressed msgpack `body`, with the headers that say so. These are the big uploads,
+    /// so a transport that can show their progress does it here.
+    async fn post_msgpack(
+        &self,
+        path: &str,
+        headers: &Headers,
+        body: Vec<u8>,
+    ) -> Result<Response, Error> {
+        self.post(path, headers, body).await
+    }
+
+    /// One chunk of an upload session, counted from `offset` on `progress`
+    async fn put(
+        &self,
+        path: &str,
+        headers: &Headers,
+        chunk: &[u8],
+        progress: &ProgressBar,
+        offset: u64,
+    ) -> Result<Response, Error>;
+
+    /// The events the server pushes at `path`. `None` if the transport can't take pushes, and
+    /// the client compares dashboards instead.
+    async fn events(&self, _path: &str) -> Result<Option<EventStream>, Error> {
+        Ok(None)
+    }
+
+    /// The server's address, for messages
+    fn url(&self) -> Option<&str> {
+        None
+    }
+
+    /// Cap of uploads in bytes per second
+    fn upload_limit(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// How [`ReqwestTransport`] sets up its HTTP client, see [`crate::WebClientBuilder`]
+#[derive(Debug, Clone, Default)]
+pub(crate) struct HttpOptions {
+    pub connect_timeout: Option<Duration>,
+    /// Between two reads of a response, rather than for the whole request, so a long upload
+    /// doesn't time out as long as the server keeps answering
+    pub read_timeout: Option<Duration>,
+    /// URL of an HTTP proxy for every request
+    pub proxy: Option<String>,
+    pub user_agent: Option<String>,
+    /// Sent with every request
+    pub headers: Vec<(String, String)>,
+}
+
+/// HTTP(S) by `reqwest`, and the event socket over `ws(s)`
+pub struct ReqwestTransport {
+    url: String,
+    client: Client,
+    options: HttpOptions,
+    /// Set by [`Self::with_tls`], for the event socket too
+    tls: Option<Arc<rustls::ClientConfig>>,
+    /// Cap of msgpack uploads in bytes per second
+    upload_limit: Option<u64>,
+    /// See [`Self::with_write_size`]
+    write_size: usize,
+    retry: RetryPolicy,
+}
+
+fn http_client(tls: Option<rustls::ClientConfig>, options: &HttpOptions) -> Result<Client, Error> {
+    let mut headers = HeaderMap::from_iter([(ACCEPT_ENCODING, HeaderValue::from_static(ZSTD))]);
+    for (name, value) in &options.headers {
+        headers.insert(HeaderName::try_from(name)?, HeaderValue::try_from(value)?);
+    }
+    let mut builder = Client::builder().default_headers(headers);
+    if let Some(tls) = tls {
+        builder = builder.use_preconfigured_tls(tls);
+    }
+    if let Some(timeout) = options.connect_timeout {
+        builder = builder.connect_timeout(timeout);
+    }
+    if let Some(timeout) = options.read_timeout {
+        builder = builder.read_timeout(timeout);
+    }
+    if let Some(proxy) = &options.proxy {
+        builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+    }
+    if let Some(user_agent) = &options.user_agent {
+        builder = builder.user_agent(user_agent);
+    }
+    Ok(builder.build()?)
+}
+
+impl ReqwestTransport {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            client: http_client(None, &HttpOptions::default())
+                .expect("Failed to build the HTTP client"),
+            options: HttpOptions::default(),
+            tls: None,
+            upload_limit: None,
+            write_size: UPLOAD_WRITE_SIZE,
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    /// Trust the server by `tls` rather than the public CAs, for an `https` URL
+    pub fn with_tls(mut self, tls: &TlsOptions) -> Result<Self, Error> {
+        self.tls = tls.client_config()?.map(Arc::new);
+        self.client = http_client(self.tls.as_deref().cloned(), &self.options)?;
+        Ok(self)
+    }
+
+    pub(crate) fn with_options(mut self, options: HttpOptions) -> Result<Self, Error> {
+        self.client = http_client(self.tls.as_deref().cloned(), &options)?;
+        self.options = options;
+        Ok(self)
+    }
+
+    /// Throttle uploads to `kb_per_sec` KB/s so a huge key share doesn't saturate a shared connection
+    pub fn with_upload_limit(self, kb_per_sec: u64) -> Self {
+        self.with_upload_rate(kb_per_sec * 1024)
+    }
+
+    /// Like [`Self::with_upload_limit`], in bytes per second
+    pub fn with_upload_rate(mut self, bytes_per_sec: u64) -> Self {
+        self.upload_limit = Some(bytes_per_sec.max(1));
+        self
+    }
+
+    /// Hand uploads to the connection `bytes` at a time, 128 KB by default. Bigger writes cost
+    /// fewer wakeups on a fast link. A throttled upload writes at most a tenth of its rate.
+    pub fn with_write_size(mut self, bytes: usize) -> Self {
+        self.write_size = bytes.max(1);
+        self
+    }
+
+    /// Retry failed requests by `policy` rather than [`RetryPolicy::default`]
+    pub fn with_retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
+    }
+
+    fn request(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        headers: &Headers,
+    ) -> reqwest::RequestBuilder {
+        headers.iter().fold(
+            self.client.request(method, format!("{}{path}", self.url)),
+            |request, (name, value)| request.header(*name, value),
+        )
+    }
+
+    /// Send again as long as the policy allows if `idempotent`, otherwise once. `sent` is the
+    /// size of the body, for the log.
+    async fn send(
+        &self,
+        idempotent: bool,
+        sent: usize,
+        build: impl Fn() -> reqwest::RequestBuilder,
+    ) -> Result<Response, Error> {
+        let response = if idempotent {
+            send_retrying(&self.retry, sent, build).await?
+        } else {
+            traced(build(), sent).await?
+        };
+        Ok(Response::from_reqwest(response))
+    }
+}
+
+#[async_trait]
+impl Transport for ReqwestTransport {
+    async fn get(&self, path: &str, headers: &Headers) -> Result<Response, Error> {
+        self.send(true, 0, || {
+            self.request(reqwest::Method::GET, path, headers)
+        })
+        .await
+    }
+
+    async fn post(&self, path: &str, headers: &Headers, body: Vec<u8>) -> Result<Response, Error> {
+        self.send(is_idempotent(headers), body.len(), || {
+            self.request(reqwest::Method::POST, path, headers)
+                .body(body.clone())
+        })
+        .await
+    }
+
+    async fn post_msgpack(
+        &self,
+        path: &str,
+        headers: &Headers,
+        body: Vec<u8>,
+    ) -> Result<Response, Error> {
+        let bar = upload_bar(body.len() as u64, self.upload_limit);
+        let response = self
+            .send(is_idempotent(headers), body.len(), || {
+                let reader =
+                    ProgressReader::new(&body, self.write_size, self.upload_limit, bar.clone(), 0);
+                self.request(reqwest::Method::POST, path, headers)
+                    .body(reqwest::Body::wrap_stream(ReaderStream::new(reader)))
+            })
+            .await?;
+        bar.finish_with_message("Upload complete");
+        Ok(response)
+    }
+
+    async fn put(
+        &self,
+        path: &str,
+        headers: &Headers,
+        chunk: &[u8],
+        progress: &ProgressBar,
+        offset: u64,
+    ) -> Result<Response, Error> {
+        let reader = ProgressReader::new(
+            chunk,
+            self.write_size,
+            self.upload_limit,
+            progress.clone(),
+            offset,
+        );
+        let request = self
+            .request(reqwest::Method::PUT, path, headers)
+            .body(reqwest::Body::wrap_stream(ReaderStream::new(reader)));
+        let response = traced(request, chunk.len()).await?;
+        Ok(Response::from_reqwest(response))
+    }
+
+    async fn events(&self, path: &str) -> Result<Option<EventStream>, Error> {
+        let ws_url = self
+            .url
+            .strip_prefix("http")
+            .map(|rest| format!("ws{rest}{path}"))
+            .ok_or_else(|| anyhow!("Expect an http(s) url, got {}", self.url))?;
+        match &self.tls {
+            Some(config) if ws_url.starts_with("wss") => {
+                let stream = tls_connect(&ws_url, config.clone()).await?;
+                let (socket, _) = client_async(ws_url, stream).await?;
+                Ok(Some(room_events(socket)))
+            }
+            _ => {
+                let (socket, _) = connect_async(ws_url).await?;
+                Ok(Some(room_events(socket)))
+            }
+        }
+    }
+
+    fn url(&self) -> Option<&str> {
+        Some(&self.url)
+    }
+
+    fn upload_limit(&self) -> Option<u64> {
+        self.upload_limit
+    }
+}
+
+/// Requests to a Rocket instance in the same process, for tests. It can't upgrade connections,
+/// so it takes no pushed events.
+pub struct RocketLocalTransport {
+    client: rocket::local::asynchronous::Client,
+}
+
+impl RocketLocalTransport {
+    pub async fn new(rocket: Rocket<Build>) -> Result<Self, Error> {
+        Ok(Self {
+            client: rocket::local::asynchronous::Client::tracked(rocket).await?,
+        })
+    }
+
+    /// For the state of the server, e.g. `client().rocket().state::<T>()`
+    pub fn client(&self) -> &rocket::local::asynchronous::Client {
+        &self.client
+    }
+}
+
+async fn local_response(response: LocalResponse<'_>) -> Response {
+    let status = response.status().code;
+    let compressed = response.headers().get_one("Content-Encoding") == Some(ZSTD);
+    let headers = response
+        .headers()
+        .iter()
+        .map(|header| (header.name().to_string(), header.value().to_string()))
+        .collect();
+    let body = response.into_bytes().await.unwrap_or_default();
+    Response {
+        headers,
+        ..Response::new(status, body, compressed)
+    }
+}
+
+#[async_trait]
+impl Transport for RocketLocalTransport {
+    async fn get(&self, path: &str, headers: &Headers) -> Result<Response, Error> {
+        let request = headers.iter().fold(
+            self.client.get(path.to_string()),
+            |request, (name, value)| request.header(Header::new(*name, value.clone())),
+        );
+        Ok(local_response(request.dispatch().await).await)
+    }
+
+    async fn post(&self, path: &str, headers: &Headers, body: Vec<u8>) -> Result<Response, Error> {
+        let request = headers.iter().fold(
+            self.client.post(path.to_string()),
+            |request, (name, value)| request.header(Header::new(*name, value.clone())),
+        );
+        Ok(local_response(request.body(body).dispatch().await).await)
+    }
+
+    async fn put(
+        &self,
+        path: &str,
+        headers: &Headers,
+        chunk: &[u8],
+        progress: &ProgressBar,
+        offset: u64,
+    ) -> Result<Response, Error> {
+        let request = headers.iter().fold(
+            self.client.put(path.to_string()),
+            |request, (name, value)| request.header(Header::new(*name, value.clone())),
+        );
+        let response = local_response(request.body(chunk).dispatch().await).await;
+        progress.set_position(offset + chunk.len() as u64);
+        Ok(response)
+    }
+}
+
+/// Writes are sent again only with an `Idempotency-Key`, so the server can't act on them twice
+fn is_idempotent(headers: &Headers) -> bool {
+    headers
+        .iter()
+        .any(|(name, _)| *name == IDEMPOTENCY_KEY_HEADER)
+}
+
+/// Send a request the server can't act on twice, and again as long as `retry` allows
+async fn send_retrying(
+    retry: &RetryPolicy,
+    sent: usize,
+    build: impl Fn() -> reqwest::RequestBuilder,
+) -> Result<reqwest::Response, Error> {
+    let mut attempt = 1;
+    loop {
+        let (wait, reason) = match traced(build(), sent).await {
+            Ok(response) => match retry.after_response(attempt, &response) {
+                Some(wait) => (wait, format!("server responded {}", response.status())),
+                None => return Ok(response),
+            },
+            Err(err) => match retry.after_error(attempt, &err) {
+                Some(wait) => (wait, err.to_string()),
+                None => return Err(err.into()),
+            },
+        };
+        info!(
+            attempt,
+            wait_ms = wait.as_millis() as u64,
+            %reason,
+            "Retrying the request"
+        );
+        sleep(wait).await;
+        attempt += 1;
+    }
+}
+
+/// Send `request`, and log at `debug` how long it took and how much went each way, e.g. for
+/// `cli -vv`. The response's size is the one it announced, if any, as its body isn't read yet.
+async fn traced(
+    request: reqwest::RequestBuilder,
+    sent: usize,
+) -> Result<reqwest::Response, reqwest::Error> {
+    let (client, request) = request.build_split();
+    let request = request?;
+    let (method, url) = (request.method().clone(), request.url().clone());
+    let started = Instant::now();
+    let result = client.execute(request).await;
+    let elapsed_ms = started.elapsed().as_millis() as u64;
+    match &result {
+        Ok(response) => debug!(
+            %method,
+            path = url.path(),
+            status = response.status().as_u16(),
+            sent,
+            received = response.content_length(),
+            elapsed_ms,
+            "HTTP request"
+        ),
+        Err(err) => {
+            debug!(%method, path = url.path(), sent, elapsed_ms, "HTTP request failed: {err}")
+        }
+    }
+    result
+}
+
+/// The room events the server sends over `socket`, as JSON text messages
+fn room_events<S>(socket: WebSocketStream<S>) -> EventStream
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    socket
+        .filter_map(|message| async move {
+            match message {
+                Ok(Message::Text(text)) => Some(serde_json::from_str(&text).map_err(Error::from)),
+                Ok(_) => None,
+                Err(err) => Some(Err(err.into())),
+            }
+        })
+        .boxed()
+}
+
+/// Open a TLS connection to the host of a `wss` URL, trusting it by `config`
+async fn tls_connect(
+    ws_url: &str,
+    config: Arc<rustls::ClientConfig>,
+) -> Result<TlsStream<TcpStream>, Error> {
+    let url = reqwest::Url::parse(ws_url)?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow!("No host in {ws_url}"))?;
+    let port = url.port_or_known_default().unwrap_or(443);
+    let tcp = TcpStream::connect((host, port)).await?;
+    let server_name = ServerName::try_from(host.to_string())?;
+    Ok(TlsConnector::from(config).connect(server_name, tcp).await?)
+}
+
+pub(crate) fn upload_bar(total_bytes: u64, rate_limit: Option<u64>) -> ProgressBar {
+    debug!(total_bytes, "Uploading");
+    let bar = ProgressBar::new(total_bytes);
+    bar.set_style(
+        ProgressStyle::with_template(
+            "[{elapsed_precise}] {bar:40.cyan/blue} {percent}% {bytes_per_sec} ETA {eta} {msg}",
+        )
+        .unwrap()
+        .progress_chars("##-"),
+    );
+    match rate_limit {
+        Some(rate) => bar.set_message(format!("Uploading at most {}/s...", HumanBytes(rate))),
+        None => bar.set_message("Uploading..."),
+    }
+    bar
+}
+
+struct ProgressReader {
+    inner: Vec<u8>,
+    progress_bar: ProgressBar,
+    /// Where `inner` starts in the bar's total
+    offset: u64,
+    position: usize,
+    chunk_size: usize,
+    /// Bytes per second
+    rate_limit: Option<u64>,
+    started: Instant,
+    throttle: Option<Pin<Box<Sleep>>>,
+}
+
+impl ProgressReader {
+    fn new(
+        body: &[u8],
+        chunk_size: usize,
+        rate_limit: Option<u64>,
+        progress_bar: ProgressBar,
+        offset: u64,
+    ) -> Self {
+        // Small chunks keep the throttled rate smooth
+        let chunk_size = match rate_limit {
+            Some(rate) => chunk_size.min((rate as usize / 10).max(1)),
+            None => chunk_size,
+        };
+
+        Self {
+            inner: body.to_vec(),
+            progress_bar,
+            offset,
+            position: 0,
+            chunk_size,
+            rate_limit,
+            started: Instant::now(),
+            throttle: None,
+        }
+    }
+
+    /// How long to wait before the bytes sent so far are within the rate limit
+    fn wait_time(&self) -> Option<Duration> {
+        let rate = self.rate_limit?;
+        let due = Duration::from_secs_f64(self.position as f64 / rate as f64);
+        due.checked_sub(self.started.elapsed())
+            .filter(|wait| !wait.is_zero())
+    }
+}
+
+impl AsyncRead for ProgressReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<tokio::io::Result<()>> {
+        if let Some(wait) = self.wait_time() {
+            let throttle = self.throttle.get_or_insert_with(|| Box::pin(sleep(wait)));
+            if throttle.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+        self.throttle = None;
+
+        let remaining = self.inner.len() - self.position;
+        let to_read = self.chunk_size.min(remaining.min(buf.remaining()));
+        let end = self.position + to_read;
+        buf.put_slice(&self.inner[self.position..end]);
+        self.position = end;
+        self.progress_bar
+            .set_position(self.offset + self.position as u64);
+
+        Poll::Ready(Ok(()))
+    }
+}
@@ -1,4 +1,13 @@
+use crate::archive::{ArchiveMeta, SessionArchive};
+use crate::auth::UserAuth;
+use crate::circuit::{CircuitId, InputContract, ParameterSet};
+use crate::cold::Cold;
 use crate::dashboard::{Dashboard, RegisteredUser};
+use crate::history::{KarmaLedger, LogEntry, RoomChange};
+use crate::idempotency::IdempotencyCache;
+use crate::persist::RoomStore;
+use crate::receipt::{artifact_hash, verify_submission};
+use crate::room::{fresh_seed, RoomId};
 use itertools::Itertools;
 use phantom_zone::{
     evaluator::NonInteractiveMultiPartyCrs,
@@ -6,30 +15,80 @@ use phantom_zone::{
     Encryptor, FheBool, KeySwitchWithId, MultiPartyDecryptor, NonInteractiveSeededFheBools,
     SampleExtractor,
 };
+use rand::{thread_rng, Rng};
+use rocket::data::ByteUnit;
+use rocket::http::Status;
+use rocket::request::FromParam;
+use rocket::response::{self, Responder, Response};
+use rocket::serde::json::Json;
 use rocket::serde::{Deserialize, Serialize};
-use rocket::tokio::sync::Mutex;
-use rocket::Responder;
+use rocket::tokio::sync::{watch, Mutex};
+use rocket::Request;
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::fmt::Display;
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 use thiserror::Error;
+use tracing::{info, warn};
 
-pub type Score = PlainWord;
+pub type Score = i16;
+/// Number of bits a [`Score`] is encrypted in
+pub const SCORE_BITS: usize = Score::BITS as usize;
 pub type ClientKey = phantom_zone::ClientKey;
+/// Position of a user in the key shares. Only meaningful for key share generation and the run,
+/// see [`ParticipantId`] for addressing outputs and decryption shares.
 pub type UserId = usize;
+/// Unix time in seconds
+pub type Timestamp = u64;
+
+/// Stable opaque identifier of a user, assigned at registration
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ParticipantId(String);
+
+impl ParticipantId {
+    pub(crate) fn random() -> Self {
+        Self(hex::encode(thread_rng().gen::<[u8; 8]>()))
+    }
+
+    /// The same for every room and round a user registers in with this hex public key
+    pub(crate) fn of_key(public_key: &str) -> Self {
+        Self(hex::encode(&Sha256::digest(public_key.as_bytes())[..8]))
+    }
+}
+
+impl Display for ParticipantId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl<'a> FromParam<'a> for ParticipantId {
+    type Error = &'a str;
+
+    fn from_param(param: &'a str) -> Result<Self, Self::Error> {
+        if !param.is_empty() && param.chars().all(|c| c.is_ascii_hexdigit()) {
+            Ok(Self(param.to_string()))
+        } else {
+            Err(param)
+        }
+    }
+}
 
 pub(crate) type Seed = [u8; 32];
-pub(crate) type ServerKeyShare = CommonReferenceSeededNonInteractiveMultiPartyServerKeyShare<
+/// What each user makes with their client key and submits, for the server to aggregate
+pub type ServerKeyShare = CommonReferenceSeededNonInteractiveMultiPartyServerKeyShare<
     Vec<Vec<u64>>,
     BoolParameters<u64>,
     NonInteractiveMultiPartyCrs<Seed>,
 >;
+/// Encrypted bits of a plaintext word, least significant bit first
 pub(crate) type Word = Vec<FheBool>;
 pub(crate) type CircuitInput = Vec<Word>;
 /// Decryption share for a word from one user.
 pub(crate) type DecryptionShare = Vec<u64>;
 
-type PlainWord = i16;
 type EncryptedWord = NonInteractiveSeededFheBools<Vec<u64>, Seed>;
 
 /// Encrypted input words contributed from one user
@@ -39,14 +98,20 @@ pub struct EncryptedInput {
 }
 
 impl EncryptedInput {
-    pub fn from_plain(ck: &ClientKey, karma: &[PlainWord]) -> Self {
+    pub fn from_plain(ck: &ClientKey, karma: &[Score]) -> Self {
         let cipher = karma
             .iter()
-            .map(|score| encrypt_plain(ck, *score))
+            // Signed scores are encrypted as their two's complement
+            .map(|score| encrypt_plain::<SCORE_BITS>(ck, *score as u16 as u64))
             .collect_vec();
         Self { karma_sent: cipher }
     }
 
+    /// Get number of encrypted words
+    pub fn n(&self) -> usize {
+        self.karma_sent.len()
+    }
+
     /// Unpack ciphers
     ///
     /// 1. Decompression: A cipher is a matrix generated from a seed. The seed is sent through the network as a compression. By calling the `unseed` method we recovered the matrix here.
@@ -64,41 +129,240 @@ impl EncryptedInput {
     }
 }
 
-fn encrypt_plain(ck: &ClientKey, plain: PlainWord) -> EncryptedWord {
-    let plain = u64_to_binary::<32>(plain as u64);
-    ck.encrypt(plain.as_slice())
+fn encrypt_plain<const N: usize>(ck: &ClientKey, plain: u64) -> EncryptedWord {
+    ck.encrypt(u64_to_binary::<N>(plain).as_slice())
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CircuitOutput {
-    /// Computed karma balance of all users
-    karma_balance: Vec<Word>,
+    /// Every output of the circuit, by output ID
+    outputs: Vec<Word>,
+    /// Everyone who ran, each makes a decryption share of every output
+    participants: Vec<ParticipantId>,
+    /// The circuit that computed the outputs
+    #[serde(default)]
+    circuit: CircuitId,
 }
 
 impl CircuitOutput {
-    pub(crate) fn new(karma_balance: Vec<Word>) -> Self {
-        Self { karma_balance }
+    pub(crate) fn new(
+        outputs: Vec<Word>,
+        participants: Vec<ParticipantId>,
+        circuit: CircuitId,
+    ) -> Self {
+        Self {
+            outputs,
+            participants,
+            circuit,
+        }
+    }
+
+    /// Everyone who ran, in [`UserId`] order
+    pub fn participants(&self) -> &[ParticipantId] {
+        &self.participants
+    }
+
+    /// Whose output `output_id` is, if the circuit's outputs belong to participants
+    pub fn owner(&self, output_id: usize) -> Option<&ParticipantId> {
+        self.participants
+            .get(self.circuit.circuit().output_owner(output_id)?)
     }
 
     /// For each output word, a user generates its decryption share
     pub fn gen_decryption_shares(&self, ck: &ClientKey) -> Vec<DecryptionShare> {
-        self.karma_balance
+        self.outputs
             .iter()
             .map(|word| gen_decryption_shares(ck, word))
             .collect_vec()
     }
 
-    pub fn decrypt(&self, ck: &ClientKey, dss: &[Vec<DecryptionShare>]) -> Vec<PlainWord> {
-        self.karma_balance
+    /// The decryption share of a single output
+    pub fn gen_decryption_share(&self, ck: &ClientKey, output_id: usize) -> DecryptionShare {
+        gen_decryption_shares(ck, &self.outputs[output_id])
+    }
+
+    /// Fails with an [`InvalidResponse`] unless the output and the shares are as wide as a score
+    pub fn decrypt(
+        &self,
+        ck: &ClientKey,
+        dss: &[Vec<DecryptionShare>],
+    ) -> Result<Vec<Score>, InvalidResponse> {
+        self.validate()?;
+        let participants = self.participants.len();
+        if dss.len() != self.n() || dss.iter().any(|shares| shares.len() != participants) {
+            return Err(InvalidResponse::ShareCount {
+                outputs: self.n(),
+                participants,
+            });
+        }
+        for (output_id, shares) in dss.iter().enumerate() {
+            for (from, share) in self.participants.iter().zip(shares) {
+                self.check_share(output_id, from, share)?;
+            }
+        }
+        self.outputs
             .iter()
-            .zip_eq(dss)
-            .map(|(word, shares)| decrypt_word(ck, word, shares))
-            .collect_vec()
+            .zip(dss)
+            .enumerate()
+            .map(|(output_id, (word, shares))| {
+                let balance = decrypt_word::<SCORE_BITS>(ck, word, shares).ok_or(
+                    InvalidResponse::WordLength {
+                        output_id,
+                        bits: word.len(),
+                        expected: SCORE_BITS,
+                    },
+                )?;
+                Ok(balance as u16 as Score)
+            })
+            .collect()
+    }
+
+    /// Arrange the shares of all participants for [`Self::decrypt`], `None` if any is missing
+    pub fn collect_shares(
+        &self,
+        shares: &DecryptionSharesMap,
+    ) -> Option<Vec<Vec<DecryptionShare>>> {
+        (0..self.n())
+            .map(|output_id| {
+                self.participants
+                    .iter()
+                    .map(|from| shares.get(&(output_id, from.clone())).cloned())
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// `from`'s shares of every output, as [`DecryptionSharesMap`] keys them
+    pub fn share_entries<'a>(
+        &self,
+        from: &'a ParticipantId,
+        shares: &'a [DecryptionShare],
+    ) -> impl Iterator<Item = ((usize, ParticipantId), DecryptionShare)> + 'a {
+        shares
+            .iter()
+            .take(self.n())
+            .enumerate()
+            .map(move |(output_id, share)| ((output_id, from.clone()), share.clone()))
     }
 
     /// Get number of outputs
     pub fn n(&self) -> usize {
-        self.karma_balance.len()
+        self.outputs.len()
+    }
+
+    /// One output, as `/fhe_output/<output_id>` serves it
+    pub(crate) fn get(&self, output_id: usize) -> Option<FheOutput> {
+        Some(FheOutput {
+            output_id,
+            participant_id: self.owner(output_id).cloned(),
+            word: self.outputs.get(output_id)?.clone(),
+            partial: false,
+        })
+    }
+
+    /// As many words as the circuit has outputs for the participants, each as wide as a [`Score`]
+    pub fn validate(&self) -> Result<(), InvalidResponse> {
+        let expected = self.circuit.circuit().output_count(self.participants.len());
+        if self.outputs.len() != expected {
+            return Err(InvalidResponse::OutputCount {
+                outputs: self.outputs.len(),
+                expected,
+                participants: self.participants.len(),
+            });
+        }
+        match self
+            .outputs
+            .iter()
+            .position(|word| word.len() != SCORE_BITS)
+        {
+            Some(output_id) => Err(InvalidResponse::WordLength {
+                output_id,
+                bits: self.outputs[output_id].len(),
+                expected: SCORE_BITS,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// `from`'s share of output `output_id` has a part for each bit of the output
+    pub fn check_share(
+        &self,
+        output_id: usize,
+        from: &ParticipantId,
+        share: &DecryptionShare,
+    ) -> Result<(), InvalidResponse> {
+        let expected = self
+            .outputs
+            .get(output_id)
+            .ok_or(InvalidResponse::UnknownOutput { output_id })?
+            .len();
+        if share.len() != expected {
+            return Err(InvalidResponse::ShareLength {
+                output_id,
+                from: from.clone(),
+                len: share.len(),
+                expected,
+            });
+        }
+        Ok(())
+    }
+
+    /// [`Self::check_share`] of every share in `shares`
+    pub fn check_shares(&self, shares: &DecryptionSharesMap) -> Result<(), InvalidResponse> {
+        shares
+            .iter()
+            .try_for_each(|((output_id, from), share)| self.check_share(*output_id, from, share))
+    }
+}
+
+/// A response that parsed, but can't be what the server computed, e.g. one cut short on the
+/// way. Client methods return it inside their error, so it never gets as far as decryption.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum InvalidResponse {
+    #[error(
+        "The output has {outputs} words, the circuit {expected} for {participants} participants"
+    )]
+    OutputCount {
+        outputs: usize,
+        expected: usize,
+        participants: usize,
+    },
+    #[error("Output word {output_id} has {bits} bits, a score {expected}")]
+    WordLength {
+        output_id: usize,
+        bits: usize,
+        expected: usize,
+    },
+    #[error("Asked for output {expected}, got output {got}")]
+    OutputId { expected: usize, got: usize },
+    #[error("There is no output {output_id}")]
+    UnknownOutput { output_id: usize },
+    #[error("The decryption share of {from} for output {output_id} has {len} parts, the output has {expected} bits")]
+    ShareLength {
+        output_id: usize,
+        from: ParticipantId,
+        len: usize,
+        expected: usize,
+    },
+    #[error("Expected the decryption shares of {participants} participants for each of {outputs} outputs")]
+    ShareCount { outputs: usize, participants: usize },
+}
+
+/// A single output of the run, available as soon as it's computed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FheOutput {
+    pub output_id: usize,
+    /// Whose output it is, see [`CircuitOutput::owner`]
+    pub participant_id: Option<ParticipantId>,
+    word: Word,
+    /// The run is still computing other outputs
+    pub partial: bool,
+}
+
+impl FheOutput {
+    /// Same as the share [`CircuitOutput::gen_decryption_shares`] makes for this output
+    pub fn gen_decryption_share(&self, ck: &ClientKey) -> DecryptionShare {
+        gen_decryption_shares(ck, &self.word)
     }
 }
 
@@ -110,7 +374,12 @@ fn gen_decryption_shares(ck: &ClientKey, fhe_output: &Word) -> DecryptionShare {
     dec_shares
 }
 
-fn decrypt_word(ck: &ClientKey, fhe_output: &Word, shares: &[DecryptionShare]) -> PlainWord {
+/// `None` unless the word has `N` bits, and every share a part for each of them
+fn decrypt_word<const N: usize>(
+    ck: &ClientKey,
+    fhe_output: &Word,
+    shares: &[DecryptionShare],
+) -> Option<u64> {
     // A DecryptionShare is user i's contribution to word j.
     // To decrypt word j at bit position k. We need to extract the position k of user i's share.
     let decrypted_bits = fhe_output
@@ -119,12 +388,13 @@ fn decrypt_word(ck: &ClientKey, fhe_output: &Word, shares: &[DecryptionShare]) -
         .map(|(bit_k, fhe_bit)| {
             let shares_for_bit_k = shares
                 .iter()
-                .map(|user_share| user_share[bit_k])
-                .collect_vec();
-            ck.aggregate_decryption_shares(fhe_bit, &shares_for_bit_k)
+                .map(|user_share| user_share.get(bit_k).copied())
+                .collect::<Option<Vec<_>>>()?;
+            Some(ck.aggregate_decryption_shares(fhe_bit, &shares_for_bit_k))
         })
-        .collect_vec();
-    recover(&decrypted_bits) as i16
+        .collect::<Option<Vec<_>>>()?;
+    let decrypted_bits: [bool; N] = decrypted_bits.try_into().ok()?;
+    Some(recover(&decrypted_bits))
 }
 
 #[derive(Debug, Error)]
@@ -135,30 +405,268 @@ pub(crate) enum Error {
     UnregisteredUser { user_id: usize },
     #[error("The ciphertext from user #{user_id} not found")]
     CipherNotFound { user_id: UserId },
-    #[error("Decryption share of {output_id} from user {user_id} not found")]
-    DecryptionShareNotFound { output_id: usize, user_id: UserId },
+    #[error("Decryption share of output {output_id} from {participant_id} not found")]
+    DecryptionShareNotFound {
+        output_id: usize,
+        participant_id: ParticipantId,
+    },
+    #[error("Participant {participant_id} is unregistered")]
+    UnknownParticipant { participant_id: ParticipantId },
     /// Temporary here
     #[error("Output not ready")]
     OutputNotReady,
+    #[error("Submission deadline {deadline} has passed")]
+    DeadlinePassed { deadline: Timestamp },
+    #[error("Proposed deadline {proposed} must be later than the current deadline {current}")]
+    DeadlineNotExtended {
+        proposed: Timestamp,
+        current: Timestamp,
+    },
+    #[error(
+        "The submission deadline is already {current}, only a majority of users can extend it"
+    )]
+    DeadlineAlreadySet { current: Timestamp },
+    #[error("No deadline extension is pending")]
+    NoPendingExtension,
+    #[error("Submission from user #{user_id} is quarantined: {reason}")]
+    Quarantined { user_id: UserId, reason: String },
+    #[error("Every cipher is quarantined, there's nothing to run")]
+    AllQuarantined,
+    #[error("User #{user_id} already submitted this input and the room takes no resubmissions")]
+    AlreadySubmitted { user_id: UserId },
+    #[error("Participant {participant_id} is already registered in this round")]
+    AlreadyRegistered { participant_id: ParticipantId },
+    #[error("Replacement from user #{user_id} is rejected, the previous one stands: {reason}")]
+    ReplacementRejected { user_id: UserId, reason: String },
+    #[error("Only the admin may do this, see `admin_token`")]
+    Unauthorized,
+    #[error("The request lacks the token user #{user_id} got at registration")]
+    WrongUserToken { user_id: UserId },
+    #[error("Invalid public key: {reason}")]
+    InvalidPublicKey { reason: String },
+    #[error("User #{user_id} didn't sign the submission with their key: {reason}")]
+    BadSignature { user_id: UserId, reason: String },
+    #[error("The server didn't sign this archive: {reason}")]
+    UnknownArchive { reason: String },
+    #[error("No parameter set supports {users} users")]
+    TooManyUsers { users: usize },
+    #[error("Registration takes an unused invite code from the admin")]
+    InvalidInvite,
+    #[error("This room doesn't publish decrypted results")]
+    ResultsDisabled,
+    #[error("Room #{room_id} not found")]
+    RoomNotFound { room_id: RoomId },
+    #[error("Failed to store the submission: {reason}")]
+    ArtifactStorage { reason: String },
+    #[error("FHE run in progress, force the reset to discard it")]
+    RunInProgress,
+    #[error("Illegal transition from {from} to {to}")]
+    IllegalTransition { from: String, to: String },
+    #[error("Upload session {session} not found")]
+    UploadNotFound { session: String },
+    #[error("Invalid upload: {reason}")]
+    InvalidUpload { reason: String },
+    #[error("Submission is over the {limit} limit, see `limits.submit`")]
+    PayloadTooLarge { limit: ByteUnit },
+    #[error("Too many registrations from this address, retry in {retry_after} s")]
+    TooManyRequests { retry_after: u64 },
+    #[error("Evaluating the shard failed: {reason}")]
+    ShardFailed { reason: String },
+    #[error("The server is shutting down, retry once it is back")]
+    ShuttingDown,
+    #[error("{task} failed: {reason}")]
+    TaskFailed { task: &'static str, reason: String },
+}
+
+impl Error {
+    pub(crate) fn code(&self) -> ErrorCode {
+        match self {
+            Error::WrongServerState { .. } => ErrorCode::WrongServerState,
+            Error::UnregisteredUser { .. } => ErrorCode::UnregisteredUser,
+            Error::CipherNotFound { .. } => ErrorCode::CipherNotFound,
+            Error::DecryptionShareNotFound { .. } => ErrorCode::DecryptionShareNotFound,
+            Error::UnknownParticipant { .. } => ErrorCode::UnknownParticipant,
+            Error::OutputNotReady => ErrorCode::OutputNotReady,
+            Error::DeadlinePassed { .. } => ErrorCode::DeadlinePassed,
+            Error::DeadlineNotExtended { .. } => ErrorCode::DeadlineNotExtended,
+            Error::DeadlineAlreadySet { .. } => ErrorCode::DeadlineAlreadySet,
+            Error::NoPendingExtension => ErrorCode::NoPendingExtension,
+            Error::Quarantined { .. } => ErrorCode::Quarantined,
+            Error::AllQuarantined => ErrorCode::AllQuarantined,
+            Error::AlreadySubmitted { .. } => ErrorCode::AlreadySubmitted,
+            Error::AlreadyRegistered { .. } => ErrorCode::AlreadyRegistered,
+            Error::ReplacementRejected { .. } => ErrorCode::ReplacementRejected,
+            Error::Unauthorized => ErrorCode::Unauthorized,
+            Error::WrongUserToken { .. } => ErrorCode::WrongUserToken,
+            Error::InvalidPublicKey { .. } => ErrorCode::InvalidPublicKey,
+            Error::BadSignature { .. } => ErrorCode::BadSignature,
+            Error::UnknownArchive { .. } => ErrorCode::UnknownArchive,
+            Error::TooManyUsers { .. } => ErrorCode::TooManyUsers,
+            Error::InvalidInvite => ErrorCode::InvalidInvite,
+            Error::ResultsDisabled => ErrorCode::ResultsDisabled,
+            Error::RoomNotFound { .. } => ErrorCode::RoomNotFound,
+            Error::ArtifactStorage { .. } => ErrorCode::ArtifactStorage,
+            Error::RunInProgress => ErrorCode::RunInProgress,
+            Error::IllegalTransition { .. } => ErrorCode::IllegalTransition,
+            Error::UploadNotFound { .. } => ErrorCode::UploadNotFound,
+            Error::InvalidUpload { .. } => ErrorCode::InvalidUpload,
+            Error::PayloadTooLarge { .. } => ErrorCode::PayloadTooLarge,
+            Error::TooManyRequests { .. } => ErrorCode::TooManyRequests,
+            Error::ShardFailed { .. } => ErrorCode::ShardFailed,
+            Error::ShuttingDown => ErrorCode::ShuttingDown,
+            Error::TaskFailed { .. } => ErrorCode::TaskFailed,
+        }
+    }
+
+    fn status(&self) -> Status {
+        match self {
+            Error::WrongServerState { .. }
+            | Error::CipherNotFound { .. }
+            | Error::DeadlinePassed { .. }
+            | Error::DeadlineAlreadySet { .. }
+            | Error::AllQuarantined
+            | Error::AlreadySubmitted { .. }
+            | Error::AlreadyRegistered { .. }
+            | Error::RunInProgress
+            | Error::IllegalTransition { .. }
+            | Error::TooManyUsers { .. } => Status::Conflict,
+            Error::DeadlineNotExtended { .. }
+            | Error::Quarantined { .. }
+            | Error::ReplacementRejected { .. }
+            | Error::InvalidUpload { .. }
+            | Error::InvalidPublicKey { .. } => Status::UnprocessableEntity,
+            Error::ArtifactStorage { .. }
+            | Error::ShardFailed { .. }
+            | Error::TaskFailed { .. } => Status::InternalServerError,
+            Error::DecryptionShareNotFound { .. }
+            | Error::UnregisteredUser { .. }
+            | Error::UnknownParticipant { .. }
+            | Error::OutputNotReady
+            | Error::NoPendingExtension
+            | Error::RoomNotFound { .. }
+            | Error::UploadNotFound { .. }
+            | Error::ResultsDisabled => Status::NotFound,
+            Error::Unauthorized
+            | Error::WrongUserToken { .. }
+            | Error::BadSignature { .. }
+            | Error::UnknownArchive { .. }
+            | Error::InvalidInvite => Status::Forbidden,
+            Error::PayloadTooLarge { .. } => Status::PayloadTooLarge,
+            Error::TooManyRequests { .. } => Status::TooManyRequests,
+            Error::ShuttingDown => Status::ServiceUnavailable,
+        }
+    }
+
+    /// Seconds the client should wait before it tries again
+    fn retry_after(&self) -> Option<u64> {
+        match self {
+            Error::TooManyRequests { retry_after } => Some(*retry_after),
+            _ => None,
+        }
+    }
+}
+
+/// What failed, for clients to match on rather than parse [`ErrorBody::message`].
+/// One per [`Error`], plus the failures of requests no route took.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub enum ErrorCode {
+    WrongServerState,
+    UnregisteredUser,
+    CipherNotFound,
+    DecryptionShareNotFound,
+    UnknownParticipant,
+    OutputNotReady,
+    DeadlinePassed,
+    DeadlineNotExtended,
+    DeadlineAlreadySet,
+    NoPendingExtension,
+    Quarantined,
+    AllQuarantined,
+    AlreadySubmitted,
+    AlreadyRegistered,
+    ReplacementRejected,
+    Unauthorized,
+    WrongUserToken,
+    InvalidPublicKey,
+    BadSignature,
+    UnknownArchive,
+    TooManyUsers,
+    InvalidInvite,
+    ResultsDisabled,
+    RoomNotFound,
+    ArtifactStorage,
+    RunInProgress,
+    IllegalTransition,
+    UploadNotFound,
+    InvalidUpload,
+    PayloadTooLarge,
+    TooManyRequests,
+    ShardFailed,
+    ShuttingDown,
+    /// A task of the server panicked, e.g. the decryption of the results
+    TaskFailed,
+    /// No route matches the path, e.g. one of another API version
+    NoSuchRoute,
+    /// Rocket refused the request before a route ran, e.g. a body that doesn't parse
+    BadRequest,
+    /// The server failed in a way it has no code for
+    Internal,
+    /// A code of a newer server
+    #[serde(other)]
+    Unknown,
+}
+
+/// The JSON body of every error response
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ErrorBody {
+    pub code: ErrorCode,
+    pub message: String,
+    /// Also in the `Retry-After` header
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
 }
 
-#[derive(Responder)]
-pub(crate) enum ErrorResponse {
-    #[response(status = 500, content_type = "json")]
-    ServerError(String),
-    #[response(status = 404, content_type = "json")]
-    NotFoundError(String),
+pub(crate) struct ErrorResponse {
+    status: Status,
+    body: ErrorBody,
+}
+
+impl ErrorResponse {
+    pub(crate) fn new(status: Status, code: ErrorCode, message: String) -> Self {
+        Self {
+            status,
+            body: ErrorBody {
+                code,
+                message,
+                retry_after: None,
+            },
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ErrorResponse {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let retry_after = self.body.retry_after;
+        let mut response = Response::build_from(Json(self.body).respond_to(req)?);
+        response.status(self.status);
+        if let Some(secs) = retry_after {
+            response.raw_header("Retry-After", secs.to_string());
+        }
+        response.ok()
+    }
 }
 
 impl From<Error> for ErrorResponse {
     fn from(error: Error) -> Self {
-        match error {
-            Error::WrongServerState { .. } | Error::CipherNotFound { .. } => {
-                ErrorResponse::ServerError(error.to_string())
-            }
-            Error::DecryptionShareNotFound { .. }
-            | Error::UnregisteredUser { .. }
-            | Error::OutputNotReady => ErrorResponse::NotFoundError(error.to_string()),
+        Self {
+            status: error.status(),
+            body: ErrorBody {
+                code: error.code(),
+                message: error.to_string(),
+                retry_after: error.retry_after(),
+            },
         }
     }
 }
@@ -167,6 +675,8 @@ impl From<Error> for ErrorResponse {
 pub enum ServerState {
     /// Users are allowed to join the computation
     ReadyForJoining,
+    /// Registration is closed and users commit to the hash of their cipher, see [`RoomConfig::commit_reveal`]
+    ReadyForCommitments,
     /// The number of user is determined now.
     /// We can now accept ciphertexts, which depends on the number of users.
     ReadyForInputs,
@@ -186,45 +696,566 @@ impl ServerState {
             })
         }
     }
-    fn transit(&mut self, next: Self) {
+
+    /// The protocol moves forward: joining, commitments if enabled, inputs, running, completed.
+    /// The only ways back are cancelling a run, and a run that leads to another rating.
+    fn can_transit(&self, next: &Self) -> bool {
+        matches!(
+            (self, next),
+            (ServerState::ReadyForJoining, ServerState::ReadyForInputs)
+                | (
+                    ServerState::ReadyForJoining,
+                    ServerState::ReadyForCommitments
+                )
+                | (
+                    ServerState::ReadyForCommitments,
+                    ServerState::ReadyForInputs
+                )
+                | (ServerState::ReadyForInputs, ServerState::ReadyForRunning)
+                // Reopened once the deadline dropped the users who missed it
+                | (
+                    ServerState::ReadyForInputs,
+                    ServerState::ReadyForCommitments
+                )
+                | (ServerState::ReadyForInputs, ServerState::ReadyForInputs)
+                | (ServerState::ReadyForRunning, ServerState::RunningFhe)
+                | (ServerState::RunningFhe, ServerState::CompletedFhe)
+                | (ServerState::RunningFhe, ServerState::ReadyForRunning)
+                // On to the next rating, see [`RoomConfig::ratings`]
+                | (ServerState::RunningFhe, ServerState::ReadyForCommitments)
+                | (ServerState::RunningFhe, ServerState::ReadyForInputs)
+        )
+    }
+
+    fn transit(&mut self, next: Self) -> Result<(), Error> {
+        if !self.can_transit(&next) {
+            return Err(Error::IllegalTransition {
+                from: self.to_string(),
+                to: next.to_string(),
+            });
+        }
         *self = next;
+        Ok(())
     }
 }
 
+/// An entry of the transition log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Transition {
+    pub from: ServerState,
+    pub to: ServerState,
+    pub at: Timestamp,
+}
+
+/// What a [`TranscriptEntry`] is the hash of
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub enum TranscriptArtifact {
+    Cipher,
+    ServerKeyShare,
+    DecryptionShares,
+}
+
+/// An input the server accepted, see `/transcript`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct TranscriptEntry {
+    pub round: u64,
+    pub participant_id: ParticipantId,
+    pub artifact: TranscriptArtifact,
+    /// [`crate::artifact_hash`] of the cipher, the key share, or the decryption shares alone
+    pub hash: String,
+    /// Length of their msgpack encoding. 0 in entries from before it was kept.
+    #[serde(default)]
+    pub bytes: u64,
+    pub at: Timestamp,
+}
+
 impl Display for ServerState {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "[[ {:?} ]]", self)
     }
 }
 
+pub(crate) fn now() -> Timestamp {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+/// A new submission deadline proposed by the admin, waiting for users' consent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct DeadlineExtension {
+    pub deadline: Timestamp,
+    /// Users who agreed to the new deadline
+    pub acks: Vec<UserId>,
+}
+
+/// How long each phase may stay open, in seconds. `None` waits forever.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(crate) struct PhaseTimeouts {
+    /// Registration closes on its own after this
+    pub(crate) registration: Option<u64>,
+    /// Users without a cipher by then are dropped
+    pub(crate) inputs: Option<u64>,
+    /// Users without decryption shares by then are dropped
+    pub(crate) decryption: Option<u64>,
+}
+
+impl PhaseTimeouts {
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.registration.is_some() || self.inputs.is_some() || self.decryption.is_some()
+    }
+}
+
+/// Server configuration applied to each new room
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(crate) struct RoomConfig {
+    pub(crate) timeouts: PhaseTimeouts,
+    /// Start the FHE run as soon as the last cipher arrives, without waiting for `/run`
+    pub(crate) auto_run: bool,
+    /// Users commit to the hash of their cipher before any cipher is accepted,
+    /// so nobody can adapt their scores to who else has submitted
+    pub(crate) commit_reveal: bool,
+    /// Anyone can fetch the decrypted karma from `/results` once every decryption share is in
+    #[serde(default)]
+    pub(crate) server_results: bool,
+    #[serde(default)]
+    pub(crate) resubmission: ResubmissionPolicy,
+    /// Runs per round. The outputs of each run but the last are kept encrypted and added to the
+    /// next, so only the sum of the ratings is ever decrypted. 0 and 1 decrypt every run.
+    #[serde(default)]
+    pub(crate) ratings: usize,
+    /// Every round starts with this seed rather than a random one
+    #[serde(default)]
+    pub(crate) seed: Option<Seed>,
+    /// Fixed parameters, rather than the smallest that fit the users once registration closes
+    #[serde(default)]
+    pub(crate) parameter: Option<ParameterSet>,
+    #[serde(default)]
+    pub(crate) circuit: CircuitId,
+}
+
+impl RoomConfig {
+    /// Seed of the next round
+    pub(crate) fn next_seed(&self) -> Seed {
+        self.seed.unwrap_or_else(fresh_seed)
+    }
+}
+
+/// What the server does when a user submits an input they already submitted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub enum ResubmissionPolicy {
+    /// Each of the cipher and the key share is accepted once
+    Reject,
+    /// Newer inputs replace older ones until the FHE run starts, also once every cipher is in
+    #[default]
+    ReplaceUntilRun,
+}
+
+/// Progress of the background FHE run
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct JobStatus {
+    pub keys_aggregated: bool,
+    pub outputs_computed: usize,
+    pub total_outputs: usize,
+    /// Outputs are stored and the server moved to [`ServerState::CompletedFhe`]
+    pub completed: bool,
+    /// The run was cancelled and the server moved back to [`ServerState::ReadyForRunning`]
+    pub cancelled: bool,
+    /// Why the run failed, if it did. It is also `cancelled`, and can be started again.
+    #[serde(default)]
+    pub failed: Option<String>,
+    /// The outputs were kept encrypted for the next rating, see [`RoomConfig::ratings`]
+    #[serde(default)]
+    pub carried: bool,
+    /// Outputs computed so far, in completion order. Each is at `/fhe_output/<output_id>`.
+    #[serde(default)]
+    pub ready_outputs: Vec<usize>,
+}
+
 pub(crate) type MutexServerStorage = Arc<Mutex<ServerStorage>>;
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
 pub(crate) struct ServerStorage {
+    /// Bumped on every reset, so a stale FHE run can't write into the new round
+    pub(crate) round: u64,
     pub(crate) seed: Seed,
+    pub(crate) parameter: ParameterSet,
     pub(crate) state: ServerState,
     pub(crate) users: Vec<UserRecord>,
-    pub(crate) fhe_outputs: Option<CircuitOutput>,
+    /// Spectators of the round. They send nothing and don't count towards the parameters.
+    #[serde(default)]
+    pub(crate) observers: Vec<Observer>,
+    /// Shared so readers clone a pointer under the lock rather than every output
+    pub(crate) fhe_outputs: Option<Arc<CircuitOutput>>,
+    /// Outputs of the run in progress, in [`UserId`] order, as they are computed
+    #[serde(skip)]
+    pub(crate) partial_outputs: Vec<Option<Word>>,
+    /// Encrypted outputs of the round's earlier ratings, by output ID, see [`RoomConfig::ratings`]
+    #[serde(default)]
+    pub(crate) carried: Vec<Word>,
+    /// Ratings of the round carried so far
+    #[serde(default)]
+    pub(crate) rating: usize,
+    pub(crate) config: RoomConfig,
+    /// Registration is closed automatically after this time
+    pub(crate) registration_deadline: Option<Timestamp>,
+    /// Ciphers submitted after this time are rejected
+    pub(crate) deadline: Option<Timestamp>,
+    pub(crate) deadline_extension: Option<DeadlineExtension>,
+    /// Users who haven't submitted decryption shares by this time are dropped
+    pub(crate) decryption_deadline: Option<Timestamp>,
+    /// Every state change so far, oldest first
+    pub(crate) transitions: Vec<Transition>,
+    /// Names of users the admin removed or the inputs deadline dropped, so other clients notice
+    /// the party changed
+    pub(crate) removed_users: Vec<String>,
+    /// Codes the admin handed out. Once there are any, registering takes an unused one.
+    #[serde(default)]
+    pub(crate) invites: Vec<Invite>,
+    /// Hash of every input the server accepted, oldest first. Kept across resets.
+    #[serde(default)]
+    pub(crate) transcript: Vec<TranscriptEntry>,
+    /// Audit log of every [`RoomChange`] applied so far, oldest first. Kept across resets.
+    #[serde(default)]
+    pub(crate) log: Vec<LogEntry>,
+    /// Responses of recent requests that carried an `Idempotency-Key`
+    #[serde(default)]
+    pub(crate) idempotency: IdempotencyCache,
+    /// Karma of every participant over the counted rounds. Kept across resets.
+    #[serde(default)]
+    pub(crate) karma: KarmaLedger,
+    /// Snapshots are taken only when a store is attached
+    #[serde(skip)]
+    pub(crate) store: Option<RoomStore>,
+    /// The latest dashboard, for readers that shouldn't wait on the lock
+    #[serde(skip)]
+    pub(crate) published: Option<watch::Sender<Dashboard>>,
 }
 
 impl ServerStorage {
-    pub(crate) fn new(seed: Seed) -> Self {
+    pub(crate) fn new(seed: Seed, parameter: ParameterSet) -> Self {
         Self {
+            round: 0,
             seed,
+            parameter,
             state: ServerState::ReadyForJoining,
             users: vec![],
+            observers: vec![],
             fhe_outputs: None,
+            partial_outputs: vec![],
+            carried: vec![],
+            rating: 0,
+            config: RoomConfig::default(),
+            registration_deadline: None,
+            deadline: None,
+            deadline_extension: None,
+            decryption_deadline: None,
+            transitions: vec![],
+            removed_users: vec![],
+            invites: vec![],
+            transcript: vec![],
+            log: vec![],
+            idempotency: IdempotencyCache::default(),
+            karma: KarmaLedger::default(),
+            store: None,
+            published: None,
+        }
+    }
+
+    pub(crate) fn with_store(mut self, store: RoomStore) -> Self {
+        self.store = Some(store);
+        self.save();
+        self
+    }
+
+    /// Arm the registration deadline now, the others when their phase begins
+    pub(crate) fn with_config(mut self, config: RoomConfig) -> Self {
+        self.config = config;
+        self.registration_deadline = config.timeouts.registration.map(|secs| now() + secs);
+        self
+    }
+
+    /// Drop everything of the current round and start over at [`ServerState::ReadyForJoining`]
+    pub(crate) fn reset(&mut self, seed: Seed) {
+        self.apply(RoomChange::RoundStarted {
+            round: self.round + 1,
+            seed,
+        });
+        self.save();
+    }
+
+    /// Apply `change` and append it to the audit log, see [`crate::history`]. Only the changes
+    /// the log records go through here. Callers snapshot afterwards, as some changes come with
+    /// more to save.
+    pub(crate) fn apply(&mut self, change: RoomChange) {
+        match &change {
+            RoomChange::UserJoined {
+                user_id,
+                participant_id,
+                name,
+            } => self.users.push(UserRecord {
+                id: *user_id,
+                participant_id: participant_id.clone(),
+                name: name.clone(),
+                contact: None,
+                commitment: None,
+                token: None,
+                public_key: None,
+                signatures: vec![],
+                storage: UserStorage::default(),
+            }),
+            RoomChange::ObserverJoined { name } => self.observers.push(Observer {
+                id: self.observers.len(),
+                name: name.clone(),
+            }),
+            RoomChange::UserRemoved { user_id, .. } | RoomChange::UserDropped { user_id } => {
+                let removed = self.users.remove(*user_id);
+                removed.storage.get_inputs().discard();
+                for (id, user) in self.users.iter_mut().enumerate() {
+                    user.id = id;
+                    user.storage.get_inputs().discard();
+                    user.storage = UserStorage::default();
+                    user.commitment = None;
+                }
+                if let Some(extension) = self.deadline_extension.as_mut() {
+                    extension.acks = extension
+                        .acks
+                        .iter()
+                        .filter(|&ack| ack != user_id)
+                        .map(|&ack| if ack > *user_id { ack - 1 } else { ack })
+                        .collect_vec();
+                }
+                self.removed_users.push(removed.name);
+            }
+            RoomChange::Committed { user_id, hash } => {
+                self.users[*user_id].commitment = Some(hash.clone());
+            }
+            RoomChange::InputAccepted {
+                participant_id,
+                artifact,
+                hash,
+                bytes,
+            } => self.transcript.push(TranscriptEntry {
+                round: self.round,
+                participant_id: participant_id.clone(),
+                artifact: *artifact,
+                hash: hash.clone(),
+                bytes: *bytes,
+                at: now(),
+            }),
+            RoomChange::StateChanged { from, to } => {
+                self.state = to.clone();
+                match self.state {
+                    // Commitments reopen after the inputs deadline dropped some users, see
+                    // [`Self::enforce_deadlines`]
+                    ServerState::ReadyForCommitments => {
+                        self.deadline = None;
+                        self.deadline_extension = None;
+                    }
+                    ServerState::ReadyForInputs => {
+                        self.deadline = self.config.timeouts.inputs.map(|secs| now() + secs);
+                        self.deadline_extension = None;
+                    }
+                    ServerState::CompletedFhe => {
+                        self.decryption_deadline =
+                            self.config.timeouts.decryption.map(|secs| now() + secs);
+                        // Left out of the run, but their key is part of the server key
+                        for user in self.users.iter_mut() {
+                            if let UserStorage::Quarantined { .. } = user.storage {
+                                user.storage = UserStorage::DecryptionShare(None);
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+                self.transitions.push(Transition {
+                    from: from.clone(),
+                    to: to.clone(),
+                    at: now(),
+                });
+            }
+            RoomChange::DeadlineSet { deadline } => self.deadline = Some(*deadline),
+            RoomChange::DeadlineExtended { deadline } => {
+                self.deadline = Some(*deadline);
+                self.deadline_extension = None;
+            }
+            RoomChange::ResultsCounted { round, karma } => self.karma.count(*round, karma),
+            RoomChange::RatingCarried { rating } => {
+                self.rating = *rating;
+                for user in self.users.iter_mut() {
+                    user.storage = UserStorage::default();
+                    user.commitment = None;
+                }
+            }
+            RoomChange::RoundStarted { round, seed } => {
+                *self = Self {
+                    round: *round,
+                    transcript: std::mem::take(&mut self.transcript),
+                    log: std::mem::take(&mut self.log),
+                    karma: std::mem::take(&mut self.karma),
+                    store: self.store.take(),
+                    published: self.published.take(),
+                    ..Self::new(*seed, self.config.parameter.unwrap_or_default())
+                        .with_config(self.config)
+                }
+            }
+        }
+        self.log.push(LogEntry {
+            seq: self.log.len() as u64,
+            round: self.round,
+            at: now(),
+            change,
+        });
+    }
+
+    /// Snapshot the room, except users' submissions, and publish the change
+    pub(crate) fn save(&self) {
+        if let Some(store) = &self.store {
+            store.save_room(self);
+        }
+        self.publish();
+    }
+
+    /// Snapshot a user's submission, and publish the change
+    pub(crate) fn save_user(&self, user_id: UserId) {
+        if let (Some(store), Some(user)) = (&self.store, self.users.get(user_id)) {
+            store.save_user(user);
+        }
+        self.publish();
+    }
+
+    fn publish(&self) {
+        if let Some(published) = &self.published {
+            published.send_modify(|dashboard| {
+                let version = dashboard.version.wrapping_add(1);
+                *dashboard = self.get_dashboard();
+                dashboard.version = version;
+            });
         }
     }
 
     pub(crate) fn add_user(&mut self, name: &str) -> RegisteredUser {
+        self.add_participant(name, ParticipantId::random())
+    }
+
+    /// Register someone who watches the round without taking part. Unlike users, observers
+    /// may join in any phase.
+    pub(crate) fn add_observer(&mut self, name: &str) -> Observer {
+        self.apply(RoomChange::ObserverJoined {
+            name: name.to_string(),
+        });
+        self.save();
+        self.observers.last().cloned().expect("Just added")
+    }
+
+    /// Register a user who signs with `public_key`. The [`ParticipantId`] is derived from it,
+    /// so a returning user keeps theirs, and their lifetime karma.
+    pub(crate) fn add_returning_user(
+        &mut self,
+        name: &str,
+        public_key: &str,
+    ) -> Result<RegisteredUser, Error> {
+        let participant_id = ParticipantId::of_key(public_key);
+        if self
+            .users
+            .iter()
+            .any(|user| user.participant_id == participant_id)
+        {
+            return Err(Error::AlreadyRegistered { participant_id });
+        }
+        let mut user = self.add_participant(name, participant_id);
+        self.users[user.id].public_key = Some(public_key.to_string());
+        self.save();
+        user.public_key = Some(public_key.to_string());
+        user.lifetime_karma = self.karma.lifetime_karma(&user.participant_id);
+        Ok(user)
+    }
+
+    /// Add the round's decrypted results to the karma ledger, once per round
+    pub(crate) fn count_results(&mut self, round: u64, karma: Vec<(ParticipantId, Score)>) {
+        if round != self.round || self.karma.is_counted(round) {
+            return;
+        }
+        self.apply(RoomChange::ResultsCounted { round, karma });
+        self.save();
+    }
+
+    fn add_participant(&mut self, name: &str, participant_id: ParticipantId) -> RegisteredUser {
         let user_id: usize = self.users.len();
-        self.users.push(UserRecord {
-            id: user_id,
+        let token = UserAuth::new_secret();
+        self.apply(RoomChange::UserJoined {
+            user_id,
+            participant_id: participant_id.clone(),
             name: name.to_string(),
-            storage: UserStorage::Empty,
         });
-        RegisteredUser::new(user_id, name)
+        self.users[user_id].token = Some(token.clone());
+        self.save();
+        // Overwrite the submission left by a previous round's user of the same ID
+        self.save_user(user_id);
+        RegisteredUser {
+            token: Some(token),
+            ..RegisteredUser::new(user_id, participant_id, name)
+        }
+    }
+
+    /// Append to the transcript and snapshot the room. Entries are never changed or removed.
+    pub(crate) fn record(
+        &mut self,
+        participant_id: &ParticipantId,
+        artifact: TranscriptArtifact,
+        (hash, bytes): (String, u64),
+    ) {
+        self.apply(RoomChange::InputAccepted {
+            participant_id: participant_id.clone(),
+            artifact,
+            hash,
+            bytes,
+        });
+        self.save();
+    }
+
+    /// Mint `count` more invite codes, one per expected participant
+    pub(crate) fn create_invites(&mut self, count: usize) -> Result<Vec<String>, Error> {
+        self.ensure(ServerState::ReadyForJoining)?;
+        let codes = (0..count)
+            .map(|_| hex::encode(thread_rng().gen::<[u8; 8]>()))
+            .collect_vec();
+        self.invites.extend(codes.iter().map(|code| Invite {
+            code: code.to_string(),
+            used: false,
+        }));
+        self.save();
+        Ok(codes)
+    }
+
+    /// Use up `code`. Anyone may register while the admin hasn't created any codes.
+    pub(crate) fn redeem_invite(&mut self, code: Option<&str>) -> Result<(), Error> {
+        if self.invites.is_empty() {
+            return Ok(());
+        }
+        let invite = self
+            .invites
+            .iter_mut()
+            .find(|invite| !invite.used && Some(invite.code.as_str()) == code)
+            .ok_or(Error::InvalidInvite)?;
+        invite.used = true;
+        Ok(())
+    }
+
+    /// Codes nobody registered with yet, if registration takes them
+    pub(crate) fn invites_left(&self) -> Option<usize> {
+        (!self.invites.is_empty())
+            .then(|| self.invites.iter().filter(|invite| !invite.used).count())
     }
 
     pub(crate) fn ensure(&self, state: ServerState) -> Result<(), Error> {
@@ -232,8 +1263,50 @@ impl ServerStorage {
         Ok(())
     }
 
-    pub(crate) fn transit(&mut self, state: ServerState) {
-        self.state.transit(state)
+    /// Move to the next state if the transition table allows it, and log the transition
+    pub(crate) fn transit(&mut self, state: ServerState) -> Result<(), Error> {
+        self.state.clone().transit(state.clone())?;
+        self.apply(RoomChange::StateChanged {
+            from: self.state.clone(),
+            to: state,
+        });
+        self.save();
+        Ok(())
+    }
+
+    /// Settle the parameters for the users there are, and move on to commitments or straight to
+    /// inputs, whichever comes after registration in this room
+    pub(crate) fn close_registration(&mut self) -> Result<(), Error> {
+        let users = self.users.len();
+        let parameter = match self.config.parameter {
+            Some(parameter) => Some(parameter).filter(|p| users <= p.max_parties()),
+            None => ParameterSet::for_parties(users),
+        };
+        self.parameter = parameter.ok_or(Error::TooManyUsers { users })?;
+        self.take_scores()
+    }
+
+    fn take_scores(&mut self) -> Result<(), Error> {
+        if self.config.commit_reveal {
+            self.transit(ServerState::ReadyForCommitments)
+        } else {
+            self.transit(ServerState::ReadyForInputs)
+        }
+    }
+
+    /// Ratings of the round still to come after the current one
+    pub(crate) fn ratings_left(&self) -> usize {
+        self.config.ratings.saturating_sub(self.rating + 1)
+    }
+
+    /// Keep the outputs of the run, which include the earlier ratings, for the next rating.
+    /// Users keep their keys and send new scores.
+    pub(crate) fn carry_rating(&mut self, outputs: Vec<Word>) -> Result<(), Error> {
+        self.carried = outputs;
+        self.apply(RoomChange::RatingCarried {
+            rating: self.rating + 1,
+        });
+        self.take_scores()
     }
 
     pub(crate) fn get_user(&mut self, user_id: UserId) -> Result<&mut UserRecord, Error> {
@@ -242,53 +1315,548 @@ impl ServerStorage {
             .ok_or(Error::UnregisteredUser { user_id })
     }
 
-    pub(crate) fn check_cipher_submission(&self) -> bool {
+    pub(crate) fn get_participant(
+        &mut self,
+        participant_id: &ParticipantId,
+    ) -> Result<&mut UserRecord, Error> {
+        self.users
+            .iter_mut()
+            .find(|user| &user.participant_id == participant_id)
+            .ok_or_else(|| Error::UnknownParticipant {
+                participant_id: participant_id.clone(),
+            })
+    }
+
+    /// Participants in [`UserId`] order
+    pub(crate) fn participant_ids(&self) -> Vec<ParticipantId> {
         self.users
             .iter()
-            .all(|user| matches!(user.storage, UserStorage::CipherSks(..)))
+            .map(|user| user.participant_id.clone())
+            .collect_vec()
+    }
+
+    /// Whether the user may submit a cipher and/or a key share now, see [`RoomConfig::resubmission`]
+    pub(crate) fn ensure_accepts_inputs(
+        &self,
+        user_id: UserId,
+        cipher: bool,
+        key_share: bool,
+    ) -> Result<(), Error> {
+        let replaceable = self.config.resubmission == ResubmissionPolicy::ReplaceUntilRun;
+        if !(replaceable && self.state == ServerState::ReadyForRunning) {
+            self.ensure(ServerState::ReadyForInputs)?;
+        }
+        if !replaceable {
+            let user = self
+                .users
+                .get(user_id)
+                .ok_or(Error::UnregisteredUser { user_id })?;
+            let held = user.storage.get_inputs();
+            if (cipher && held.cipher.is_some()) || (key_share && held.sks.is_some()) {
+                return Err(Error::AlreadySubmitted { user_id });
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn ensure_before_deadline(&self) -> Result<(), Error> {
+        match self.deadline {
+            Some(deadline) if now() > deadline => Err(Error::DeadlinePassed { deadline }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Set the submission deadline of a rating that has none, from the config or the admin.
+    /// Once it's set, only [`Self::propose_deadline_extension`] moves it.
+    pub(crate) fn set_deadline(&mut self, deadline: Timestamp) -> Result<(), Error> {
+        if self.state != ServerState::ReadyForInputs {
+            return Err(Error::WrongServerState {
+                expect: ServerState::ReadyForInputs.to_string(),
+                got: self.state.to_string(),
+            });
+        }
+        if let Some(current) = self.deadline {
+            return Err(Error::DeadlineAlreadySet { current });
+        }
+        if deadline <= now() {
+            return Err(Error::DeadlinePassed { deadline });
+        }
+        self.apply(RoomChange::DeadlineSet { deadline });
+        self.save();
+        Ok(())
     }
 
-    pub(crate) fn get_ciphers_and_sks(
+    /// Propose a later deadline. It replaces any pending proposal and takes effect once a majority acks.
+    pub(crate) fn propose_deadline_extension(
         &mut self,
-    ) -> Result<(Vec<ServerKeyShare>, Vec<EncryptedInput>), Error> {
-        let mut server_key_shares = vec![];
-        let mut ciphers = vec![];
-        for (user_id, user) in self.users.iter_mut().enumerate() {
-            if let Some((cipher, sks)) = user.storage.get_cipher_sks() {
-                server_key_shares.push(sks.clone());
-                ciphers.push(cipher.clone());
-                user.storage = UserStorage::DecryptionShare(None);
+        deadline: Timestamp,
+    ) -> Result<&DeadlineExtension, Error> {
+        if let Some(current) = self.deadline {
+            if deadline <= current {
+                return Err(Error::DeadlineNotExtended {
+                    proposed: deadline,
+                    current,
+                });
+            }
+        }
+        self.deadline_extension = Some(DeadlineExtension {
+            deadline,
+            acks: vec![],
+        });
+        self.save();
+        Ok(self.deadline_extension.as_ref().expect("just inserted"))
+    }
+
+    /// Record a user's consent. Returns the new deadline if this ack reached the majority.
+    pub(crate) fn ack_deadline_extension(
+        &mut self,
+        user_id: UserId,
+    ) -> Result<Option<Timestamp>, Error> {
+        self.get_user(user_id)?;
+        let total_users = self.users.len();
+        let extension = self
+            .deadline_extension
+            .as_mut()
+            .ok_or(Error::NoPendingExtension)?;
+        if !extension.acks.contains(&user_id) {
+            extension.acks.push(user_id);
+        }
+        if extension.acks.len() * 2 > total_users {
+            let deadline = extension.deadline;
+            self.apply(RoomChange::DeadlineExtended { deadline });
+            self.save();
+            Ok(Some(deadline))
+        } else {
+            self.save();
+            Ok(None)
+        }
+    }
+
+    /// Remove a user before the run and return their name. The others are re-indexed, so any key share, cipher
+    /// or commitment submitted so far is discarded: they depend on the number of users and their IDs.
+    pub(crate) fn remove_user(&mut self, user_id: UserId) -> Result<String, Error> {
+        let removable = match self.state {
+            ServerState::ReadyForJoining | ServerState::ReadyForCommitments => true,
+            // Ciphers must match the commitments, which can't be made again once revealing
+            ServerState::ReadyForInputs => !self.config.commit_reveal,
+            _ => false,
+        };
+        if !removable {
+            return Err(Error::WrongServerState {
+                expect: format!(
+                    "{}, {} or {} without commitments",
+                    ServerState::ReadyForJoining,
+                    ServerState::ReadyForCommitments,
+                    ServerState::ReadyForInputs
+                ),
+                got: self.state.to_string(),
+            });
+        }
+        let name = self.get_user(user_id)?.name.clone();
+        self.apply(RoomChange::UserRemoved {
+            user_id,
+            name: name.clone(),
+        });
+        self.save();
+        for id in 0..self.users.len() {
+            self.save_user(id);
+        }
+        Ok(name)
+    }
+
+    /// An output of the completed run, or of the run in progress once it's computed
+    pub(crate) fn get_fhe_output(&self, output_id: usize) -> Result<FheOutput, Error> {
+        if let Some(outputs) = &self.fhe_outputs {
+            return outputs.get(output_id).ok_or(Error::OutputNotReady);
+        }
+        let word = self
+            .partial_outputs
+            .get(output_id)
+            .cloned()
+            .flatten()
+            .ok_or(Error::OutputNotReady)?;
+        let owner = self.config.circuit.circuit().output_owner(output_id);
+        Ok(FheOutput {
+            output_id,
+            participant_id: owner
+                .and_then(|user_id| Some(self.users.get(user_id)?.participant_id.clone())),
+            word,
+            partial: true,
+        })
+    }
+
+    /// Every decryption share submitted so far, except those `except` made
+    pub(crate) fn get_decryption_shares(
+        &self,
+        except: Option<UserId>,
+    ) -> Result<DecryptionSharesMap, Error> {
+        let outputs = self.fhe_outputs.as_ref().ok_or(Error::OutputNotReady)?;
+        if let Some(user_id) = except {
+            self.users
+                .get(user_id)
+                .ok_or(Error::UnregisteredUser { user_id })?;
+        }
+        let mut map = DecryptionSharesMap::new();
+        for user in self.users.iter().filter(|user| Some(user.id) != except) {
+            if let UserStorage::DecryptionShare(Some(shares)) = &user.storage {
+                map.extend(outputs.share_entries(&user.participant_id, shares));
+            }
+        }
+        Ok(map)
+    }
+
+    pub(crate) fn get_decryption_status(&self) -> Result<DecryptionStatus, Error> {
+        let outputs = self.fhe_outputs.as_ref().ok_or(Error::OutputNotReady)?;
+        let users = self
+            .users
+            .iter()
+            .map(|user| {
+                let shares = match &user.storage {
+                    UserStorage::DecryptionShare(Some(shares)) => shares.len(),
+                    _ => 0,
+                };
+                UserShareStatus {
+                    name: user.name.clone(),
+                    participant_id: user.participant_id.clone(),
+                    submitted: (0..outputs.n())
+                        .map(|output_id| output_id < shares)
+                        .collect(),
+                }
+            })
+            .collect_vec();
+        Ok(DecryptionStatus {
+            outputs: (0..outputs.n())
+                .map(|output_id| outputs.owner(output_id).cloned())
+                .collect(),
+            users,
+        })
+    }
+
+    pub(crate) fn check_commitments(&self) -> bool {
+        self.users.iter().all(|user| user.commitment.is_some())
+    }
+
+    /// Every user is ready to run, and at least one cipher isn't quarantined
+    pub(crate) fn check_cipher_submission(&self) -> bool {
+        self.users.iter().all(|user| user.storage.is_ready_to_run())
+            && self
+                .users
+                .iter()
+                .any(|user| user.storage.has_complete_inputs())
+    }
+
+    /// Handles of every user's inputs, in [`UserId`] order. A quarantined user has no cipher
+    /// there, so the run leaves them out, and keeps their storage until the run completes.
+    pub(crate) fn get_ciphers_and_sks(&mut self) -> Result<Vec<UserInputs>, Error> {
+        if let Some(user_id) = self
+            .users
+            .iter()
+            .position(|user| !user.storage.is_ready_to_run())
+        {
+            return Err(Error::CipherNotFound { user_id });
+        }
+        let ciphers_and_sks = self
+            .users
+            .iter()
+            .map(|user| user.storage.get_inputs())
+            .collect_vec();
+        if ciphers_and_sks.iter().all(|inputs| inputs.cipher.is_none()) {
+            return Err(Error::AllQuarantined);
+        }
+        for user in self.users.iter_mut() {
+            if let UserStorage::Quarantined { reason, .. } = &user.storage {
+                info!(
+                    user_id = user.id,
+                    reason, "Leaving a quarantined cipher out of the run"
+                );
             } else {
-                return Err(Error::CipherNotFound { user_id });
+                user.storage = UserStorage::DecryptionShare(None);
+            }
+        }
+        Ok(ciphers_and_sks)
+    }
+
+    /// Undo [`Self::get_ciphers_and_sks`] for a cancelled run
+    pub(crate) fn restore_ciphers_and_sks(&mut self, ciphers_and_sks: Vec<UserInputs>) {
+        for (user, inputs) in self.users.iter_mut().zip(ciphers_and_sks) {
+            // Quarantined users kept their storage through the run
+            if inputs.cipher.is_some() {
+                user.storage = UserStorage::Inputs(inputs);
             }
         }
-        Ok((server_key_shares, ciphers))
+    }
+
+    /// Snapshot a completed session for the archive
+    pub(crate) fn get_archive(&self) -> Result<SessionArchive, Error> {
+        self.ensure(ServerState::CompletedFhe)?;
+        let meta = ArchiveMeta {
+            seed: self.seed,
+            parameter: self.parameter,
+            archived_at: now(),
+            users: self
+                .users
+                .iter()
+                .map(|user| (user.id, user.name.to_string()))
+                .collect_vec(),
+        };
+        let decryption_shares = self
+            .users
+            .iter()
+            .map(|user| match &user.storage {
+                UserStorage::DecryptionShare(shares) => shares.clone(),
+                _ => None,
+            })
+            .collect_vec();
+        Ok(SessionArchive {
+            meta,
+            fhe_output: self
+                .fhe_outputs
+                .as_deref()
+                .cloned()
+                .expect("Should exist after CompletedFhe"),
+            decryption_shares,
+        })
+    }
+
+    /// Act on the deadline of the current phase once it passes. Returns whether anything changed.
+    pub(crate) fn enforce_deadlines(&mut self, now: Timestamp) -> bool {
+        let passed = |deadline: Option<Timestamp>| deadline.is_some_and(|d| now > d);
+        match self.state {
+            ServerState::ReadyForJoining
+                if passed(self.registration_deadline) && !self.users.is_empty() =>
+            {
+                info!("Registration deadline passed, closing registration");
+                self.close_registration()
+                    .expect("ReadyForJoining → ReadyForCommitments or ReadyForInputs");
+                true
+            }
+            ServerState::ReadyForInputs if passed(self.deadline) => self.drop_missing_inputs(),
+            ServerState::CompletedFhe if passed(self.decryption_deadline) => {
+                let missing = self
+                    .users
+                    .iter()
+                    .filter(|user| matches!(user.storage, UserStorage::DecryptionShare(None)))
+                    .count();
+                if missing == 0 {
+                    return false;
+                }
+                // Decrypting takes the share of every user
+                warn!(missing, "Decryption deadline passed, the round failed");
+                self.fail_round();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Remove the users who missed the inputs deadline. The inputs of the others depend on the
+    /// users and their IDs, so they commit or submit again before a fresh deadline. With nobody
+    /// left, or outputs carried from earlier ratings of the old party, the round fails instead.
+    fn drop_missing_inputs(&mut self) -> bool {
+        let missing = self
+            .users
+            .iter()
+            .filter(|user| !user.storage.is_ready_to_run())
+            .map(|user| user.id)
+            .collect_vec();
+        if missing.is_empty() {
+            return false;
+        }
+        // From the last, so the IDs of the ones still to drop stay put
+        for &user_id in missing.iter().rev() {
+            info!(
+                user_id,
+                name = self.users[user_id].name,
+                "User missed the deadline and is dropped"
+            );
+            self.apply(RoomChange::UserDropped { user_id });
+        }
+        if self.users.is_empty() || self.rating > 0 {
+            warn!("Inputs deadline passed, the round failed");
+            self.fail_round();
+            return true;
+        }
+        self.take_scores()
+            .expect("ReadyForInputs → ReadyForCommitments or ReadyForInputs");
+        for user_id in 0..self.users.len() {
+            self.save_user(user_id);
+        }
+        true
+    }
+
+    /// Give up on the round and start the next, discarding the inputs still held
+    fn fail_round(&mut self) {
+        for user in self.users.iter() {
+            user.storage.get_inputs().discard();
+        }
+        self.reset(self.config.next_seed());
     }
 
     pub(crate) fn get_dashboard(&self) -> Dashboard {
-        Dashboard::new(&self.state, &self.users.iter().map_into().collect_vec())
+        Dashboard::new(self)
     }
 }
 
-#[derive(Debug)]
+/// Someone watching the round, see `/rooms/<room_id>/register_observer`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Observer {
+    pub id: usize,
+    pub name: String,
+}
+
+/// Admits one user to the room, see [`ServerStorage::redeem_invite`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(crate) struct Invite {
+    pub(crate) code: String,
+    pub(crate) used: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
 pub(crate) struct UserRecord {
     pub(crate) id: UserId,
+    pub(crate) participant_id: ParticipantId,
     pub(crate) name: String,
+    /// Where the user serves decryption shares to peers, see [`crate::p2p`]
+    #[serde(default)]
+    pub(crate) contact: Option<String>,
+    /// Hash of the cipher the user will reveal, see [`RoomConfig::commit_reveal`]
+    #[serde(default)]
+    pub(crate) commitment: Option<String>,
+    /// Handed out once at registration. Users of older snapshots have none and aren't checked.
+    #[serde(default)]
+    pub(crate) token: Option<String>,
+    /// Hex ed25519 key the user signs submissions with, if they registered one
+    #[serde(default)]
+    pub(crate) public_key: Option<String>,
+    /// The user's signature of every submission the server accepted, so they can't deny sending it
+    #[serde(default)]
+    pub(crate) signatures: Vec<SignedArtifact>,
+    /// Snapshotted separately, see [`crate::persist::Persistence`]
+    #[serde(skip)]
     pub(crate) storage: UserStorage,
 }
 
-#[derive(Debug, Clone)]
+impl UserRecord {
+    /// The request comes from this user
+    pub(crate) fn authorize(&self, auth: &UserAuth) -> Result<(), Error> {
+        match &self.token {
+            Some(expected) if !auth.token_matches(expected) => {
+                Err(Error::WrongUserToken { user_id: self.id })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// A user who registered a key must sign every submission. The signature is for
+    /// [`Self::signatures`] once the submission is accepted, `None` for users without a key.
+    pub(crate) fn check_signature(
+        &self,
+        artifact: &str,
+        auth: &UserAuth,
+    ) -> Result<Option<SignedArtifact>, Error> {
+        let Some(public_key) = &self.public_key else {
+            return Ok(None);
+        };
+        let signature = auth.signature.clone().unwrap_or_default();
+        verify_submission(public_key, artifact, &signature).map_err(|err| Error::BadSignature {
+            user_id: self.id,
+            reason: err.to_string(),
+        })?;
+        Ok(Some(SignedArtifact {
+            artifact_hash: artifact.to_string(),
+            signature,
+        }))
+    }
+}
+
+/// A submission's [`artifact_hash`] and its author's signature of it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(crate) struct SignedArtifact {
+    pub(crate) artifact_hash: String,
+    pub(crate) signature: String,
+}
+
+/// A user's inputs to the run, kept out of memory when the server has somewhere to put them.
+/// The cipher and the key share are submitted and replaced independently.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(crate) struct UserInputs {
+    pub(crate) cipher: Option<Cold<EncryptedInput>>,
+    pub(crate) sks: Option<Cold<ServerKeyShare>>,
+    /// Bumped on every accepted submission, so clients can tell a replacement went through
+    #[serde(default)]
+    pub(crate) version: u32,
+}
+
+impl UserInputs {
+    pub(crate) fn is_complete(&self) -> bool {
+        self.cipher.is_some() && self.sks.is_some()
+    }
+
+    /// Take the parts `newer` has, discarding the ones they replace
+    pub(crate) fn update(&mut self, newer: UserInputs) {
+        if let Some(cipher) = newer.cipher {
+            self.cipher.replace(cipher).inspect(Cold::discard);
+        }
+        if let Some(sks) = newer.sks {
+            self.sks.replace(sks).inspect(Cold::discard);
+        }
+        self.version += 1;
+    }
+
+    pub(crate) fn discard(&self) {
+        self.cipher.iter().for_each(Cold::discard);
+        self.sks.iter().for_each(Cold::discard);
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
 pub(crate) enum UserStorage {
-    Empty,
-    CipherSks(EncryptedInput, Box<ServerKeyShare>),
+    Inputs(UserInputs),
+    /// A rejected cipher and the reason, along with the key share if it arrived.
+    /// The user can resubmit while the server is `ReadyForInputs`.
+    Quarantined {
+        reason: String,
+        sks: Option<Cold<ServerKeyShare>>,
+    },
     DecryptionShare(Option<Vec<DecryptionShare>>),
 }
 
+impl Default for UserStorage {
+    fn default() -> Self {
+        Self::Inputs(UserInputs::default())
+    }
+}
+
 impl UserStorage {
-    pub(crate) fn get_cipher_sks(&self) -> Option<(&EncryptedInput, &ServerKeyShare)> {
+    /// Whatever inputs the user submitted so far, also while quarantined
+    pub(crate) fn get_inputs(&self) -> UserInputs {
         match self {
-            Self::CipherSks(cipher, sks) => Some((cipher, sks)),
-            _ => None,
+            Self::Inputs(inputs) => inputs.clone(),
+            Self::Quarantined { sks, .. } => UserInputs {
+                cipher: None,
+                sks: sks.clone(),
+                version: 0,
+            },
+            _ => UserInputs::default(),
+        }
+    }
+
+    pub(crate) fn has_complete_inputs(&self) -> bool {
+        matches!(self, Self::Inputs(inputs) if inputs.is_complete())
+    }
+
+    /// Complete inputs, or a quarantined cipher with the key share in. The server key needs
+    /// every user's key share, while the quarantined cipher is left out of the run.
+    pub(crate) fn is_ready_to_run(&self) -> bool {
+        match self {
+            Self::Quarantined { sks, .. } => sks.is_some(),
+            _ => self.has_complete_inputs(),
         }
     }
 
@@ -302,8 +1870,36 @@ impl UserStorage {
     }
 }
 
-/// ([`Word`] index, user_id) -> decryption share
-pub type DecryptionSharesMap = HashMap<(usize, UserId), DecryptionShare>;
+/// (output ID, owner of the share) -> decryption share
+pub type DecryptionSharesMap = HashMap<(usize, ParticipantId), DecryptionShare>;
+
+/// Which decryption shares the server holds, see `/decryption_status`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct DecryptionStatus {
+    /// Owner of each output, by output ID, see [`CircuitOutput::owner`]
+    pub outputs: Vec<Option<ParticipantId>>,
+    pub users: Vec<UserShareStatus>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct UserShareStatus {
+    pub name: String,
+    pub participant_id: ParticipantId,
+    /// Whether the share for each output is in, in the order of [`DecryptionStatus::outputs`]
+    pub submitted: Vec<bool>,
+}
+
+impl DecryptionStatus {
+    /// Users still holding back a share of some output
+    pub fn pending(&self) -> Vec<&UserShareStatus> {
+        self.users
+            .iter()
+            .filter(|user| !user.submitted.iter().all(|&submitted| submitted))
+            .collect_vec()
+    }
+}
 
 #[derive(Serialize, Deserialize)]
 #[serde(crate = "rocket::serde")]
@@ -313,14 +1909,96 @@ pub(crate) struct InputSubmission {
     pub(crate) sks: ServerKeyShare,
 }
 
+/// Hash of the cipher a user will submit once every user has committed.
+/// `hash` is [`artifact_hash`] of the [`EncryptedInput`].
 #[derive(Serialize, Deserialize)]
 #[serde(crate = "rocket::serde")]
-pub(crate) struct DecryptionShareSubmission {
+pub(crate) struct CipherCommitment {
     pub(crate) user_id: UserId,
+    pub(crate) hash: String,
+}
+
+/// A new cipher for a user whose key share is already on the server, or about to be
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(crate) struct CipherSubmission {
+    pub(crate) user_id: UserId,
+    pub(crate) ei: EncryptedInput,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(crate) struct KeyShareSubmission {
+    pub(crate) user_id: UserId,
+    pub(crate) sks: ServerKeyShare,
+}
+
+/// Whichever of a user's inputs arrived in one request
+pub(crate) struct InputParts {
+    pub(crate) user_id: UserId,
+    pub(crate) ei: Option<EncryptedInput>,
+    pub(crate) sks: Option<ServerKeyShare>,
+}
+
+impl InputParts {
+    /// Sanity checks of the cipher against the circuit's `contract` before accepting it into
+    /// the run, see [`InputContract::check_cipher`]. With a `commitment`, the cipher must be the
+    /// one committed to.
+    pub(crate) fn validate(
+        &self,
+        contract: &InputContract,
+        commitment: Option<&str>,
+    ) -> Result<(), String> {
+        let Some(ei) = &self.ei else {
+            return Ok(());
+        };
+        contract.check_cipher(ei)?;
+        if commitment.is_some_and(|commitment| artifact_hash(ei) != commitment) {
+            return Err("Cipher doesn't match the commitment".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl From<InputSubmission> for InputParts {
+    fn from(InputSubmission { user_id, ei, sks }: InputSubmission) -> Self {
+        Self {
+            user_id,
+            ei: Some(ei),
+            sks: Some(sks),
+        }
+    }
+}
+
+impl From<CipherSubmission> for InputParts {
+    fn from(CipherSubmission { user_id, ei }: CipherSubmission) -> Self {
+        Self {
+            user_id,
+            ei: Some(ei),
+            sks: None,
+        }
+    }
+}
+
+impl From<KeyShareSubmission> for InputParts {
+    fn from(KeyShareSubmission { user_id, sks }: KeyShareSubmission) -> Self {
+        Self {
+            user_id,
+            ei: None,
+            sks: Some(sks),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(crate) struct DecryptionShareSubmission {
+    pub(crate) participant_id: ParticipantId,
     /// The user sends decryption share for each [`Word`].
     pub(crate) decryption_shares: Vec<DecryptionShare>,
 }
 
+/// The `N` least significant bits of `v`, least significant first. Panics if `v` doesn't fit in `N` bits.
 pub fn u64_to_binary<const N: usize>(v: u64) -> [bool; N] {
     assert!((v as u128) < 2u128.pow(N as u32));
     let mut result = [false; N];
@@ -332,10 +2010,12 @@ pub fn u64_to_binary<const N: usize>(v: u64) -> [bool; N] {
     result
 }
 
-pub fn recover(bits: &[bool]) -> u16 {
+/// The inverse of [`u64_to_binary`]
+pub fn recover<const N: usize>(bits: &[bool; N]) -> u64 {
+    const { assert!(N <= 64, "A word has at most 64 bits") };
     let mut out = 0;
     for (i, bit) in bits.iter().enumerate() {
-        out |= (*bit as u16) << i;
+        out |= (*bit as u64) << i;
     }
     out
 }
@@ -0,0 +1,262 @@
+use crate::limits::decode_file;
+use crate::types::{Error, UserId};
+use itertools::Itertools;
+use rand::{thread_rng, Rng};
+use rocket::data::ByteUnit;
+use rocket::serde::{Deserialize, DeserializeOwned, Serialize};
+use std::collections::HashMap;
+use std::io::{Seek, SeekFrom};
+use std::time::{Duration, Instant};
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+/// What the msgpack body of an upload decodes to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(crate) enum UploadKind {
+    /// A [`crate::types::InputSubmission`], as `/submit` takes
+    #[default]
+    Inputs,
+    /// A [`crate::types::KeyShareSubmission`], as `/submit_key_share` takes
+    KeyShare,
+}
+
+/// Opens an upload session, see `/submit/start`
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(crate) struct UploadStart {
+    /// Who uploads. Every request of the session carries their token.
+    pub(crate) user_id: UserId,
+    /// Bytes of the whole msgpack body
+    pub(crate) size: u64,
+    /// Every chunk but the last is exactly this long
+    pub(crate) chunk_size: u64,
+    #[serde(default)]
+    pub(crate) kind: UploadKind,
+    /// The body is zstd compressed and `size` counts the compressed bytes
+    #[serde(default)]
+    pub(crate) compressed: bool,
+}
+
+/// What the server holds of an upload so far. The client resumes from `chunks_received`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(crate) struct UploadProgress {
+    pub(crate) session: String,
+    pub(crate) chunks_received: u64,
+    pub(crate) total_chunks: u64,
+}
+
+/// A msgpack body arriving in chunks, so a dropped connection only costs the chunk in flight.
+/// The chunks are spooled to a temp file like a large [`crate::limits::Submission`].
+#[derive(Debug)]
+pub(crate) struct Upload {
+    start: UploadStart,
+    file: File,
+    received: u64,
+    /// When the session was opened or last took a chunk, see [`UPLOAD_IDLE_TIMEOUT`]
+    touched: Instant,
+}
+
+impl Upload {
+    /// The whole body must fit in `limit`, as a single `/submit` would
+    pub(crate) fn new(
+        start: UploadStart,
+        limit: ByteUnit,
+        file: std::fs::File,
+        now: Instant,
+    ) -> Result<Self, Error> {
+        if start.size == 0 || start.chunk_size == 0 {
+            return Err(Error::InvalidUpload {
+                reason: "Size and chunk size must be positive".to_string(),
+            });
+        }
+        if ByteUnit::from(start.size) > limit {
+            return Err(Error::PayloadTooLarge { limit });
+        }
+        Ok(Self {
+            start,
+            file: File::from_std(file),
+            received: 0,
+            touched: now,
+        })
+    }
+
+    pub(crate) fn progress(&self, session: &str) -> UploadProgress {
+        UploadProgress {
+            session: session.to_string(),
+            chunks_received: self.received.div_ceil(self.start.chunk_size),
+            total_chunks: self.start.size.div_ceil(self.start.chunk_size),
+        }
+    }
+
+    /// The user the session was opened for
+    pub(crate) fn user_id(&self) -> UserId {
+        self.start.user_id
+    }
+
+    /// Where chunk `n` goes, `None` if the server already has it. Chunks arrive in order, so
+    /// resending one is a no-op. The slot has a handle of its own on the temp file, so the chunk
+    /// is read off the network without holding the room's uploads.
+    pub(crate) async fn slot(&self, n: u64) -> Result<Option<ChunkSlot>, Error> {
+        let UploadStart {
+            size, chunk_size, ..
+        } = self.start;
+        let offset = n.saturating_mul(chunk_size);
+        if offset < self.received {
+            return Ok(None);
+        }
+        if offset > self.received {
+            return Err(Error::InvalidUpload {
+                reason: format!("Expect chunk {} but got {n}", self.received / chunk_size),
+            });
+        }
+        Ok(Some(ChunkSlot {
+            file: self.file.try_clone().await.map_err(storage_failed)?,
+            n,
+            offset,
+            len: chunk_size.min(size - offset),
+        }))
+    }
+
+    /// Count a chunk written to its slot. A slot another request filled first is a no-op.
+    pub(crate) fn commit(&mut self, slot: ChunkSlot, now: Instant) {
+        if slot.offset == self.received {
+            self.received += slot.len;
+        }
+        self.touched = now;
+    }
+
+    pub(crate) fn ensure_complete(&self) -> Result<(), Error> {
+        if self.received != self.start.size {
+            return Err(Error::InvalidUpload {
+                reason: format!("Only {} of {} B received", self.received, self.start.size),
+            });
+        }
+        Ok(())
+    }
+
+    pub(crate) fn kind(&self) -> UploadKind {
+        self.start.kind
+    }
+
+    /// Decode the body as `T`, inflating it within `limit` if it was compressed
+    pub(crate) async fn decode<T: DeserializeOwned + Send + 'static>(
+        mut self,
+        limit: ByteUnit,
+    ) -> Result<T, Error> {
+        self.file.flush().await.map_err(storage_failed)?;
+        let mut file = self.file.into_std().await;
+        file.rewind().map_err(storage_failed)?;
+        let compressed = self.start.compressed;
+        tokio::task::spawn_blocking(move || decode_file(file, compressed, limit))
+            .await
+            .expect("Decoding panicked")
+            .map_err(|(_, err)| err)
+    }
+}
+
+/// Where one chunk of an [`Upload`] goes in its temp file, see [`Upload::slot`]
+#[derive(Debug)]
+pub(crate) struct ChunkSlot {
+    file: File,
+    n: u64,
+    offset: u64,
+    len: u64,
+}
+
+impl ChunkSlot {
+    /// Stream the chunk into the temp file. It must be exactly as long as the slot.
+    pub(crate) async fn write(&mut self, chunk: impl AsyncRead + Unpin) -> Result<(), Error> {
+        let Self { n, offset, len, .. } = *self;
+        // From the offset, so a write that failed halfway is overwritten by the resent chunk
+        self.file
+            .seek(SeekFrom::Start(offset))
+            .await
+            .map_err(storage_failed)?;
+        // One byte past the slot tells a long chunk from one that fits
+        let written = tokio::io::copy(&mut chunk.take(len + 1), &mut self.file)
+            .await
+            .map_err(storage_failed)?;
+        if written > len {
+            // Don't leave the extra byte past the end of the body
+            self.file
+                .set_len(offset + len)
+                .await
+                .map_err(storage_failed)?;
+        }
+        if written > len {
+            return Err(Error::InvalidUpload {
+                reason: format!("Chunk {n} is over its {len} B"),
+            });
+        }
+        if written < len {
+            return Err(Error::InvalidUpload {
+                reason: format!("Chunk {n} should be {len} B but got {written} B"),
+            });
+        }
+        self.file.flush().await.map_err(storage_failed)
+    }
+}
+
+fn storage_failed(err: std::io::Error) -> Error {
+    Error::ArtifactStorage {
+        reason: err.to_string(),
+    }
+}
+
+/// Uploads of a room in progress, by session. Each user has at most [`MAX_UPLOADS_PER_USER`]
+/// open, and sessions idle for [`UPLOAD_IDLE_TIMEOUT`] are dropped with their temp file.
+#[derive(Debug, Default)]
+pub(crate) struct Uploads(HashMap<String, Upload>);
+
+/// The inputs and the key share may upload side by side
+pub(crate) const MAX_UPLOADS_PER_USER: usize = 2;
+
+/// A client resumes well within this, after the retries of a dropped connection
+pub(crate) const UPLOAD_IDLE_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+impl Uploads {
+    /// Returns the new session. The user's oldest session makes room for it if they have too
+    /// many, so a client that crashed mid upload can start over.
+    pub(crate) fn open(&mut self, upload: Upload, now: Instant) -> String {
+        self.drop_idle(now);
+        let user_id = upload.user_id();
+        let mut open = self
+            .0
+            .iter()
+            .filter(|(_, upload)| upload.user_id() == user_id)
+            .map(|(session, upload)| (upload.touched, session.clone()))
+            .collect_vec();
+        open.sort();
+        for (_, session) in open.iter().rev().skip(MAX_UPLOADS_PER_USER - 1) {
+            self.0.remove(session);
+        }
+        let session = hex::encode(thread_rng().gen::<[u8; 16]>());
+        self.0.insert(session.clone(), upload);
+        session
+    }
+
+    pub(crate) fn get_mut(&mut self, session: &str, now: Instant) -> Result<&mut Upload, Error> {
+        self.drop_idle(now);
+        self.0
+            .get_mut(session)
+            .ok_or_else(|| Error::UploadNotFound {
+                session: session.to_string(),
+            })
+    }
+
+    pub(crate) fn remove(&mut self, session: &str) -> Option<Upload> {
+        self.0.remove(session)
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.0.clear();
+    }
+
+    fn drop_idle(&mut self, now: Instant) {
+        self.0.retain(|_, upload| {
+            now.saturating_duration_since(upload.touched) < UPLOAD_IDLE_TIMEOUT
+        });
+    }
+}
@@ -0,0 +1,47 @@
+//! Which API a server speaks. Every route is under [`API_BASE`], except `/version`, which a
+//! client reads first so it can refuse a server it can't talk to, rather than fail later on a
+//! response it can't parse.
+use crate::circuit::ParameterSet;
+use anyhow::{bail, Error};
+use rocket::serde::{Deserialize, Serialize};
+
+/// Bumped on a change of routes or bodies that clients of the previous version can't follow
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Prefix of the routes of [`PROTOCOL_VERSION`]
+pub(crate) const API_BASE: &str = "/v1";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ServerVersion {
+    /// Of the `karma_calculator` crate the server was built from
+    pub crate_version: String,
+    pub protocol: u32,
+    /// The parameters every room uses, if the server fixes them. Otherwise each room picks them
+    /// when registration closes, see [`crate::Dashboard::parameter_set`].
+    pub parameter_set: Option<ParameterSet>,
+}
+
+impl ServerVersion {
+    pub(crate) fn new(parameter_set: Option<ParameterSet>) -> Self {
+        Self {
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+            protocol: PROTOCOL_VERSION,
+            parameter_set,
+        }
+    }
+
+    /// Fails unless this client speaks the server's protocol
+    pub fn check(&self) -> Result<(), Error> {
+        if self.protocol != PROTOCOL_VERSION {
+            bail!(
+                "The server speaks protocol version {} (karma_calculator {}), this client speaks version {} (karma_calculator {}). Use a client of the server's version.",
+                self.protocol,
+                self.crate_version,
+                PROTOCOL_VERSION,
+                env!("CARGO_PKG_VERSION")
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,404 @@
+//! The FHE evaluation, in the server's process or in a separate `worker` process, so a
+//! panic or OOM inside phantom_zone can't take the server down. See the `worker` config.
+use crate::circuit::{derive_server_key, evaluate_circuit, CircuitId, ParameterSet};
+use crate::client::WebClient;
+use crate::types::{EncryptedInput, ServerKeyShare, Word};
+use anyhow::{bail, ensure, Context, Error};
+use itertools::Itertools;
+use phantom_zone::set_parameter_set;
+use rocket::serde::{msgpack, Deserialize, Serialize};
+use std::io::{BufReader, Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+/// Where the server evaluates the circuit
+#[derive(Debug, Clone, Default)]
+pub(crate) enum Evaluator {
+    #[default]
+    InProcess,
+    /// Program and arguments that start a `worker` binary, possibly on another machine
+    Worker(Vec<String>),
+    /// Servers that each compute a share of the outputs, see [`shard`]
+    Workers {
+        urls: Vec<String>,
+        admin_token: Option<String>,
+    },
+}
+
+/// Everything the run needs, written to the worker's stdin as msgpack
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(crate) struct WorkerJob {
+    pub(crate) parameter: ParameterSet,
+    #[serde(default)]
+    pub(crate) circuit: CircuitId,
+    pub(crate) server_key_shares: Vec<ServerKeyShare>,
+    /// In [`crate::UserId`] order, `None` for a user whose cipher is quarantined
+    pub(crate) encrypted_inputs: Vec<Option<EncryptedInput>>,
+    /// The outputs to compute, by ID, see [`crate::circuit::Circuit::output_count`]
+    pub(crate) output_ids: Vec<usize>,
+    /// Every output of the earlier ratings of the round, see [`evaluate_circuit`]
+    #[serde(default)]
+    pub(crate) carried: Vec<Word>,
+}
+
+/// What the worker reports on its stdout as it goes, each in a length-prefixed msgpack frame
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+enum WorkerMessage {
+    KeysAggregated { ms: u128 },
+    Output { output_id: usize, output: Word },
+    Done { evaluation_ms: u128 },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(crate) struct Evaluation {
+    /// In the order of [`WorkerJob::output_ids`]
+    pub(crate) outputs: Vec<Word>,
+    pub(crate) key_aggregation_ms: u128,
+    pub(crate) evaluation_ms: u128,
+}
+
+impl Evaluator {
+    /// Blocking. `on_keys` is called once the server key is aggregated, `on_output` with each
+    /// output as it is computed. Returns `None` if `cancel` fires, or if the worker fails.
+    pub(crate) fn run(
+        &self,
+        job: WorkerJob,
+        cancel: &CancellationToken,
+        on_keys: impl FnOnce(u128) + Send,
+        on_output: impl Fn(usize, &Word) + Sync + Send,
+    ) -> Option<Evaluation> {
+        match self {
+            Self::InProcess => evaluate(job, cancel, on_keys, on_output),
+            Self::Worker(command) => run_in_worker(command, job, cancel, on_keys, on_output)
+                .unwrap_or_else(|err| {
+                    warn!("The FHE worker failed: {:?}", err);
+                    None
+                }),
+            Self::Workers { urls, admin_token } => run_on_workers(
+                urls,
+                admin_token.as_deref(),
+                job,
+                cancel,
+                on_keys,
+                on_output,
+            )
+            .unwrap_or_else(|err| {
+                warn!("A worker server failed: {:?}", err);
+                None
+            }),
+        }
+    }
+}
+
+/// Split `output_ids` into at most `workers` contiguous shares of nearly equal size
+pub(crate) fn shard(output_ids: &[usize], workers: usize) -> Vec<Vec<usize>> {
+    let size = output_ids.len().div_ceil(workers.max(1)).max(1);
+    output_ids.chunks(size).map(<[usize]>::to_vec).collect()
+}
+
+/// Send each worker the whole job but a share of the outputs, then gather the outputs in order.
+/// Each worker aggregates the server key itself. Cancelling stops waiting on the workers,
+/// though they finish their share.
+fn run_on_workers(
+    urls: &[String],
+    admin_token: Option<&str>,
+    job: WorkerJob,
+    cancel: &CancellationToken,
+    on_keys: impl FnOnce(u128),
+    on_output: impl Fn(usize, &Word),
+) -> Result<Option<Evaluation>, Error> {
+    let WorkerJob {
+        parameter,
+        circuit,
+        server_key_shares,
+        encrypted_inputs,
+        output_ids,
+        carried,
+    } = job;
+    let shards = shard(&output_ids, urls.len());
+    let (sender, receiver) = mpsc::channel();
+    let runtime = tokio::runtime::Handle::current();
+    let tasks = urls
+        .iter()
+        .zip(&shards)
+        .enumerate()
+        .map(|(index, (url, output_ids))| {
+            let client = match admin_token {
+                Some(token) => WebClient::new(url).with_admin_token(token),
+                None => WebClient::new(url),
+            };
+            let job = WorkerJob {
+                parameter,
+                circuit,
+                server_key_shares: server_key_shares.clone(),
+                encrypted_inputs: encrypted_inputs.clone(),
+                output_ids: output_ids.clone(),
+                carried: carried.clone(),
+            };
+            let sender = sender.clone();
+            runtime.spawn(async move {
+                let evaluation = client
+                    .evaluate_shard(&job)
+                    .await
+                    .with_context(|| format!("Worker {} failed", client.url()));
+                sender.send((index, evaluation)).ok();
+            })
+        })
+        .collect_vec();
+    drop((sender, server_key_shares, encrypted_inputs));
+
+    let mut on_keys = Some(on_keys);
+    let mut gathered = vec![None; shards.len()];
+    let mut key_aggregation_ms = 0;
+    let mut evaluation_ms = 0;
+    for _ in 0..shards.len() {
+        let (index, evaluation) = loop {
+            if cancel.is_cancelled() {
+                tasks.iter().for_each(|task| task.abort());
+                return Ok(None);
+            }
+            match receiver.recv_timeout(Duration::from_secs(1)) {
+                Ok(received) => break received,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => bail!("A worker task panicked"),
+            }
+        };
+        let evaluation = match evaluation {
+            Ok(evaluation) => evaluation,
+            Err(err) => {
+                tasks.iter().for_each(|task| task.abort());
+                return Err(err);
+            }
+        };
+        ensure!(
+            evaluation.outputs.len() == shards[index].len(),
+            "Worker {} sent {} outputs for {}",
+            urls[index],
+            evaluation.outputs.len(),
+            shards[index].len()
+        );
+        if let Some(on_keys) = on_keys.take() {
+            on_keys(evaluation.key_aggregation_ms);
+        }
+        for (output_id, output) in shards[index].iter().zip(&evaluation.outputs) {
+            on_output(*output_id, output);
+        }
+        key_aggregation_ms = key_aggregation_ms.max(evaluation.key_aggregation_ms);
+        evaluation_ms = evaluation_ms.max(evaluation.evaluation_ms);
+        gathered[index] = Some(evaluation.outputs);
+    }
+    let outputs = gathered.into_iter().flatten().flatten().collect();
+    Ok(Some(Evaluation {
+        outputs,
+        key_aggregation_ms,
+        evaluation_ms,
+    }))
+}
+
+/// Aggregate the key shares and evaluate the circuit on a fresh rayon pool. The parameters live
+/// in the pool's thread-local storage, so they are freed with the pool. The server key is set
+/// once per process, which is why rooms with several ratings need [`Evaluator::Worker`].
+/// The key shares are the bulk of the job, so they are dropped as soon as they are aggregated,
+/// and the ciphers once they are unpacked.
+pub(crate) fn evaluate(
+    job: WorkerJob,
+    cancel: &CancellationToken,
+    on_keys: impl FnOnce(u128) + Send,
+    on_output: impl Fn(usize, &Word) + Sync + Send,
+) -> Option<Evaluation> {
+    let WorkerJob {
+        parameter,
+        circuit,
+        server_key_shares,
+        encrypted_inputs,
+        output_ids,
+        carried,
+    } = job;
+    rayon::ThreadPoolBuilder::new()
+        .build_scoped(
+            |thread| {
+                set_parameter_set(parameter.selector());
+                thread.run()
+            },
+            |pool| {
+                pool.install(|| {
+                    // Long running, global variable change
+                    let start = Instant::now();
+                    derive_server_key(&server_key_shares);
+                    drop(server_key_shares);
+                    let key_aggregation_ms = start.elapsed().as_millis();
+                    on_keys(key_aggregation_ms);
+
+                    let contract = circuit.circuit().input_contract(encrypted_inputs.len());
+                    let cis = encrypted_inputs
+                        .iter()
+                        .enumerate()
+                        .map(|(user_id, ei)| {
+                            let Some(ei) = ei else {
+                                return vec![];
+                            };
+                            let input = ei.unpack(user_id);
+                            match contract.check_input(&input) {
+                                Ok(()) => input,
+                                Err(reason) => {
+                                    warn!(
+                                        user_id,
+                                        reason, "Leaving a malformed cipher out of the run"
+                                    );
+                                    vec![]
+                                }
+                            }
+                        })
+                        .collect_vec();
+                    drop(encrypted_inputs);
+                    let start = Instant::now();
+                    let outputs = evaluate_circuit(
+                        circuit.circuit(),
+                        &cis,
+                        &carried,
+                        &output_ids,
+                        parameter,
+                        cancel,
+                        on_output,
+                    )?;
+                    Some(Evaluation {
+                        outputs,
+                        key_aggregation_ms,
+                        evaluation_ms: start.elapsed().as_millis(),
+                    })
+                })
+            },
+        )
+        .expect("Failed to build the thread pool")
+}
+
+/// Cancelling kills the worker, though only once it reports its next step.
+/// The job is dropped once the worker has it.
+fn run_in_worker(
+    command: &[String],
+    job: WorkerJob,
+    cancel: &CancellationToken,
+    on_keys: impl FnOnce(u128),
+    on_output: impl Fn(usize, &Word),
+) -> Result<Option<Evaluation>, Error> {
+    let (program, args) = command.split_first().context("Empty worker command")?;
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to start {program}"))?;
+    let mut stdin = child.stdin.take().expect("piped");
+    let mut stdout = BufReader::new(child.stdout.take().expect("piped"));
+    let output_ids = job.output_ids.clone();
+    let bytes = msgpack::to_compact_vec(&job);
+    drop(job);
+    let sent = bytes
+        .map_err(Error::from)
+        .and_then(|bytes| Ok(stdin.write_all(&bytes)?));
+    drop(stdin);
+    if let Err(err) = sent {
+        child.kill().ok();
+        let status = child.wait()?;
+        bail!("Worker exited with {status} before taking the job: {err}");
+    }
+
+    let mut on_keys = Some(on_keys);
+    let mut key_aggregation_ms = 0;
+    let mut outputs = vec![None; output_ids.len()];
+    loop {
+        if cancel.is_cancelled() {
+            child.kill()?;
+            child.wait()?;
+            return Ok(None);
+        }
+        let message = match read_frame(&mut stdout) {
+            Ok(message) => message,
+            Err(err) => {
+                let status = child.wait()?;
+                bail!("Worker exited with {status}: {err}");
+            }
+        };
+        match message {
+            WorkerMessage::KeysAggregated { ms } => {
+                key_aggregation_ms = ms;
+                if let Some(on_keys) = on_keys.take() {
+                    on_keys(ms);
+                }
+            }
+            WorkerMessage::Output { output_id, output } => {
+                let slot = output_ids
+                    .iter()
+                    .position(|&id| id == output_id)
+                    .and_then(|index| outputs.get_mut(index))
+                    .with_context(|| format!("Worker sent unknown output #{output_id}"))?;
+                on_output(output_id, &output);
+                *slot = Some(output);
+            }
+            WorkerMessage::Done { evaluation_ms } => {
+                child.wait()?;
+                let outputs = outputs
+                    .into_iter()
+                    .collect::<Option<Vec<_>>>()
+                    .context("Worker finished without every output")?;
+                return Ok(Some(Evaluation {
+                    outputs,
+                    key_aggregation_ms,
+                    evaluation_ms,
+                }));
+            }
+        }
+    }
+}
+
+fn read_frame(input: &mut impl Read) -> Result<WorkerMessage, Error> {
+    let mut len = [0u8; 8];
+    input.read_exact(&mut len)?;
+    let mut bytes = vec![0; u64::from_be_bytes(len) as usize];
+    input.read_exact(&mut bytes)?;
+    Ok(msgpack::from_slice(&bytes)?)
+}
+
+fn write_frame(output: &mut impl Write, message: &WorkerMessage) -> Result<(), Error> {
+    let bytes = msgpack::to_compact_vec(message)?;
+    output.write_all(&(bytes.len() as u64).to_be_bytes())?;
+    output.write_all(&bytes)?;
+    output.flush()?;
+    Ok(())
+}
+
+/// The `worker` binary side: take a job from `input` and report to `output`, see [`Evaluator::Worker`].
+/// Nothing else may be written to `output`.
+pub fn run_worker(mut input: impl Read, output: impl Write + Send) -> Result<(), Error> {
+    let mut bytes = vec![];
+    input.read_to_end(&mut bytes)?;
+    let job: WorkerJob = msgpack::from_slice(&bytes).context("Invalid job")?;
+    drop(bytes);
+    let output = Mutex::new(output);
+    let send = |message: WorkerMessage| {
+        write_frame(&mut *output.lock().unwrap(), &message).expect("The server hung up");
+    };
+    let evaluation = evaluate(
+        job,
+        &CancellationToken::new(),
+        |ms| send(WorkerMessage::KeysAggregated { ms }),
+        |output_id, word| {
+            send(WorkerMessage::Output {
+                output_id,
+                output: word.clone(),
+            })
+        },
+    )
+    .expect("Never cancelled");
+    send(WorkerMessage::Done {
+        evaluation_ms: evaluation.evaluation_ms,
+    });
+    Ok(())
+}